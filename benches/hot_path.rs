@@ -0,0 +1,155 @@
+//! Benchmarks for the per-message hot path of the milter.
+//!
+//! The test PKI is only compiled for the unit tests of the library, so it is included
+//! directly, with the library's `der` module where it expects it.
+
+#![allow(dead_code)]
+
+#[path = "../src/test_pki.rs"]
+mod test_pki;
+
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use pantosmime::der;
+use pantosmime::mime_parser::MimeContainer;
+use pantosmime::{smime, transfer_encoding, DirectoryStore};
+use std::hint::black_box;
+
+/// Size of the body chunks an MTA hands to the milter.
+const CHUNK_SIZE: usize = 65535;
+
+/// Build a multipart/mixed message with `parts` base64 attachments of `part_size` bytes each.
+fn multipart_message(parts: usize, part_size: usize) -> String {
+    let attachment = transfer_encoding::encode_base64_wrapped(&vec![0x5a; part_size * 3 / 4], 76);
+    let attachment = String::from_utf8(attachment.to_vec()).unwrap();
+
+    let mut message = String::from(
+        "MIME-Version: 1.0\r\nContent-Type: multipart/mixed; boundary=\"bench-boundary\"\r\n\r\n",
+    );
+    for i in 0..parts {
+        message.push_str("--bench-boundary\r\n");
+        message.push_str("Content-Type: application/octet-stream\r\n");
+        message.push_str("Content-Transfer-Encoding: base64\r\n");
+        message.push_str(&format!(
+            "Content-Disposition: attachment; filename=\"part{}.bin\"\r\n\r\n",
+            i
+        ));
+        message.push_str(&attachment);
+        message.push_str("\r\n");
+    }
+    message.push_str("--bench-boundary--\r\n");
+    message
+}
+
+fn bench_body_accumulation(c: &mut Criterion) {
+    let mut group = c.benchmark_group("body_accumulation");
+    let chunk = vec![b'x'; CHUNK_SIZE];
+    for size in [64usize << 10, 1 << 20, 16 << 20] {
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            b.iter(|| {
+                let mut body = BytesMut::new();
+                for _ in 0..size.div_ceil(CHUNK_SIZE) {
+                    body.extend_from_slice(black_box(&chunk));
+                }
+                body
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_base64_wrap(c: &mut Criterion) {
+    let mut group = c.benchmark_group("base64_wrap");
+    for size in [64 << 10, 1 << 20, 16 << 20] {
+        let data = vec![0xa5u8; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &data, |b, data| {
+            b.iter(|| transfer_encoding::encode_base64_wrapped(black_box(data), 76))
+        });
+    }
+    group.finish();
+}
+
+fn bench_mime_parsing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mime_parse_multipart");
+    for (parts, part_size) in [(2, 64 << 10), (10, 1 << 20), (50, 1 << 20)] {
+        let message = multipart_message(parts, part_size);
+        group.throughput(Throughput::Bytes(message.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{}x{}", parts, part_size)),
+            &message,
+            |b, message| {
+                b.iter(|| MimeContainer::parse_mime_container(black_box(message)).unwrap())
+            },
+        );
+    }
+    group.finish();
+}
+
+fn bench_encrypt(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let cert_dir = tempfile::tempdir().unwrap();
+    let ca = test_pki::TestCa::new("Bench CA");
+    let recipients: Vec<String> = (0..20).map(|i| format!("rcpt{}@example.com", i)).collect();
+    for recipient in &recipients {
+        let (cert, _) = ca.issue(recipient);
+        runtime
+            .block_on(smime::write_pem_stack(
+                [&cert, &ca.cert],
+                &cert_dir.path().join(format!("{}.pem", recipient)),
+            ))
+            .unwrap();
+    }
+    let content = multipart_message(1, 100 << 10);
+
+    let mut group = c.benchmark_group("encrypt_data");
+    group.throughput(Throughput::Bytes(content.len() as u64));
+    for count in [1, 5, 20] {
+        group.bench_with_input(BenchmarkId::from_parameter(count), &count, |b, &count| {
+            b.iter(|| {
+                runtime
+                    .block_on(smime::encrypt_data(
+                        black_box(content.as_bytes()),
+                        &recipients[..count],
+                        &DirectoryStore::new(cert_dir.path()),
+                    ))
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+
+    // What a message costs once the certificates are parsed and cached, as in the daemon.
+    let certs: Vec<_> = recipients
+        .iter()
+        .map(|recipient| {
+            let chain = runtime
+                .block_on(smime::load_pem_stack(
+                    cert_dir.path().join(format!("{}.pem", recipient)),
+                ))
+                .unwrap();
+            smime::find_encryption_cert(&chain, recipient).unwrap()
+        })
+        .collect();
+    let mut group = c.benchmark_group("encrypt_for");
+    group.throughput(Throughput::Bytes(content.len() as u64));
+    for count in [1, 5, 20] {
+        group.bench_with_input(BenchmarkId::from_parameter(count), &count, |b, &count| {
+            b.iter(|| smime::encrypt_for(black_box(content.as_bytes()), &certs[..count]).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_body_accumulation,
+    bench_base64_wrap,
+    bench_mime_parsing,
+    bench_encrypt
+);
+criterion_main!(benches);
@@ -3,6 +3,9 @@
   rustPlatform,
   pkg-config,
   openssl,
+  zlib,
+  # Cargo features to build, see Cargo.toml.
+  features ? ["replay"],
   ...
 }:
 rustPlatform.buildRustPackage {
@@ -13,8 +16,11 @@ rustPlatform.buildRustPackage {
     (path: type: builtins.any (suf: hasPrefix (toString suf) path) [./src ./data ./Cargo.toml ./Cargo.lock])
     ./.;
 
+  buildNoDefaultFeatures = true;
+  buildFeatures = features;
+
   nativeBuildInputs = [pkg-config];
-  buildInputs = [openssl];
+  buildInputs = [openssl zlib];
 
   cargoLock = {
     lockFile = ./Cargo.lock;
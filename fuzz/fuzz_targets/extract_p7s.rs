@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use pantosmime::smime;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(certs) = smime::extract_certificates_from_p7s(data) {
+        let _ = smime::find_cert_for_email(&certs, "alice@example.com");
+    }
+});
@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use pantosmime::mime_parser::MimeContainer;
+
+fuzz_target!(|data: &[u8]| {
+    // The milter hands the accumulated body to the parser the same way.
+    let input = String::from_utf8_lossy(data);
+    if let Ok((_, container)) = MimeContainer::parse_mime_container(&input) {
+        // Whatever we accept, we must be able to serialize again.
+        let _ = container.to_mime_string();
+    }
+});
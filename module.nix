@@ -20,13 +20,410 @@ in {
     bindAddress = mkOption {
       type = types.str;
       default = "127.0.0.1";
-      description = "IP address on which pantosmime should listen.";
+      description = "IP address or host name on which pantosmime should listen. Host names are bound on every address they resolve to.";
     };
     port = mkOption {
       type = types.port;
       default = 22666;
       description = "Listen port for pantosmime.";
     };
+    extraListeners = mkOption {
+      type = types.listOf types.str;
+      default = [];
+      example = ["127.0.0.1:22667=encrypt-only"];
+      description = "Additional milter sockets, each optionally bound to a mode as ADDRESS=MODE.";
+    };
+
+    mode = mkOption {
+      type = types.enum ["both" "encrypt-only" "harvest-only"];
+      default = "both";
+      description = "Processing done by this instance, for dedicated instances sharing a certificate store.";
+    };
+
+    milterSkipSteps = mkOption {
+      type = types.listOf (types.enum ["connect" "helo" "data" "unknown"]);
+      default = [];
+      description = "Milter protocol steps the MTA is asked to leave out, as they are ignored anyway.";
+    };
+
+    skipUnsignedBodies = mkOption {
+      type = types.bool;
+      default = false;
+      description = "Accept messages to harvest from right after their headers unless they are multipart, sparing the transfer of bodies that can't carry a signature.";
+    };
+
+    internalNetworks = mkOption {
+      type = types.listOf types.str;
+      default = [];
+      example = ["10.0.0.0/8" "*.corp.example.com"];
+      description = "Networks or verified host name patterns of clients to consider internal. Postfix needs {client_addr} and {client_name} in milter_connect_macros.";
+    };
+
+    encryptInternalOnly = mkOption {
+      type = types.bool;
+      default = false;
+      description = "Leave mail from clients outside the internal networks unencrypted.";
+    };
+
+    harvestExternalOnly = mkOption {
+      type = types.bool;
+      default = false;
+      description = "Don't harvest certificates from mail of clients in the internal networks.";
+    };
+
+    originFromReceived = mkOption {
+      type = types.bool;
+      default = false;
+      description = "Whether to classify messages by the client in their Received headers when the MTA doesn't pass the client macros.";
+    };
+
+    idleTimeout = mkOption {
+      type = types.ints.positive;
+      default = 7210;
+      description = "Seconds after which idle milter connections are closed.";
+    };
+
+    decisionCacheTtl = mkOption {
+      type = types.ints.unsigned;
+      default = 0;
+      description = "Seconds to reuse the policy decision and recipient certificates of an envelope, 0 to disable.";
+    };
+
+    workerThreads = mkOption {
+      type = types.nullOr types.ints.positive;
+      default = null;
+      description = "Threads handling milter connections, by default one per CPU core.";
+    };
+
+    maxBlockingThreads = mkOption {
+      type = types.ints.positive;
+      default = 512;
+      description = "Largest number of threads for blocking work, like file access and OpenSSL.";
+    };
+
+    cryptoJobs = mkOption {
+      type = types.nullOr types.ints.positive;
+      default = null;
+      description = "Largest number of messages encrypted at once.";
+    };
+
+    subaddressActions = mkOption {
+      type = types.attrsOf (types.enum ["plain" "encrypt"]);
+      default = {};
+      example = {"nocrypt" = "plain";};
+      description = "Recipient subaddress tags choosing the handling of a message.";
+    };
+
+    cryptoProfiles = mkOption {
+      type = types.attrsOf types.str;
+      default = {};
+      example = {"legacy.example" = "cipher=3des";};
+      description = "Crypto profiles (cipher, AEAD, key transport, compression) for recipient domains.";
+    };
+
+    pipelines = mkOption {
+      type = types.attrsOf (types.listOf types.str);
+      default = {};
+      example = {encrypt = ["rollout" "build-entity" "encrypt" "emit-envelope" "mark-processed"];};
+      description = "Stages the encrypt, harvest and enroll actions run, in order, instead of the default ones.";
+    };
+
+    trustAnchors = mkOption {
+      type = types.attrsOf types.path;
+      default = {};
+      example = {"partner.example" = "/etc/pantosmime/partner-ca.pem";};
+      description = "CA bundles the certificates harvested from senders at a domain must be issued by.";
+    };
+
+    harvestRequireAuth = mkOption {
+      type = types.listOf (types.enum ["dmarc" "dkim" "spf"]);
+      default = [];
+      example = ["dmarc" "dkim"];
+      description = "Only harvest certificates from messages that passed one of these methods aligned with the sender.";
+    };
+
+    authservIds = mkOption {
+      type = types.listOf types.str;
+      default = [];
+      example = ["mx.example.com"];
+      description = "Authentication service identifiers whose Authentication-Results headers are trusted.";
+    };
+
+    harvestFromHeader = mkOption {
+      type = types.enum ["ignore" "same-domain" "same-address"];
+      default = "ignore";
+      description = "How closely the From header of a message must match its envelope sender for its certificates to be harvested.";
+    };
+
+    maxChainCerts = mkOption {
+      type = types.ints.positive;
+      default = 10;
+      description = "Don't store chains of more certificates than this from a signature or enrollment.";
+    };
+
+    maxChainBytes = mkOption {
+      type = types.ints.positive;
+      default = 65536;
+      description = "Don't store chains larger than this many bytes, DER encoded, from a signature or enrollment.";
+    };
+
+    compat = mkOption {
+      type = types.listOf types.str;
+      default = [];
+      example = ["disposition=inline" "legacy-content-type"];
+      description = "Workarounds for picky receiving clients, see the README.";
+    };
+
+    stripHeaders = mkOption {
+      type = types.listOf types.str;
+      default = [];
+      example = ["X-Mailer"];
+      description = "Headers to remove from encrypted messages.";
+    };
+
+    captureHeaders = mkOption {
+      type = types.listOf (types.enum ["From" "Reply-To" "To" "Cc"]);
+      default = [];
+      example = ["From" "Reply-To"];
+      description = "Address headers to copy into the encrypted entity, protecting them in transit.";
+    };
+
+    exemptCalendar = mkOption {
+      type = types.bool;
+      default = false;
+      description = "Whether to leave calendar invitations unencrypted.";
+    };
+
+    inlinePgpAction = mkOption {
+      type = types.enum ["warn" "skip"];
+      default = "warn";
+      description = "What to do with messages already encrypted with inline PGP: encrypt them with a warning, or leave them as they are.";
+    };
+
+    encryptPercent = mkOption {
+      type = types.ints.between 0 100;
+      default = 100;
+      description = "Percentage of the messages to encrypt that actually are, chosen by queue ID; the others are marked with a header.";
+    };
+
+    schedules = mkOption {
+      type = types.listOf types.str;
+      default = [];
+      example = ["2025-01-01=encrypt:partner.example" "sun 02:00-04:00=tempfail"];
+      description = "Policy changes applying within time windows, as <window>=<change>.";
+    };
+
+    missingCertAction = mkOption {
+      type = types.enum ["reject" "tempfail" "exclude"];
+      default = "reject";
+      description = "What to do with messages to recipients without a usable certificate.";
+    };
+
+    expiredCertAction = mkOption {
+      type = types.enum ["reject" "tempfail" "exclude"];
+      default = "reject";
+      description = "What to do with messages to recipients whose certificate expired, after the grace period.";
+    };
+
+    expiredCertGraceDays = mkOption {
+      type = types.ints.unsigned;
+      default = 0;
+      description = "Days after the expiry of a recipient's certificate to tempfail messages to them.";
+    };
+
+    tlsPolicy = mkOption {
+      type = types.enum ["always" "unprotected-submission" "skip-verified-tls"];
+      default = "always";
+      description = "Which messages TLS protects well enough to leave them unencrypted.";
+    };
+
+    verifiedTlsDomains = mkOption {
+      type = types.listOf types.str;
+      default = [];
+      example = ["partner.example"];
+      description = "Domains delivered to with verified TLS only, for the skip-verified-tls policy.";
+    };
+
+    enrollmentAddresses = mkOption {
+      type = types.listOf types.str;
+      default = [];
+      example = ["smime-keys@example.com"];
+      description = "Service addresses importing the certificates of certs-only messages sent to them.";
+    };
+
+    enrollmentReply = mkOption {
+      type = types.bool;
+      default = false;
+      description = "Whether to confirm imported certificates with a reply to the sender.";
+    };
+
+    reinjectionSecretFile = mkOption {
+      type = types.nullOr types.path;
+      default = null;
+      description = "File with a secret shared by all pantosmime hosts, to mark processed messages so they are never processed twice.";
+    };
+
+    resultSecretFile = mkOption {
+      type = types.nullOr types.path;
+      default = null;
+      description = "File with a secret shared with downstream filters, to add an authenticated X-Pantosmime-Result header to processed messages.";
+    };
+
+    escrowCertificate = mkOption {
+      type = types.nullOr types.path;
+      default = null;
+      description = "PEM certificate every message is also encrypted to, to recover it with `escrow decrypt` and the escrow key.";
+    };
+
+    replication = {
+      peers = lib.mkOption {
+        type = types.listOf types.str;
+        default = [];
+        example = ["standby.example.com:9467"];
+        description = "Standby hosts to copy harvested certificates to, at their replication listen address.";
+      };
+      listen = lib.mkOption {
+        type = types.nullOr types.str;
+        default = null;
+        example = "0.0.0.0:9467";
+        description = "Address to take certificates replicated by the active host on.";
+      };
+      secretFile = lib.mkOption {
+        type = types.nullOr types.path;
+        default = null;
+        description = "File with a secret shared by the replicating hosts, required for replication.";
+      };
+      interval = lib.mkOption {
+        type = types.ints.unsigned;
+        default = 3600;
+        description = "Seconds between copies of all certificates to the standby hosts, 0 disables them.";
+      };
+    };
+
+    maxMessageSize = mkOption {
+      type = types.nullOr types.ints.positive;
+      default = null;
+      description = "Reject messages larger than this many bytes.";
+    };
+
+    oversizeAction = mkOption {
+      type = types.enum ["reject" "compress" "proceed"];
+      default = "reject";
+      description = "What to do with messages exceeding maxMessageSize once encrypted: reject them, encrypt them compressed, or pass them on anyway.";
+    };
+
+    maxCmsRecipients = mkOption {
+      type = types.nullOr types.ints.positive;
+      default = null;
+      description = "Reject messages to encrypt to more than this many recipients.";
+    };
+
+    maxHeaders = mkOption {
+      type = types.ints.positive;
+      default = 10000;
+      description = "Most headers accepted per message.";
+    };
+
+    maxHeaderBytes = mkOption {
+      type = types.ints.positive;
+      default = 1048576;
+      description = "Largest total size of the headers of a message, in bytes.";
+    };
+
+    headerOverflowAction = mkOption {
+      type = types.enum ["tempfail" "pass-through"];
+      default = "tempfail";
+      description = "What to do with messages exceeding the header limits; pass-through never applies to messages to encrypt.";
+    };
+
+    normalizeBody = mkOption {
+      type = types.listOf (types.enum ["unescape-from" "unstuff-dots"]);
+      default = [];
+      description = "Artifacts the MTA leaves in message bodies to undo before encrypting them.";
+    };
+
+    deadLetterDirectory = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "/var/lib/pantosmime/dead-letters";
+      description = "Directory to save messages rejected because processing failed to, for replaying them after a fix.";
+    };
+
+    harvestSpoolDirectory = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "/var/lib/pantosmime/harvest-spool";
+      description = "Directory to keep certificate chains harvested while the certificate directory is unavailable in, until they can be stored.";
+    };
+
+    maxInFlight = mkOption {
+      type = types.nullOr types.ints.positive;
+      default = null;
+      description = "Close new milter connections while this many messages are being processed.";
+    };
+
+    maxMemory = mkOption {
+      type = types.nullOr types.ints.positive;
+      default = null;
+      description = "Close new milter connections while the process uses this many bytes of memory.";
+    };
+
+    envelopeEncoding = mkOption {
+      type = types.enum ["base64" "binary"];
+      default = "base64";
+      description = "Content transfer encoding of encrypted messages, binary requires BINARYMIME on every hop.";
+    };
+
+    base64LineLength = mkOption {
+      type = types.ints.between 4 76;
+      default = 76;
+      description = "Line length of base64 encoded messages, must be a multiple of 4.";
+    };
+
+    eventReport = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "udp://siem.example.com:5140";
+      description = "File, udp://HOST:PORT or tcp://HOST:PORT to send per-message JSON event reports to.";
+    };
+
+    watchdogSec = mkOption {
+      type = types.nullOr types.ints.positive;
+      default = 60;
+      description = "Restart pantosmime if it doesn't ping the systemd watchdog for this many seconds.";
+    };
+
+    postProcessHook = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "http://127.0.0.1:8080/hooks/smime";
+      description = "Executable or http:// webhook getting the JSON event report of each encrypted or harvested message.";
+    };
+
+    metricsListen = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "127.0.0.1:9466";
+      description = "Address to serve Prometheus metrics on. Requires a package built with the metrics feature.";
+    };
+
+    metricsMaxDomains = mkOption {
+      type = types.ints.unsigned;
+      default = 100;
+      description = "Most recipient domains to label metrics with, further ones are counted as other.";
+    };
+
+    importScanInterval = mkOption {
+      type = types.ints.unsigned;
+      default = 10;
+      description = "Seconds between scans of the import subdirectory of each certificate directory, 0 disables it.";
+    };
+
+    sandbox = mkOption {
+      type = types.bool;
+      default = false;
+      description = "Confine pantosmimed with Landlock to the files it uses, on top of the systemd hardening.";
+    };
 
     user = mkOption {
       type = types.str;
@@ -60,8 +457,177 @@ in {
 
     addresses = lib.mkOption {
       type = types.listOf types.str;
+      default = [];
       description = "List of emails to forcibly encrypt messages for.";
     };
+
+    addressFile = lib.mkOption {
+      type = types.nullOr types.path;
+      default = null;
+      description = "File with one address or wildcard pattern per line, supporting comments and includes.";
+    };
+
+    addressFileCheckInterval = lib.mkOption {
+      type = types.ints.unsigned;
+      default = 10;
+      description = "Seconds between checks of the address file and its includes for changes, which reload the addresses, 0 disables it.";
+    };
+
+    addressNormalization = lib.mkOption {
+      type = types.attrsOf types.str;
+      default = {};
+      example = {"gmail.com" = "lowercase,strip-dots";};
+      description = "Local part normalization rules (lowercase, strip-dots) per domain, applied before matching and certificate lookups.";
+    };
+
+    addressRewrites = lib.mkOption {
+      type = types.attrsOf types.str;
+      default = {};
+      example = {"old.example" = "new.example";};
+      description = "Addresses or domains handled as another one for matching and certificate lookups, e.g. during a domain migration.";
+    };
+
+    localDomain = lib.mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "example.com";
+      description = "Domain for bare local usernames and address literals; messages from or to them are accepted unchanged without one.";
+    };
+
+    certificateDirectoryOverrides = lib.mkOption {
+      type = types.attrsOf types.str;
+      default = {};
+      example = {"*@tenant.example" = "/var/lib/pantosmime/tenant";};
+      description = "Alternate certificate directories for address patterns, e.g. to keep tenants apart. Patterns are tried in alphabetical order.";
+    };
+
+    policyScript = lib.mkOption {
+      type = types.nullOr types.path;
+      default = null;
+      description = "Lua script deciding what to do with each message. Requires a package built with the lua feature.";
+    };
+
+    expiryNotifications = {
+      from = lib.mkOption {
+        type = types.nullOr types.str;
+        default = null;
+        description = "Sender address of expiry notifications, which are only sent if set.";
+      };
+      admin = lib.mkOption {
+        type = types.nullOr types.str;
+        default = null;
+        description = "Address receiving a summary of expiring and expired certificates.";
+      };
+      notifyUsers = lib.mkOption {
+        type = types.bool;
+        default = false;
+        description = "Whether to notify the owners of expiring certificates, once per certificate.";
+      };
+      days = lib.mkOption {
+        type = types.ints.unsigned;
+        default = 30;
+        description = "Warn about certificates expiring within this many days.";
+      };
+      smtpServer = lib.mkOption {
+        type = types.str;
+        default = "localhost:25";
+        description = "SMTP server to submit notifications and enrollment replies to, without TLS or authentication.";
+      };
+    };
+
+    keyRequest = {
+      from = lib.mkOption {
+        type = types.nullOr types.str;
+        default = null;
+        description = "Sender address of requests to recipients without a certificate to reply with a signed message, which are only sent if set.";
+      };
+      template = lib.mkOption {
+        type = types.nullOr types.path;
+        default = null;
+        description = "Text of the requests, with {sender} and {recipient} placeholders and optionally a Subject: first line.";
+      };
+      intervalDays = lib.mkOption {
+        type = types.ints.unsigned;
+        default = 30;
+        description = "Ask each recipient at most once within this many days.";
+      };
+    };
+
+    gatewayCertificate = lib.mkOption {
+      type = types.nullOr types.path;
+      default = null;
+      description = "PEM certificate, followed by its chain, signing the notifications and replies pantosmime sends.";
+    };
+
+    gatewayKey = lib.mkOption {
+      type = types.nullOr types.path;
+      default = null;
+      description = "PEM key of the gateway certificate, required with it.";
+    };
+
+    encryptNotifications = lib.mkOption {
+      type = types.bool;
+      default = false;
+      description = "Whether to encrypt the notifications and replies pantosmime sends to recipients with a certificate on file.";
+    };
+
+    publishGatewayCertificate = lib.mkOption {
+      type = types.bool;
+      default = false;
+      description = "Whether to attach the gateway certificate chain as a certs-only part to the notifications and replies pantosmime signs.";
+    };
+
+    templateDir = lib.mkOption {
+      type = types.nullOr types.path;
+      default = null;
+      description = "Directory with templates replacing the texts of notifications and replies, per recipient domain and language.";
+    };
+
+    templateLanguages = lib.mkOption {
+      type = types.listOf types.str;
+      default = [];
+      example = ["*.de=de" "example.at=de"];
+      description = "Language of the templates for recipients in matching domains, as DOMAIN=LANGUAGE. The first match wins.";
+    };
+
+    ldap = {
+      url = lib.mkOption {
+        type = types.nullOr types.str;
+        default = null;
+        example = "ldaps://dc.example.com";
+        description = "LDAP server to synchronize user certificates from. Requires a package built with the ldap feature.";
+      };
+      bindDn = lib.mkOption {
+        type = types.nullOr types.str;
+        default = null;
+        description = "DN to bind as, binds anonymously if null.";
+      };
+      bindPasswordFile = lib.mkOption {
+        type = types.nullOr types.path;
+        default = null;
+        description = "File containing the bind password.";
+      };
+      baseDn = lib.mkOption {
+        type = types.str;
+        default = "";
+        description = "Base DN to search for users.";
+      };
+      filter = lib.mkOption {
+        type = types.str;
+        default = "(&(mail=*)(userCertificate;binary=*))";
+        description = "Filter selecting the users to synchronize.";
+      };
+      syncInterval = lib.mkOption {
+        type = types.ints.unsigned;
+        default = 3600;
+        description = "Seconds between synchronizations.";
+      };
+      overwrite = lib.mkOption {
+        type = types.bool;
+        default = false;
+        description = "Replace harvested or imported certificates with the directory's.";
+      };
+    };
   };
   config = lib.mkIf cfg.enable {
     users.users = lib.optionalAttrs (cfg.user == "pantosmime") {
@@ -75,9 +641,14 @@ in {
       pantosmime = {};
     };
 
-    systemd.tmpfiles.rules = [
-      "d ${cfg.certificateDirectory} 750 ${cfg.user} ${cfg.group} -"
-    ];
+    systemd.tmpfiles.rules =
+      [
+        "d ${cfg.certificateDirectory} 750 ${cfg.user} ${cfg.group} -"
+        "d ${cfg.certificateDirectory}/import 750 ${cfg.user} ${cfg.group} -"
+      ]
+      ++ lib.mapAttrsToList (_: dir: "d ${dir} 750 ${cfg.user} ${cfg.group} -") cfg.certificateDirectoryOverrides
+      ++ lib.optional (cfg.deadLetterDirectory != null) "d ${cfg.deadLetterDirectory} 700 ${cfg.user} ${cfg.group} -"
+      ++ lib.optional (cfg.harvestSpoolDirectory != null) "d ${cfg.harvestSpoolDirectory} 700 ${cfg.user} ${cfg.group} -";
 
     systemd.services.pantosmime = {
       wantedBy = ["multi-user.target"];
@@ -90,8 +661,85 @@ in {
         Group = cfg.group;
         Type = "simple";
         ExecStart =
-          "${cfg.package}/bin/pantosmimed -l ${cfg.bindAddress}:${builtins.toString cfg.port} -c ${cfg.certificateDirectory} "
+          "${cfg.package}/bin/pantosmimed -l ${if lib.hasInfix ":" cfg.bindAddress then "[${cfg.bindAddress}]" else cfg.bindAddress}:${builtins.toString cfg.port} -c ${cfg.certificateDirectory} ${lib.concatMapStrings (listener: "-l '${listener}' ") cfg.extraListeners}--mode ${cfg.mode} --idle-timeout ${builtins.toString cfg.idleTimeout} --import-scan-interval ${builtins.toString cfg.importScanInterval} --envelope-encoding ${cfg.envelopeEncoding} --base64-line-length ${builtins.toString cfg.base64LineLength} --missing-cert-action ${cfg.missingCertAction} --expired-cert-action ${cfg.expiredCertAction} --expired-cert-grace-days ${builtins.toString cfg.expiredCertGraceDays} --tls-policy ${cfg.tlsPolicy} --smtp-server ${cfg.expiryNotifications.smtpServer} "
+          + lib.concatStrings (lib.mapAttrsToList (domain: rules: "--address-normalization '${domain}=${rules}' ") cfg.addressNormalization)
+          + lib.concatStrings (lib.mapAttrsToList (from: to: "--address-rewrite '${from}=${to}' ") cfg.addressRewrites)
+          + lib.optionalString (cfg.localDomain != null) "--local-domain ${cfg.localDomain} "
+          + lib.concatStrings (lib.mapAttrsToList (pattern: dir: "--certificate-directory-override '${pattern}=${dir}' ") cfg.certificateDirectoryOverrides)
+          + lib.optionalString (cfg.addressFile != null) "--address-file ${cfg.addressFile} --address-file-check-interval ${builtins.toString cfg.addressFileCheckInterval} "
+          + lib.concatStrings (lib.mapAttrsToList (tag: action: "--subaddress '${tag}=${action}' ") cfg.subaddressActions)
+          + lib.concatStrings (lib.mapAttrsToList (domain: options: "--crypto-profile '${domain}=${options}' ") cfg.cryptoProfiles)
+          + lib.concatStrings (lib.mapAttrsToList (action: stages: "--pipeline '${action}=${lib.concatStringsSep "," stages}' ") cfg.pipelines)
+          + lib.concatStrings (lib.mapAttrsToList (domain: bundle: "--trust-anchors '${domain}=${bundle}' ") cfg.trustAnchors)
+          + lib.concatMapStrings (method: "--harvest-require-auth ${method} ") cfg.harvestRequireAuth
+          + lib.concatMapStrings (id: "--authserv-id '${id}' ") cfg.authservIds
+          + "--harvest-from-header ${cfg.harvestFromHeader} "
+          + "--max-chain-certs ${builtins.toString cfg.maxChainCerts} --max-chain-bytes ${builtins.toString cfg.maxChainBytes} "
+          + lib.concatMapStrings (toggle: "--compat '${toggle}' ") cfg.compat
+          + lib.concatMapStrings (header: "--strip-header '${header}' ") cfg.stripHeaders
+          + lib.concatMapStrings (header: "--capture-header ${header} ") cfg.captureHeaders
+          + lib.optionalString cfg.exemptCalendar "--exempt-calendar "
+          + "--inline-pgp-action ${cfg.inlinePgpAction} "
+          + "--encrypt-percent ${builtins.toString cfg.encryptPercent} "
+          + lib.concatMapStrings (schedule: "--schedule '${schedule}' ") cfg.schedules
+          + lib.concatMapStrings (step: "--milter-skip-step ${step} ") cfg.milterSkipSteps
+          + lib.optionalString cfg.skipUnsignedBodies "--skip-unsigned-bodies "
+          + lib.concatMapStrings (domain: "--verified-tls-domain '${domain}' ") cfg.verifiedTlsDomains
+          + lib.concatMapStrings (address: "--enrollment-address '${address}' ") cfg.enrollmentAddresses
+          + lib.optionalString cfg.enrollmentReply "--enrollment-reply "
+          + lib.optionalString (cfg.reinjectionSecretFile != null) "--reinjection-secret-file ${cfg.reinjectionSecretFile} "
+          + lib.optionalString (cfg.resultSecretFile != null) "--result-secret-file ${cfg.resultSecretFile} "
+          + lib.optionalString (cfg.escrowCertificate != null) "--escrow-certificate ${cfg.escrowCertificate} "
+          + lib.optionalString (cfg.replication.secretFile != null) (
+            "--replication-secret-file ${cfg.replication.secretFile} --replication-interval ${builtins.toString cfg.replication.interval} "
+            + lib.concatMapStrings (peer: "--replicate-to '${peer}' ") cfg.replication.peers
+            + lib.optionalString (cfg.replication.listen != null) "--replication-listen ${cfg.replication.listen} "
+          )
+          + lib.optionalString (cfg.maxMessageSize != null) "--max-message-size ${builtins.toString cfg.maxMessageSize} --oversize-action ${cfg.oversizeAction} "
+          + lib.optionalString (cfg.maxCmsRecipients != null) "--max-cms-recipients ${builtins.toString cfg.maxCmsRecipients} "
+          + "--max-blocking-threads ${builtins.toString cfg.maxBlockingThreads} --decision-cache-ttl ${builtins.toString cfg.decisionCacheTtl} "
+          + "--max-headers ${builtins.toString cfg.maxHeaders} --max-header-bytes ${builtins.toString cfg.maxHeaderBytes} --header-overflow-action ${cfg.headerOverflowAction} "
+          + lib.optionalString (cfg.workerThreads != null) "--worker-threads ${builtins.toString cfg.workerThreads} "
+          + lib.optionalString (cfg.cryptoJobs != null) "--crypto-jobs ${builtins.toString cfg.cryptoJobs} "
+          + lib.optionalString (cfg.maxInFlight != null) "--max-in-flight ${builtins.toString cfg.maxInFlight} "
+          + lib.optionalString (cfg.maxMemory != null) "--max-memory ${builtins.toString cfg.maxMemory} "
+          + lib.concatMapStrings (normalization: "--normalize-body ${normalization} ") cfg.normalizeBody
+          + lib.concatMapStrings (network: "--internal-network '${network}' ") cfg.internalNetworks
+          + lib.optionalString cfg.encryptInternalOnly "--encrypt-internal-only "
+          + lib.optionalString cfg.harvestExternalOnly "--harvest-external-only "
+          + lib.optionalString cfg.originFromReceived "--origin-from-received "
+          + lib.optionalString cfg.sandbox "--sandbox "
+          + lib.optionalString (cfg.deadLetterDirectory != null) "--dead-letter-dir ${cfg.deadLetterDirectory} "
+          + lib.optionalString (cfg.harvestSpoolDirectory != null) "--harvest-spool-dir ${cfg.harvestSpoolDirectory} "
+          + lib.optionalString (cfg.eventReport != null) "--event-report ${cfg.eventReport} "
+          + lib.optionalString (cfg.postProcessHook != null) "--post-process-hook '${cfg.postProcessHook}' "
+          + lib.optionalString (cfg.metricsListen != null) "--metrics-listen ${cfg.metricsListen} --metrics-max-domains ${builtins.toString cfg.metricsMaxDomains} "
+          + lib.optionalString (cfg.policyScript != null) "--policy-script ${cfg.policyScript} "
+          + lib.optionalString (cfg.expiryNotifications.from != null) (
+            "--expiry-notify-from '${cfg.expiryNotifications.from}' --expiry-notify-days ${builtins.toString cfg.expiryNotifications.days} "
+            + lib.optionalString (cfg.expiryNotifications.admin != null) "--expiry-notify-admin '${cfg.expiryNotifications.admin}' "
+            + lib.optionalString cfg.expiryNotifications.notifyUsers "--expiry-notify-users "
+          )
+          + lib.optionalString (cfg.keyRequest.from != null) (
+            "--key-request-from '${cfg.keyRequest.from}' --key-request-interval-days ${builtins.toString cfg.keyRequest.intervalDays} "
+            + lib.optionalString (cfg.keyRequest.template != null) "--key-request-template ${cfg.keyRequest.template} "
+          )
+          + lib.optionalString (cfg.gatewayCertificate != null) "--gateway-certificate ${cfg.gatewayCertificate} --gateway-key ${cfg.gatewayKey} "
+          + lib.optionalString cfg.encryptNotifications "--encrypt-notifications "
+          + lib.optionalString cfg.publishGatewayCertificate "--publish-gateway-certificate "
+          + lib.optionalString (cfg.templateDir != null) "--template-dir ${cfg.templateDir} "
+          + lib.concatMapStrings (language: "--template-language '${language}' ") cfg.templateLanguages
+          + lib.optionalString (cfg.ldap.url != null) (
+            "--ldap-url '${cfg.ldap.url}' --ldap-base-dn '${cfg.ldap.baseDn}' --ldap-filter '${cfg.ldap.filter}' --ldap-sync-interval ${builtins.toString cfg.ldap.syncInterval} "
+            + lib.optionalString (cfg.ldap.bindDn != null) "--ldap-bind-dn '${cfg.ldap.bindDn}' "
+            + lib.optionalString (cfg.ldap.bindPasswordFile != null) "--ldap-bind-password-file ${cfg.ldap.bindPasswordFile} "
+            + lib.optionalString cfg.ldap.overwrite "--ldap-overwrite "
+          )
           + lib.concatMapStringsSep " " (m: "-a ${m}") cfg.addresses;
+        ExecReload = "${pkgs.coreutils}/bin/kill -HUP $MAINPID";
+        Type = "notify";
+        NotifyAccess = "main";
+        WatchdogSec = lib.mkIf (cfg.watchdogSec != null) cfg.watchdogSec;
         Restart = "always";
         RestartSec = "10";
 
@@ -119,21 +767,28 @@ in {
         ProtectKernelTunables = true;
         ProtectProc = "invisible";
         ProtectSystem = "strict";
-        ReadWritePaths = [cfg.certificateDirectory];
+        ReadWritePaths =
+          [cfg.certificateDirectory]
+          ++ lib.attrValues cfg.certificateDirectoryOverrides
+          ++ lib.optional (cfg.deadLetterDirectory != null) cfg.deadLetterDirectory
+          ++ lib.optional (cfg.harvestSpoolDirectory != null) cfg.harvestSpoolDirectory;
         RemoveIPC = true;
         RestrictAddressFamilies = [
           "AF_INET"
           "AF_INET6"
+          "AF_UNIX"
         ];
         RestrictNamespaces = true;
         RestrictRealtime = true;
         RestrictSUIDSGID = true;
         SystemCallArchitectures = "native";
-        SystemCallFilter = [
-          "@system-service"
-          "~@privileged"
-          "~@resources"
-        ];
+        SystemCallFilter =
+          [
+            "@system-service"
+            "~@privileged"
+            "~@resources"
+          ]
+          ++ lib.optional cfg.sandbox "@sandbox";
         UMask = "0027";
       };
     };
@@ -0,0 +1,326 @@
+//! Normalization of email addresses, so every spelling of an address finds the same certificate.
+//!
+//! Internationalized domains are handled in their ASCII (A-label, `xn--`) form
+//! internally, certificates are stored under that form as well. Local parts
+//! are normalized according to per-domain rules, e.g. for providers ignoring
+//! dots in them.
+
+use lazy_static::lazy_static;
+use std::path::Path;
+use std::sync::RwLock;
+
+/// How local parts are normalized for a domain.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LocalPartRule {
+    /// Lowercase the local part.
+    pub lowercase: bool,
+    /// Remove all dots from the local part, like Gmail does.
+    pub strip_dots: bool,
+}
+
+lazy_static! {
+    static ref RULES: RwLock<Vec<(String, LocalPartRule)>> = RwLock::new(Vec::new());
+}
+
+/// Parse a `<DOMAIN>=<RULE>[,<RULE>...]` normalization rule, with the rules
+/// being `lowercase` and `strip-dots`. The domain may be `*` or `*.<DOMAIN>`.
+pub fn parse_rule(s: &str) -> Result<(String, LocalPartRule), String> {
+    let (domain, rules) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <DOMAIN>=<RULES>, got {:?}", s))?;
+    let mut rule = LocalPartRule::default();
+    for name in rules.split(',').map(str::trim) {
+        match name {
+            "lowercase" => rule.lowercase = true,
+            "strip-dots" => rule.strip_dots = true,
+            other => return Err(format!("unknown normalization {:?}", other)),
+        }
+    }
+    let domain = match idna::domain_to_ascii(domain.trim()) {
+        Ok(ascii) if !ascii.is_empty() => ascii,
+        _ => return Err(format!("invalid domain {:?}", domain)),
+    };
+    Ok((domain, rule))
+}
+
+/// Set the local part rules used by [`normalize`], first matching domain wins.
+pub fn set_rules(rules: Vec<(String, LocalPartRule)>) {
+    *RULES.write().unwrap() = rules;
+}
+
+/// Parse a `<FROM>=<TO>` rewrite, of a whole address like
+/// `alice@old.example=alice.smith@new.example` or of a domain like `old.example=new.example`.
+pub fn parse_rewrite(s: &str) -> Result<(String, String), String> {
+    let (from, to) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <FROM>=<TO>, got {:?}", s))?;
+    let ascii = |value: &str| match value.trim().rsplit_once('@') {
+        Some((local, domain)) if !local.is_empty() => match idna::domain_to_ascii(domain) {
+            Ok(domain) if !domain.is_empty() => Ok(format!("{}@{}", local, domain)),
+            _ => Err(format!("invalid address {:?}", value)),
+        },
+        Some(_) => Err(format!("invalid address {:?}", value)),
+        None => match idna::domain_to_ascii(value.trim()) {
+            Ok(domain) if !domain.is_empty() => Ok(domain),
+            _ => Err(format!("invalid domain {:?}", value)),
+        },
+    };
+    let (from, to) = (ascii(from)?, ascii(to)?);
+    if from.contains('@') != to.contains('@') {
+        return Err(format!(
+            "expected two addresses or two domains, got {:?}",
+            s
+        ));
+    }
+    Ok((from, to))
+}
+
+/// Rewrite an address with the first matching rule, e.g. to the new domain of its owner.
+pub fn rewrite(rules: &[(String, String)], email: &str) -> Option<String> {
+    let (local, domain) = email.rsplit_once('@')?;
+    rules
+        .iter()
+        .find_map(|(from, to)| match from.contains('@') {
+            true => same_address(from, email).then(|| to.clone()),
+            false => domain_matches(from, domain).then(|| format!("{}@{}", local, to)),
+        })
+}
+
+/// Whether a domain matches `example.com`, `*.example.com` or `*`.
+pub fn domain_matches(pattern: &str, domain: &str) -> bool {
+    match pattern.strip_prefix("*") {
+        Some("") => true,
+        Some(suffix) if suffix.starts_with('.') => {
+            domain.len() > suffix.len()
+                && domain[domain.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+        }
+        _ => pattern.eq_ignore_ascii_case(domain),
+    }
+}
+
+fn normalize_with(email: &str, rules: &[(String, LocalPartRule)]) -> String {
+    let Some((local, domain)) = email.rsplit_once('@') else {
+        return email.to_string();
+    };
+    let domain = match idna::domain_to_ascii(domain) {
+        Ok(ascii) if !ascii.is_empty() => ascii,
+        _ => return email.to_string(),
+    };
+
+    let mut local = local.to_string();
+    // Quoted local parts are taken literally.
+    if !local.starts_with('"') {
+        if let Some((_, rule)) = rules.iter().find(|(d, _)| domain_matches(d, &domain)) {
+            if rule.lowercase {
+                local = local.to_lowercase();
+            }
+            if rule.strip_dots {
+                local.retain(|c| c != '.');
+            }
+        }
+    }
+    format!("{}@{}", local, domain)
+}
+
+/// Convert the domain of an address to its lowercase ASCII (A-label) form and
+/// apply the local part rules for it. Addresses without a valid domain are
+/// returned unchanged.
+pub fn normalize(email: &str) -> String {
+    normalize_with(email, &RULES.read().unwrap())
+}
+
+/// Convert the domain of an address to its Unicode (U-label) form.
+pub fn to_unicode(email: &str) -> String {
+    match email.rsplit_once('@') {
+        Some((local, domain)) => match idna::domain_to_unicode(domain) {
+            (unicode, Ok(())) => format!("{}@{}", local, unicode),
+            _ => email.to_string(),
+        },
+        None => email.to_string(),
+    }
+}
+
+/// Qualify a bare local username, like `root` from a command line submission, or an address
+/// literal like `user@[192.0.2.1]` with `local_domain`. Without one, such addresses give
+/// `None`; others are returned unchanged.
+pub fn qualify(email: &str, local_domain: Option<&str>) -> Option<String> {
+    let local = match email.rsplit_once('@') {
+        None => email,
+        Some((local, domain)) if domain.starts_with('[') && domain.ends_with(']') => local,
+        Some(_) => return Some(email.to_string()),
+    };
+    local_domain.map(|domain| format!("{}@{}", local, domain))
+}
+
+/// Split the `+<TAG>` subaddress off an address, returning the address without it and the tag.
+pub fn split_tag(email: &str) -> Option<(String, &str)> {
+    let (local, domain) = email.rsplit_once('@')?;
+    if local.starts_with('"') {
+        return None;
+    }
+    let (base, tag) = local.split_once('+')?;
+    Some((format!("{}@{}", base, domain), tag))
+}
+
+/// Compare two addresses, regardless of case and domain encoding.
+pub fn same_address(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b) || normalize(a).eq_ignore_ascii_case(&normalize(b))
+}
+
+/// Name the certificate of an address is stored under in `cert_dir`. That is
+/// the normalized form, unless only a certificate under the Unicode form exists.
+pub fn cert_name(cert_dir: &Path, email: &str) -> String {
+    let normalized = normalize(email);
+    let unicode = to_unicode(email);
+    if unicode != normalized
+        && !cert_dir.join(format!("{}.pem", normalized)).exists()
+        && cert_dir.join(format!("{}.pem", unicode)).exists()
+    {
+        return unicode;
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_tag() {
+        assert_eq!(
+            split_tag("user+plain@example.com"),
+            Some(("user@example.com".to_string(), "plain"))
+        );
+        assert_eq!(
+            split_tag("user+a+b@example.com"),
+            Some(("user@example.com".to_string(), "a+b"))
+        );
+        assert_eq!(split_tag("user@example.com"), None);
+        assert_eq!(split_tag("\"a+b\"@example.com"), None);
+        assert_eq!(split_tag("user+plain"), None);
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(
+            normalize("jörg@bücher.example"),
+            "jörg@xn--bcher-kva.example"
+        );
+        assert_eq!(normalize("a@Bücher.Example"), "a@xn--bcher-kva.example");
+        assert_eq!(
+            normalize("a@xn--bcher-kva.example"),
+            "a@xn--bcher-kva.example"
+        );
+        assert_eq!(normalize("A@Example.com"), "A@example.com");
+        assert_eq!(normalize("*@bücher.example"), "*@xn--bcher-kva.example");
+        assert_eq!(normalize("no-domain"), "no-domain");
+    }
+
+    #[test]
+    fn test_normalize_with_rules() {
+        let rules = vec![
+            parse_rule("gmail.com=lowercase,strip-dots").unwrap(),
+            parse_rule("*.bücher.example=lowercase").unwrap(),
+        ];
+        let cases = [
+            ("John.Doe@gmail.com", "johndoe@gmail.com"),
+            ("John.Doe@Gmail.com", "johndoe@gmail.com"),
+            ("\"J.D\"@gmail.com", "\"J.D\"@gmail.com"),
+            ("John.Doe@example.com", "John.Doe@example.com"),
+            (
+                "John.Doe@mail.bücher.example",
+                "john.doe@mail.xn--bcher-kva.example",
+            ),
+            ("John.Doe@bücher.example", "John.Doe@xn--bcher-kva.example"),
+        ];
+        for (email, expected) in cases {
+            assert_eq!(normalize_with(email, &rules), expected);
+        }
+    }
+
+    #[test]
+    fn test_parse_rule() {
+        assert_eq!(
+            parse_rule("*=lowercase"),
+            Ok((
+                "*".to_string(),
+                LocalPartRule {
+                    lowercase: true,
+                    strip_dots: false
+                }
+            ))
+        );
+        assert!(parse_rule("gmail.com").is_err());
+        assert!(parse_rule("gmail.com=uppercase").is_err());
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let rules = [
+            parse_rewrite("alice@old.example=alice.smith@new.example").unwrap(),
+            parse_rewrite("old.example=new.example").unwrap(),
+            parse_rewrite("*.bücher.example=bücher.example").unwrap(),
+        ];
+        assert_eq!(
+            rewrite(&rules, "Alice@old.example").as_deref(),
+            Some("alice.smith@new.example")
+        );
+        assert_eq!(
+            rewrite(&rules, "bob@old.example").as_deref(),
+            Some("bob@new.example")
+        );
+        assert_eq!(
+            rewrite(&rules, "c@eu.xn--bcher-kva.example").as_deref(),
+            Some("c@xn--bcher-kva.example")
+        );
+        assert_eq!(rewrite(&rules, "bob@new.example"), None);
+
+        assert!(parse_rewrite("old.example").is_err());
+        assert!(parse_rewrite("alice@old.example=new.example").is_err());
+    }
+
+    #[test]
+    fn test_qualify() {
+        assert_eq!(
+            qualify("a@example.com", None).as_deref(),
+            Some("a@example.com")
+        );
+        assert_eq!(qualify("root", None), None);
+        assert_eq!(qualify("user@[192.168.1.10]", None), None);
+        let local = Some("example.com");
+        assert_eq!(qualify("root", local).as_deref(), Some("root@example.com"));
+        assert_eq!(
+            qualify("user@[IPv6:2001:db8::1]", local).as_deref(),
+            Some("user@example.com")
+        );
+        assert_eq!(
+            qualify("a@example.org", local).as_deref(),
+            Some("a@example.org")
+        );
+    }
+
+    #[test]
+    fn test_to_unicode() {
+        assert_eq!(to_unicode("a@xn--bcher-kva.example"), "a@bücher.example");
+        assert_eq!(to_unicode("a@example.com"), "a@example.com");
+    }
+
+    #[test]
+    fn test_same_address() {
+        assert!(same_address("a@bücher.example", "A@XN--BCHER-KVA.example"));
+        assert!(!same_address("a@bücher.example", "a@bucher.example"));
+    }
+
+    #[test]
+    fn test_cert_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let email = "a@xn--bcher-kva.example";
+        assert_eq!(cert_name(dir.path(), "a@bücher.example"), email);
+
+        std::fs::write(dir.path().join("a@bücher.example.pem"), "").unwrap();
+        assert_eq!(cert_name(dir.path(), email), "a@bücher.example");
+
+        std::fs::write(dir.path().join(format!("{}.pem", email)), "").unwrap();
+        assert_eq!(cert_name(dir.path(), "a@bücher.example"), email);
+    }
+}
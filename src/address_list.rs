@@ -0,0 +1,292 @@
+//! Lists of responsible addresses, from the command line or address files.
+//!
+//! Address files contain one address or pattern per line: `*` is a wildcard, and
+//! `@example.com` stands for every address at the domain. `/.../` is a regular
+//! expression. `#` starts a comment, and `include <path>` pulls in another file,
+//! relative to the including one.
+
+use anyhow::{anyhow, bail, Context, Error, Result};
+use regex::{Regex, RegexBuilder};
+use std::path::{Path, PathBuf};
+
+/// Maximum nesting of includes, to catch include loops.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Check whether an email address matches an address pattern. `*` matches any
+/// run of characters, comparisons are case insensitive.
+pub fn matches(pattern: &str, email: &str) -> bool {
+    if !pattern.contains('*') {
+        return pattern.eq_ignore_ascii_case(email);
+    }
+    let pattern = pattern.to_ascii_lowercase();
+    let email = email.to_ascii_lowercase();
+
+    let mut pieces = pattern.split('*');
+    let first = pieces.next().unwrap_or_default();
+    let Some(mut rest) = email.strip_prefix(first) else {
+        return false;
+    };
+    let pieces: Vec<&str> = pieces.collect();
+    let (last, middle) = pieces.split_last().expect("pattern contains a wildcard");
+    for piece in middle {
+        match rest.find(piece) {
+            Some(pos) => rest = &rest[pos + piece.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// The wildcard pattern of an entry: `@example.com` is `*@example.com`, other entries are
+/// patterns already.
+pub fn pattern(entry: &str) -> String {
+    match entry.starts_with('@') {
+        true => format!("*{}", entry),
+        false => entry.to_string(),
+    }
+}
+
+/// A compiled entry of an address list.
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// An address, or a pattern with `*` wildcards.
+    Wildcard(String),
+    /// A regular expression, with the entry it was written as.
+    Regex(String, Regex),
+}
+
+impl Pattern {
+    /// Compile an entry: `/^sales-.*@corp\.example$/` is a regular expression, anything else
+    /// a wildcard pattern.
+    pub fn parse(entry: &str) -> Result<Self> {
+        let Some(source) = regex_source(entry) else {
+            return Ok(Pattern::Wildcard(pattern(entry)));
+        };
+        let regex = RegexBuilder::new(source)
+            .case_insensitive(true)
+            .build()
+            .with_context(|| format!("Invalid regular expression {:?}", entry))?;
+        Ok(Pattern::Regex(entry.to_string(), regex))
+    }
+
+    /// Whether the address matches, case insensitively. Regular expressions match anywhere
+    /// in the address unless anchored. The empty null reverse-path matches nothing.
+    pub fn matches(&self, email: &str) -> bool {
+        if email.is_empty() {
+            return false;
+        }
+        match self {
+            Pattern::Wildcard(pattern) => matches(pattern, email),
+            Pattern::Regex(_, regex) => regex.is_match(email),
+        }
+    }
+
+    /// The pattern as written, for logs.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Pattern::Wildcard(pattern) => pattern,
+            Pattern::Regex(entry, _) => entry,
+        }
+    }
+}
+
+/// The regular expression of an entry written as `/.../`.
+fn regex_source(entry: &str) -> Option<&str> {
+    entry.strip_prefix('/')?.strip_suffix('/')
+}
+
+/// Check an entry of an address list.
+pub fn validate(entry: &str) -> Result<()> {
+    if regex_source(entry).is_some() {
+        return Pattern::parse(entry).map(|_| ());
+    }
+    if entry
+        .chars()
+        .any(|c| c.is_whitespace() || c == '<' || c == '>')
+    {
+        bail!("Invalid address {:?}", entry);
+    }
+    if (!entry.contains('@') && entry != "*") || entry.ends_with('@') {
+        bail!("Address {:?} lacks a domain", entry);
+    }
+    Ok(())
+}
+
+/// Load the valid entries of `path` into `addresses` and everything wrong into `errors`.
+fn load_into(
+    path: &Path,
+    depth: usize,
+    addresses: &mut Vec<String>,
+    errors: &mut Vec<Error>,
+    files: &mut Vec<PathBuf>,
+) {
+    if depth > MAX_INCLUDE_DEPTH {
+        errors.push(anyhow!("Includes nested too deeply at {:?}", path));
+        return;
+    }
+    files.push(path.to_path_buf());
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(error) => {
+            errors
+                .push(Error::new(error).context(format!("Failed to read address file {:?}", path)));
+            return;
+        }
+    };
+
+    for (number, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(include) = line.strip_prefix("include ") {
+            let include = PathBuf::from(include.trim());
+            let include = match path.parent() {
+                Some(parent) if include.is_relative() => parent.join(include),
+                _ => include,
+            };
+            let mut included = Vec::new();
+            load_into(&include, depth + 1, addresses, &mut included, files);
+            errors.extend(included.into_iter().map(|error| {
+                error.context(format!("Included from {:?} line {}", path, number + 1))
+            }));
+            continue;
+        }
+        match validate(line) {
+            Ok(()) => addresses.push(line.to_string()),
+            Err(error) => errors.push(error.context(format!("In {:?} line {}", path, number + 1))),
+        }
+    }
+}
+
+/// Load all addresses from an address file and its includes.
+pub fn load_address_file(path: &Path) -> Result<Vec<String>> {
+    load_address_file_tracked(path).map(|(addresses, _)| addresses)
+}
+
+/// Load all addresses from an address file and its includes, along with every file read, to
+/// watch them for changes.
+pub fn load_address_file_tracked(path: &Path) -> Result<(Vec<String>, Vec<PathBuf>)> {
+    let mut addresses = Vec::new();
+    let mut errors = Vec::new();
+    let mut files = Vec::new();
+    load_into(path, 0, &mut addresses, &mut errors, &mut files);
+    match errors.into_iter().next() {
+        Some(error) => Err(error),
+        None => Ok((addresses, files)),
+    }
+}
+
+/// Everything wrong with an address file and its includes, rather than just the first.
+pub fn check_address_file(path: &Path) -> Vec<Error> {
+    let mut errors = Vec::new();
+    load_into(path, 0, &mut Vec::new(), &mut errors, &mut Vec::new());
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let cases = [
+            ("a@example.com", "A@Example.com", true),
+            ("a@example.com", "b@example.com", false),
+            ("*@example.com", "a@example.com", true),
+            ("*@example.com", "a@sub.example.com", false),
+            ("*@*.example.com", "a@sub.example.com", true),
+            ("noreply-*@example.com", "noreply-billing@example.com", true),
+            ("noreply-*@example.com", "reply@example.com", false),
+            ("a*a@example.com", "a@example.com", false),
+            ("*", "anyone@anywhere.org", true),
+            (&pattern("@example.com"), "a@Example.com", true),
+            (&pattern("@example.com"), "a@sub.example.com", false),
+            (&pattern("@*.example.com"), "a@sub.example.com", true),
+            (&pattern("a@example.com"), "b@example.com", false),
+        ];
+        for (pattern, email, expected) in cases {
+            assert_eq!(
+                matches(pattern, email),
+                expected,
+                "{} vs {}",
+                pattern,
+                email
+            );
+        }
+    }
+
+    #[test]
+    fn test_regex_pattern() {
+        let pattern = Pattern::parse(r"/^sales-.*@corp\.example$/").unwrap();
+        assert!(pattern.matches("sales-emea@corp.example"));
+        assert!(pattern.matches("Sales-EMEA@Corp.Example"));
+        assert!(!pattern.matches("sales-emea@corp.example.org"));
+        assert!(!pattern.matches("presales-emea@corp.example"));
+        assert_eq!(pattern.as_str(), r"/^sales-.*@corp\.example$/");
+
+        // Unanchored, it matches anywhere.
+        assert!(Pattern::parse("/billing/")
+            .unwrap()
+            .matches("a-billing-b@x.example"));
+        assert!(Pattern::parse("@example.com")
+            .unwrap()
+            .matches("a@example.com"));
+        assert!(validate("/^(sales@/").is_err());
+        assert!(validate(r"/^\w+@corp\.example$/").is_ok());
+    }
+
+    #[test]
+    fn test_load_address_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("tenants")).unwrap();
+        std::fs::write(
+            dir.path().join("responsible.txt"),
+            "# Notification senders\n\
+             alerts@example.com\n\
+             \n\
+             noreply-*@example.com   # all of them\n\
+             include tenants/acme.txt\n",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("tenants/acme.txt"), "*@acme.test\n").unwrap();
+
+        let addresses = load_address_file(&dir.path().join("responsible.txt")).unwrap();
+        assert_eq!(
+            addresses,
+            ["alerts@example.com", "noreply-*@example.com", "*@acme.test"]
+        );
+    }
+
+    #[test]
+    fn test_load_address_file_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("loop.txt");
+        std::fs::write(&path, "include loop.txt\n").unwrap();
+        assert!(load_address_file(&path).is_err());
+
+        std::fs::write(&path, "John Doe <john@example.com>\n").unwrap();
+        assert!(load_address_file(&path).is_err());
+
+        assert!(load_address_file(&dir.path().join("missing.txt")).is_err());
+
+        std::fs::write(
+            &path,
+            "a@example.com\nb example.com\ninclude missing.txt\nc\n@example.com\nd@\n",
+        )
+        .unwrap();
+        let errors: Vec<String> = check_address_file(&path)
+            .iter()
+            .map(|error| error.to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                format!("In {:?} line 2", path),
+                format!("Included from {:?} line 3", path),
+                format!("In {:?} line 4", path),
+                format!("In {:?} line 6", path),
+            ]
+        );
+    }
+}
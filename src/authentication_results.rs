@@ -0,0 +1,286 @@
+//! Only harvesting certificates from messages whose sender passed SPF, DKIM or DMARC, as told
+//! by the `Authentication-Results` headers (RFC 8601) the MTA or an earlier milter added.
+//!
+//! Anyone can send a signed message with a forged envelope sender, poisoning the certificate
+//! store with their own certificate for someone else's address. Requiring a passing method
+//! whose domain is aligned with the envelope sender raises the bar to controlling the domain.
+//! Only headers of trusted authentication services count; the MTA must remove ones claiming
+//! to be from them that came with the message.
+
+/// Authentication method whose pass allows harvesting.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Method {
+    /// DMARC, for the domain of the `From` header.
+    Dmarc,
+    /// A DKIM signature, for its signing domain.
+    Dkim,
+    /// SPF, for the domain of the envelope sender.
+    Spf,
+}
+
+impl Method {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Method::Dmarc => "dmarc",
+            Method::Dkim => "dkim",
+            Method::Spf => "spf",
+        }
+    }
+
+    /// Properties naming the domain the method authenticated.
+    fn domain_properties(self) -> &'static [&'static str] {
+        match self {
+            Method::Dmarc => &["header.from"],
+            Method::Dkim => &["header.d", "header.i"],
+            Method::Spf => &["smtp.mailfrom"],
+        }
+    }
+}
+
+/// Parse `dmarc`, `dkim` or `spf`.
+pub fn parse_method(s: &str) -> Result<Method, String> {
+    match s {
+        "dmarc" => Ok(Method::Dmarc),
+        "dkim" => Ok(Method::Dkim),
+        "spf" => Ok(Method::Spf),
+        other => Err(format!(
+            "unknown authentication method {:?}, expected dmarc, dkim or spf",
+            other
+        )),
+    }
+}
+
+/// The result of one method in an `Authentication-Results` header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthResult {
+    /// Method name, lowercase and without its version.
+    pub method: String,
+    /// Result, lowercase.
+    pub result: String,
+    /// `ptype.property` names, lowercase, and their values.
+    pub properties: Vec<(String, String)>,
+}
+
+/// Split a header value into its `;` separated parts and those into words, dropping comments
+/// and the quotes around quoted strings.
+fn tokenize(value: &str) -> Vec<Vec<String>> {
+    let mut parts = vec![Vec::new()];
+    let mut word = String::new();
+    let mut in_word = false;
+    let (mut depth, mut quoted, mut escaped) = (0, false, false);
+    let end_word = |parts: &mut Vec<Vec<String>>, word: &mut String, in_word: &mut bool| {
+        if *in_word {
+            parts.last_mut().unwrap().push(std::mem::take(word));
+            *in_word = false;
+        }
+    };
+    for c in value.chars() {
+        if escaped {
+            escaped = false;
+            if depth == 0 {
+                word.push(c);
+            }
+            continue;
+        }
+        match c {
+            '\\' if quoted || depth > 0 => escaped = true,
+            '"' if depth == 0 => {
+                quoted = !quoted;
+                in_word = true;
+            }
+            _ if quoted => word.push(c),
+            '(' => {
+                end_word(&mut parts, &mut word, &mut in_word);
+                depth += 1;
+            }
+            ')' if depth > 0 => depth -= 1,
+            _ if depth > 0 => {}
+            ';' => {
+                end_word(&mut parts, &mut word, &mut in_word);
+                parts.push(Vec::new());
+            }
+            c if c.is_whitespace() => end_word(&mut parts, &mut word, &mut in_word),
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    end_word(&mut parts, &mut word, &mut in_word);
+    // Whitespace is allowed around the `=` of a method or property, unlike in values like
+    // base64 DKIM signatures that end with one.
+    for part in &mut parts {
+        let mut joined: Vec<String> = Vec::new();
+        for word in part.drain(..) {
+            let incomplete = |last: &String| match last.find('=') {
+                Some(i) => i == last.len() - 1,
+                None => word.starts_with('='),
+            };
+            match joined.last_mut() {
+                Some(last) if incomplete(last) => last.push_str(&word),
+                _ => joined.push(word),
+            }
+        }
+        *part = joined;
+    }
+    parts
+}
+
+/// Parse an `Authentication-Results` header value into the authentication service identifier
+/// and the results of its methods.
+pub fn parse(value: &str) -> Option<(String, Vec<AuthResult>)> {
+    let mut parts = tokenize(value).into_iter();
+    let authserv_id = parts.next()?.into_iter().next()?;
+    let results = parts
+        .filter_map(|words| {
+            let mut words = words.into_iter();
+            let (method, result) = words.next()?.split_once('=').map(|(method, result)| {
+                let method = method.split('/').next().unwrap_or_default();
+                (method.to_ascii_lowercase(), result.to_ascii_lowercase())
+            })?;
+            let properties = words
+                .filter_map(|word| {
+                    let (name, value) = word.split_once('=')?;
+                    Some((name.to_ascii_lowercase(), value.to_string()))
+                })
+                .collect();
+            Some(AuthResult {
+                method,
+                result,
+                properties,
+            })
+        })
+        .collect();
+    Some((authserv_id, results))
+}
+
+/// Whether two domains are aligned as by DMARC's relaxed mode: the same, or one below the
+/// other. Without the public suffix list, an organizational domain is not told apart from the
+/// subdomains of another one.
+fn aligned(a: &str, b: &str) -> bool {
+    let (a, b) = (a.trim_end_matches('.'), b.trim_end_matches('.'));
+    let below = |sub: &str, parent: &str| {
+        sub.len() > parent.len() + 1
+            && sub[sub.len() - parent.len()..].eq_ignore_ascii_case(parent)
+            && sub.as_bytes()[sub.len() - parent.len() - 1] == b'.'
+    };
+    !a.is_empty() && (a.eq_ignore_ascii_case(b) || below(a, b) || below(b, a))
+}
+
+/// The first of `methods` that passed for a domain aligned with `sender`, by the
+/// `Authentication-Results` header values of the trusted `authserv_ids`.
+pub fn passed(
+    methods: &[Method],
+    authserv_ids: &[String],
+    headers: &[String],
+    sender: &str,
+) -> Option<Method> {
+    let (_, sender_domain) = sender.rsplit_once('@')?;
+    let results: Vec<AuthResult> = headers
+        .iter()
+        .filter_map(|value| parse(value))
+        .filter(|(id, _)| {
+            authserv_ids
+                .iter()
+                .any(|trusted| trusted.eq_ignore_ascii_case(id))
+        })
+        .flat_map(|(_, results)| results)
+        .collect();
+    methods.iter().copied().find(|method| {
+        results.iter().any(|result| {
+            result.method == method.as_str()
+                && result.result == "pass"
+                && result.properties.iter().any(|(name, value)| {
+                    let domain = value.rsplit_once('@').map_or(value.as_str(), |(_, d)| d);
+                    method.domain_properties().contains(&name.as_str())
+                        && aligned(domain, sender_domain)
+                })
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let (id, results) = parse(
+            "mx.example.com (version 1) 1;\r\n\tdkim=pass (2048-bit key) header.d=partner.example \
+             header.b=AbC1= header.i=@partner.example;\r\n\tspf = fail smtp.mailfrom=\"a@partner.example\";\r\n\t\
+             dmarc/1=PASS (p=reject) header.from=partner.example",
+        )
+        .unwrap();
+        assert_eq!(id, "mx.example.com");
+        assert_eq!(
+            results,
+            [
+                AuthResult {
+                    method: "dkim".into(),
+                    result: "pass".into(),
+                    properties: vec![
+                        ("header.d".into(), "partner.example".into()),
+                        ("header.b".into(), "AbC1=".into()),
+                        ("header.i".into(), "@partner.example".into()),
+                    ],
+                },
+                AuthResult {
+                    method: "spf".into(),
+                    result: "fail".into(),
+                    properties: vec![("smtp.mailfrom".into(), "a@partner.example".into())],
+                },
+                AuthResult {
+                    method: "dmarc".into(),
+                    result: "pass".into(),
+                    properties: vec![("header.from".into(), "partner.example".into())],
+                },
+            ]
+        );
+        assert_eq!(
+            parse("mx.example.com; none"),
+            Some(("mx.example.com".into(), vec![]))
+        );
+        assert_eq!(parse(" (only a comment) "), None);
+    }
+
+    #[test]
+    fn test_passed() {
+        let ids = vec!["mx.example.com".to_string()];
+        let passed = |methods: &[Method], headers: &[&str], sender| {
+            let headers: Vec<String> = headers.iter().map(|h| h.to_string()).collect();
+            passed(methods, &ids, &headers, sender)
+        };
+        let dkim = "mx.example.com; dkim=pass header.d=partner.example; spf=softfail \
+                    smtp.mailfrom=partner.example";
+
+        assert_eq!(
+            passed(&[Method::Spf, Method::Dkim], &[dkim], "a@partner.example"),
+            Some(Method::Dkim)
+        );
+        assert_eq!(
+            passed(&[Method::Dkim], &[dkim], "a@mail.partner.example"),
+            Some(Method::Dkim)
+        );
+        assert_eq!(passed(&[Method::Spf], &[dkim], "a@partner.example"), None);
+        assert_eq!(passed(&[Method::Dkim], &[dkim], "a@other.example"), None);
+        assert_eq!(passed(&[Method::Dkim], &[dkim], "a@xpartner.example"), None);
+        // Headers of untrusted services don't count.
+        assert_eq!(
+            passed(
+                &[Method::Dmarc],
+                &["evil.example; dmarc=pass header.from=partner.example"],
+                "a@partner.example"
+            ),
+            None
+        );
+        assert_eq!(
+            passed(
+                &[Method::Dmarc],
+                &["MX.example.com; dmarc=pass header.from=partner.example"],
+                "a@partner.example"
+            ),
+            Some(Method::Dmarc)
+        );
+        assert!(parse_method("arc").is_err());
+    }
+}
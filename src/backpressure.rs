@@ -0,0 +1,176 @@
+//! Shedding load before it becomes a problem: while too many messages are in flight or the
+//! process uses too much memory, new milter connections are closed right away. The MTA then
+//! applies its milter default action, like Postfix' `milter_default_action`, instead of waiting
+//! on a milter that degrades unpredictably.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::task::{ready, Context, Poll};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+use crate::metrics;
+
+/// Messages being processed, also without the metrics.
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// Share of the watermarks the load has to drop below before accepting connections again.
+const RESUME_AT: f64 = 0.9;
+
+/// Load from which on new connections are turned away.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Watermarks {
+    /// Messages being processed.
+    pub max_in_flight: Option<usize>,
+    /// Resident memory of the process, in bytes.
+    pub max_memory: Option<u64>,
+}
+
+impl Watermarks {
+    /// Whether the load reaches the watermarks, scaled by `factor`.
+    fn reached(&self, load: Load, factor: f64) -> bool {
+        self.max_in_flight
+            .is_some_and(|max| load.in_flight as f64 >= max as f64 * factor)
+            || self
+                .max_memory
+                .zip(load.memory)
+                .is_some_and(|(max, memory)| memory as f64 >= max as f64 * factor)
+    }
+}
+
+/// Load of the process.
+#[derive(Debug, Clone, Copy)]
+struct Load {
+    in_flight: usize,
+    /// Resident memory in bytes, if known.
+    memory: Option<u64>,
+}
+
+impl Load {
+    /// The current load, only measuring the memory if there is a watermark for it.
+    fn current(watermarks: &Watermarks) -> Self {
+        Self {
+            in_flight: IN_FLIGHT.load(Ordering::Relaxed),
+            memory: watermarks.max_memory.and_then(|_| resident_memory()),
+        }
+    }
+}
+
+/// Resident memory of the process in bytes, from procfs.
+fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let value = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?;
+    let kib: u64 = value.trim().strip_suffix("kB")?.trim().parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// A message being processed, counted as long as it is alive.
+pub struct InFlight(());
+
+impl InFlight {
+    /// Start counting a message.
+    pub fn start() -> Self {
+        IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+        metrics::MESSAGES_IN_FLIGHT.inc();
+        Self(())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+        metrics::MESSAGES_IN_FLIGHT.dec();
+    }
+}
+
+/// Milter socket closing new connections while overloaded.
+pub struct BackpressureListener {
+    listener: TcpListener,
+    watermarks: Watermarks,
+    shedding: AtomicBool,
+}
+
+impl BackpressureListener {
+    pub fn new(listener: TcpListener, watermarks: Watermarks) -> Self {
+        Self {
+            listener,
+            watermarks,
+            shedding: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether to turn away connections under `load`: from reaching the watermarks on, until
+    /// the load dropped well below them again.
+    fn shed(&self, load: Load) -> bool {
+        let shedding = self.shedding.load(Ordering::Relaxed);
+        let shed = match shedding {
+            true => self.watermarks.reached(load, RESUME_AT),
+            false => self.watermarks.reached(load, 1.0),
+        };
+        if shed != shedding {
+            self.shedding.store(shed, Ordering::Relaxed);
+            match shed {
+                true => warn!(
+                    in_flight = load.in_flight,
+                    memory = ?load.memory,
+                    "Overloaded; closing new milter connections"
+                ),
+                false => info!(
+                    in_flight = load.in_flight,
+                    memory = ?load.memory,
+                    "Load is back to normal; accepting milter connections"
+                ),
+            }
+        }
+        shed
+    }
+}
+
+impl indymilter::Listener for BackpressureListener {
+    type Io = TcpStream;
+
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<TcpStream>> {
+        loop {
+            let (stream, peer) = ready!(self.listener.poll_accept(cx))?;
+            if !self.shed(Load::current(&self.watermarks)) {
+                return Poll::Ready(Ok(stream));
+            }
+            metrics::CONNECTIONS_SHED.inc();
+            debug!(%peer, "Closed milter connection while overloaded");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let watermarks = Watermarks {
+            max_in_flight: Some(10),
+            max_memory: Some(1000),
+        };
+        let listener = BackpressureListener::new(listener, watermarks);
+        let load = |in_flight, memory| Load {
+            in_flight,
+            memory: Some(memory),
+        };
+
+        assert!(!listener.shed(load(9, 500)));
+        assert!(listener.shed(load(10, 500)));
+        // Only resuming well below the watermark.
+        assert!(listener.shed(load(9, 500)));
+        assert!(!listener.shed(load(8, 500)));
+        assert!(listener.shed(load(0, 1000)));
+        assert!(!listener.shed(load(0, 899)));
+        // Unknown memory never counts as overloaded.
+        assert!(!listener.shed(Load {
+            in_flight: 0,
+            memory: None
+        }));
+    }
+}
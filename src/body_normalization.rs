@@ -0,0 +1,104 @@
+//! Undo artifacts some MTAs leave in the body handed to the milter, so the encrypted content is
+//! what the user actually sent.
+//!
+//! Lines are normalized as the body chunks arrive. A line split across chunks is held back
+//! until it is complete, or until the end of the message.
+
+use bytes::BytesMut;
+
+/// An artifact to undo.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Normalization {
+    /// mbox escaping (mboxrd): `>From ` back to `From `, `>>From ` to `>From ` and so on.
+    UnescapeFrom,
+    /// Remnants of SMTP dot-stuffing: `..` at the start of a line back to `.`.
+    UnstuffDots,
+}
+
+/// Parse `unescape-from` or `unstuff-dots`.
+pub fn parse_normalization(s: &str) -> Result<Normalization, String> {
+    match s {
+        "unescape-from" => Ok(Normalization::UnescapeFrom),
+        "unstuff-dots" => Ok(Normalization::UnstuffDots),
+        _ => Err(format!("unknown body normalization {:?}", s)),
+    }
+}
+
+/// The line with the artifacts undone.
+fn normalize_line<'l>(line: &'l [u8], normalizations: &[Normalization]) -> &'l [u8] {
+    let undo = normalizations
+        .iter()
+        .any(|normalization| match normalization {
+            Normalization::UnescapeFrom => {
+                let unquoted = line.iter().position(|b| *b != b'>').unwrap_or(line.len());
+                unquoted > 0 && line[unquoted..].starts_with(b"From ")
+            }
+            Normalization::UnstuffDots => line.starts_with(b".."),
+        });
+    match undo {
+        true => &line[1..],
+        false => line,
+    }
+}
+
+/// Append a body chunk, keeping an incomplete last line in `partial` for the next one.
+pub fn push(
+    body: &mut BytesMut,
+    partial: &mut Vec<u8>,
+    data: &[u8],
+    normalizations: &[Normalization],
+) {
+    if normalizations.is_empty() {
+        body.extend_from_slice(data);
+        return;
+    }
+    partial.extend_from_slice(data);
+    let Some(end) = partial.iter().rposition(|b| *b == b'\n') else {
+        return;
+    };
+    for line in partial[..=end].split_inclusive(|b| *b == b'\n') {
+        body.extend_from_slice(normalize_line(line, normalizations));
+    }
+    partial.drain(..=end);
+}
+
+/// Append the last line, once the body is complete.
+pub fn finish(body: &mut BytesMut, partial: &mut Vec<u8>, normalizations: &[Normalization]) {
+    if !partial.is_empty() {
+        body.extend_from_slice(normalize_line(partial, normalizations));
+        partial.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_chunks() {
+        let normalizations = [Normalization::UnescapeFrom, Normalization::UnstuffDots];
+        let mut body = BytesMut::new();
+        let mut partial = Vec::new();
+        for chunk in [
+            &b"Hi,\r\n>Fr"[..],
+            b"om here on\r\n>>From ",
+            b"a quote\r\n..signature\r\n",
+            b">Frogs\r\n.",
+        ] {
+            push(&mut body, &mut partial, chunk, &normalizations);
+        }
+        finish(&mut body, &mut partial, &normalizations);
+        assert_eq!(
+            &body[..],
+            b"Hi,\r\nFrom here on\r\n>From a quote\r\n.signature\r\n>Frogs\r\n."
+        );
+
+        let mut body = BytesMut::new();
+        push(&mut body, &mut partial, b">From\r\n..", &[]);
+        finish(&mut body, &mut partial, &[]);
+        assert_eq!(&body[..], b">From\r\n..");
+
+        assert!(parse_normalization("unstuff-dots").is_ok());
+        assert!(parse_normalization("crlf").is_err());
+    }
+}
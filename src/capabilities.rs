@@ -0,0 +1,144 @@
+//! Machine-readable report of what this build supports, for `capabilities`: the compiled
+//! features, crypto, certificate and report backends, what is negotiated with the MTA and the
+//! version of the configuration, for configuration management and support tickets.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::crypto_profile::{self, CryptoProfile};
+use crate::milter_callbacks::{self, MilterAction};
+use crate::pipeline::Pipeline;
+use crate::settings;
+
+/// Version of the command line options and the certificate directory layout, raised when an
+/// existing configuration or directory would no longer work as before.
+pub const CONFIG_SCHEMA: u32 = 1;
+
+/// The report, serialized as JSON.
+#[derive(Serialize)]
+pub struct Capabilities {
+    version: &'static str,
+    config_schema: u32,
+    features: BTreeMap<&'static str, bool>,
+    crypto: Crypto,
+    certificate_stores: Vec<&'static str>,
+    event_report_sinks: Vec<&'static str>,
+    actions: Vec<&'static str>,
+    /// Stages each action runs by default, the ones `--pipeline` selects from.
+    stages: BTreeMap<&'static str, Vec<&'static str>>,
+    milter: Milter,
+}
+
+#[derive(Serialize)]
+struct Crypto {
+    backend: &'static str,
+    /// Names in crypto profiles.
+    profile_ciphers: Vec<&'static str>,
+    /// Content encryption algorithms, as in event reports.
+    content_ciphers: Vec<&'static str>,
+    key_transports: Vec<&'static str>,
+    recipient_ids: Vec<&'static str>,
+    compression: Vec<&'static str>,
+    /// Formats of the gateway's own signatures, by their names in crypto profiles.
+    signature_formats: Vec<&'static str>,
+}
+
+#[derive(Serialize)]
+struct Milter {
+    /// Actions requested from the MTA.
+    actions: Vec<&'static str>,
+    /// Protocol steps the MTA can be asked to leave out with `--milter-skip-step`.
+    skippable_steps: Vec<&'static str>,
+}
+
+const ACTIONS: [MilterAction; 3] = [
+    MilterAction::Encrypt,
+    MilterAction::ExtractKeys,
+    MilterAction::Enroll,
+];
+
+/// What this build supports.
+pub fn report() -> Capabilities {
+    let features = BTreeMap::from([
+        ("archive", cfg!(feature = "archive")),
+        ("chaos", cfg!(feature = "chaos")),
+        ("ldap", cfg!(feature = "ldap")),
+        ("lua", cfg!(feature = "lua")),
+        ("metrics", cfg!(feature = "metrics")),
+        ("replay", cfg!(feature = "replay")),
+    ]);
+
+    let mut content_ciphers = Vec::new();
+    for (_, cipher) in crypto_profile::CIPHERS {
+        for aead in [false, true] {
+            let profile = CryptoProfile {
+                cipher,
+                aead,
+                ..CryptoProfile::default()
+            };
+            let (_, name) = profile.cipher();
+            if !content_ciphers.contains(&name) {
+                content_ciphers.push(name);
+            }
+        }
+    }
+
+    let mut certificate_stores = vec!["filesystem"];
+    if cfg!(feature = "ldap") {
+        certificate_stores.push("ldap");
+    }
+
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        config_schema: CONFIG_SCHEMA,
+        features,
+        crypto: Crypto {
+            backend: openssl::version::version(),
+            profile_ciphers: crypto_profile::CIPHERS.map(|(name, _)| name).to_vec(),
+            content_ciphers,
+            key_transports: crypto_profile::KEY_TRANSPORTS
+                .map(|(name, _)| name)
+                .to_vec(),
+            recipient_ids: vec!["issuer-serial", "key-id"],
+            compression: vec!["zlib"],
+            signature_formats: crypto_profile::SIGNATURE_FORMATS
+                .map(|(name, _)| name)
+                .to_vec(),
+        },
+        certificate_stores,
+        event_report_sinks: vec!["file", "udp", "tcp"],
+        actions: ACTIONS.map(|action| action.as_str()).to_vec(),
+        stages: ACTIONS
+            .iter()
+            .map(|action| (action.as_str(), Pipeline::default_for(action).stage_names()))
+            .collect(),
+        milter: Milter {
+            actions: milter_callbacks::REQUESTED_ACTIONS
+                .map(|(name, _)| name)
+                .to_vec(),
+            skippable_steps: settings::MILTER_STEPS.map(|(name, _)| name).to_vec(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let report = serde_json::to_value(report()).unwrap();
+        assert_eq!(report["config_schema"], CONFIG_SCHEMA);
+        assert_eq!(report["features"]["lua"], cfg!(feature = "lua"));
+        let ciphers = report["crypto"]["content_ciphers"].as_array().unwrap();
+        assert_eq!(ciphers.len(), 7);
+        assert!(ciphers.contains(&"aes-256-gcm".into()));
+        assert!(!report["crypto"]["backend"].as_str().unwrap().is_empty());
+        assert_eq!(
+            report["actions"],
+            serde_json::json!(["encrypt", "harvest", "enroll"])
+        );
+        assert_eq!(report["stages"]["enroll"][0], "require-certs-only");
+        assert_eq!(report["milter"]["skippable_steps"][0], "connect");
+    }
+}
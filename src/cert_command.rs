@@ -0,0 +1,112 @@
+//! Administrative commands operating on the certificate store.
+
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::Path;
+use tracing::{info, warn};
+
+use crate::address;
+use crate::contacts;
+use crate::smime;
+
+/// Counts of an import run.
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportSummary {
+    pub imported: usize,
+    pub skipped_existing: usize,
+    pub skipped_mismatch: usize,
+}
+
+/// Import the certificates of all contacts in a vCard or CSV contact export into
+/// `cert_dir`. Certificates are stored for each address of the contact they are
+/// issued for.
+pub async fn import_contacts(
+    cert_dir: &Path,
+    file: &Path,
+    overwrite: bool,
+) -> Result<ImportSummary> {
+    let data = tokio::fs::read(file)
+        .await
+        .with_context(|| format!("Failed to read contacts from {:?}", file))?;
+    let contacts = contacts::parse_contacts(&String::from_utf8_lossy(&data))?;
+
+    let mut summary = ImportSummary::default();
+    for contact in contacts.iter().filter(|c| !c.certificates.is_empty()) {
+        for email in &contact.emails {
+            let email = address::normalize(email);
+            let Ok(cert) = smime::find_cert_for_email(&contact.certificates, &email) else {
+                warn!(%email, "No certificate of the contact is issued for this address");
+                summary.skipped_mismatch += 1;
+                continue;
+            };
+
+            let path = cert_dir.join(format!("{}.pem", email));
+            if !overwrite && path.exists() {
+                info!(%email, "Certificate already stored, skipping");
+                summary.skipped_existing += 1;
+                continue;
+            }
+            smime::write_pem_stack([&cert], &path).await?;
+            info!(%email, ?path, "Imported certificate");
+            summary.imported += 1;
+        }
+    }
+    Ok(summary)
+}
+
+/// Name of the manifest of addresses synchronized from LDAP, in the certificate directory.
+pub const LDAP_SYNC_MANIFEST: &str = ".ldap-sync";
+
+/// Write the certificate and chain in the PEM file `cert` as DER encoded certs-only
+/// signed-data to `output`. Returns the number of certificates.
+pub async fn publish(cert: &Path, output: &Path) -> Result<usize> {
+    let certs = smime::load_pem_stack(cert).await?;
+    if certs.is_empty() {
+        bail!("No certificate in {:?}", cert);
+    }
+    fs::write(output, smime::certs_only(&certs)?)
+        .with_context(|| format!("Failed to write {:?}", output))?;
+    Ok(certs.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_pki::self_signed_identity;
+
+    #[tokio::test]
+    async fn test_import_contacts() {
+        let dir = tempfile::tempdir().unwrap();
+        let (alice, _) = self_signed_identity("alice@example.com");
+        let pem = String::from_utf8(alice.to_pem().unwrap()).unwrap();
+        let file = dir.path().join("contacts.csv");
+        std::fs::write(
+            &file,
+            format!(
+                "E-mail Address,E-mail 2 Address,Certificate\n\
+                 alice@example.com,alice@home.test,\"{}\"\n",
+                pem
+            ),
+        )
+        .unwrap();
+
+        let summary = import_contacts(dir.path(), &file, false).await.unwrap();
+        assert_eq!(
+            summary,
+            ImportSummary {
+                imported: 1,
+                skipped_existing: 0,
+                skipped_mismatch: 1
+            }
+        );
+        let stored = smime::load_pem_stack(dir.path().join("alice@example.com.pem"))
+            .await
+            .unwrap();
+        assert_eq!(stored, [alice]);
+
+        let summary = import_contacts(dir.path(), &file, false).await.unwrap();
+        assert_eq!(summary.skipped_existing, 1);
+        let summary = import_contacts(dir.path(), &file, true).await.unwrap();
+        assert_eq!(summary.imported, 1);
+    }
+}
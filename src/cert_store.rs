@@ -0,0 +1,543 @@
+//! Cached certificate lookups in the certificate directories.
+//!
+//! Parsed certificates are kept in memory and reused as long as their file is
+//! unchanged, so certificates harvested or imported meanwhile are picked up.
+//!
+//! Several instances may share a directory, over NFS for example. Files are always replaced
+//! at once, and writes that depend on what is stored already hold the [`StoreLock`].
+//!
+//! Intermediate CA certificates of harvested chains are kept once in the
+//! [`smime::INTERMEDIATES`] pool, referenced by fingerprint from the metadata of each chain.
+//! Programs embedding the gateway can keep certificates elsewhere by implementing
+//! [`CertStore`], for [`crate::encrypt_data`] or the milter with [`crate::Builder::store`];
+//! otherwise the milter works on [`DirectoryStore`]s.
+//!
+//! Should a directory become unreachable, e.g. in an NFS outage, [`StoreHealth`] defers mail
+//! to be encrypted until it is back, rather than refusing it for lack of certificates.
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use openssl::asn1::Asn1Time;
+use openssl::x509::X509;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{error, info, warn};
+
+use crate::address;
+use crate::event_report;
+use crate::metrics;
+use crate::smime;
+use crate::smime_attributes::CertMetadata;
+
+/// Certificate chains by address, the owner's certificates first.
+#[async_trait]
+pub trait CertStore: Send + Sync {
+    /// The chain stored for `email`, failing if there is none.
+    async fn load(&self, email: &str) -> Result<Vec<X509>>;
+
+    /// Store `chain` for `email`, replacing any stored before.
+    async fn store(&self, email: &str, chain: &[X509]) -> Result<()>;
+}
+
+/// A certificate directory, laid out like the milter keeps it: `<address>.pem` with the
+/// owner's certificates, and their intermediates pooled.
+pub struct DirectoryStore {
+    pub dir: PathBuf,
+}
+
+impl DirectoryStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Remove the chain stored for `email` and its metadata, returning whether there was
+    /// one. Pooled intermediates stay, as other chains may refer to them.
+    pub async fn remove(&self, email: &str) -> Result<bool> {
+        let name = address::cert_name(&self.dir, email);
+        let _lock = StoreLock::acquire(&self.dir).await?;
+        let mut removed = false;
+        for path in [
+            self.dir.join(format!("{}.pem", name)),
+            CertMetadata::path(&self.dir, &name),
+        ] {
+            match tokio::fs::remove_file(&path).await {
+                Ok(()) => removed = true,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).with_context(|| format!("Failed to remove {:?}", path)),
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[async_trait]
+impl CertStore for DirectoryStore {
+    async fn load(&self, email: &str) -> Result<Vec<X509>> {
+        smime::load_chain(&self.dir, &address::cert_name(&self.dir, email))
+            .await
+            .with_context(|| format!("Failed to load certificates for {}", email))
+    }
+
+    async fn store(&self, email: &str, chain: &[X509]) -> Result<()> {
+        let name = address::cert_name(&self.dir, email);
+        let _lock = StoreLock::acquire(&self.dir).await?;
+        let owner = address::normalize(email);
+        let (owned, intermediates) = pool_intermediates(&self.dir, &owner, chain).await?;
+        let metadata = CertMetadata {
+            intermediates,
+            ..CertMetadata::default()
+        };
+        metadata
+            .store(&CertMetadata::path(&self.dir, &name))
+            .await?;
+        smime::write_pem_stack(&owned, &self.dir.join(format!("{}.pem", name))).await
+    }
+}
+
+/// Find the certificate for `email` in `store` and its fingerprint, failing with [`Expired`]
+/// if it is no longer valid.
+pub async fn lookup_in(store: &dyn CertStore, email: &str) -> Result<(X509, String)> {
+    let _timer = metrics::CERT_LOOKUP_SECONDS.start_timer();
+    let chain = store.load(email).await?;
+    let cert = smime::find_encryption_cert(&chain, email)?;
+    check_expiry(email, &cert)?;
+    let fingerprint = event_report::fingerprint(&cert);
+    Ok((cert, fingerprint))
+}
+
+fn check_expiry(email: &str, cert: &X509) -> Result<()> {
+    let diff = Asn1Time::days_from_now(0)?.diff(cert.not_after())?;
+    if diff.days < 0 || diff.secs < 0 {
+        return Err(Expired {
+            email: email.to_string(),
+            not_after: cert.not_after().to_string(),
+            days_ago: diff.days.unsigned_abs(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Modification time and size of a certificate file, to detect changes.
+type Version = (SystemTime, u64);
+
+/// Cache of the certificates looked up for encryption, with their SHA-256 fingerprint, so
+/// neither is computed again for every message.
+#[derive(Default)]
+pub struct CertCache {
+    entries: Mutex<HashMap<PathBuf, (Version, X509, String)>>,
+}
+
+/// Lock file in each certificate directory.
+pub const LOCK: &str = ".lock";
+
+/// Advisory lock of a certificate directory, shared with other processes and hosts, held
+/// until dropped. Linux emulates `flock` with POSIX locks on NFS, so it works across clients.
+pub struct StoreLock {
+    _file: File,
+}
+
+impl StoreLock {
+    /// Wait for the lock of `cert_dir`, blocking the thread.
+    pub fn acquire_blocking(cert_dir: &Path) -> Result<Self> {
+        let path = cert_dir.join(LOCK);
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open lock file {:?}", path))?;
+        file.lock()
+            .with_context(|| format!("Failed to lock {:?}", path))?;
+        Ok(Self { _file: file })
+    }
+
+    /// Wait for the lock of `cert_dir`.
+    pub async fn acquire(cert_dir: &Path) -> Result<Self> {
+        let cert_dir = cert_dir.to_path_buf();
+        tokio::task::spawn_blocking(move || Self::acquire_blocking(&cert_dir)).await?
+    }
+}
+
+/// Whether `name`, relative to the certificate directory, is a file of the intermediates pool.
+pub fn is_pooled(name: &str) -> bool {
+    name.strip_prefix(smime::INTERMEDIATES)
+        .and_then(|name| name.strip_prefix('/'))
+        .is_some_and(|name| {
+            !name.starts_with('.') && !name.contains(['/', '\\']) && name.ends_with(".pem")
+        })
+}
+
+/// Add the certificates of `chain` not issued to `owner` to the intermediates pool of
+/// `cert_dir`, unless they are there already. Returns the owner's certificates and the
+/// fingerprints of the pooled ones; chains without a certificate of the owner are kept whole.
+pub async fn pool_intermediates(
+    cert_dir: &Path,
+    owner: &str,
+    chain: &[X509],
+) -> Result<(Vec<X509>, Vec<String>)> {
+    let (owned, intermediates): (Vec<X509>, Vec<X509>) = chain
+        .iter()
+        .cloned()
+        .partition(|cert| smime::find_cert_for_email([cert], owner).is_ok());
+    if owned.is_empty() {
+        return Ok((intermediates, Vec::new()));
+    }
+    let mut fingerprints = Vec::new();
+    for cert in intermediates {
+        let fingerprint = event_report::fingerprint(&cert);
+        let path = smime::pooled_path(cert_dir, &fingerprint);
+        if !tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            let pool = cert_dir.join(smime::INTERMEDIATES);
+            tokio::fs::create_dir_all(&pool)
+                .await
+                .with_context(|| format!("Failed to create {:?}", pool))?;
+            smime::write_pem_stack([&cert], &path).await?;
+        }
+        fingerprints.push(fingerprint);
+    }
+    Ok((owned, fingerprints))
+}
+
+/// A certificate is on file for the recipient, but it has expired.
+#[derive(Debug, thiserror::Error)]
+#[error("S/MIME certificate for {email} expired on {not_after}")]
+pub struct Expired {
+    pub email: String,
+    pub not_after: String,
+    pub days_ago: u32,
+}
+
+/// How often an unavailable certificate directory is checked for recovery.
+const PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A certificate directory cannot be read, so a missing certificate tells nothing.
+#[derive(Debug, thiserror::Error)]
+#[error("Certificate directory {cert_dir:?} is unavailable")]
+pub struct Unavailable {
+    pub cert_dir: PathBuf,
+}
+
+/// Circuit breaker for certificate directories: once one is found unavailable, messages
+/// needing it fail fast until a probe finds it readable again.
+pub struct StoreHealth {
+    probe_interval: Duration,
+    /// Unavailable directories, with when they were last probed.
+    unavailable: Mutex<HashMap<PathBuf, Instant>>,
+}
+
+impl Default for StoreHealth {
+    fn default() -> Self {
+        Self::new(PROBE_INTERVAL)
+    }
+}
+
+/// Whether a lookup failed for the directory rather than the certificate, like with `EACCES`
+/// or `ESTALE`.
+pub fn is_store_failure(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() != std::io::ErrorKind::NotFound)
+}
+
+impl StoreHealth {
+    pub fn new(probe_interval: Duration) -> Self {
+        Self {
+            probe_interval,
+            unavailable: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check that `cert_dir` is available. Once it is not, it is only probed again after the
+    /// probe interval.
+    pub async fn check(&self, cert_dir: &Path) -> Result<(), Unavailable> {
+        let unavailable = || Unavailable {
+            cert_dir: cert_dir.to_path_buf(),
+        };
+        if let Some(probed) = self.unavailable.lock().unwrap().get_mut(cert_dir) {
+            if probed.elapsed() < self.probe_interval {
+                return Err(unavailable());
+            }
+            *probed = Instant::now();
+        }
+        match tokio::fs::read_dir(cert_dir).await {
+            Ok(_) => {
+                if self.unavailable.lock().unwrap().remove(cert_dir).is_some() {
+                    info!(?cert_dir, "Certificate directory is available again");
+                    metrics::store_available(cert_dir, true);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                self.trip(cert_dir, &e.into());
+                Err(unavailable())
+            }
+        }
+    }
+
+    /// Mark `cert_dir` unavailable after `error`, alerting the first time.
+    pub fn trip(&self, cert_dir: &Path, error: &anyhow::Error) {
+        let mut unavailable = self.unavailable.lock().unwrap();
+        if !unavailable.contains_key(cert_dir) {
+            error!(
+                ?cert_dir,
+                ?error,
+                "Certificate directory is unavailable, deferring messages to encrypt"
+            );
+            metrics::store_available(cert_dir, false);
+        }
+        unavailable.insert(cert_dir.to_path_buf(), Instant::now());
+    }
+}
+
+impl CertCache {
+    /// Find the certificate for `email` in `cert_dir` and its fingerprint, failing with
+    /// [`Expired`] if it is no longer valid.
+    pub async fn lookup(&self, cert_dir: &Path, email: &str) -> Result<(X509, String)> {
+        let _timer = metrics::CERT_LOOKUP_SECONDS.start_timer();
+        let (cert, fingerprint) = self.load(cert_dir, email).await?;
+        check_expiry(email, &cert)?;
+        Ok((cert, fingerprint))
+    }
+
+    /// Find the certificate, from memory if its file is unchanged.
+    async fn load(&self, cert_dir: &Path, email: &str) -> Result<(X509, String)> {
+        let name = address::cert_name(cert_dir, email);
+        let path = cert_dir.join(format!("{}.pem", name));
+
+        let metadata = match tokio::fs::metadata(&path).await {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                self.entries.lock().unwrap().remove(&path);
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    metrics::negative_lookup(&name);
+                }
+                return Err(e)
+                    .with_context(|| format!("Failed to load certificates for {}", email));
+            }
+        };
+        let version = (metadata.modified()?, metadata.len());
+        if let Some((cached, cert, fingerprint)) = self.entries.lock().unwrap().get(&path) {
+            if *cached == version {
+                metrics::CERT_CACHE_HITS.inc();
+                return Ok((cert.clone(), fingerprint.clone()));
+            }
+        }
+
+        metrics::CERT_CACHE_MISSES.inc();
+        let chain = smime::load_pem_stack(&path).await.with_context(|| {
+            format!("Failed to load certificates for {} from {:?}", email, path)
+        })?;
+        // Honor the encryption certificate the owner declared when it was harvested.
+        let preferred = match CertMetadata::load(&CertMetadata::path(cert_dir, &name)).await {
+            Ok(metadata) => metadata.and_then(|m| m.encryption_certificate),
+            Err(error) => {
+                warn!(?error, "Ignoring unreadable certificate metadata");
+                None
+            }
+        };
+        let cert = match preferred.and_then(|fingerprint| {
+            chain.iter().find(|cert| {
+                event_report::fingerprint(cert) == fingerprint
+                    && smime::cert_usage(cert).is_ok_and(|usage| usage.encryption)
+                    && smime::has_encryption_key(cert)
+            })
+        }) {
+            Some(cert) => cert.clone(),
+            None => smime::find_encryption_cert(&chain, &name)?,
+        };
+        let fingerprint = event_report::fingerprint(&cert);
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(path, (version, cert.clone(), fingerprint.clone()));
+        Ok((cert, fingerprint))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_pki::{
+        self_signed_identity, self_signed_identity_until, self_signed_signing_identity, TestCa,
+    };
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_lookup() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CertCache::default();
+        let path = dir.path().join("a@example.com.pem");
+        assert!(cache.lookup(dir.path(), "a@example.com").await.is_err());
+
+        let (first, _) = self_signed_identity("a@example.com");
+        smime::write_pem_stack([&first], &path).await.unwrap();
+        assert_eq!(
+            cache.lookup(dir.path(), "a@example.com").await.unwrap().0,
+            first
+        );
+        assert_eq!(
+            cache.lookup(dir.path(), "a@example.com").await.unwrap(),
+            (first.clone(), event_report::fingerprint(&first))
+        );
+
+        // A renewed certificate replaces the cached one.
+        let (renewed, _) = self_signed_identity("a@example.com");
+        smime::write_pem_stack([&renewed], &path).await.unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(
+            cache.lookup(dir.path(), "a@example.com").await.unwrap().0,
+            renewed
+        );
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(cache.lookup(dir.path(), "a@example.com").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pool_intermediates() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = TestCa::new("Partner CA");
+        let (a, _) = ca.issue("a@example.com");
+        let (b, _) = ca.issue("b@example.com");
+        let chain = [a.clone(), ca.cert.clone()];
+        let (owned, pooled) = pool_intermediates(dir.path(), "a@example.com", &chain)
+            .await
+            .unwrap();
+        assert_eq!(owned, [a]);
+        assert_eq!(pooled, [event_report::fingerprint(&ca.cert)]);
+        let (_, again) = pool_intermediates(dir.path(), "b@example.com", &[b, ca.cert.clone()])
+            .await
+            .unwrap();
+        assert_eq!(again, pooled);
+        assert_eq!(
+            std::fs::read_dir(dir.path().join(smime::INTERMEDIATES))
+                .unwrap()
+                .count(),
+            1
+        );
+        assert!(is_pooled(&format!(
+            "{}/{}.pem",
+            smime::INTERMEDIATES,
+            pooled[0]
+        )));
+        assert!(!is_pooled("intermediates/../a@example.com.pem"));
+        assert!(!is_pooled("a@example.com.pem"));
+
+        smime::write_pem_stack(&owned, &dir.path().join("a@example.com.pem"))
+            .await
+            .unwrap();
+        let metadata = CertMetadata {
+            intermediates: pooled,
+            ..CertMetadata::default()
+        };
+        metadata
+            .store(&CertMetadata::path(dir.path(), "a@example.com"))
+            .await
+            .unwrap();
+        assert_eq!(
+            smime::load_chain(dir.path(), "a@example.com")
+                .await
+                .unwrap(),
+            chain
+        );
+    }
+
+    #[tokio::test]
+    async fn test_store_health() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert_dir = dir.path().join("certs");
+        std::fs::create_dir(&cert_dir).unwrap();
+        let health = StoreHealth::default();
+        health.check(&cert_dir).await.unwrap();
+
+        std::fs::remove_dir(&cert_dir).unwrap();
+        assert!(health.check(&cert_dir).await.is_err());
+        // Not probed again right away.
+        std::fs::create_dir(&cert_dir).unwrap();
+        assert!(health.check(&cert_dir).await.is_err());
+
+        let health = StoreHealth::new(Duration::ZERO);
+        let error = CertCache::default()
+            .lookup(&cert_dir, "a@example.com")
+            .await
+            .unwrap_err();
+        assert!(!is_store_failure(&error));
+        let denied = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        let error = anyhow::Error::from(denied).context("Failed to load certificates");
+        assert!(is_store_failure(&error));
+        health.trip(&cert_dir, &error);
+        // Recovered on the next probe.
+        health.check(&cert_dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_lookup_signing_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CertCache::default();
+        let path = dir.path().join("a@example.com.pem");
+        let (signing, _) = self_signed_signing_identity("a@example.com");
+        smime::write_pem_stack([&signing], &path).await.unwrap();
+        let error = cache.lookup(dir.path(), "a@example.com").await.unwrap_err();
+        assert!(format!("{:#}", error).contains("Only a signing certificate on file"));
+
+        // A preference for the signing certificate is not honored.
+        let (encryption, _) = self_signed_identity("a@example.com");
+        smime::write_pem_stack([&signing, &encryption], &path)
+            .await
+            .unwrap();
+        let metadata = CertMetadata {
+            encryption_certificate: Some(event_report::fingerprint(&signing)),
+            ..CertMetadata::default()
+        };
+        metadata
+            .store(&CertMetadata::path(dir.path(), "a@example.com"))
+            .await
+            .unwrap();
+        assert_eq!(
+            cache.lookup(dir.path(), "a@example.com").await.unwrap().0,
+            encryption
+        );
+    }
+
+    #[tokio::test]
+    async fn test_store_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = StoreLock::acquire(dir.path()).await.unwrap();
+        let other = File::open(dir.path().join(LOCK)).unwrap();
+        assert!(other.try_lock().is_err());
+        drop(lock);
+        assert!(other.try_lock().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_lookup_expired() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CertCache::default();
+        let expired_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+            - 3 * 86400
+            - 60;
+        let (cert, _) = self_signed_identity_until("a@example.com", expired_at);
+        smime::write_pem_stack([&cert], &dir.path().join("a@example.com.pem"))
+            .await
+            .unwrap();
+        let error = cache.lookup(dir.path(), "a@example.com").await.unwrap_err();
+        let expired = error.downcast_ref::<Expired>().unwrap();
+        assert_eq!(expired.days_ago, 3);
+        assert_eq!(expired.not_after, cert.not_after().to_string());
+
+        let error = cache.lookup(dir.path(), "b@example.com").await.unwrap_err();
+        assert!(error.downcast_ref::<Expired>().is_none());
+    }
+}
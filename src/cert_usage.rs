@@ -0,0 +1,194 @@
+//! Usage statistics of stored certificates: when each was last used for encryption and how
+//! often, to prune certificates of recipients no longer written to and to measure how much mail
+//! actually gets encrypted.
+//!
+//! Uses are counted in memory and merged into `.cert-usage` in each certificate directory
+//! periodically, so hosts sharing a directory add up their statistics.
+
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, error};
+
+use crate::cert_store::StoreLock;
+use crate::metrics;
+use crate::settings::Settings;
+
+/// Usage record in each certificate directory, one `<name>\t<last used>\t<count>` line per
+/// certificate.
+const USAGE: &str = ".cert-usage";
+
+/// How a certificate was used.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Usage {
+    /// Seconds since the epoch.
+    pub last_used: u64,
+    /// Messages encrypted for it.
+    pub count: u64,
+}
+
+impl Usage {
+    fn merge(&mut self, other: Usage) {
+        self.last_used = self.last_used.max(other.last_used);
+        self.count += other.count;
+    }
+}
+
+/// Seconds since the epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Read the usage recorded in `cert_dir`, by certificate name.
+pub fn read(cert_dir: &Path) -> BTreeMap<String, Usage> {
+    let content = std::fs::read_to_string(cert_dir.join(USAGE)).unwrap_or_default();
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let name = fields.next()?;
+            let last_used = fields.next()?.parse().ok()?;
+            let count = fields.next()?.parse().ok()?;
+            Some((name.to_string(), Usage { last_used, count }))
+        })
+        .collect()
+}
+
+fn write(cert_dir: &Path, usage: &BTreeMap<String, Usage>) -> Result<()> {
+    let content: String = usage
+        .iter()
+        .map(|(name, u)| format!("{}\t{}\t{}\n", name, u.last_used, u.count))
+        .collect();
+    // Replace the file at once, so concurrent readers never see half of it.
+    let tmp = cert_dir.join(format!("{}.tmp", USAGE));
+    std::fs::write(&tmp, content).with_context(|| format!("Failed to write {:?}", tmp))?;
+    std::fs::rename(&tmp, cert_dir.join(USAGE))
+        .with_context(|| format!("Failed to replace usage record in {:?}", cert_dir))
+}
+
+/// Uses not merged into the usage records yet.
+#[derive(Default)]
+pub struct UsageTracker {
+    pending: Mutex<HashMap<PathBuf, BTreeMap<String, Usage>>>,
+}
+
+impl UsageTracker {
+    /// Count a message encrypted for the certificate stored under `name` in `cert_dir`.
+    pub fn record(&self, cert_dir: &Path, name: &str, now: u64) {
+        metrics::CERT_USES.inc();
+        self.pending
+            .lock()
+            .unwrap()
+            .entry(cert_dir.to_path_buf())
+            .or_default()
+            .entry(name.to_string())
+            .or_default()
+            .merge(Usage {
+                last_used: now,
+                count: 1,
+            });
+    }
+
+    /// Merge the pending uses into the usage records.
+    pub fn flush(&self) -> Result<()> {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        for (cert_dir, uses) in pending {
+            // Other hosts sharing the directory merge their uses, too.
+            let _lock = StoreLock::acquire_blocking(&cert_dir)?;
+            let mut usage = read(&cert_dir);
+            for (name, u) in uses {
+                usage.entry(name).or_default().merge(u);
+            }
+            write(&cert_dir, &usage)?;
+            debug!(?cert_dir, "Updated certificate usage");
+        }
+        Ok(())
+    }
+}
+
+/// The certificates stored in `cert_dir` with their usage, least recently used first. With
+/// `unused_days`, only those unused for at least that many days.
+pub fn list(cert_dir: &Path, unused_days: Option<u32>, now: u64) -> Result<Vec<(String, Usage)>> {
+    let usage = read(cert_dir);
+    let mut list = Vec::new();
+    for entry in std::fs::read_dir(cert_dir)
+        .with_context(|| format!("Failed to read certificate directory {:?}", cert_dir))?
+    {
+        let file_name = entry?.file_name().to_string_lossy().into_owned();
+        let Some(name) = file_name.strip_suffix(".pem") else {
+            continue;
+        };
+        if name.starts_with('.') {
+            continue;
+        }
+        let u = usage.get(name).copied().unwrap_or_default();
+        if unused_days.is_some_and(|days| now.saturating_sub(u.last_used) < days as u64 * 86400) {
+            continue;
+        }
+        list.push((name.to_string(), u));
+    }
+    list.sort_by(|(a_name, a), (b_name, b)| (a.last_used, a_name).cmp(&(b.last_used, b_name)));
+    Ok(list)
+}
+
+/// Merge the uses into the records periodically, forever.
+pub async fn run_periodically(settings: Arc<Settings>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        // Other hosts may hold the store lock for a moment.
+        let settings = settings.clone();
+        match tokio::task::spawn_blocking(move || settings.cert_usage.flush()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(error)) => error!(?error, "Updating certificate usage failed"),
+            Err(error) => error!(?error, "Updating certificate usage panicked"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_and_list() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["a@example.com", "b@example.com", "c@example.com"] {
+            std::fs::write(dir.path().join(format!("{}.pem", name)), "").unwrap();
+        }
+        let day = 86400;
+        let tracker = UsageTracker::default();
+        tracker.record(dir.path(), "a@example.com", 10 * day);
+        tracker.record(dir.path(), "b@example.com", 100 * day);
+        tracker.flush().unwrap();
+        // Later uses, possibly from another host, add up.
+        tracker.record(dir.path(), "b@example.com", 90 * day);
+        tracker.flush().unwrap();
+
+        let usage = read(dir.path());
+        assert_eq!(
+            usage["b@example.com"],
+            Usage {
+                last_used: 100 * day,
+                count: 2
+            }
+        );
+        let names = |list: Vec<(String, Usage)>| -> Vec<String> {
+            list.into_iter().map(|(name, _)| name).collect()
+        };
+        assert_eq!(
+            names(list(dir.path(), None, 100 * day).unwrap()),
+            ["c@example.com", "a@example.com", "b@example.com"]
+        );
+        assert_eq!(
+            names(list(dir.path(), Some(30), 100 * day).unwrap()),
+            ["c@example.com", "a@example.com"]
+        );
+    }
+}
@@ -0,0 +1,119 @@
+//! Fault injection for resilience testing, only built with the `chaos` feature.
+//!
+//! Failures are injected right before the fallible steps of the milter, so they
+//! take exactly the same error paths as real ones.
+
+use anyhow::{bail, Result};
+use lazy_static::lazy_static;
+use std::sync::RwLock;
+use std::time::Duration;
+use tracing::warn;
+
+/// Probabilities of the injected faults.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct ChaosArgs {
+    /// Probability (0.0 to 1.0) of failing to read recipient certificates.
+    #[arg(long, default_value_t = 0.0, value_parser = probability)]
+    pub chaos_cert_read_error: f64,
+
+    /// Probability (0.0 to 1.0) of OpenSSL operations failing.
+    #[arg(long, default_value_t = 0.0, value_parser = probability)]
+    pub chaos_openssl_error: f64,
+
+    /// Probability (0.0 to 1.0) of certificate store access being delayed.
+    #[arg(long, default_value_t = 0.0, value_parser = probability)]
+    pub chaos_slow_backend: f64,
+
+    /// Delay of slow certificate store access in milliseconds.
+    #[arg(long, default_value_t = 5000)]
+    pub chaos_delay_ms: u64,
+}
+
+/// Kind of fault to inject.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    CertRead,
+    OpenSsl,
+    SlowBackend,
+}
+
+lazy_static! {
+    static ref CONFIG: RwLock<ChaosArgs> = RwLock::new(ChaosArgs::default());
+}
+
+fn probability(s: &str) -> Result<f64, String> {
+    let p: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if !(0.0..=1.0).contains(&p) {
+        return Err(format!("{} is not between 0.0 and 1.0", p));
+    }
+    Ok(p)
+}
+
+/// Set the fault probabilities.
+pub fn configure(args: ChaosArgs) {
+    if args.chaos_cert_read_error > 0.0
+        || args.chaos_openssl_error > 0.0
+        || args.chaos_slow_backend > 0.0
+    {
+        warn!(
+            ?args,
+            "Fault injection is enabled, do not use in production!"
+        );
+    }
+    *CONFIG.write().unwrap() = args;
+}
+
+/// Roll the dice for each of the given faults, delaying or failing accordingly.
+pub async fn inject(faults: &[Fault]) -> Result<()> {
+    let config = CONFIG.read().unwrap().clone();
+    inject_with(&config, faults).await
+}
+
+async fn inject_with(config: &ChaosArgs, faults: &[Fault]) -> Result<()> {
+    for fault in faults {
+        let p = match fault {
+            Fault::CertRead => config.chaos_cert_read_error,
+            Fault::OpenSsl => config.chaos_openssl_error,
+            Fault::SlowBackend => config.chaos_slow_backend,
+        };
+        if p <= 0.0 || fastrand::f64() >= p {
+            continue;
+        }
+        warn!(?fault, "Injecting fault");
+        match fault {
+            Fault::SlowBackend => {
+                tokio::time::sleep(Duration::from_millis(config.chaos_delay_ms)).await
+            }
+            Fault::CertRead => bail!("Injected fault: failed to read certificate"),
+            Fault::OpenSsl => bail!("Injected fault: OpenSSL operation failed"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probability() {
+        assert_eq!(probability("0.25"), Ok(0.25));
+        assert!(probability("1.5").is_err());
+        assert!(probability("-0.1").is_err());
+        assert!(probability("often").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_inject() {
+        let config = ChaosArgs {
+            chaos_openssl_error: 1.0,
+            chaos_slow_backend: 1.0,
+            chaos_delay_ms: 1,
+            ..Default::default()
+        };
+        let faults = [Fault::CertRead, Fault::SlowBackend];
+        assert!(inject_with(&config, &faults).await.is_ok());
+        assert!(inject_with(&config, &[Fault::OpenSsl]).await.is_err());
+        assert!(inject(&[Fault::OpenSsl]).await.is_ok());
+    }
+}
@@ -0,0 +1,369 @@
+//! `check-config`: validate the options and the files they name without starting the milter,
+//! e.g. in the CI of the repository a deployment is configured in.
+//!
+//! Unlike at startup, checking goes on after the first problem, so everything wrong is told at
+//! once. Files and directories that can't be used are errors, options that have no effect
+//! with the others given are warnings.
+
+use anyhow::Result;
+use openssl::asn1::Asn1Time;
+use openssl::x509::X509;
+use std::fmt;
+use std::path::Path;
+
+use crate::privileges;
+use crate::sandbox;
+use crate::Cli;
+use pantosmime::address_list;
+use pantosmime::cert_usage;
+use pantosmime::crypto_profile::SignatureFormat;
+use pantosmime::escrow;
+use pantosmime::gateway_identity::GatewayIdentity;
+use pantosmime::key_request::KeyRequest;
+use pantosmime::milter_callbacks::MilterAction;
+use pantosmime::reinjection;
+use pantosmime::settings::{FromHeaderMatch, TlsPolicy};
+use pantosmime::trust::TrustAnchors;
+
+/// Warn about certificates expiring within this many days.
+const EXPIRY_WARNING_DAYS: i32 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => f.write_str("error"),
+            Severity::Warning => f.write_str("warning"),
+        }
+    }
+}
+
+/// A problem with the configuration.
+#[derive(Debug)]
+pub struct Finding {
+    pub severity: Severity,
+    pub message: String,
+}
+
+#[derive(Default)]
+struct Findings(Vec<Finding>);
+
+impl Findings {
+    fn error(&mut self, message: String) {
+        self.0.push(Finding {
+            severity: Severity::Error,
+            message,
+        });
+    }
+
+    fn warning(&mut self, message: String) {
+        self.0.push(Finding {
+            severity: Severity::Warning,
+            message,
+        });
+    }
+
+    /// The value, or an error for the option.
+    fn check<T>(&mut self, option: &str, result: Result<T>) -> Option<T> {
+        result
+            .map_err(|error| self.error(format!("{}: {:#}", option, error)))
+            .ok()
+    }
+
+    fn directory(&mut self, option: &str, path: &Path) {
+        if !path.is_dir() {
+            self.error(format!("{}: {:?} is not a directory", option, path));
+        }
+    }
+
+    /// An error for an expired certificate, a warning for one expiring soon.
+    fn expiry(&mut self, option: &str, cert: &X509) {
+        let days = Asn1Time::days_from_now(0)
+            .and_then(|now| now.diff(cert.not_after()))
+            .map_or(0, |diff| diff.days);
+        if days < 0 {
+            self.error(format!("{}: expired on {}", option, cert.not_after()));
+        } else if days <= EXPIRY_WARNING_DAYS {
+            self.warning(format!("{}: expires on {}", option, cert.not_after()));
+        }
+    }
+}
+
+/// Check the configuration given on the command line.
+pub fn check(cli: &Cli) -> Vec<Finding> {
+    let mut findings = Findings::default();
+
+    findings.directory("--certificate-directory", &cli.certificate_directory);
+    for (_, dir) in &cli.cert_dir_overrides {
+        findings.directory("--certificate-directory-override", dir);
+    }
+    for address in &cli.address {
+        findings.check("--address", address_list::validate(address));
+    }
+    if let Some(path) = &cli.address_file {
+        for error in address_list::check_address_file(path) {
+            findings.error(format!("--address-file: {:#}", error));
+        }
+    }
+    if cli.address.is_empty() && cli.address_file.is_none() && cli.enrollment_addresses.is_empty() {
+        findings.warning(
+            "No --address, --address-file or --enrollment-address: every message is accepted \
+             unchanged"
+                .to_string(),
+        );
+    }
+    for (domain, path) in &cli.trust_anchors {
+        findings.check("--trust-anchors", TrustAnchors::load(domain.clone(), path));
+    }
+    #[cfg(feature = "lua")]
+    if let Some(path) = &cli.policy_script {
+        findings.check(
+            "--policy-script",
+            pantosmime::policy_script::PolicyScript::load(path),
+        );
+    }
+
+    let secrets = [
+        ("--reinjection-secret-file", &cli.reinjection_secret_file),
+        ("--result-secret-file", &cli.result_secret_file),
+        ("--replication-secret-file", &cli.replication_secret_file),
+    ];
+    for (option, path) in secrets {
+        if let Some(path) = path {
+            findings.check(option, reinjection::load_secret(path));
+        }
+    }
+    if let Some(path) = &cli.escrow_certificate {
+        if let Some(cert) = findings.check("--escrow-certificate", escrow::load_certificate(path)) {
+            findings.expiry("--escrow-certificate", &cert);
+        }
+    }
+    if let Some(dir) = &cli.template_dir {
+        findings.directory("--template-dir", dir);
+    }
+    if let Some(dir) = &cli.dead_letter_dir {
+        findings.directory("--dead-letter-dir", dir);
+    }
+    if let Some(dir) = &cli.harvest_spool_dir {
+        findings.directory("--harvest-spool-dir", dir);
+    }
+    if let Some(user) = &cli.user {
+        findings.check("--user", privileges::lookup(user, cli.group.as_deref()));
+    }
+    if let Some(hook) = &cli.post_process_hook {
+        findings.check("--post-process-hook", hook.check());
+    }
+    if cli.sandbox && sandbox::landlock_abi().is_none() {
+        findings.check("--sandbox", sandbox::chroot_root(cli));
+    }
+
+    check_pipelines(cli, &mut findings);
+    check_modes(cli, &mut findings);
+    check_notifications(cli, &mut findings);
+    findings.0
+}
+
+/// Pipelines leaving out the stages their action is for.
+fn check_pipelines(cli: &Cli, findings: &mut Findings) {
+    for (action, names) in &cli.pipelines {
+        let essential: &[&str] = match action {
+            MilterAction::Encrypt => &["encrypt", "emit-envelope"],
+            MilterAction::ExtractKeys | MilterAction::Enroll => &["store-certificates"],
+        };
+        for stage in essential
+            .iter()
+            .filter(|stage| !names.iter().any(|n| n == *stage))
+        {
+            findings.warning(format!(
+                "--pipeline: the {} pipeline leaves out the {} stage",
+                action.as_str(),
+                stage
+            ));
+        }
+    }
+}
+
+/// Options that have no effect with the modes and policy chosen.
+fn check_modes(cli: &Cli, findings: &mut Findings) {
+    let encrypts = cli.listen.iter().any(|listen| {
+        listen
+            .mode
+            .unwrap_or(cli.mode)
+            .allows(&MilterAction::Encrypt)
+    });
+    if !encrypts {
+        let unused = [
+            ("--key-request-from", cli.key_request_from.is_some()),
+            ("--escrow-certificate", cli.escrow_certificate.is_some()),
+            // Opaque signatures apply to the gateway's own mail as well.
+            (
+                "--crypto-profile",
+                cli.crypto_profiles
+                    .iter()
+                    .any(|(_, profile)| profile.signature == SignatureFormat::Clear),
+            ),
+        ];
+        for (option, _) in unused.iter().filter(|(_, given)| *given) {
+            findings.warning(format!("{}: no listener encrypts", option));
+        }
+    }
+    let harvests = cli.listen.iter().any(|listen| {
+        listen
+            .mode
+            .unwrap_or(cli.mode)
+            .allows(&MilterAction::ExtractKeys)
+    });
+    if !harvests {
+        let unused = [
+            (
+                "--harvest-require-auth",
+                !cli.harvest_require_auth.is_empty(),
+            ),
+            (
+                "--harvest-from-header",
+                cli.harvest_from_header != FromHeaderMatch::Ignore,
+            ),
+        ];
+        for (option, _) in unused.iter().filter(|(_, given)| *given) {
+            findings.warning(format!("{}: no listener harvests", option));
+        }
+    }
+    match cli.tls_policy {
+        TlsPolicy::SkipVerifiedTls if cli.verified_tls_domains.is_empty() => findings.warning(
+            "--tls-policy skip-verified-tls: no --verified-tls-domain, every message is \
+             encrypted"
+                .to_string(),
+        ),
+        TlsPolicy::Always | TlsPolicy::UnprotectedSubmission
+            if !cli.verified_tls_domains.is_empty() =>
+        {
+            findings.warning(
+                "--verified-tls-domain: only used with --tls-policy skip-verified-tls".to_string(),
+            )
+        }
+        _ => {}
+    }
+    let now = cert_usage::now() as i64;
+    for scheduled in cli.schedules.iter().filter(|s| s.window.has_ended(now)) {
+        findings.warning(format!("--schedule: {} has ended", scheduled.change));
+    }
+    if encrypts && cli.encrypt_percent == 0 {
+        findings.warning("--encrypt-percent 0: no message is encrypted".to_string());
+    }
+    if cli.enrollment_reply && cli.enrollment_addresses.is_empty() {
+        findings.warning("--enrollment-reply: no --enrollment-address".to_string());
+    }
+}
+
+/// The mail pantosmime sends itself, and how it is signed.
+fn check_notifications(cli: &Cli, findings: &mut Findings) {
+    if let Some(from) = &cli.key_request_from {
+        findings.check(
+            "--key-request-template",
+            KeyRequest::new(
+                from.clone(),
+                cli.key_request_template.as_deref(),
+                cli.key_request_interval_days,
+            ),
+        );
+    }
+    if cli.expiry.expiry_notify_from.is_some() && !cli.expiry.enabled() {
+        findings.warning(
+            "--expiry-notify-from: no --expiry-notify-admin or --expiry-notify-users".to_string(),
+        );
+    }
+
+    let mut senders: Vec<&str> = Vec::new();
+    senders.extend(cli.key_request_from.as_deref());
+    if cli.expiry.enabled() {
+        senders.extend(cli.expiry.expiry_notify_from.as_deref());
+    }
+    if cli.enrollment_reply {
+        senders.extend(cli.enrollment_addresses.iter().map(String::as_str));
+    }
+    if senders.is_empty() {
+        if cli.gateway_certificate.is_some() {
+            findings.warning("--gateway-certificate: no notifications or replies are sent".into());
+        }
+        if cli.encrypt_notifications {
+            findings
+                .warning("--encrypt-notifications: no notifications or replies are sent".into());
+        }
+    }
+    if let (Some(cert), Some(key)) = (&cli.gateway_certificate, &cli.gateway_key) {
+        let Some(identity) =
+            findings.check("--gateway-certificate", GatewayIdentity::load(cert, key))
+        else {
+            return;
+        };
+        findings.expiry("--gateway-certificate", identity.certificate());
+        for sender in senders {
+            if !identity.is_issued_for(sender) {
+                findings.warning(format!(
+                    "--gateway-certificate: not issued for {}, MUAs flag the signature",
+                    sender
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert_dir = dir.path().to_str().unwrap();
+        let address_file = dir.path().join("responsible.txt");
+        std::fs::write(&address_file, "*@example.com\nJohn <john@example.com>\n").unwrap();
+        let missing = dir.path().join("missing.pem");
+
+        let cli = Cli::try_parse_from([
+            "pantosmimed",
+            "-c",
+            cert_dir,
+            "--address-file",
+            address_file.to_str().unwrap(),
+            "--escrow-certificate",
+            missing.to_str().unwrap(),
+            "--mode",
+            "harvest-only",
+            "--key-request-from",
+            "postmaster@example.com",
+            "check-config",
+        ])
+        .unwrap();
+        let findings: Vec<String> = check(&cli)
+            .iter()
+            .map(|finding| format!("{}: {}", finding.severity, finding.message))
+            .collect();
+        assert_eq!(findings.len(), 4, "{:#?}", findings);
+        assert_eq!(
+            findings[0],
+            format!(
+                "error: --address-file: In {:?} line 2: Invalid address \"John <john@example.com>\"",
+                address_file
+            )
+        );
+        assert!(findings[1].starts_with("error: --escrow-certificate: Failed to read"));
+        assert_eq!(
+            findings[2..],
+            [
+                "warning: --key-request-from: no listener encrypts",
+                "warning: --escrow-certificate: no listener encrypts",
+            ]
+        );
+
+        let cli =
+            Cli::try_parse_from(["pantosmimed", "-c", cert_dir, "-a", "*@example.com"]).unwrap();
+        assert!(check(&cli).is_empty());
+    }
+}
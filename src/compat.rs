@@ -0,0 +1,151 @@
+//! Workarounds for receiving clients that are picky about the shape of S/MIME messages, like
+//! older Outlook versions or webmailers only offering to decrypt with the right file name.
+
+/// How recipients are identified in the envelope (RecipientIdentifier of RFC 5652).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecipientId {
+    /// Issuer and serial number of the certificate, understood by every client.
+    IssuerSerial,
+    /// Subject key identifier, for certificates having one.
+    KeyId,
+}
+
+/// Content-Disposition of the encrypted message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Disposition {
+    Attachment,
+    Inline,
+    /// No Content-Disposition header at all.
+    None,
+}
+
+/// Shape of encrypted messages.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Compatibility {
+    pub recipient_id: RecipientId,
+    /// File name of the envelope, `smime.p7m` as suggested by RFC 8551.
+    pub attachment_name: String,
+    pub disposition: Disposition,
+    /// Use `application/x-pkcs7-mime`, as older clients expect.
+    pub legacy_content_type: bool,
+}
+
+impl Default for Compatibility {
+    fn default() -> Self {
+        Self {
+            recipient_id: RecipientId::IssuerSerial,
+            attachment_name: "smime.p7m".to_string(),
+            disposition: Disposition::Attachment,
+            legacy_content_type: false,
+        }
+    }
+}
+
+/// A single workaround, as given on the command line.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Toggle {
+    RecipientId(RecipientId),
+    AttachmentName(String),
+    Disposition(Disposition),
+    LegacyContentType,
+}
+
+/// Parse `recipient-id=<issuer-serial|key-id>`, `attachment-name=<NAME>`,
+/// `disposition=<attachment|inline|none>` or `legacy-content-type`.
+pub fn parse_toggle(s: &str) -> Result<Toggle, String> {
+    match s.split_once('=') {
+        Some(("recipient-id", "issuer-serial")) => {
+            Ok(Toggle::RecipientId(RecipientId::IssuerSerial))
+        }
+        Some(("recipient-id", "key-id")) => Ok(Toggle::RecipientId(RecipientId::KeyId)),
+        Some(("attachment-name", name))
+            if !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c)) =>
+        {
+            Ok(Toggle::AttachmentName(name.to_string()))
+        }
+        Some(("disposition", "attachment")) => Ok(Toggle::Disposition(Disposition::Attachment)),
+        Some(("disposition", "inline")) => Ok(Toggle::Disposition(Disposition::Inline)),
+        Some(("disposition", "none")) => Ok(Toggle::Disposition(Disposition::None)),
+        None if s == "legacy-content-type" => Ok(Toggle::LegacyContentType),
+        _ => Err(format!("unknown compatibility toggle {:?}", s)),
+    }
+}
+
+impl Compatibility {
+    /// The defaults with the toggles applied, later ones winning.
+    pub fn from_toggles(toggles: impl IntoIterator<Item = Toggle>) -> Self {
+        let mut compat = Self::default();
+        for toggle in toggles {
+            match toggle {
+                Toggle::RecipientId(id) => compat.recipient_id = id,
+                Toggle::AttachmentName(name) => compat.attachment_name = name,
+                Toggle::Disposition(disposition) => compat.disposition = disposition,
+                Toggle::LegacyContentType => compat.legacy_content_type = true,
+            }
+        }
+        compat
+    }
+
+    /// Content-Type of an envelope with the given `smime-type`.
+    pub fn content_type(&self, smime_type: &str) -> String {
+        let mime_type = match self.legacy_content_type {
+            true => "application/x-pkcs7-mime",
+            false => "application/pkcs7-mime",
+        };
+        format!(
+            "{}; name={}; smime-type={}",
+            mime_type, self.attachment_name, smime_type
+        )
+    }
+
+    /// Content-Disposition of the envelope, if any.
+    pub fn content_disposition(&self) -> Option<String> {
+        match self.disposition {
+            Disposition::Attachment => {
+                Some(format!("attachment; filename={}", self.attachment_name))
+            }
+            Disposition::Inline => Some(format!("inline; filename={}", self.attachment_name)),
+            Disposition::None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_toggles() {
+        let default = Compatibility::default();
+        assert_eq!(
+            default.content_type("enveloped-data"),
+            "application/pkcs7-mime; name=smime.p7m; smime-type=enveloped-data"
+        );
+        assert_eq!(
+            default.content_disposition().as_deref(),
+            Some("attachment; filename=smime.p7m")
+        );
+
+        let toggles = [
+            "recipient-id=key-id",
+            "attachment-name=smime-mime.p7m",
+            "disposition=none",
+            "legacy-content-type",
+        ];
+        let compat =
+            Compatibility::from_toggles(toggles.into_iter().map(|t| parse_toggle(t).unwrap()));
+        assert_eq!(compat.recipient_id, RecipientId::KeyId);
+        assert_eq!(
+            compat.content_type("enveloped-data"),
+            "application/x-pkcs7-mime; name=smime-mime.p7m; smime-type=enveloped-data"
+        );
+        assert_eq!(compat.content_disposition(), None);
+
+        assert!(parse_toggle("attachment-name=a b.p7m").is_err());
+        assert!(parse_toggle("disposition=hidden").is_err());
+        assert!(parse_toggle("quirks").is_err());
+    }
+}
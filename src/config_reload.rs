@@ -0,0 +1,283 @@
+//! Reloading the file based configuration on SIGHUP: the responsible addresses, the trust
+//! anchor bundles and the policy script, without dropping milter sessions. The address file
+//! is also re-read by itself when it or one of its includes changes.
+//!
+//! Everything is loaded before anything is replaced, so a broken file keeps the running
+//! configuration. Messages in progress keep the version they started reading.
+
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
+
+use crate::address_list;
+#[cfg(feature = "lua")]
+use crate::policy_script::PolicyScript;
+use crate::settings::Settings;
+use crate::trust::TrustAnchors;
+
+/// Part of the settings replaced on reload.
+#[derive(Default)]
+pub struct Reloadable<T>(RwLock<Arc<T>>);
+
+impl<T> Reloadable<T> {
+    pub fn new(value: T) -> Self {
+        Self(RwLock::new(Arc::new(value)))
+    }
+
+    /// The current value, unaffected by reloads while it is held.
+    pub fn get(&self) -> Arc<T> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn set(&self, value: T) {
+        *self.0.write().unwrap() = Arc::new(value);
+    }
+}
+
+/// Where the reloadable configuration comes from.
+#[derive(Debug, Clone, Default)]
+pub struct Sources {
+    /// Responsible addresses given on the command line.
+    pub addresses: Vec<String>,
+    pub address_file: Option<PathBuf>,
+    pub trust_anchors: Vec<(String, PathBuf)>,
+    #[cfg(feature = "lua")]
+    pub policy_script: Option<PathBuf>,
+}
+
+impl Sources {
+    /// The responsible addresses, given directly and in the address file.
+    pub fn responsible(&self) -> Result<Vec<String>> {
+        self.responsible_tracked().map(|(addresses, _)| addresses)
+    }
+
+    /// The responsible addresses, and the address files they were read from.
+    fn responsible_tracked(&self) -> Result<(Vec<String>, Vec<PathBuf>)> {
+        for address in &self.addresses {
+            address_list::Pattern::parse(address).context("Invalid --address")?;
+        }
+        let mut addresses = self.addresses.clone();
+        let mut files = Vec::new();
+        if let Some(path) = &self.address_file {
+            let (loaded, read) = address_list::load_address_file_tracked(path)?;
+            addresses.extend(loaded);
+            files = read;
+        }
+        Ok((addresses, files))
+    }
+
+    pub fn trust_anchors(&self) -> Result<Vec<TrustAnchors>> {
+        self.trust_anchors
+            .iter()
+            .map(|(domain, path)| TrustAnchors::load(domain.clone(), path))
+            .collect()
+    }
+
+    #[cfg(feature = "lua")]
+    pub fn policy_script(&self) -> Result<Option<PolicyScript>> {
+        self.policy_script
+            .as_deref()
+            .map(PolicyScript::load)
+            .transpose()
+    }
+}
+
+/// Load the configuration from `sources` and replace the running one, all or nothing.
+pub fn reload(settings: &Settings, sources: &Sources) -> Result<()> {
+    let responsible = sources
+        .responsible()
+        .context("Failed to load responsible addresses")?;
+    let trust_anchors = sources
+        .trust_anchors()
+        .context("Failed to load trust anchors")?;
+    #[cfg(feature = "lua")]
+    let policy_script = sources
+        .policy_script()
+        .context("Failed to load policy script")?;
+
+    info!(count = responsible.len(), "Reloaded responsible addresses");
+    settings.set_responsible(responsible);
+    settings.trust_anchors.set(trust_anchors);
+    #[cfg(feature = "lua")]
+    {
+        settings.policy_script.set(policy_script);
+        settings.policy_decisions.clear();
+    }
+    Ok(())
+}
+
+/// Reload the configuration on every SIGHUP, forever.
+pub async fn reload_on_signal(settings: Arc<Settings>, sources: Sources) {
+    let mut signals = match signal(SignalKind::hangup()) {
+        Ok(signals) => signals,
+        Err(error) => {
+            warn!(
+                ?error,
+                "Cannot listen for SIGHUP; the configuration is fixed"
+            );
+            return;
+        }
+    };
+    while signals.recv().await.is_some() {
+        match reload(&settings, &sources) {
+            Ok(()) => info!("Reloaded configuration"),
+            Err(error) => error!(
+                ?error,
+                "Failed to reload configuration, keeping the running one"
+            ),
+        }
+    }
+}
+
+/// The address files as last loaded, with their modification time and size, to notice when
+/// they change.
+struct Watched {
+    files: Vec<PathBuf>,
+    versions: Vec<Option<(SystemTime, u64)>>,
+}
+
+impl Watched {
+    fn new(files: Vec<PathBuf>) -> Self {
+        let versions = Self::versions(&files);
+        Self { files, versions }
+    }
+
+    fn versions(files: &[PathBuf]) -> Vec<Option<(SystemTime, u64)>> {
+        files
+            .iter()
+            .map(|file| {
+                let metadata = std::fs::metadata(file).ok()?;
+                Some((metadata.modified().ok()?, metadata.len()))
+            })
+            .collect()
+    }
+
+    /// Whether a file changed since the last call, or since loading.
+    fn changed(&mut self) -> bool {
+        let versions = Self::versions(&self.files);
+        let changed = versions != self.versions;
+        self.versions = versions;
+        changed
+    }
+}
+
+/// Reload the responsible addresses if an address file changed, and tell whether it did.
+/// A broken file keeps the running addresses until it changes again.
+fn reload_changed_addresses(
+    settings: &Settings,
+    sources: &Sources,
+    watched: &mut Watched,
+) -> Result<bool> {
+    if !watched.changed() {
+        return Ok(false);
+    }
+    let (responsible, files) = sources
+        .responsible_tracked()
+        .context("Failed to load responsible addresses")?;
+    info!(
+        count = responsible.len(),
+        "Reloaded responsible addresses after the address file changed"
+    );
+    settings.set_responsible(responsible);
+    if files != watched.files {
+        *watched = Watched::new(files);
+    }
+    Ok(true)
+}
+
+/// Check the address file and its includes for changes every `interval`, forever.
+pub async fn reload_on_change(settings: Arc<Settings>, sources: Sources, interval: Duration) {
+    let Some(path) = &sources.address_file else {
+        return;
+    };
+    let files = match sources.responsible_tracked() {
+        Ok((_, files)) => files,
+        Err(_) => vec![path.clone()],
+    };
+    let mut watched = Watched::new(files);
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        if let Err(error) = reload_changed_addresses(&settings, &sources, &mut watched) {
+            error!(
+                ?error,
+                "Failed to reload the changed address file, keeping the running addresses"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let address_file = dir.path().join("responsible.txt");
+        std::fs::write(&address_file, "a@example.com\n").unwrap();
+        let mut sources = Sources {
+            addresses: vec!["*@example.org".into()],
+            address_file: Some(address_file.clone()),
+            ..Sources::default()
+        };
+        let settings = Settings::new(dir.path().to_path_buf(), sources.responsible().unwrap());
+        assert!(settings.is_responsible("a@example.com"));
+
+        let held = settings.responsible.get();
+        std::fs::write(&address_file, "B@Example.com\n").unwrap();
+        reload(&settings, &sources).unwrap();
+        assert!(!settings.is_responsible("a@example.com"));
+        assert!(settings.is_responsible("B@example.com"));
+        assert!(settings.is_responsible("c@example.org"));
+        assert!(held
+            .iter()
+            .any(|pattern| pattern.as_str() == "a@example.com"));
+
+        // A broken file keeps everything as it is.
+        sources.trust_anchors = vec![("*".into(), dir.path().join("missing.pem"))];
+        std::fs::write(&address_file, "d@example.com\n").unwrap();
+        assert!(reload(&settings, &sources).is_err());
+        assert!(settings.is_responsible("B@example.com"));
+        assert!(!settings.is_responsible("d@example.com"));
+    }
+
+    #[test]
+    fn test_reload_changed_addresses() {
+        let dir = tempfile::tempdir().unwrap();
+        let address_file = dir.path().join("responsible.txt");
+        let tenant_file = dir.path().join("tenant.txt");
+        std::fs::write(&address_file, "a@example.com\ninclude tenant.txt\n").unwrap();
+        std::fs::write(&tenant_file, "b@example.org\n").unwrap();
+        let sources = Sources {
+            address_file: Some(address_file.clone()),
+            ..Sources::default()
+        };
+        let (responsible, files) = sources.responsible_tracked().unwrap();
+        assert_eq!(files, [address_file.clone(), tenant_file.clone()]);
+        let settings = Settings::new(dir.path().to_path_buf(), responsible);
+        let mut watched = Watched::new(files);
+        assert!(!reload_changed_addresses(&settings, &sources, &mut watched).unwrap());
+
+        // Changes to included files count too.
+        std::fs::write(&tenant_file, "b@example.org\nc@example.org\n").unwrap();
+        assert!(reload_changed_addresses(&settings, &sources, &mut watched).unwrap());
+        assert!(settings.is_responsible("c@example.org"));
+        assert!(!reload_changed_addresses(&settings, &sources, &mut watched).unwrap());
+
+        // A broken file is tried again once it changes, the addresses are kept meanwhile.
+        std::fs::write(&address_file, "<a@example.com>\n").unwrap();
+        assert!(reload_changed_addresses(&settings, &sources, &mut watched).is_err());
+        assert!(settings.is_responsible("a@example.com"));
+        assert!(!reload_changed_addresses(&settings, &sources, &mut watched).unwrap());
+        std::fs::write(&address_file, "d@example.com\n").unwrap();
+        assert!(reload_changed_addresses(&settings, &sources, &mut watched).unwrap());
+        assert!(settings.is_responsible("d@example.com"));
+        assert!(!settings.is_responsible("c@example.org"));
+        assert_eq!(watched.files, [address_file]);
+    }
+}
@@ -0,0 +1,263 @@
+//! Parsing of address book exports carrying S/MIME certificates.
+//!
+//! Supports vCards (2.1 to 4.0) with `KEY` properties, and CSV contact exports
+//! as written by Outlook, with certificates in columns named like "Certificate".
+
+use anyhow::{bail, Context, Result};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use openssl::x509::X509;
+use tracing::warn;
+
+use crate::smime;
+
+/// A contact with its addresses and certificates.
+#[derive(Debug, Default)]
+pub struct Contact {
+    pub emails: Vec<String>,
+    pub certificates: Vec<X509>,
+}
+
+/// Decode a certificate from PEM, or base64 encoded DER X.509 or PKCS#7.
+fn decode_certificates(data: &str) -> Result<Vec<X509>> {
+    if data.contains("-----BEGIN") {
+        return X509::stack_from_pem(data.as_bytes()).with_context(|| "Failed to parse PEM");
+    }
+    let mut encoded = data.to_string();
+    encoded.retain(|c| !c.is_whitespace());
+    let der = BASE64_STANDARD
+        .decode(encoded)
+        .with_context(|| "Failed to decode base64")?;
+    match X509::from_der(&der) {
+        Ok(cert) => Ok(vec![cert]),
+        Err(_) => smime::extract_certificates_from_p7s(&der)
+            .with_context(|| "Neither a X.509 certificate nor PKCS#7 data"),
+    }
+}
+
+/// Join folded vCard lines.
+fn unfold(data: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in data.lines() {
+        match line.strip_prefix([' ', '\t']) {
+            Some(continued) if !lines.is_empty() => {
+                lines.last_mut().unwrap().push_str(continued.trim_start())
+            }
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+/// Parse all contacts of a vCard file.
+pub fn parse_vcards(data: &str) -> Vec<Contact> {
+    let mut contacts = Vec::new();
+    let mut current: Option<Contact> = None;
+
+    for (number, line) in unfold(data).iter().enumerate() {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let mut params = name.split(';');
+        let property = params.next().unwrap_or_default();
+        // Properties can be grouped, like `item1.EMAIL`.
+        let property = property.rsplit('.').next().unwrap_or_default();
+        let params: Vec<&str> = params.collect();
+
+        match (property.to_ascii_uppercase().as_str(), current.as_mut()) {
+            ("BEGIN", _) if value.trim().eq_ignore_ascii_case("VCARD") => {
+                current = Some(Contact::default());
+            }
+            ("END", Some(_)) if value.trim().eq_ignore_ascii_case("VCARD") => {
+                contacts.extend(current.take());
+            }
+            ("EMAIL", Some(contact)) => {
+                let email = value.trim();
+                if !email.is_empty() {
+                    contact.emails.push(email.to_string());
+                }
+            }
+            ("KEY", Some(contact)) => {
+                // vCard 4.0 carries the key as data URI, older versions as
+                // inline base64 marked with an ENCODING parameter.
+                let data = match value.split_once(";base64,") {
+                    Some((_, data)) if value.starts_with("data:") => Some(data),
+                    _ if params.iter().any(|p| {
+                        let p = p.to_ascii_uppercase();
+                        p == "ENCODING=B" || p == "ENCODING=BASE64" || p == "BASE64"
+                    }) =>
+                    {
+                        Some(value)
+                    }
+                    _ => None,
+                };
+                match data.map(decode_certificates) {
+                    Some(Ok(certs)) => contact.certificates.extend(certs),
+                    Some(Err(error)) => warn!(?error, line = number + 1, "Skipping vCard key"),
+                    None => warn!(line = number + 1, "Skipping vCard key of unknown encoding"),
+                }
+            }
+            _ => {}
+        }
+    }
+    contacts
+}
+
+/// Split CSV data into records, honoring quoted fields.
+fn parse_csv_records(data: &str) -> Vec<Vec<String>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = data.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => record.push(std::mem::take(&mut field)),
+            '\r' if !quoted => {}
+            '\n' if !quoted => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            c => field.push(c),
+        }
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    records
+}
+
+/// Parse all contacts of a CSV contact export.
+pub fn parse_csv(data: &str) -> Result<Vec<Contact>> {
+    let mut records = parse_csv_records(data.trim_start_matches('\u{feff}')).into_iter();
+    let header = records.next().unwrap_or_default();
+    let columns = |matches: fn(&str) -> bool| -> Vec<usize> {
+        header
+            .iter()
+            .enumerate()
+            .filter(|(_, name)| matches(&name.trim().to_lowercase()))
+            .map(|(i, _)| i)
+            .collect()
+    };
+    let email_columns =
+        columns(|name| name == "email" || (name.contains("mail") && name.contains("address")));
+    let cert_columns = columns(|name| name.contains("certificate"));
+    if email_columns.is_empty() {
+        bail!("No email address column found in CSV header");
+    }
+    if cert_columns.is_empty() {
+        bail!("No certificate column found in CSV header");
+    }
+
+    let mut contacts = Vec::new();
+    for (number, record) in records.enumerate() {
+        let field = |i: &usize| record.get(*i).map(|f| f.trim()).unwrap_or_default();
+        let mut contact = Contact {
+            emails: email_columns
+                .iter()
+                .map(field)
+                .filter(|e| e.contains('@'))
+                .map(str::to_string)
+                .collect(),
+            ..Default::default()
+        };
+        for data in cert_columns.iter().map(field).filter(|d| !d.is_empty()) {
+            match decode_certificates(data) {
+                Ok(certs) => contact.certificates.extend(certs),
+                Err(error) => warn!(?error, row = number + 2, "Skipping CSV certificate"),
+            }
+        }
+        contacts.push(contact);
+    }
+    Ok(contacts)
+}
+
+/// Parse a vCard file or CSV contact export, depending on its content.
+pub fn parse_contacts(data: &str) -> Result<Vec<Contact>> {
+    let start = data.trim_start_matches('\u{feff}').trim_start();
+    if start
+        .get(..11)
+        .is_some_and(|s| s.eq_ignore_ascii_case("BEGIN:VCARD"))
+    {
+        Ok(parse_vcards(data))
+    } else {
+        parse_csv(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_pki::self_signed_identity;
+
+    fn folded_base64(cert: &X509) -> String {
+        let encoded = BASE64_STANDARD.encode(cert.to_der().unwrap());
+        let lines: Vec<&str> = encoded
+            .as_bytes()
+            .chunks(74)
+            .map(|c| std::str::from_utf8(c).unwrap())
+            .collect();
+        lines.join("\r\n ")
+    }
+
+    #[test]
+    fn test_parse_vcards() {
+        let (alice, _) = self_signed_identity("alice@example.com");
+        let (bob, _) = self_signed_identity("bob@example.com");
+        let data = format!(
+            "BEGIN:VCARD\r\n\
+             VERSION:3.0\r\n\
+             FN:Alice\r\n\
+             EMAIL;TYPE=INTERNET:alice@example.com\r\n\
+             KEY;X509;ENCODING=b:{}\r\n\
+             END:VCARD\r\n\
+             BEGIN:VCARD\r\n\
+             VERSION:4.0\r\n\
+             item1.EMAIL:bob@example.com\r\n\
+             KEY;MEDIATYPE=application/pkix-cert:data:application/pkix-cert;base64,{}\r\n\
+             END:VCARD\r\n\
+             BEGIN:VCARD\r\n\
+             EMAIL:nokey@example.com\r\n\
+             KEY;TYPE=PGP:https://example.com/key.asc\r\n\
+             END:VCARD\r\n",
+            folded_base64(&alice),
+            folded_base64(&bob)
+        );
+
+        let contacts = parse_contacts(&data).unwrap();
+        assert_eq!(contacts.len(), 3);
+        assert_eq!(contacts[0].emails, ["alice@example.com"]);
+        assert_eq!(contacts[0].certificates, [alice]);
+        assert_eq!(contacts[1].emails, ["bob@example.com"]);
+        assert_eq!(contacts[1].certificates, [bob]);
+        assert_eq!(contacts[2].emails, ["nokey@example.com"]);
+        assert!(contacts[2].certificates.is_empty());
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        let (alice, _) = self_signed_identity("alice@example.com");
+        let pem = String::from_utf8(alice.to_pem().unwrap()).unwrap();
+        let data = format!(
+            "\u{feff}First Name,Last Name,E-mail Address,E-mail 2 Address,E-mail Display Name,User Certificate\r\n\
+             Alice,\"Doe, Jr.\",alice@example.com,alice@home.test,\"Alice \"\"A\"\" Doe\",\"{}\"\r\n\
+             Bob,Doe,bob@example.com,,Bob,\r\n",
+            pem
+        );
+
+        let contacts = parse_contacts(&data).unwrap();
+        assert_eq!(contacts.len(), 2);
+        assert_eq!(contacts[0].emails, ["alice@example.com", "alice@home.test"]);
+        assert_eq!(contacts[0].certificates, [alice]);
+        assert_eq!(contacts[1].emails, ["bob@example.com"]);
+        assert!(contacts[1].certificates.is_empty());
+
+        assert!(parse_contacts("Name,Phone\nAlice,123\n").is_err());
+    }
+}
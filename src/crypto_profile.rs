@@ -0,0 +1,556 @@
+//! Crypto profiles per destination domain, for partner gateways that need other settings than
+//! the default, be it weaker ones for interoperability or stronger ones.
+//!
+//! The content encryption is shared by all recipients of a message, so it is negotiated down to
+//! what every recipient's profile allows. Key transport is chosen for each recipient.
+
+use anyhow::{bail, Context, Result};
+use foreign_types::ForeignType;
+use openssl::cms::{CMSOptions, CmsContentInfo};
+use openssl::error::ErrorStack;
+use openssl::stack::Stack;
+use openssl::symm::Cipher;
+use openssl::x509::X509;
+use std::ffi::{c_int, c_uint, c_ulong, c_void};
+
+use crate::address;
+use crate::compat::RecipientId;
+use crate::der;
+use crate::transfer_encoding;
+
+/// Content encryption algorithm, weakest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ContentCipher {
+    /// Triple DES, only for gateways that know nothing else.
+    Des3,
+    Aes128,
+    Aes192,
+    Aes256,
+}
+
+impl ContentCipher {
+    fn parse(s: &str) -> Result<Self, String> {
+        CIPHERS
+            .iter()
+            .find(|(name, _)| *name == s)
+            .map(|(_, cipher)| *cipher)
+            .ok_or_else(|| format!("unknown cipher {:?}", s))
+    }
+}
+
+/// The content ciphers with their names in profiles.
+pub const CIPHERS: [(&str, ContentCipher); 4] = [
+    ("3des", ContentCipher::Des3),
+    ("aes-128", ContentCipher::Aes128),
+    ("aes-192", ContentCipher::Aes192),
+    ("aes-256", ContentCipher::Aes256),
+];
+
+/// How the content encryption key is encrypted for a recipient.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyTransport {
+    /// RSA with PKCS#1 v1.5 padding, understood everywhere.
+    RsaPkcs1,
+    /// RSA-OAEP with SHA-256, as recommended by RFC 8551.
+    RsaOaep,
+}
+
+/// The key transports with their names in profiles.
+pub const KEY_TRANSPORTS: [(&str, KeyTransport); 2] = [
+    ("rsa-pkcs1", KeyTransport::RsaPkcs1),
+    ("rsa-oaep", KeyTransport::RsaOaep),
+];
+
+/// How the mail the gateway generates itself is signed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SignatureFormat {
+    /// `multipart/signed`, readable without S/MIME support.
+    Clear,
+    /// `application/pkcs7-mime` signed-data, for receivers mangling clear-signed messages.
+    Opaque,
+}
+
+/// The signature formats with their names in profiles.
+pub const SIGNATURE_FORMATS: [(&str, SignatureFormat); 2] = [
+    ("clear", SignatureFormat::Clear),
+    ("opaque", SignatureFormat::Opaque),
+];
+
+/// Crypto settings for the recipients at a domain.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CryptoProfile {
+    pub cipher: ContentCipher,
+    /// Use authenticated encryption (AES-GCM in AuthEnvelopedData).
+    pub aead: bool,
+    /// Use authenticated encryption for recipients announcing AES-GCM in the S/MIME
+    /// capabilities of their harvested signature, as S/MIME 4.0 clients do.
+    pub aead_auto: bool,
+    pub key_transport: KeyTransport,
+    /// Compress the content before encrypting it (CompressedData, RFC 3274).
+    pub compress: bool,
+    pub signature: SignatureFormat,
+}
+
+/// What all messages used before there were profiles.
+const DEFAULT_PROFILE: CryptoProfile = CryptoProfile {
+    cipher: ContentCipher::Aes256,
+    aead: false,
+    aead_auto: false,
+    key_transport: KeyTransport::RsaPkcs1,
+    compress: false,
+    signature: SignatureFormat::Clear,
+};
+
+impl Default for CryptoProfile {
+    fn default() -> Self {
+        DEFAULT_PROFILE
+    }
+}
+
+/// Parse a `<DOMAIN>=<OPTION>[,<OPTION>...]` profile, the options being `cipher=<CIPHER>`
+/// (`3des`, `aes-128`, `aes-192` or `aes-256`), `aead` or `aead=auto`,
+/// `key-transport=<rsa-pkcs1|rsa-oaep>`, `compress` and `signature=<clear|opaque>`. Unset
+/// options keep their default. The domain may be `*` or `*.<DOMAIN>`.
+pub fn parse_profile(s: &str) -> Result<(String, CryptoProfile), String> {
+    let (domain, options) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <DOMAIN>=<OPTIONS>, got {:?}", s))?;
+    let mut profile = CryptoProfile::default();
+    for option in options.split(',').map(str::trim) {
+        match option.split_once('=') {
+            Some(("cipher", cipher)) => profile.cipher = ContentCipher::parse(cipher)?,
+            Some(("key-transport", name)) => {
+                profile.key_transport = KEY_TRANSPORTS
+                    .iter()
+                    .find(|(known, _)| *known == name)
+                    .map(|(_, key_transport)| *key_transport)
+                    .ok_or_else(|| format!("unknown key transport {:?}", name))?
+            }
+            Some(("aead", "auto")) => profile.aead_auto = true,
+            Some(("signature", name)) => {
+                profile.signature = SIGNATURE_FORMATS
+                    .iter()
+                    .find(|(known, _)| *known == name)
+                    .map(|(_, format)| *format)
+                    .ok_or_else(|| format!("unknown signature format {:?}", name))?
+            }
+            None if option == "aead" => profile.aead = true,
+            None if option == "compress" => profile.compress = true,
+            _ => return Err(format!("unknown profile option {:?}", option)),
+        }
+    }
+    if (profile.aead || profile.aead_auto) && profile.cipher == ContentCipher::Des3 {
+        return Err("aead requires an AES cipher".to_string());
+    }
+    let domain = match idna::domain_to_ascii(domain.trim()) {
+        Ok(ascii) if !ascii.is_empty() => ascii,
+        _ => return Err(format!("invalid domain {:?}", domain)),
+    };
+    Ok((domain, profile))
+}
+
+/// Profile for a recipient, from the first matching domain.
+pub fn profile_for<'p>(profiles: &'p [(String, CryptoProfile)], email: &str) -> &'p CryptoProfile {
+    let domain = email.rsplit_once('@').map_or("", |(_, domain)| domain);
+    profiles
+        .iter()
+        .find(|(pattern, _)| address::domain_matches(pattern, domain))
+        .map_or(&DEFAULT_PROFILE, |(_, profile)| profile)
+}
+
+/// Content encryption every recipient can handle: the weakest cipher, and AEAD or compression
+/// only if all of them use it.
+pub fn negotiate<'p>(profiles: impl IntoIterator<Item = &'p CryptoProfile>) -> CryptoProfile {
+    profiles
+        .into_iter()
+        .fold(None, |common: Option<CryptoProfile>, profile| {
+            Some(match common {
+                None => profile.clone(),
+                Some(common) => CryptoProfile {
+                    cipher: common.cipher.min(profile.cipher),
+                    aead: common.aead && profile.aead,
+                    aead_auto: common.aead_auto && profile.aead_auto,
+                    key_transport: common.key_transport,
+                    compress: common.compress && profile.compress,
+                    signature: common.signature,
+                },
+            })
+        })
+        .unwrap_or_default()
+}
+
+/// Whether S/MIME capabilities, as harvested, announce AES-GCM and so AuthEnvelopedData.
+pub fn announces_aead(capabilities: &[String]) -> bool {
+    capabilities
+        .iter()
+        .any(|c| ["id-aes128-GCM", "id-aes192-GCM", "id-aes256-GCM"].contains(&c.as_str()))
+}
+
+impl CryptoProfile {
+    /// The OpenSSL cipher and its name, e.g. for event reports.
+    pub fn cipher(&self) -> (Cipher, &'static str) {
+        match (self.cipher, self.aead) {
+            (ContentCipher::Des3, _) => (Cipher::des_ede3_cbc(), "des-ede3-cbc"),
+            (ContentCipher::Aes128, false) => (Cipher::aes_128_cbc(), "aes-128-cbc"),
+            (ContentCipher::Aes192, false) => (Cipher::aes_192_cbc(), "aes-192-cbc"),
+            (ContentCipher::Aes256, false) => (Cipher::aes_256_cbc(), "aes-256-cbc"),
+            (ContentCipher::Aes128, true) => (Cipher::aes_128_gcm(), "aes-128-gcm"),
+            (ContentCipher::Aes192, true) => (Cipher::aes_192_gcm(), "aes-192-gcm"),
+            (ContentCipher::Aes256, true) => (Cipher::aes_256_gcm(), "aes-256-gcm"),
+        }
+    }
+
+    /// Value of the `smime-type` parameter of the encrypted message.
+    pub fn smime_type(&self) -> &'static str {
+        match self.aead && self.cipher != ContentCipher::Des3 {
+            true => "authEnveloped-data",
+            false => "enveloped-data",
+        }
+    }
+}
+
+extern "C" {
+    fn CMS_add1_recipient_cert(
+        cms: *mut openssl_sys::CMS_ContentInfo,
+        recipient: *mut openssl_sys::X509,
+        flags: c_uint,
+    ) -> *mut c_void;
+    fn CMS_RecipientInfo_get0_pkey_ctx(ri: *mut c_void) -> *mut openssl_sys::EVP_PKEY_CTX;
+    fn CMS_final(
+        cms: *mut openssl_sys::CMS_ContentInfo,
+        data: *mut openssl_sys::BIO,
+        dcont: *mut openssl_sys::BIO,
+        flags: c_uint,
+    ) -> c_int;
+}
+
+fn check<T>(ptr: *mut T) -> Result<*mut T, ErrorStack> {
+    match ptr.is_null() {
+        true => Err(ErrorStack::get()),
+        false => Ok(ptr),
+    }
+}
+
+/// Encrypt with RSA-OAEP for the recipients asking for it, or identifying recipients by their
+/// subject key identifier, which the safe bindings of OpenSSL can't do.
+fn encrypt_with_key_params(
+    content: &[u8],
+    to: &[(X509, KeyTransport)],
+    cipher: Cipher,
+    recipient_id: RecipientId,
+) -> Result<CmsContentInfo, ErrorStack> {
+    let flags = openssl_sys::CMS_BINARY | openssl_sys::CMS_PARTIAL;
+    let len = c_int::try_from(content.len()).map_err(|_| ErrorStack::get())?;
+    // SAFETY: The BIO only borrows `content`, and is freed before returning. The recipient
+    // infos and their key contexts are owned by the CMS structure, which is owned by the
+    // returned value as soon as it exists.
+    unsafe {
+        let bio = check(openssl_sys::BIO_new_mem_buf(
+            content.as_ptr() as *const c_void,
+            len,
+        ))?;
+        let result = (|| {
+            let cms = CmsContentInfo::from_ptr(check(openssl_sys::CMS_encrypt(
+                std::ptr::null_mut(),
+                bio,
+                cipher.as_ptr(),
+                flags,
+            ))?);
+            for (cert, key_transport) in to {
+                // Certificates without a key identifier can only be named by issuer and serial.
+                let id_flag = match recipient_id {
+                    RecipientId::KeyId if cert.subject_key_id().is_some() => {
+                        openssl_sys::CMS_USE_KEYID
+                    }
+                    _ => 0,
+                };
+                let ri = check(CMS_add1_recipient_cert(
+                    cms.as_ptr(),
+                    cert.as_ptr(),
+                    flags | openssl_sys::CMS_KEY_PARAM | id_flag,
+                ))?;
+                if *key_transport == KeyTransport::RsaOaep {
+                    let ctx = check(CMS_RecipientInfo_get0_pkey_ctx(ri))?;
+                    let sha256 = openssl_sys::EVP_sha256() as *mut _;
+                    if openssl_sys::EVP_PKEY_CTX_set_rsa_padding(
+                        ctx,
+                        openssl_sys::RSA_PKCS1_OAEP_PADDING,
+                    ) <= 0
+                        || openssl_sys::EVP_PKEY_CTX_set_rsa_oaep_md(ctx, sha256) <= 0
+                        || openssl_sys::EVP_PKEY_CTX_set_rsa_mgf1_md(ctx, sha256) <= 0
+                    {
+                        return Err(ErrorStack::get());
+                    }
+                }
+            }
+            if CMS_final(cms.as_ptr(), bio, std::ptr::null_mut(), flags) <= 0 {
+                return Err(ErrorStack::get());
+            }
+            Ok(cms)
+        })();
+        openssl_sys::BIO_free_all(bio);
+        result
+    }
+}
+
+/// Encrypt content for the recipients, returning DER encoded CMS (auth) enveloped data.
+pub fn encrypt(
+    content: &[u8],
+    to: &[(X509, KeyTransport)],
+    profile: &CryptoProfile,
+    recipient_id: RecipientId,
+) -> Result<Vec<u8>> {
+    let (cipher, _) = profile.cipher();
+    let cms = if recipient_id == RecipientId::IssuerSerial
+        && to.iter().all(|(_, kt)| *kt == KeyTransport::RsaPkcs1)
+    {
+        let mut recipients = Stack::new().context("Failed to create Stack for Recipient Certs")?;
+        for (cert, _) in to {
+            recipients
+                .push(cert.clone())
+                .context("Failed to add X509 Cert to Stack")?;
+        }
+        CmsContentInfo::encrypt(&recipients, content, cipher, CMSOptions::BINARY)
+    } else {
+        encrypt_with_key_params(content, to, cipher, recipient_id)
+    }
+    .context("Failed to encrypt content")?;
+    cms.to_der().context("Failed to convert CMS result to DER")
+}
+
+#[link(name = "z")]
+extern "C" {
+    fn compressBound(source_len: c_ulong) -> c_ulong;
+    fn compress2(
+        dest: *mut u8,
+        dest_len: *mut c_ulong,
+        source: *const u8,
+        source_len: c_ulong,
+        level: c_int,
+    ) -> c_int;
+    #[cfg(test)]
+    fn uncompress(
+        dest: *mut u8,
+        dest_len: *mut c_ulong,
+        source: *const u8,
+        source_len: c_ulong,
+    ) -> c_int;
+}
+
+fn zlib_compress(data: &[u8]) -> Result<Vec<u8>> {
+    // SAFETY: zlib writes at most `dest_len` bytes, which compressBound makes large enough.
+    unsafe {
+        let mut dest_len = compressBound(data.len() as c_ulong);
+        let mut dest = vec![0; dest_len as usize];
+        if compress2(
+            dest.as_mut_ptr(),
+            &mut dest_len,
+            data.as_ptr(),
+            data.len() as c_ulong,
+            9,
+        ) != 0
+        {
+            bail!("Failed to compress content");
+        }
+        dest.truncate(dest_len as usize);
+        Ok(dest)
+    }
+}
+
+/// id-ct-compressedData, 1.2.840.113549.1.9.16.1.9
+const COMPRESSED_DATA: &[u8] = &[
+    0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x10, 0x01, 0x09,
+];
+/// id-alg-zlibCompress, 1.2.840.113549.1.9.16.3.8
+const ZLIB_COMPRESS: &[u8] = &[
+    0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x10, 0x03, 0x08,
+];
+
+/// Wrap a MIME entity into a compressed S/MIME entity, as OpenSSL is usually built without
+/// zlib.
+pub fn compress_entity(entity: &[u8]) -> Result<Vec<u8>> {
+    let compressed_data = der::encode(
+        0x30,
+        &[
+            der::encode(0x02, &[0]),
+            der::encode(0x30, &der::encode(0x06, ZLIB_COMPRESS)),
+            der::encode(
+                0x30,
+                &[
+                    der::encode(0x06, der::DATA),
+                    der::encode(0xa0, &der::encode(0x04, &zlib_compress(entity)?)),
+                ]
+                .concat(),
+            ),
+        ]
+        .concat(),
+    );
+    let content_info = der::encode(
+        0x30,
+        &[
+            der::encode(0x06, COMPRESSED_DATA),
+            der::encode(0xa0, &compressed_data),
+        ]
+        .concat(),
+    );
+    let mut compressed =
+        b"Content-Type: application/pkcs7-mime; smime-type=compressed-data; name=smime.p7z\r\n\
+        Content-Transfer-Encoding: base64\r\n\
+        Content-Disposition: attachment; filename=smime.p7z\r\n\r\n"
+            .to_vec();
+    compressed.extend_from_slice(&transfer_encoding::encode_base64_wrapped(&content_info, 76));
+    compressed.extend_from_slice(b"\r\n");
+    Ok(compressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smime;
+    use crate::test_pki::self_signed_identity;
+    use base64::{prelude::BASE64_STANDARD, Engine};
+
+    #[test]
+    fn test_parse_profile() {
+        assert_eq!(
+            parse_profile("legacy.example=cipher=3des"),
+            Ok((
+                "legacy.example".to_string(),
+                CryptoProfile {
+                    cipher: ContentCipher::Des3,
+                    ..CryptoProfile::default()
+                }
+            ))
+        );
+        assert_eq!(
+            parse_profile(
+                "*=cipher=aes-128, aead, key-transport=rsa-oaep, compress, signature=opaque"
+            ),
+            Ok((
+                "*".to_string(),
+                CryptoProfile {
+                    cipher: ContentCipher::Aes128,
+                    aead: true,
+                    aead_auto: false,
+                    key_transport: KeyTransport::RsaOaep,
+                    compress: true,
+                    signature: SignatureFormat::Opaque,
+                }
+            ))
+        );
+        assert!(parse_profile("*=aead=auto").unwrap().1.aead_auto);
+        assert!(parse_profile("legacy.example=cipher=3des,aead").is_err());
+        assert!(parse_profile("legacy.example=cipher=3des,aead=auto").is_err());
+        assert!(parse_profile("legacy.example=aead=yes").is_err());
+        assert!(parse_profile("legacy.example=cipher=rc2").is_err());
+        assert!(parse_profile("legacy.example=signature=detached").is_err());
+        assert!(parse_profile("legacy.example").is_err());
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let profiles = vec![
+            parse_profile("legacy.example=cipher=aes-128").unwrap(),
+            parse_profile("*.partner.example=aead,compress,key-transport=rsa-oaep").unwrap(),
+        ];
+        let partner = profile_for(&profiles, "a@mx.partner.example");
+        assert!(partner.aead);
+        assert_eq!(
+            profile_for(&profiles, "a@example.com"),
+            &CryptoProfile::default()
+        );
+
+        let common = negotiate([partner, partner]);
+        assert_eq!(common.cipher().1, "aes-256-gcm");
+        assert_eq!(common.smime_type(), "authEnveloped-data");
+        let common = negotiate([partner, profile_for(&profiles, "b@legacy.example")]);
+        assert_eq!(common.cipher().1, "aes-128-cbc");
+        assert!(!common.compress);
+        assert_eq!(negotiate([]), CryptoProfile::default());
+    }
+
+    #[test]
+    fn test_encrypt_decryptable() {
+        let (pkcs1, pkcs1_key) = self_signed_identity("a@example.com");
+        let (oaep, oaep_key) = self_signed_identity("b@example.com");
+        for aead in [false, true] {
+            let profile = CryptoProfile {
+                aead,
+                ..CryptoProfile::default()
+            };
+            let encrypted = encrypt(
+                b"hello",
+                &[
+                    (pkcs1.clone(), KeyTransport::RsaPkcs1),
+                    (oaep.clone(), KeyTransport::RsaOaep),
+                ],
+                &profile,
+                RecipientId::IssuerSerial,
+            )
+            .unwrap();
+            assert_eq!(
+                smime::decrypt_data(&encrypted, &pkcs1, &pkcs1_key).unwrap(),
+                b"hello"
+            );
+            assert_eq!(
+                smime::decrypt_data(&encrypted, &oaep, &oaep_key).unwrap(),
+                b"hello"
+            );
+        }
+    }
+
+    #[test]
+    fn test_encrypt_key_id() {
+        let (cert, key) = self_signed_identity("a@example.com");
+        let key_id = cert.subject_key_id().unwrap().as_slice().to_vec();
+        let named_by_key_id =
+            |encrypted: &[u8]| encrypted.windows(key_id.len()).any(|w| w == key_id);
+        let to = [(cert.clone(), KeyTransport::RsaPkcs1)];
+        let profile = CryptoProfile::default();
+
+        let encrypted = encrypt(b"hello", &to, &profile, RecipientId::IssuerSerial).unwrap();
+        assert!(!named_by_key_id(&encrypted));
+        let encrypted = encrypt(b"hello", &to, &profile, RecipientId::KeyId).unwrap();
+        assert!(named_by_key_id(&encrypted));
+        assert_eq!(
+            smime::decrypt_data(&encrypted, &cert, &key).unwrap(),
+            b"hello"
+        );
+    }
+
+    #[test]
+    fn test_compress_entity() {
+        let entity = b"Content-Type: text/plain\r\n\r\nhello hello hello hello\r\n";
+        let compressed = compress_entity(entity).unwrap();
+        let text = String::from_utf8(compressed).unwrap();
+        let (headers, body) = text.split_once("\r\n\r\n").unwrap();
+        assert!(headers.contains("smime-type=compressed-data"));
+        let content_info = BASE64_STANDARD.decode(body.replace("\r\n", "")).unwrap();
+
+        // ContentInfo / [0] / CompressedData / EncapsulatedContentInfo / [0] / OCTET STRING
+        let (content_info, _) = der::read_tlv(&content_info).unwrap();
+        let parts = der::children(content_info.value).unwrap();
+        assert_eq!(
+            der::oid_to_string(parts[0].value),
+            "1.2.840.113549.1.9.16.1.9"
+        );
+        let (compressed_data, _) = der::read_tlv(parts[1].value).unwrap();
+        let fields = der::children(compressed_data.value).unwrap();
+        let encapsulated = der::children(fields[2].value).unwrap();
+        let (octets, _) = der::read_tlv(encapsulated[1].value).unwrap();
+
+        let mut out = vec![0u8; 1024];
+        let mut out_len = out.len() as c_ulong;
+        // SAFETY: zlib writes at most `out_len` bytes.
+        let status = unsafe {
+            uncompress(
+                out.as_mut_ptr(),
+                &mut out_len,
+                octets.value.as_ptr(),
+                octets.value.len() as c_ulong,
+            )
+        };
+        assert_eq!(status, 0);
+        assert_eq!(&out[..out_len as usize], entity);
+    }
+}
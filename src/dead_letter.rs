@@ -0,0 +1,75 @@
+//! Quarantine of messages rejected because processing failed, to run them through the
+//! `replay` subcommand once the problem is fixed.
+//!
+//! Each message is kept as `<id>.eml`, with its envelope and the error in `<id>.json`. Only the
+//! owner can read either, as the messages were meant to be encrypted.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::io::AsyncWriteExt;
+
+use crate::event_report;
+use crate::milter_callbacks::MilterContext;
+
+/// Envelope and failure of a quarantined message.
+#[derive(Serialize)]
+struct Metadata<'a> {
+    #[serde(rename = "@timestamp")]
+    timestamp: String,
+    queue_id: &'a str,
+    sender: &'a str,
+    recipients: &'a [String],
+    stage: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_code: Option<&'a str>,
+}
+
+/// Create a file only the owner can access.
+async fn write_private(path: &Path, content: &[u8]) -> Result<()> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .await
+        .with_context(|| format!("Failed to create {:?}", path))?;
+    file.write_all(content).await?;
+    file.sync_all().await?;
+    Ok(())
+}
+
+/// Save the message in `dir`, returning the path of its content. The metadata is written last,
+/// so a message with metadata is complete.
+pub async fn save(dir: &Path, ctx: &MilterContext<'_>) -> Result<PathBuf> {
+    tokio::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .await
+        .with_context(|| format!("Failed to create dead-letter directory {:?}", dir))?;
+    let id = uuid::Uuid::new_v4();
+    let path = dir.join(format!("{}.eml", id));
+    let mut content = Vec::with_capacity(ctx.raw_headers.len() + 2 + ctx.body.len());
+    content.extend_from_slice(&ctx.raw_headers);
+    content.extend_from_slice(b"\r\n");
+    content.extend_from_slice(&ctx.body);
+    write_private(&path, &content).await?;
+
+    let metadata = Metadata {
+        timestamp: event_report::rfc3339(SystemTime::now()),
+        queue_id: ctx.queue_id.as_deref().unwrap_or_default(),
+        sender: &ctx.sender,
+        recipients: &ctx.recipients,
+        stage: ctx.report.failed_stage.as_deref().unwrap_or_default(),
+        error: ctx.report.error.as_deref(),
+        error_code: ctx.report.error_code.as_deref(),
+    };
+    let mut json = serde_json::to_vec_pretty(&metadata)?;
+    json.push(b'\n');
+    write_private(&dir.join(format!("{}.json", id)), &json).await?;
+    Ok(path)
+}
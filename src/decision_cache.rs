@@ -0,0 +1,93 @@
+//! Short-lived memory of what was decided for a sender and its recipients, so the messages of
+//! a bulk mailing repeating the same envelope skip the policy script and the certificate
+//! lookups after the first one.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Most envelopes remembered at once, to bound the memory of mailings to ever new recipients.
+const MAX_ENTRIES: usize = 10_000;
+
+type Envelope = (String, Vec<String>);
+
+/// Values remembered per envelope for a while.
+pub struct DecisionCache<V> {
+    ttl: Duration,
+    entries: Mutex<HashMap<Envelope, (Instant, V)>>,
+}
+
+impl<V: Clone> DecisionCache<V> {
+    /// A cache keeping values for `ttl`, or nothing at all if it is zero.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// The value remembered for the envelope, unless it is too old.
+    pub fn get(&self, sender: &str, recipients: &[String]) -> Option<V> {
+        if self.ttl.is_zero() {
+            return None;
+        }
+        let key = (sender.to_string(), recipients.to_vec());
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(&key) {
+            Some((at, value)) if at.elapsed() < self.ttl => Some(value.clone()),
+            Some(_) => {
+                entries.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Forget all values, e.g. after what they were decided by changed.
+    #[cfg(feature = "lua")]
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// Remember the value for the envelope.
+    pub fn insert(&self, sender: &str, recipients: &[String], value: V) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, (at, _)| at.elapsed() < self.ttl);
+            if entries.len() >= MAX_ENTRIES {
+                return;
+            }
+        }
+        let key = (sender.to_string(), recipients.to_vec());
+        entries.insert(key, (Instant::now(), value));
+    }
+}
+
+impl<V: Clone> Default for DecisionCache<V> {
+    fn default() -> Self {
+        Self::new(Duration::ZERO)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expiry() {
+        let recipients = vec!["b@example.com".to_string(), "c@example.com".to_string()];
+        let cache = DecisionCache::new(Duration::from_millis(50));
+        cache.insert("a@example.com", &recipients, 1);
+        assert_eq!(cache.get("a@example.com", &recipients), Some(1));
+        assert_eq!(cache.get("a@example.com", &recipients[..1]), None);
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(cache.get("a@example.com", &recipients), None);
+
+        let disabled = DecisionCache::default();
+        disabled.insert("a@example.com", &recipients, 1);
+        assert_eq!(disabled.get("a@example.com", &recipients), None);
+    }
+}
@@ -0,0 +1,128 @@
+//! Just enough DER to get at what OpenSSL does not expose, like signed attributes of
+//! signatures and the key usage of certificates, or build what it can't, like compressed data
+//! and certs-only messages.
+
+use anyhow::{bail, Context, Result};
+
+/// id-data, 1.2.840.113549.1.7.1
+pub const DATA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x01];
+/// id-signedData, 1.2.840.113549.1.7.2
+pub const SIGNED_DATA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x02];
+
+/// A DER element.
+pub struct Tlv<'a> {
+    pub tag: u8,
+    pub value: &'a [u8],
+    /// The whole encoding, tag and length included.
+    pub raw: &'a [u8],
+}
+
+/// Read the element at the start of `input`, returning it and what follows.
+pub fn read_tlv(input: &[u8]) -> Result<(Tlv<'_>, &[u8])> {
+    let (&tag, rest) = input.split_first().context("Truncated DER")?;
+    if tag & 0x1f == 0x1f {
+        bail!("Unsupported DER tag {:#x}", tag);
+    }
+    let (&first, mut rest) = rest.split_first().context("Truncated DER")?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            bail!("Unsupported DER length");
+        }
+        let (bytes, remaining) = rest.split_at(count);
+        rest = remaining;
+        bytes.iter().fold(0, |len, b| len << 8 | *b as usize)
+    };
+    if rest.len() < len {
+        bail!("Truncated DER");
+    }
+    let header = input.len() - rest.len();
+    Ok((
+        Tlv {
+            tag,
+            value: &rest[..len],
+            raw: &input[..header + len],
+        },
+        &rest[len..],
+    ))
+}
+
+/// Encode an element from its tag and contents.
+pub fn encode(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    match content.len() {
+        len @ 0..=0x7f => encoded.push(len as u8),
+        len => {
+            let bytes: Vec<u8> = len
+                .to_be_bytes()
+                .into_iter()
+                .skip_while(|b| *b == 0)
+                .collect();
+            encoded.push(0x80 | bytes.len() as u8);
+            encoded.extend(bytes);
+        }
+    }
+    encoded.extend_from_slice(content);
+    encoded
+}
+
+/// The elements inside a constructed element.
+pub fn children(mut input: &[u8]) -> Result<Vec<Tlv<'_>>> {
+    let mut children = Vec::new();
+    while !input.is_empty() {
+        let (child, rest) = read_tlv(input)?;
+        children.push(child);
+        input = rest;
+    }
+    Ok(children)
+}
+
+/// Dotted form of the contents of an OBJECT IDENTIFIER.
+pub fn oid_to_string(value: &[u8]) -> String {
+    let mut arcs = Vec::new();
+    let mut arc: u64 = 0;
+    for b in value {
+        arc = arc << 7 | u64::from(b & 0x7f);
+        if b & 0x80 == 0 {
+            if arcs.is_empty() {
+                let first = (arc / 40).min(2);
+                arcs.push(first);
+                arcs.push(arc - first * 40);
+            } else {
+                arcs.push(arc);
+            }
+            arc = 0;
+        }
+    }
+    arcs.iter()
+        .map(|a| a.to_string())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_tlv() {
+        let long = encode(0x04, &[7; 300]);
+        let input = [long.as_slice(), &[0x05, 0x00]].concat();
+        let (tlv, rest) = read_tlv(&input).unwrap();
+        assert_eq!(
+            (tlv.tag, tlv.value.len(), tlv.raw),
+            (0x04, 300, long.as_slice())
+        );
+        assert_eq!(children(rest).unwrap().len(), 1);
+        assert!(read_tlv(&long[..100]).is_err());
+    }
+
+    #[test]
+    fn test_oid_to_string() {
+        let encoded = [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x0f];
+        assert_eq!(oid_to_string(&encoded), "1.2.840.113549.1.9.15");
+        assert_eq!(oid_to_string(&[0x55, 0x1d, 0x0f]), "2.5.29.15");
+    }
+}
@@ -0,0 +1,84 @@
+//! Reproducible output for golden-file tests: generated MIME boundaries and all randomness
+//! OpenSSL uses, like content keys, IVs and key transport padding, come from a seeded
+//! generator instead. This makes the encryption worthless, so it is only enabled by a hidden
+//! flag of the test suite.
+
+use std::ffi::{c_int, c_void};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static STATE: Mutex<u64> = Mutex::new(0);
+
+/// `RAND_METHOD` of OpenSSL, still honored by OpenSSL 3 unless built without deprecated APIs.
+#[repr(C)]
+struct RandMethod {
+    seed: Option<unsafe extern "C" fn(*const c_void, c_int) -> c_int>,
+    bytes: Option<unsafe extern "C" fn(*mut u8, c_int) -> c_int>,
+    cleanup: Option<unsafe extern "C" fn()>,
+    add: Option<unsafe extern "C" fn(*const c_void, c_int, f64) -> c_int>,
+    pseudorand: Option<unsafe extern "C" fn(*mut u8, c_int) -> c_int>,
+    status: Option<unsafe extern "C" fn() -> c_int>,
+}
+
+extern "C" {
+    fn RAND_set_rand_method(method: *const RandMethod) -> c_int;
+}
+
+static METHOD: RandMethod = RandMethod {
+    seed: None,
+    bytes: Some(rand_bytes),
+    cleanup: None,
+    add: None,
+    pseudorand: Some(rand_bytes),
+    status: Some(rand_status),
+};
+
+/// Next value of SplitMix64.
+fn next() -> u64 {
+    let mut state = STATE.lock().unwrap();
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+fn fill(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(8) {
+        chunk.copy_from_slice(&next().to_le_bytes()[..chunk.len()]);
+    }
+}
+
+unsafe extern "C" fn rand_bytes(buf: *mut u8, num: c_int) -> c_int {
+    if let Ok(len) = usize::try_from(num) {
+        // SAFETY: OpenSSL hands a buffer of `num` bytes.
+        fill(unsafe { std::slice::from_raw_parts_mut(buf, len) });
+    }
+    1
+}
+
+unsafe extern "C" fn rand_status() -> c_int {
+    1
+}
+
+/// Derive everything random from `seed` from now on, for the whole process.
+pub fn enable(seed: u64) {
+    *STATE.lock().unwrap() = seed;
+    ENABLED.store(true, Ordering::Relaxed);
+    // SAFETY: The method is static and its functions never fail.
+    unsafe {
+        RAND_set_rand_method(&METHOD);
+    }
+}
+
+/// A random UUID, or the next one from the seed.
+pub fn uuid() -> Uuid {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return Uuid::new_v4();
+    }
+    let mut bytes = [0; 16];
+    fill(&mut bytes);
+    uuid::Builder::from_random_bytes(bytes).into_uuid()
+}
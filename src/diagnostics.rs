@@ -0,0 +1,116 @@
+//! Translation of OpenSSL failures into what an operator can do about them.
+//!
+//! OpenSSL reports a stack of library and reason strings, buried in the context chain of an
+//! error. The common failure modes get a stable code, logged as `error_code` and put into the
+//! event report for alerting, and a hint on how to fix them.
+
+use openssl::error::ErrorStack;
+
+/// A known OpenSSL failure mode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Diagnosis {
+    /// The key of a certificate can't be encrypted to, like an Ed25519 key.
+    UnsupportedKeyType,
+    /// A private key doesn't belong to the certificate it is used with.
+    KeyMismatch,
+    /// Data that should be a certificate or CMS structure isn't valid DER or PEM.
+    MalformedDer,
+    /// Any other OpenSSL failure.
+    Other,
+}
+
+impl Diagnosis {
+    /// Stable code for alerting.
+    pub fn code(self) -> &'static str {
+        match self {
+            Diagnosis::UnsupportedKeyType => "unsupported-key-type",
+            Diagnosis::KeyMismatch => "key-mismatch",
+            Diagnosis::MalformedDer => "malformed-der",
+            Diagnosis::Other => "openssl",
+        }
+    }
+
+    /// What to do about it.
+    pub fn hint(self) -> &'static str {
+        match self {
+            Diagnosis::UnsupportedKeyType => {
+                "S/MIME can't encrypt to the key type of the certificate; the recipient needs an RSA or EC encryption certificate"
+            }
+            Diagnosis::KeyMismatch => {
+                "The private key does not belong to the certificate; check both files are from the same key pair"
+            }
+            Diagnosis::MalformedDer => {
+                "The data is not a valid certificate or CMS structure; check the file or message for truncation or a wrong format"
+            }
+            Diagnosis::Other => "See the OpenSSL error stack for details",
+        }
+    }
+}
+
+/// Diagnose the OpenSSL failure in the chain of `error`, if there is one.
+pub fn diagnose(error: &anyhow::Error) -> Option<Diagnosis> {
+    let stack = error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<ErrorStack>())?;
+    Some(diagnose_stack(stack))
+}
+
+fn diagnose_stack(stack: &ErrorStack) -> Diagnosis {
+    for error in stack.errors() {
+        let library = error.library().unwrap_or_default();
+        match (library, error.reason().unwrap_or_default()) {
+            (_, "operation not supported for this keytype" | "unsupported public key type") => {
+                return Diagnosis::UnsupportedKeyType
+            }
+            (_, "key values mismatch" | "private key does not match certificate") => {
+                return Diagnosis::KeyMismatch
+            }
+            ("asn1 encoding routines" | "PEM routines", _) => return Diagnosis::MalformedDer,
+            _ => {}
+        }
+    }
+    Diagnosis::Other
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_pki::{self_signed_ed25519_identity, self_signed_identity};
+    use anyhow::Context;
+    use openssl::cms::{CMSOptions, CmsContentInfo};
+    use openssl::ssl::{SslContext, SslMethod};
+    use openssl::stack::Stack;
+    use openssl::symm::Cipher;
+    use openssl::x509::X509;
+
+    #[test]
+    fn test_diagnose() {
+        let (cert, _) = self_signed_ed25519_identity("a@example.com");
+        let mut recipients = Stack::new().unwrap();
+        recipients.push(cert).unwrap();
+        let error = CmsContentInfo::encrypt(
+            &recipients,
+            b"Hello.",
+            Cipher::aes_256_cbc(),
+            CMSOptions::BINARY,
+        )
+        .context("Failed to encrypt content")
+        .err()
+        .unwrap();
+        assert_eq!(diagnose(&error), Some(Diagnosis::UnsupportedKeyType));
+
+        let error = X509::from_der(b"\x30\x03abc")
+            .context("Failed to parse")
+            .unwrap_err();
+        assert_eq!(diagnose(&error), Some(Diagnosis::MalformedDer));
+
+        let (cert, _) = self_signed_identity("a@example.com");
+        let (_, other_key) = self_signed_identity("b@example.com");
+        let mut context = SslContext::builder(SslMethod::tls()).unwrap();
+        context.set_certificate(&cert).unwrap();
+        let error = anyhow::Error::from(context.set_private_key(&other_key).unwrap_err());
+        assert_eq!(diagnose(&error), Some(Diagnosis::KeyMismatch));
+
+        assert_eq!(diagnose(&anyhow::anyhow!("Not OpenSSL")), None);
+    }
+}
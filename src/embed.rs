@@ -0,0 +1,232 @@
+//! Entry points for programs embedding the gateway rather than running `pantosmimed`, like an
+//! MTA with its own milter server, or integration tests driving the pipeline in-process.
+
+use anyhow::{anyhow, Context, Result};
+use indymilter::Callbacks;
+use openssl::x509::X509;
+use std::borrow::Cow;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+use crate::cert_store::CertStore;
+use crate::milter_callbacks::{self, MilterContext};
+#[cfg(feature = "replay")]
+use crate::milter_client::Outcome;
+use crate::mime_parser::MimeContainer;
+use crate::pipeline;
+use crate::settings::{Mode, Settings};
+use crate::smime;
+
+/// A configured gateway, cheap to clone.
+#[derive(Clone)]
+pub struct Pantosmime {
+    settings: Arc<Settings>,
+}
+
+/// Configuration of a [`Pantosmime`], starting from the defaults of `pantosmimed`.
+pub struct Builder {
+    settings: Settings,
+}
+
+impl Pantosmime {
+    /// Start configuring a gateway keeping its certificates in `cert_dir`.
+    pub fn builder(cert_dir: impl Into<PathBuf>) -> Builder {
+        Builder {
+            settings: Settings::new(cert_dir.into(), Vec::new()),
+        }
+    }
+
+    pub fn settings(&self) -> &Arc<Settings> {
+        &self.settings
+    }
+
+    /// Milter callbacks for an own [`indymilter`] server.
+    pub fn callbacks<'a>(&self) -> Callbacks<MilterContext<'a>> {
+        milter_callbacks::assemble_callbacks(self.settings.clone())
+    }
+
+    /// Serve the milter on `listener` until `shutdown` completes.
+    pub async fn serve(
+        &self,
+        listener: TcpListener,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<()> {
+        indymilter::run(listener, self.callbacks(), Default::default(), shutdown)
+            .await
+            .context("Milter execution failed")
+    }
+
+    /// Run a single message through the milter, like the MTA would, and return its answers.
+    #[cfg(feature = "replay")]
+    pub async fn process(
+        &self,
+        queue_id: &str,
+        sender: &str,
+        recipients: &[&str],
+        message: &[u8],
+    ) -> Result<Outcome> {
+        crate::replay::replay_message(self.settings.clone(), queue_id, sender, recipients, message)
+            .await
+    }
+}
+
+impl Builder {
+    /// Addresses and patterns like `*@example.com` or `/^sales-.*@corp\.example$/` to encrypt
+    /// and harvest for.
+    pub fn responsible<S: AsRef<str>>(self, addresses: impl IntoIterator<Item = S>) -> Self {
+        let addresses = addresses
+            .into_iter()
+            .map(|address| address.as_ref().to_string())
+            .collect();
+        self.settings.set_responsible(addresses);
+        self
+    }
+
+    /// Look up recipients in and store harvested chains to `store` rather than the certificate
+    /// directory. Imports, replication, usage records and expiry notifications still work on
+    /// the directory.
+    pub fn store(mut self, store: Arc<dyn CertStore>) -> Self {
+        self.settings.store = Some(store);
+        self
+    }
+
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.settings.mode = mode;
+        self
+    }
+
+    /// Change any other setting.
+    pub fn configure(mut self, configure: impl FnOnce(&mut Settings)) -> Self {
+        configure(&mut self.settings);
+        self
+    }
+
+    pub fn build(self) -> Pantosmime {
+        Pantosmime {
+            settings: Arc::new(self.settings),
+        }
+    }
+}
+
+/// The certificates in the S/MIME signature of `message`, a complete message with headers,
+/// or an empty list if it isn't signed. They are neither verified nor checked to belong to
+/// the sender.
+pub fn extract_certificates(message: &[u8]) -> Result<Vec<X509>> {
+    let text = pipeline::body_text(message);
+    let (_, container) = MimeContainer::parse_mime_container(&text)
+        .map_err(|e| anyhow!("{:?}", e))
+        .context("Failed to parse MIME message")?;
+    match pipeline::find_signature(&container, matches!(text, Cow::Owned(_)))? {
+        Some(signature) => smime::extract_certificates_from_p7s(&signature),
+        None => Ok(Vec::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cert_store::DirectoryStore;
+    #[cfg(feature = "replay")]
+    use crate::milter_client::Response;
+    use crate::test_pki::{self_signed_identity, signed_message};
+
+    #[tokio::test]
+    async fn test_encrypt_and_extract() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = self_signed_identity("a@partner.example");
+        let signed = signed_message(&cert, &key, "a@partner.example", "Hello there.");
+        let certs = extract_certificates(&signed).unwrap();
+        assert_eq!(certs.len(), 1);
+        assert_eq!(certs[0], cert);
+        assert!(extract_certificates(b"Subject: Hi\r\n\r\nHello there.\r\n")
+            .unwrap()
+            .is_empty());
+
+        let store = DirectoryStore::new(dir.path());
+        store.store("a@Partner.example", &certs).await.unwrap();
+        let encrypted = smime::encrypt_data(b"Hello there.", ["a@partner.example"], &store)
+            .await
+            .unwrap();
+        let decrypted = smime::decrypt_data(&encrypted, &cert, &key).unwrap();
+        assert_eq!(decrypted, b"Hello there.");
+    }
+
+    #[cfg(feature = "replay")]
+    #[tokio::test]
+    async fn test_process() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = self_signed_identity("a@partner.example");
+        let signed = signed_message(&cert, &key, "a@partner.example", "Hello there.");
+        let gateway = Pantosmime::builder(dir.path())
+            .responsible(["b@example.com"])
+            .build();
+        let outcome = gateway
+            .process("Q1", "a@partner.example", &["b@example.com"], &signed)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        let stored = DirectoryStore::new(dir.path())
+            .load("a@partner.example")
+            .await
+            .unwrap();
+        assert_eq!(stored, [cert]);
+    }
+
+    /// Chains kept in memory, like a database would.
+    #[cfg(feature = "replay")]
+    #[derive(Default)]
+    struct MemoryStore(std::sync::Mutex<std::collections::HashMap<String, Vec<X509>>>);
+
+    #[cfg(feature = "replay")]
+    #[async_trait::async_trait]
+    impl CertStore for MemoryStore {
+        async fn load(&self, email: &str) -> Result<Vec<X509>> {
+            self.0
+                .lock()
+                .unwrap()
+                .get(email)
+                .cloned()
+                .ok_or_else(|| anyhow!("No certificate for {}", email))
+        }
+
+        async fn store(&self, email: &str, chain: &[X509]) -> Result<()> {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(email.to_string(), chain.to_vec());
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "replay")]
+    #[tokio::test]
+    async fn test_process_with_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = self_signed_identity("a@partner.example");
+        let signed = signed_message(&cert, &key, "a@partner.example", "Hello there.");
+        let store = Arc::new(MemoryStore::default());
+        let gateway = Pantosmime::builder(dir.path())
+            .responsible(["b@example.com"])
+            .store(store.clone())
+            .build();
+
+        let outcome = gateway
+            .process("Q1", "a@partner.example", &["b@example.com"], &signed)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert_eq!(store.load("a@partner.example").await.unwrap(), [cert]);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        let message = b"Subject: Hi\r\nContent-Type: text/plain\r\n\r\nHello there.\r\n";
+        let outcome = gateway
+            .process("Q2", "b@example.com", &["a@partner.example"], message)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        let encrypted = outcome.apply(message);
+        assert!(String::from_utf8_lossy(&encrypted).contains("application/pkcs7-mime"));
+    }
+}
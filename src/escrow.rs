@@ -0,0 +1,219 @@
+//! Key escrow: every encrypted message is also encrypted to an escrow certificate, so the
+//! organization can recover it, e.g. for a legal hold or after its recipient left.
+//!
+//! Messages are only decrypted with the escrow key through `escrow decrypt`, which requires a
+//! reason and appends who decrypted which message, and why, to an audit log before anything
+//! is decrypted. Without the audit record, nothing is decrypted.
+
+use anyhow::{bail, Context, Result};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use openssl::pkey::{PKey, Private};
+use openssl::x509::X509;
+use serde::Serialize;
+use std::io::Write;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::Path;
+use std::time::SystemTime;
+use tracing::warn;
+
+use crate::event_report;
+use crate::reinjection::hex;
+use crate::smime;
+
+/// Audit log in the certificate directory, unless another one is given.
+pub const AUDIT_LOG: &str = ".escrow-audit.log";
+
+/// An escrow decryption, one JSON line in the audit log.
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    #[serde(rename = "@timestamp")]
+    timestamp: String,
+    operator: &'a str,
+    uid: u32,
+    reason: &'a str,
+    file: &'a Path,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_id: Option<&'a str>,
+    sha256: String,
+}
+
+/// Load the escrow certificate, which has to be usable for encryption.
+pub fn load_certificate(path: &Path) -> Result<X509> {
+    let data = std::fs::read(path)
+        .with_context(|| format!("Failed to read escrow certificate {:?}", path))?;
+    let cert = X509::from_pem(&data)
+        .with_context(|| format!("Failed to parse escrow certificate {:?}", path))?;
+    if !smime::cert_usage(&cert)?.encryption {
+        bail!("Escrow certificate {:?} is not usable for encryption", path);
+    }
+    Ok(cert)
+}
+
+/// Who runs the command, the user invoking sudo rather than root.
+fn operator() -> Result<(String, u32)> {
+    let name = ["SUDO_USER", "LOGNAME", "USER"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok().filter(|name| !name.is_empty()))
+        .unwrap_or_else(|| "unknown".to_string());
+    let uid = std::fs::metadata("/proc/self")
+        .context("Failed to determine the user ID")?
+        .uid();
+    Ok((name, uid))
+}
+
+/// Headers of a message, unfolded, and its body.
+fn split_message(data: &[u8]) -> (Vec<(String, String)>, &[u8]) {
+    let (head, body) = match data.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => (&data[..end], &data[end + 4..]),
+        None => match data.windows(2).position(|w| w == b"\n\n") {
+            Some(end) => (&data[..end], &data[end + 2..]),
+            None => (data, &[][..]),
+        },
+    };
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in String::from_utf8_lossy(head).lines() {
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    (headers, body)
+}
+
+/// The Message-ID and the DER encoded CMS envelope of an encrypted message.
+fn envelope(data: &[u8]) -> Result<(Option<String>, Vec<u8>)> {
+    let (headers, body) = split_message(data);
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    };
+    let content_type = header("Content-Type").unwrap_or_default();
+    if !content_type.to_ascii_lowercase().contains("pkcs7-mime") {
+        bail!("Not an S/MIME encrypted message: {:?}", content_type);
+    }
+    let der = match header("Content-Transfer-Encoding") {
+        Some(encoding) if encoding.eq_ignore_ascii_case("base64") => {
+            let encoded: Vec<u8> = body
+                .iter()
+                .copied()
+                .filter(|b| !b.is_ascii_whitespace())
+                .collect();
+            BASE64_STANDARD
+                .decode(encoded)
+                .context("Failed to decode the base64 body")?
+        }
+        _ => body.to_vec(),
+    };
+    Ok((header("Message-ID").map(str::to_string), der))
+}
+
+/// Append a record to the audit log, created readable by the owner only.
+fn append_audit(path: &Path, record: &AuditRecord) -> Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Failed to open audit log {:?}", path))?;
+    file.write_all(&line)
+        .and_then(|_| file.sync_all())
+        .with_context(|| format!("Failed to write audit log {:?}", path))
+}
+
+/// Decrypt the message in `file` with the escrow key, returning its content. The decryption
+/// is recorded in `audit_log` first.
+pub fn decrypt(
+    file: &Path,
+    cert: &X509,
+    key: &PKey<Private>,
+    reason: &str,
+    audit_log: &Path,
+) -> Result<Vec<u8>> {
+    let reason = reason.trim();
+    if reason.is_empty() {
+        bail!("A reason is required to decrypt with the escrow key");
+    }
+    if !cert.public_key()?.public_eq(key) {
+        bail!("The key does not belong to the escrow certificate");
+    }
+    let data = std::fs::read(file).with_context(|| format!("Failed to read {:?}", file))?;
+    let (message_id, der) = envelope(&data)?;
+
+    let (operator, uid) = operator()?;
+    let record = AuditRecord {
+        timestamp: event_report::rfc3339(SystemTime::now()),
+        operator: &operator,
+        uid,
+        reason,
+        file,
+        message_id: message_id.as_deref(),
+        sha256: hex(&openssl::sha::sha256(&data)),
+    };
+    append_audit(audit_log, &record).context("Refusing to decrypt without an audit record")?;
+    warn!(
+        %operator,
+        uid,
+        ?file,
+        message_id = message_id.as_deref().unwrap_or_default(),
+        %reason,
+        "Decrypting message with the escrow key"
+    );
+    smime::decrypt_data(&der, cert, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compat::RecipientId;
+    use crate::crypto_profile::{self, CryptoProfile, KeyTransport};
+    use crate::test_pki::self_signed_identity;
+
+    #[test]
+    fn test_decrypt() {
+        let (recipient, _) = self_signed_identity("b@example.com");
+        let (escrow, escrow_key) = self_signed_identity("escrow@example.com");
+        let content = b"Content-Type: text/plain\r\n\r\nHello\r\n";
+        let recipients = [
+            (recipient, KeyTransport::RsaPkcs1),
+            (escrow.clone(), KeyTransport::RsaPkcs1),
+        ];
+        let der = crypto_profile::encrypt(
+            content,
+            &recipients,
+            &CryptoProfile::default(),
+            RecipientId::IssuerSerial,
+        )
+        .unwrap();
+        let message = format!(
+            "Message-ID: <1@example.com>\r\nContent-Type: application/pkcs7-mime;\r\n\
+             \tsmime-type=enveloped-data; name=smime.p7m\r\n\
+             Content-Transfer-Encoding: base64\r\n\r\n{}\r\n",
+            BASE64_STANDARD.encode(&der)
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("message.eml");
+        std::fs::write(&file, message).unwrap();
+        let audit_log = dir.path().join(AUDIT_LOG);
+
+        assert!(decrypt(&file, &escrow, &escrow_key, " ", &audit_log).is_err());
+        assert!(!audit_log.exists());
+        let missing = dir.path().join("missing").join(AUDIT_LOG);
+        assert!(decrypt(&file, &escrow, &escrow_key, "Case 42", &missing).is_err());
+
+        let decrypted = decrypt(&file, &escrow, &escrow_key, "Case 42", &audit_log).unwrap();
+        assert_eq!(decrypted, content);
+        let log = std::fs::read_to_string(&audit_log).unwrap();
+        let record: serde_json::Value = serde_json::from_str(log.trim()).unwrap();
+        assert_eq!(record["reason"], "Case 42");
+        assert_eq!(record["message_id"], "<1@example.com>");
+        assert!(record["operator"].is_string());
+    }
+}
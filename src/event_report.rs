@@ -0,0 +1,324 @@
+//! Per-message JSON event reports, one line per completed message, for SIEMs
+//! like Splunk or Elastic.
+//!
+//! Reports are appended to a file, or sent to `udp://<HOST>:<PORT>` (one
+//! datagram each) or `tcp://<HOST>:<PORT>` (newline delimited). A background
+//! task writes them, dropping reports while too many are waiting.
+
+use anyhow::{bail, Context, Result};
+use openssl::hash::MessageDigest;
+use openssl::x509::X509Ref;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
+
+/// Reports waiting to be written, beyond which new ones are dropped.
+const QUEUE_LENGTH: usize = 1024;
+
+/// Longest writing a report may take, including connecting to the sink.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A recipient of a processed message.
+#[derive(Debug, Default, Serialize)]
+pub struct RecipientReport {
+    pub address: String,
+    /// Whether a certificate was found for encryption.
+    pub certificate: bool,
+    /// SHA-256 fingerprint of the certificate used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    /// Why no certificate was used, `missing` or `expired`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub problem: Option<String>,
+    /// Whether the recipient was removed, the message being encrypted for the others.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub excluded: bool,
+}
+
+/// Time spent in the steps of processing, in milliseconds.
+#[derive(Debug, Default, Serialize)]
+pub struct Durations {
+    pub total_ms: f64,
+    pub lookup_ms: f64,
+    pub crypto_ms: f64,
+}
+
+/// Everything known about a message once it is completed.
+#[derive(Debug, Default, Serialize)]
+pub struct MessageReport {
+    #[serde(rename = "@timestamp")]
+    pub timestamp: String,
+    pub queue_id: String,
+    pub sender: String,
+    /// `internal` or `external`, if internal networks are configured.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    /// `encrypt`, `harvest`, `enroll` or `none`.
+    pub decision: String,
+    pub recipients: Vec<RecipientReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cipher: Option<String>,
+    /// Whether the message was left unencrypted as it is outside `--encrypt-percent`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub outside_rollout: bool,
+    /// SHA-256 fingerprints of harvested certificates.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub harvested: Vec<String>,
+    /// `accept`, `reject` or `tempfail`.
+    pub outcome: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Code of a known OpenSSL failure, like `malformed-der`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
+    /// Stage of the pipeline that failed, rejecting the message.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failed_stage: Option<String>,
+    pub durations: Durations,
+}
+
+/// SHA-256 fingerprint of a certificate, as lowercase hex.
+pub fn fingerprint(cert: &X509Ref) -> String {
+    cert.digest(MessageDigest::sha256())
+        .map(|digest| digest.iter().map(|b| format!("{:02x}", b)).collect())
+        .unwrap_or_default()
+}
+
+/// Format a time as RFC 3339 in UTC, with milliseconds.
+pub fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() as i64;
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+    // Civil date from days since the epoch, after Howard Hinnant.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Where reports go.
+pub enum ReportSink {
+    File(PathBuf),
+    Udp(UdpSocket),
+    Tcp {
+        address: String,
+        stream: Option<TcpStream>,
+    },
+}
+
+impl ReportSink {
+    /// Open the sink described by a path, `udp://<HOST>:<PORT>` or `tcp://<HOST>:<PORT>`.
+    pub async fn open(target: &str) -> Result<Self> {
+        if let Some(address) = target.strip_prefix("udp://") {
+            let bind = if address.starts_with('[') {
+                "[::]:0"
+            } else {
+                "0.0.0.0:0"
+            };
+            let socket = UdpSocket::bind(bind).await?;
+            socket
+                .connect(address)
+                .await
+                .with_context(|| format!("Failed to resolve report sink {}", address))?;
+            Ok(Self::Udp(socket))
+        } else if let Some(address) = target.strip_prefix("tcp://") {
+            Ok(Self::Tcp {
+                address: address.to_string(),
+                stream: None,
+            })
+        } else if target.contains("://") {
+            bail!("Unsupported report sink {:?}", target);
+        } else {
+            Ok(Self::File(PathBuf::from(target)))
+        }
+    }
+
+    async fn write(&mut self, line: &str) -> Result<()> {
+        match self {
+            Self::File(path) => {
+                let mut file = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&*path)
+                    .await
+                    .with_context(|| format!("Failed to open report file {:?}", path))?;
+                file.write_all(line.as_bytes()).await?;
+                // Tokio writes in the background, make sure it is done before the file closes.
+                file.flush().await?;
+            }
+            Self::Udp(socket) => {
+                socket.send(line.trim_end().as_bytes()).await?;
+            }
+            Self::Tcp { address, stream } => {
+                if stream.is_none() {
+                    *stream = Some(TcpStream::connect(address.as_str()).await?);
+                }
+                if let Err(error) = stream.as_mut().unwrap().write_all(line.as_bytes()).await {
+                    // Reconnect with the next report.
+                    *stream = None;
+                    return Err(error.into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the reports queued for the sink in a background task.
+    pub fn spawn(mut self) -> ReportQueue {
+        let (sender, mut receiver) = mpsc::channel(QUEUE_LENGTH);
+        tokio::spawn(async move {
+            while let Some(queued) = receiver.recv().await {
+                let line = match queued {
+                    Queued::Line(line) => line,
+                    Queued::Flush(done) => {
+                        let _ = done.send(());
+                        continue;
+                    }
+                };
+                match tokio::time::timeout(WRITE_TIMEOUT, self.write(&line)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(error)) => warn!(?error, "Failed to send event report"),
+                    Err(_) => {
+                        warn!("Timed out sending event report");
+                        // A half written report would garble the next one.
+                        if let Self::Tcp { stream, .. } = &mut self {
+                            *stream = None;
+                        }
+                    }
+                }
+            }
+        });
+        ReportQueue { sender }
+    }
+}
+
+enum Queued {
+    Line(String),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Reports on their way to a sink.
+pub struct ReportQueue {
+    sender: mpsc::Sender<Queued>,
+}
+
+impl ReportQueue {
+    /// Queue a report. Failures are logged, reports must never hold up mail.
+    pub fn send(&self, report: &MessageReport) {
+        let line = match serde_json::to_string(report) {
+            Ok(json) => format!("{}\n", json),
+            Err(error) => {
+                warn!(?error, "Failed to serialize event report");
+                return;
+            }
+        };
+        if let Err(error) = self.sender.try_send(Queued::Line(line)) {
+            warn!(%error, "Dropping event report, the sink doesn't keep up");
+        }
+    }
+
+    /// Wait until the reports queued so far are written.
+    pub async fn flush(&self) {
+        let (done, written) = oneshot::channel();
+        if self.sender.send(Queued::Flush(done)).await.is_ok() {
+            let _ = written.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rfc3339() {
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_210_096_789);
+        assert_eq!(rfc3339(time), "2024-02-29T12:34:56.789Z");
+    }
+
+    #[tokio::test]
+    async fn test_sinks() {
+        let report = MessageReport {
+            queue_id: "Q1".into(),
+            decision: "encrypt".into(),
+            recipients: vec![RecipientReport {
+                address: "b@example.com".into(),
+                ..Default::default()
+            }],
+            outcome: "reject".into(),
+            ..Default::default()
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.json");
+        let sink = ReportSink::open(path.to_str().unwrap())
+            .await
+            .unwrap()
+            .spawn();
+        sink.send(&report);
+        sink.send(&report);
+        sink.flush().await;
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["queue_id"], "Q1");
+        assert_eq!(lines[0]["recipients"][0]["certificate"], false);
+        assert!(lines[0].get("cipher").is_none());
+
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = format!("udp://{}", receiver.local_addr().unwrap());
+        ReportSink::open(&target)
+            .await
+            .unwrap()
+            .spawn()
+            .send(&report);
+        let mut buffer = [0; 4096];
+        let len = receiver.recv(&mut buffer).await.unwrap();
+        let datagram: serde_json::Value = serde_json::from_slice(&buffer[..len]).unwrap();
+        assert_eq!(datagram["outcome"], "reject");
+
+        assert!(ReportSink::open("http://example.com").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_sink() {
+        let report = MessageReport::default();
+        // Nothing listens, and the first report takes until the connection is refused.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = format!("tcp://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let sink = ReportSink::open(&target).await.unwrap().spawn();
+        let started = std::time::Instant::now();
+        for _ in 0..QUEUE_LENGTH * 2 {
+            sink.send(&report);
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+        sink.flush().await;
+    }
+}
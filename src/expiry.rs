@@ -0,0 +1,531 @@
+//! Notifications about expiring and expired certificates in the store.
+//!
+//! A summary goes to an administrator address, and optionally each affected
+//! user is told once per certificate, so they can send a signed message with
+//! their renewed one. Mail is submitted to an SMTP server, usually the local
+//! MTA, which takes care of relaying and transport encryption.
+
+use anyhow::{bail, Context, Result};
+use openssl::asn1::Asn1Time;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tracing::{debug, error, info};
+
+use crate::cert_usage;
+use crate::gateway_identity;
+use crate::settings::Settings;
+use crate::smime;
+use crate::templates::{self, Templates};
+
+/// Record of the user notifications sent, in each certificate directory.
+const NOTIFIED: &str = ".expiry-notified";
+
+/// Recipients and schedule of expiry notifications.
+#[derive(clap::Args, Debug, Clone)]
+pub struct ExpiryArgs {
+    /// Mail a summary of expiring and expired certificates to this address.
+    #[arg(long, requires = "expiry_notify_from")]
+    pub expiry_notify_admin: Option<String>,
+
+    /// Also notify the owners of expiring certificates, once per certificate.
+    #[arg(long, requires = "expiry_notify_from")]
+    pub expiry_notify_users: bool,
+
+    /// Sender address of the notifications.
+    #[arg(long)]
+    pub expiry_notify_from: Option<String>,
+
+    /// Warn about certificates expiring within this many days.
+    #[arg(long, default_value_t = 30)]
+    pub expiry_notify_days: u32,
+
+    /// Check for expiring certificates every this many seconds.
+    #[arg(long, default_value_t = 86400, value_parser = clap::value_parser!(u64).range(1..))]
+    pub expiry_notify_interval: u64,
+
+    /// SMTP server to submit notifications and replies to.
+    #[arg(long, default_value = "localhost:25")]
+    pub smtp_server: String,
+}
+
+impl ExpiryArgs {
+    /// Whether any notifications are to be sent.
+    pub fn enabled(&self) -> bool {
+        self.expiry_notify_admin.is_some() || self.expiry_notify_users
+    }
+}
+
+/// A certificate expiring soon, or already expired.
+#[derive(Debug, PartialEq)]
+pub struct Expiring {
+    pub email: String,
+    pub cert_dir: String,
+    pub not_after: String,
+    /// Days until expiry, negative once expired.
+    pub days_left: i32,
+}
+
+/// Find the certificates in `cert_dir` expiring within `days`.
+pub async fn find_expiring(cert_dir: &Path, days: u32) -> Result<Vec<Expiring>> {
+    let now = Asn1Time::days_from_now(0)?;
+    let mut expiring = Vec::new();
+    let mut entries = tokio::fs::read_dir(cert_dir)
+        .await
+        .with_context(|| format!("Failed to read certificate directory {:?}", cert_dir))?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Some(email) = name.strip_suffix(".pem") else {
+            continue;
+        };
+        if name.starts_with('.') {
+            continue;
+        }
+        let stack = match smime::load_pem_stack(entry.path()).await {
+            Ok(stack) => stack,
+            Err(error) => {
+                debug!(?error, "Skipping unreadable certificate");
+                continue;
+            }
+        };
+        let Some(cert) = smime::find_cert_for_email(&stack, email)
+            .ok()
+            .or_else(|| stack.into_iter().next())
+        else {
+            continue;
+        };
+        let days_left = now.diff(cert.not_after())?.days;
+        if days_left <= days as i32 {
+            expiring.push(Expiring {
+                email: email.to_string(),
+                cert_dir: cert_dir.to_string_lossy().to_string(),
+                not_after: cert.not_after().to_string(),
+                days_left,
+            });
+        }
+    }
+    expiring.sort_by_key(|e| e.days_left);
+    Ok(expiring)
+}
+
+fn admin_summary(expiring: &[Expiring]) -> String {
+    let mut text = format!(
+        "{} certificates in the pantosmime store expired or expire soon:\r\n\r\n",
+        expiring.len()
+    );
+    for e in expiring {
+        let state = if e.days_left < 0 {
+            format!("expired {} days ago", -e.days_left)
+        } else {
+            format!("expires in {} days", e.days_left)
+        };
+        text.push_str(&format!(
+            "{} ({}, {}) in {}\r\n",
+            e.email, e.not_after, state, e.cert_dir
+        ));
+    }
+    text
+}
+
+/// Subject and text of the notice to the owner of a certificate.
+fn user_notice(templates: &Templates, e: &Expiring) -> (String, String) {
+    let (template, days) = match e.days_left < 0 {
+        true => (&templates::EXPIRED_CERTIFICATE, -e.days_left),
+        false => (&templates::EXPIRING_CERTIFICATE, e.days_left),
+    };
+    let days = days.to_string();
+    let values = [
+        ("email", e.email.as_str()),
+        ("not_after", e.not_after.as_str()),
+        ("days", days.as_str()),
+    ];
+    templates.render(template, &e.email, &values)
+}
+
+/// Read an SMTP reply, failing unless its code is the expected one.
+async fn expect_reply(stream: &mut BufReader<TcpStream>, code: &str) -> Result<()> {
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await? == 0 {
+            bail!("SMTP server closed the connection");
+        }
+        if !line.starts_with(code) {
+            bail!(
+                "Unexpected SMTP reply {:?}, expected {}",
+                line.trim_end(),
+                code
+            );
+        }
+        // Multiline replies continue with a dash after the code.
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+async fn command(stream: &mut BufReader<TcpStream>, line: &str, code: &str) -> Result<()> {
+    stream
+        .get_mut()
+        .write_all(format!("{}\r\n", line).as_bytes())
+        .await?;
+    expect_reply(stream, code)
+        .await
+        .with_context(|| format!("SMTP command {:?} failed", line))
+}
+
+/// An RFC 5322 date in UTC, for the `Date` header, from seconds since the epoch.
+fn format_date(secs: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (days, time) = (secs / 86400, secs % 86400);
+    // Civil date of the days since the epoch, after Howard Hinnant.
+    let z = days + 719468;
+    let (era, doe) = (z / 146097, z % 146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} +0000",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Submit a plain-text message to the SMTP server, signed and encrypted as configured.
+pub async fn send_mail(
+    settings: &Settings,
+    from: &str,
+    to: &str,
+    subject: &str,
+    text: &str,
+) -> Result<()> {
+    send_mail_with_headers(settings, from, to, subject, &[], text).await
+}
+
+/// Submit a plain-text message with additional headers, like `Reply-To`, to the SMTP server,
+/// signed and encrypted as configured.
+pub async fn send_mail_with_headers(
+    settings: &Settings,
+    from: &str,
+    to: &str,
+    subject: &str,
+    headers: &[(&str, &str)],
+    text: &str,
+) -> Result<()> {
+    let mut entity = String::from("Content-Type: text/plain; charset=utf-8\r\n\r\n");
+    for line in text.lines() {
+        entity.push_str(line);
+        entity.push_str("\r\n");
+    }
+    let entity = gateway_identity::protect(settings, from, to, entity.into_bytes()).await?;
+
+    let server = &settings.smtp_server;
+    let stream = TcpStream::connect(server)
+        .await
+        .with_context(|| format!("Failed to connect to SMTP server {}", server))?;
+    let mut stream = BufReader::new(stream);
+    expect_reply(&mut stream, "220").await?;
+
+    let helo = from
+        .rsplit_once('@')
+        .map_or("localhost", |(_, domain)| domain);
+    command(&mut stream, &format!("EHLO {}", helo), "250").await?;
+    command(&mut stream, &format!("MAIL FROM:<{}>", from), "250").await?;
+    command(&mut stream, &format!("RCPT TO:<{}>", to), "250").await?;
+    command(&mut stream, "DATA", "354").await?;
+
+    let mut message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{}@{}>\r\n",
+        from,
+        to,
+        subject,
+        format_date(cert_usage::now()),
+        uuid::Uuid::new_v4(),
+        helo
+    );
+    for (name, value) in headers {
+        message.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    message.push_str("MIME-Version: 1.0\r\nAuto-Submitted: auto-generated\r\n");
+    for line in String::from_utf8_lossy(&entity).lines() {
+        // Dot-stuffing, so lines can't end the DATA section early.
+        if line.starts_with('.') {
+            message.push('.');
+        }
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message.push('.');
+    command(&mut stream, &message, "250").await?;
+    command(&mut stream, "QUIT", "221").await?;
+    Ok(())
+}
+
+fn read_notified(cert_dir: &Path) -> BTreeSet<String> {
+    std::fs::read_to_string(cert_dir.join(NOTIFIED))
+        .map(|s| s.lines().map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+async fn write_notified(cert_dir: &Path, notified: &BTreeSet<String>) -> Result<()> {
+    let content: String = notified.iter().map(|k| format!("{}\n", k)).collect();
+    tokio::fs::write(cert_dir.join(NOTIFIED), content)
+        .await
+        .with_context(|| format!("Failed to record notifications in {:?}", cert_dir))
+}
+
+/// Check all certificate directories once and send the notifications. A directory or user
+/// failing doesn't keep the others or the administrator from being notified; the run fails
+/// afterwards.
+pub async fn notify(args: &ExpiryArgs, settings: &Settings) -> Result<()> {
+    let from = args.expiry_notify_from.as_deref().unwrap_or_default();
+    let mut all = Vec::new();
+    let mut failed = 0;
+    for cert_dir in settings.all_cert_dirs() {
+        let expiring = match find_expiring(cert_dir, args.expiry_notify_days).await {
+            Ok(expiring) => expiring,
+            Err(error) => {
+                error!(?error, "Failed to check for expiring certificates");
+                failed += 1;
+                continue;
+            }
+        };
+        if args.expiry_notify_users {
+            let mut notified = read_notified(cert_dir);
+            for e in &expiring {
+                // Remember the expiry date too, so renewed certificates get noticed again.
+                let key = format!("{} {}", e.email, e.not_after);
+                if notified.contains(&key) {
+                    continue;
+                }
+                let (subject, text) = user_notice(&settings.templates, e);
+                if let Err(error) = send_mail(settings, from, &e.email, &subject, &text).await {
+                    error!(
+                        ?error,
+                        email = e.email,
+                        "Failed to notify user about expiring certificate"
+                    );
+                    failed += 1;
+                    continue;
+                }
+                info!(email = e.email, "Notified user about expiring certificate");
+                notified.insert(key);
+                // Recorded right away, so a later failure doesn't notify the user again.
+                if let Err(error) = write_notified(cert_dir, &notified).await {
+                    error!(?error, "Failed to record notification");
+                    failed += 1;
+                }
+            }
+        }
+        all.extend(expiring);
+    }
+
+    if let Some(admin) = &args.expiry_notify_admin {
+        if !all.is_empty() {
+            all.sort_by_key(|e| e.days_left);
+            let subject = format!("{} S/MIME certificates expiring", all.len());
+            send_mail(settings, from, admin, &subject, &admin_summary(&all)).await?;
+            info!(count = all.len(), "Sent expiry summary to administrator");
+        }
+    }
+    if failed > 0 {
+        bail!("{} expiry notifications or checks failed", failed);
+    }
+    Ok(())
+}
+
+/// Send notifications periodically, forever.
+pub async fn run_periodically(args: ExpiryArgs, settings: Arc<Settings>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(args.expiry_notify_interval));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if let Err(error) = notify(&args, &settings).await {
+            error!(?error, "Sending expiry notifications failed");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_pki::{self_signed_identity, self_signed_identity_until};
+    use tokio::net::TcpListener;
+
+    fn unix_days_from_now(days: i64) -> i64 {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        now + days * 86400 + days.signum() * 3600
+    }
+
+    async fn store(cert_dir: &Path, email: &str, cert: &openssl::x509::X509) {
+        smime::write_pem_stack([cert], &cert_dir.join(format!("{}.pem", email)))
+            .await
+            .unwrap();
+    }
+
+    /// Accept SMTP sessions, returning the recipients and data of each message. `refused` is
+    /// refused as recipient.
+    async fn smtp_sink(
+        listener: TcpListener,
+        sessions: usize,
+        refused: &str,
+    ) -> Vec<(String, String)> {
+        let mut messages = Vec::new();
+        for _ in 0..sessions {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            stream.get_mut().write_all(b"220 test\r\n").await.unwrap();
+            let (mut rcpt, mut data, mut in_data) = (String::new(), String::new(), false);
+            loop {
+                let mut line = String::new();
+                if stream.read_line(&mut line).await.unwrap() == 0 {
+                    break;
+                }
+                let reply: &[u8] = if in_data {
+                    if line == ".\r\n" {
+                        in_data = false;
+                        b"250 queued\r\n"
+                    } else {
+                        data.push_str(&line);
+                        continue;
+                    }
+                } else if line.starts_with("EHLO") {
+                    b"250-test\r\n250 8BITMIME\r\n"
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line.starts_with("QUIT") {
+                    stream.get_mut().write_all(b"221 bye\r\n").await.unwrap();
+                    break;
+                } else if line.trim_end() == format!("RCPT TO:<{}>", refused) {
+                    b"550 no such user\r\n"
+                } else {
+                    if let Some(to) = line.strip_prefix("RCPT TO:") {
+                        rcpt = to.trim().to_string();
+                    }
+                    b"250 ok\r\n"
+                };
+                stream.get_mut().write_all(reply).await.unwrap();
+            }
+            messages.push((rcpt, data));
+        }
+        messages
+    }
+
+    #[tokio::test]
+    async fn test_find_expiring() {
+        let dir = tempfile::tempdir().unwrap();
+        let (fresh, _) = self_signed_identity("fresh@example.com");
+        let (soon, _) = self_signed_identity_until("soon@example.com", unix_days_from_now(10));
+        let (gone, _) = self_signed_identity_until("gone@example.com", unix_days_from_now(-5));
+        store(dir.path(), "fresh@example.com", &fresh).await;
+        store(dir.path(), "soon@example.com", &soon).await;
+        store(dir.path(), "gone@example.com", &gone).await;
+
+        let expiring = find_expiring(dir.path(), 30).await.unwrap();
+        let found: Vec<(&str, i32)> = expiring
+            .iter()
+            .map(|e| (e.email.as_str(), e.days_left))
+            .collect();
+        assert_eq!(found, [("gone@example.com", -5), ("soon@example.com", 10)]);
+    }
+
+    #[tokio::test]
+    async fn test_notify() {
+        let dir = tempfile::tempdir().unwrap();
+        let (soon, _) = self_signed_identity_until("soon@example.com", unix_days_from_now(10));
+        store(dir.path(), "soon@example.com", &soon).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let args = ExpiryArgs {
+            expiry_notify_admin: Some("admin@example.com".into()),
+            expiry_notify_users: true,
+            expiry_notify_from: Some("pantosmime@example.com".into()),
+            expiry_notify_days: 30,
+            expiry_notify_interval: 86400,
+            smtp_server: listener.local_addr().unwrap().to_string(),
+        };
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec![]);
+        settings.smtp_server = args.smtp_server.clone();
+        let sink = tokio::spawn(smtp_sink(listener, 3, "nobody@example.com"));
+
+        notify(&args, &settings).await.unwrap();
+        // The user was told already, only the administrator hears again.
+        notify(&args, &settings).await.unwrap();
+
+        let messages = sink.await.unwrap();
+        let recipients: Vec<&str> = messages.iter().map(|(r, _)| r.as_str()).collect();
+        assert_eq!(
+            recipients,
+            [
+                "<soon@example.com>",
+                "<admin@example.com>",
+                "<admin@example.com>"
+            ]
+        );
+        assert!(messages[1].1.contains("soon@example.com"));
+        assert!(messages[1].1.contains("expires in 10 days"));
+        assert!(messages[1].1.contains("\r\nDate: "));
+    }
+
+    #[tokio::test]
+    async fn test_notify_refused_user() {
+        let dir = tempfile::tempdir().unwrap();
+        let (soon, _) = self_signed_identity_until("soon@example.com", unix_days_from_now(10));
+        let (gone, _) = self_signed_identity_until("gone@example.com", unix_days_from_now(-5));
+        store(dir.path(), "soon@example.com", &soon).await;
+        store(dir.path(), "gone@example.com", &gone).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let args = ExpiryArgs {
+            expiry_notify_admin: Some("admin@example.com".into()),
+            expiry_notify_users: true,
+            expiry_notify_from: Some("pantosmime@example.com".into()),
+            expiry_notify_days: 30,
+            expiry_notify_interval: 86400,
+            smtp_server: listener.local_addr().unwrap().to_string(),
+        };
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec![]);
+        settings.smtp_server = args.smtp_server.clone();
+        let sink = tokio::spawn(smtp_sink(listener, 3, "gone@example.com"));
+
+        // The refused user fails the run, but not the other user or the summary.
+        assert!(notify(&args, &settings).await.is_err());
+
+        let messages = sink.await.unwrap();
+        let recipients: Vec<&str> = messages.iter().map(|(r, _)| r.as_str()).collect();
+        assert_eq!(
+            recipients,
+            ["", "<soon@example.com>", "<admin@example.com>"]
+        );
+        assert!(messages[2].1.contains("gone@example.com"));
+        let notified = read_notified(dir.path());
+        assert_eq!(notified.len(), 1);
+        assert!(notified
+            .iter()
+            .all(|key| key.starts_with("soon@example.com ")));
+    }
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!(format_date(0), "Thu, 01 Jan 1970 00:00:00 +0000");
+        assert_eq!(format_date(951825600), "Tue, 29 Feb 2000 12:00:00 +0000");
+        assert_eq!(format_date(1792152000), "Fri, 16 Oct 2026 12:00:00 +0000");
+    }
+}
@@ -0,0 +1,390 @@
+//! Step by step account of how an envelope would be handled, for `policy explain`: how the
+//! addresses are normalized and rewritten, which rules match, the action chosen and the
+//! certificates and crypto profile it would use.
+//!
+//! Only the envelope is known, so anything depending on the connection or the headers is
+//! told as a condition.
+
+use crate::address;
+use crate::crypto_profile;
+use crate::event_report;
+use crate::milter_callbacks::{self, MilterAction};
+#[cfg(feature = "lua")]
+use crate::policy_script::{Overrun, PolicyDecision, PolicyInput};
+use crate::settings::{CertFailureAction, FromHeaderMatch, Settings, SubaddressAction};
+use crate::trust;
+
+/// Explain the handling of a message from `from` to `to`, one line per step.
+pub async fn explain(settings: &Settings, from: &str, to: &[String]) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    let local_domain = settings.local_domain.as_deref();
+    lines.push(format!("Sender {}", from));
+    let sender = match milter_callbacks::extract_email(from) {
+        Some("") => {
+            lines.push("  the null reverse-path: never responsible".to_string());
+            String::new()
+        }
+        _ => {
+            let Some(qualified) = address::qualify(from, local_domain) else {
+                lines.push("  a local user or address literal: accepted unchanged".to_string());
+                return lines;
+            };
+            let normalized = address::normalize(&qualified);
+            let sender = milter_callbacks::rewrite_address(settings, normalized.clone());
+            if normalized != from {
+                lines.push(format!("  normalized to {}", normalized));
+            }
+            if sender != normalized {
+                lines.push(format!("  rewritten to {}", sender));
+            }
+            sender
+        }
+    };
+
+    let mut recipients = Vec::new();
+    let mut subaddress_action = None;
+    for recipient in to {
+        lines.push(format!("Recipient {}", recipient));
+        let Some(qualified) = address::qualify(recipient, local_domain) else {
+            if settings.is_responsible(&sender) && settings.encryption_required(recipient) {
+                lines.push(
+                    "  a local user or address literal, but a schedule requires encryption: refused"
+                        .to_string(),
+                );
+                return lines;
+            }
+            lines.push("  a local user or address literal: left unencrypted".to_string());
+            continue;
+        };
+        let mut email = address::normalize(&qualified);
+        if email != *recipient {
+            lines.push(format!("  normalized to {}", email));
+        }
+        if let Some((untagged, tag)) = address::split_tag(&email) {
+            if let Some(action) = settings.subaddress_action(tag) {
+                lines.push(format!(
+                    "  subaddress +{} chooses {:?}, delivered to {}",
+                    tag, action, untagged
+                ));
+                subaddress_action = Some(action);
+                email = untagged;
+            }
+        }
+        let rewritten = milter_callbacks::rewrite_address(settings, email.clone());
+        if rewritten != email {
+            lines.push(format!("  rewritten to {}", rewritten));
+        }
+        recipients.push(rewritten);
+    }
+
+    let enrollment =
+        !recipients.is_empty() && recipients.iter().all(|r| settings.is_enrollment_address(r));
+    let mut action = if enrollment {
+        lines.push("All recipients are enrollment addresses: enroll".to_string());
+        Some(MilterAction::Enroll)
+    } else {
+        let responsible = settings.responsible.get();
+        match milter_callbacks::responsible_match(&sender, &recipients, &responsible) {
+            Some((action, pattern, email)) => {
+                let role = match email == sender {
+                    true => "Sender",
+                    false => "Recipient",
+                };
+                lines.push(format!(
+                    "{} {} matches responsible {:?}: {}",
+                    role,
+                    email,
+                    pattern,
+                    action.as_str()
+                ));
+                Some(action)
+            }
+            None => {
+                lines.push("No responsible address matches".to_string());
+                None
+            }
+        }
+    };
+
+    match subaddress_action {
+        Some(SubaddressAction::Plain)
+            if action == Some(MilterAction::Encrypt)
+                && recipients.iter().any(|r| settings.encryption_required(r)) =>
+        {
+            lines.push("Subaddress asks for no encryption, but a schedule requires it".to_string())
+        }
+        Some(SubaddressAction::Plain) if action == Some(MilterAction::Encrypt) => {
+            lines.push("Subaddress asks for no encryption".to_string());
+            action = None;
+        }
+        Some(SubaddressAction::Encrypt) => {
+            lines.push("Subaddress asks for encryption".to_string());
+            action = Some(MilterAction::Encrypt);
+        }
+        _ if action == Some(MilterAction::Encrypt) => {
+            if let Some(reason) = settings.tls_suffices(None, &recipients) {
+                lines.push(format!("TLS policy: {}, not encrypting", reason));
+                action = None;
+            } else if let Some(reason) = settings.tls_suffices(Some("TLSv1.3"), &recipients) {
+                lines.push(format!("TLS policy: not encrypting if {}", reason));
+            }
+        }
+        _ => {}
+    }
+
+    if action == Some(MilterAction::Encrypt) && recipients.is_empty() {
+        lines.push("No recipient with a domain to encrypt to".to_string());
+        action = None;
+    }
+    match action {
+        Some(MilterAction::Encrypt) if settings.encrypt_internal_only => {
+            lines.push("Not encrypting if the client is outside the internal networks".to_string())
+        }
+        Some(MilterAction::ExtractKeys) if settings.harvest_external_only => {
+            lines.push("Not harvesting if the client is in the internal networks".to_string())
+        }
+        _ => {}
+    }
+    if let Some(denied) = action
+        .as_ref()
+        .filter(|action| !settings.mode.allows(action))
+    {
+        lines.push(format!(
+            "Mode {:?} leaves {} to another instance",
+            settings.mode,
+            denied.as_str()
+        ));
+        action = None;
+    }
+
+    #[cfg(feature = "lua")]
+    if let Some(script) = settings.policy_script.get().as_ref() {
+        let input = PolicyInput {
+            sender: &sender,
+            recipients: &recipients,
+            headers: &[],
+            action: action.as_ref(),
+            origin: None,
+        };
+        match script.decide(&input, settings.cert_dir_for(&sender)).await {
+            Ok(PolicyDecision::Process(decided)) => {
+                lines.push(format!(
+                    "Policy script, without headers: {}",
+                    decided.as_str()
+                ));
+                action = Some(decided).filter(|action| settings.mode.allows(action));
+            }
+            Ok(decision) => {
+                lines.push(format!("Policy script, without headers: {:?}", decision));
+                action = None;
+            }
+            Err(error) if error.is::<Overrun>() => {
+                lines.push(format!("Policy script fails, deferring: {:#}", error));
+                action = None;
+            }
+            Err(error) => {
+                lines.push(format!("Policy script fails, rejecting: {:#}", error));
+                action = None;
+            }
+        }
+    }
+
+    match &action {
+        None => lines.push("Action: none, the message is accepted unchanged".to_string()),
+        Some(action) => lines.push(format!("Action: {}", action.as_str())),
+    }
+    match action {
+        Some(MilterAction::Encrypt) => {
+            explain_encryption(settings, &sender, &recipients, &mut lines).await
+        }
+        Some(MilterAction::ExtractKeys) | Some(MilterAction::Enroll) if sender.is_empty() => {
+            lines.push("Nothing is harvested from the null reverse-path".to_string())
+        }
+        Some(MilterAction::ExtractKeys) | Some(MilterAction::Enroll) => {
+            let cert_dir = settings.cert_dir_for(&sender);
+            lines.push(format!("Certificates are stored in {:?}", cert_dir));
+            match trust::anchors_for(&settings.trust_anchors.get(), &sender) {
+                Some(anchors) => lines.push(format!(
+                    "Certificates must be issued by the trust anchors for {}",
+                    anchors.domain
+                )),
+                None => lines.push("Certificates are trusted on first use".to_string()),
+            }
+            let methods: Vec<&str> = settings
+                .harvest_require_auth
+                .iter()
+                .map(|method| method.as_str())
+                .collect();
+            if action == Some(MilterAction::ExtractKeys) && !methods.is_empty() {
+                lines.push(format!(
+                    "Only harvesting if {} passed for the sender's domain",
+                    methods.join(" or ")
+                ));
+            }
+            let from_header = match settings.harvest_from_header {
+                FromHeaderMatch::Ignore => None,
+                FromHeaderMatch::SameDomain => Some("domain"),
+                FromHeaderMatch::SameAddress => Some("address"),
+            };
+            if let Some(part) = from_header.filter(|_| action == Some(MilterAction::ExtractKeys)) {
+                lines.push(format!(
+                    "Only harvesting if the From header has the sender's {}",
+                    part
+                ));
+            }
+        }
+        None => {}
+    }
+    lines
+}
+
+/// The certificates and crypto profile encrypting a message would use.
+async fn explain_encryption(
+    settings: &Settings,
+    sender: &str,
+    recipients: &[String],
+    lines: &mut Vec<String>,
+) {
+    if settings.encrypt_percent < 100 {
+        lines.push(format!(
+            "Only {}% of messages are encrypted, chosen by queue ID",
+            settings.encrypt_percent
+        ));
+    }
+    let cert_dir = settings.cert_dir_for(sender);
+    lines.push(format!("Certificates are looked up in {:?}", cert_dir));
+    let mut missing = false;
+    for recipient in recipients {
+        let path = cert_dir.join(format!("{}.pem", address::cert_name(cert_dir, recipient)));
+        match settings.cert_cache.lookup(cert_dir, recipient).await {
+            Ok((cert, fingerprint)) => lines.push(format!(
+                "  {}: {:?}, SHA-256 {}, valid until {}",
+                recipient,
+                path,
+                fingerprint,
+                cert.not_after()
+            )),
+            Err(error) => {
+                lines.push(format!(
+                    "  {}: no usable certificate: {:#}",
+                    recipient, error
+                ));
+                missing = true;
+            }
+        }
+    }
+    if missing {
+        let action = match settings.missing_cert_action {
+            CertFailureAction::Reject if settings.failures_tempfail() => {
+                "deferred, as a schedule asks to"
+            }
+            CertFailureAction::Reject => "rejected",
+            CertFailureAction::Tempfail => "deferred",
+            CertFailureAction::Exclude => "encrypted for the other recipients only",
+        };
+        lines.push(format!(
+            "The message would be {}, expired certificates depending on --expired-cert-action",
+            action
+        ));
+    }
+    if let Some(max) = settings
+        .max_cms_recipients
+        .filter(|max| recipients.len() > *max)
+    {
+        lines.push(format!(
+            "The message would be rejected for exceeding {} recipients",
+            max
+        ));
+    }
+
+    let profiles: Vec<_> = recipients
+        .iter()
+        .map(|r| crypto_profile::profile_for(&settings.crypto_profiles, r))
+        .collect();
+    let profile = crypto_profile::negotiate(profiles.iter().copied());
+    let (_, cipher) = profile.cipher();
+    lines.push(format!(
+        "Cipher {}{}",
+        cipher,
+        match profile.compress {
+            true => ", compressed",
+            false => "",
+        }
+    ));
+    for (recipient, profile) in recipients.iter().zip(&profiles) {
+        lines.push(format!(
+            "  {}: key transport {:?}",
+            recipient, profile.key_transport
+        ));
+    }
+    if let Some(escrow) = &settings.escrow_certificate {
+        lines.push(format!(
+            "Also encrypted to the escrow certificate, SHA-256 {}",
+            event_report::fingerprint(escrow)
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smime;
+    use crate::test_pki::self_signed_identity;
+
+    #[tokio::test]
+    async fn test_explain() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, _) = self_signed_identity("b@example.com");
+        smime::write_pem_stack([&cert], &dir.path().join("b@example.com.pem"))
+            .await
+            .unwrap();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["*@example.com".into()]);
+        settings.address_rewrites =
+            vec![address::parse_rewrite("old.example=example.com").unwrap()];
+
+        let to = ["b@example.com".to_string(), "c@example.net".to_string()];
+        let lines = explain(&settings, "a@old.example", &to).await;
+        assert!(lines.contains(&"  rewritten to a@example.com".to_string()));
+        assert!(lines.contains(
+            &"Sender a@example.com matches responsible \"*@example.com\": encrypt".to_string()
+        ));
+        assert!(lines.contains(&"Action: encrypt".to_string()));
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("  b@example.com: ") && line.contains("SHA-256")));
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("  c@example.net: no usable certificate")));
+
+        let lines = explain(&settings, "x@example.org", &["y@example.org".into()]).await;
+        assert_eq!(
+            lines.last().unwrap(),
+            "Action: none, the message is accepted unchanged"
+        );
+
+        let lines = explain(&settings, "a@example.com", &["root".into()]).await;
+        assert!(lines.contains(&"  a local user or address literal: left unencrypted".to_string()));
+        assert_eq!(
+            lines.last().unwrap(),
+            "Action: none, the message is accepted unchanged"
+        );
+        let lines = explain(
+            &settings,
+            "a@example.com",
+            &["root".into(), "b@example.com".into()],
+        )
+        .await;
+        assert!(lines.contains(&"Action: encrypt".to_string()));
+
+        let lines = explain(&settings, "<>", &["b@example.com".into()]).await;
+        assert!(lines.contains(&"  the null reverse-path: never responsible".to_string()));
+        assert_eq!(
+            lines.last().unwrap(),
+            "Nothing is harvested from the null reverse-path"
+        );
+        settings.local_domain = Some("example.com".into());
+        let lines = explain(&settings, "a@example.com", &["root".into()]).await;
+        assert!(lines.contains(&"Action: encrypt".to_string()));
+    }
+}
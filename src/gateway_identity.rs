@@ -0,0 +1,359 @@
+//! The gateway's own S/MIME identity, signing the mail pantosmime generates itself: key
+//! requests, expiry notifications and enrollment replies. Those are also encrypted to their
+//! recipient if asked to and a usable certificate is on file, so the gateway's own mail meets
+//! the standards it enforces.
+//!
+//! Signatures are clear, readable without S/MIME support, unless the crypto profile of the
+//! recipient asks for opaque ones, as some receivers mangle clear-signed messages.
+//!
+//! The signatures carry the gateway's chain, and the chain can also be attached as a
+//! certs-only part, so gateways harvesting certificates like pantosmime learn it either way.
+
+use anyhow::{bail, Context, Result};
+use openssl::pkcs7::{Pkcs7, Pkcs7Flags};
+use openssl::pkey::{PKey, Private};
+use openssl::stack::Stack;
+use openssl::x509::X509;
+use std::path::Path;
+use tracing::debug;
+
+use crate::compat::Compatibility;
+use crate::crypto_profile::{self, SignatureFormat};
+use crate::settings::Settings;
+use crate::smime;
+use crate::transfer_encoding::encode_base64_wrapped;
+
+/// Line length of the base64 encoded signatures and envelopes.
+const LINE_LENGTH: usize = 76;
+
+/// Certificate, chain and key the generated mail is signed with.
+pub struct GatewayIdentity {
+    cert: X509,
+    chain: Vec<X509>,
+    key: PKey<Private>,
+}
+
+impl GatewayIdentity {
+    /// Load the certificate, followed by its chain, and the key from PEM files.
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Self> {
+        let data = std::fs::read(cert_path)
+            .with_context(|| format!("Failed to read gateway certificate {:?}", cert_path))?;
+        let mut chain = X509::stack_from_pem(&data)
+            .with_context(|| format!("Failed to parse gateway certificate {:?}", cert_path))?;
+        if chain.is_empty() {
+            bail!("Gateway certificate {:?} holds no certificates", cert_path);
+        }
+        let cert = chain.remove(0);
+        let data = std::fs::read(key_path)
+            .with_context(|| format!("Failed to read gateway key {:?}", key_path))?;
+        let key = PKey::private_key_from_pem(&data)
+            .with_context(|| format!("Failed to parse gateway key {:?}", key_path))?;
+        if !cert.public_key()?.public_eq(&key) {
+            bail!("The gateway key does not belong to {:?}", cert_path);
+        }
+        if !smime::cert_usage(&cert)?.signing {
+            bail!(
+                "Gateway certificate {:?} is not usable for signing",
+                cert_path
+            );
+        }
+        Ok(Self { cert, chain, key })
+    }
+
+    /// The certificate the mail is signed with.
+    pub fn certificate(&self) -> &X509 {
+        &self.cert
+    }
+
+    /// Whether the certificate is issued for `email`, as MUAs expect of the sender.
+    pub fn is_issued_for(&self, email: &str) -> bool {
+        smime::find_cert_for_email([&self.cert], email).is_ok()
+    }
+
+    /// DER encoded signed-data of the MIME entity, carrying the certificate and its chain, and
+    /// the entity itself unless the signature is detached.
+    fn signed_data(&self, entity: &[u8], detached: bool) -> Result<Vec<u8>> {
+        let mut certs = Stack::new()?;
+        for cert in &self.chain {
+            certs.push(cert.clone())?;
+        }
+        let mut flags = Pkcs7Flags::BINARY;
+        if detached {
+            flags |= Pkcs7Flags::DETACHED;
+        }
+        Pkcs7::sign(&self.cert, &self.key, &certs, entity, flags)
+            .and_then(|pkcs7| pkcs7.to_der())
+            .context("Failed to sign message")
+    }
+
+    /// Wrap the MIME entity, headers and body, into a multipart/signed entity with a detached
+    /// signature carrying the certificate and its chain.
+    pub fn sign(&self, entity: &[u8]) -> Result<Vec<u8>> {
+        let signature = self.signed_data(entity, true)?;
+
+        let boundary = format!("----=_signed_{}", uuid::Uuid::new_v4().simple());
+        let mut signed = format!(
+            "Content-Type: multipart/signed; protocol=\"application/pkcs7-signature\"; \
+             micalg=sha-256; boundary=\"{boundary}\"\r\n\r\n--{boundary}\r\n"
+        )
+        .into_bytes();
+        // The line break before a boundary belongs to it, not to the signed content.
+        signed.extend_from_slice(entity);
+        signed.extend_from_slice(
+            format!(
+                "\r\n--{boundary}\r\n\
+                 Content-Type: application/pkcs7-signature; name=smime.p7s\r\n\
+                 Content-Transfer-Encoding: base64\r\n\
+                 Content-Disposition: attachment; filename=smime.p7s\r\n\r\n"
+            )
+            .as_bytes(),
+        );
+        signed.extend_from_slice(&encode_base64_wrapped(&signature, LINE_LENGTH));
+        signed.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+        Ok(signed)
+    }
+
+    /// Wrap the MIME entity into an opaque signed-data entity, with the content type and
+    /// disposition of `compat`.
+    pub fn sign_opaque(&self, entity: &[u8], compat: &Compatibility) -> Result<Vec<u8>> {
+        Ok(smime_entity(
+            compat,
+            "signed-data",
+            &self.signed_data(entity, false)?,
+        ))
+    }
+
+    /// Wrap the MIME entity into a multipart/mixed entity, followed by an
+    /// `application/pkcs7-mime` certs-only part with the certificate and its chain.
+    pub fn attach_certificates(&self, entity: &[u8]) -> Result<Vec<u8>> {
+        let certs: Vec<X509> = std::iter::once(&self.cert)
+            .chain(&self.chain)
+            .cloned()
+            .collect();
+        let certs_only = smime::certs_only(&certs)?;
+
+        let boundary = format!("----=_certs_{}", uuid::Uuid::new_v4().simple());
+        let mut mixed = format!(
+            "Content-Type: multipart/mixed; boundary=\"{boundary}\"\r\n\r\n--{boundary}\r\n"
+        )
+        .into_bytes();
+        mixed.extend_from_slice(entity);
+        mixed.extend_from_slice(
+            format!(
+                "\r\n--{boundary}\r\n\
+                 Content-Type: application/pkcs7-mime; smime-type=certs-only; name=smime.p7c\r\n\
+                 Content-Transfer-Encoding: base64\r\n\
+                 Content-Disposition: attachment; filename=smime.p7c\r\n\r\n"
+            )
+            .as_bytes(),
+        );
+        mixed.extend_from_slice(&encode_base64_wrapped(&certs_only, LINE_LENGTH));
+        mixed.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+        Ok(mixed)
+    }
+}
+
+/// A base64 encoded `application/pkcs7-mime` entity of the given `smime-type`.
+fn smime_entity(compat: &Compatibility, smime_type: &str, der: &[u8]) -> Vec<u8> {
+    let mut entity = format!(
+        "Content-Type: {}\r\nContent-Transfer-Encoding: base64\r\n",
+        compat.content_type(smime_type)
+    );
+    if let Some(disposition) = compat.content_disposition() {
+        entity.push_str(&format!("Content-Disposition: {}\r\n", disposition));
+    }
+    entity.push_str("\r\n");
+    let mut entity = entity.into_bytes();
+    entity.extend_from_slice(&encode_base64_wrapped(der, LINE_LENGTH));
+    entity.extend_from_slice(b"\r\n");
+    entity
+}
+
+/// Sign the MIME entity of a generated message from `from` with the gateway identity, if any,
+/// in the signature format of the crypto profile of `to`, and encrypt it to them if asked to
+/// and a usable certificate of theirs is on file. The gateway's chain is attached as a
+/// certs-only part first if asked to.
+pub async fn protect(
+    settings: &Settings,
+    from: &str,
+    to: &str,
+    entity: Vec<u8>,
+) -> Result<Vec<u8>> {
+    let profile = crypto_profile::profile_for(&settings.crypto_profiles, to);
+    let entity = match &settings.gateway_identity {
+        Some(identity) if settings.publish_gateway_certificate => {
+            identity.attach_certificates(&entity)?
+        }
+        _ => entity,
+    };
+    let entity = match (&settings.gateway_identity, profile.signature) {
+        (Some(identity), SignatureFormat::Clear) => identity.sign(&entity)?,
+        (Some(identity), SignatureFormat::Opaque) => {
+            identity.sign_opaque(&entity, &settings.compat)?
+        }
+        (None, _) => entity,
+    };
+    if !settings.encrypt_notifications {
+        return Ok(entity);
+    }
+    let cert = match settings
+        .cert_cache
+        .lookup(settings.cert_dir_for(from), to)
+        .await
+    {
+        Ok((cert, _)) => cert,
+        Err(error) => {
+            debug!(%to, ?error, "Sending generated message unencrypted");
+            return Ok(entity);
+        }
+    };
+    let profile = crypto_profile::CryptoProfile {
+        compress: false,
+        ..profile.clone()
+    };
+    let mut recipients = vec![(cert, profile.key_transport)];
+    if let Some(escrow) = &settings.escrow_certificate {
+        recipients.push((escrow.clone(), profile.key_transport));
+    }
+    let envelope =
+        crypto_profile::encrypt(&entity, &recipients, &profile, settings.compat.recipient_id)?;
+    Ok(smime_entity(
+        &settings.compat,
+        profile.smime_type(),
+        &envelope,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_pki::self_signed_identity;
+    use base64::{prelude::BASE64_STANDARD, Engine};
+
+    const ENTITY: &[u8] = b"Content-Type: text/plain; charset=utf-8\r\n\r\nHello\r\n";
+
+    fn gateway(dir: &Path) -> GatewayIdentity {
+        let (cert, key) = self_signed_identity("postmaster@example.com");
+        let (cert_path, key_path) = (dir.join("gateway.pem"), dir.join("gateway.key"));
+        std::fs::write(&cert_path, cert.to_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        GatewayIdentity::load(&cert_path, &key_path).unwrap()
+    }
+
+    /// The base64 body of the last part of a message.
+    fn last_part(message: &[u8]) -> Vec<u8> {
+        let message = String::from_utf8_lossy(message);
+        let body = message.rsplit("\r\n\r\n").next().unwrap();
+        let body: String = body
+            .lines()
+            .take_while(|line| !line.starts_with("--"))
+            .collect();
+        BASE64_STANDARD.decode(body).unwrap()
+    }
+
+    #[test]
+    fn test_sign() {
+        let dir = tempfile::tempdir().unwrap();
+        let identity = gateway(dir.path());
+        let signed = identity.sign(ENTITY).unwrap();
+        assert!(signed.starts_with(b"Content-Type: multipart/signed;"));
+
+        let signature = last_part(&signed);
+        let certs = smime::extract_certificates_from_p7s(&signature).unwrap();
+        assert!(smime::find_cert_for_email(&certs, "postmaster@example.com").is_ok());
+        let mut store = openssl::x509::store::X509StoreBuilder::new().unwrap();
+        store.add_cert(identity.cert.clone()).unwrap();
+        let store = store.build();
+        Pkcs7::from_der(&signature)
+            .unwrap()
+            .verify(
+                &Stack::new().unwrap(),
+                &store,
+                Some(ENTITY),
+                None,
+                Pkcs7Flags::BINARY,
+            )
+            .unwrap();
+
+        let (other, _) = self_signed_identity("other@example.com");
+        let other_path = dir.path().join("other.pem");
+        std::fs::write(&other_path, other.to_pem().unwrap()).unwrap();
+        assert!(GatewayIdentity::load(&other_path, &dir.path().join("gateway.key")).is_err());
+    }
+
+    #[test]
+    fn test_attach_certificates() {
+        let dir = tempfile::tempdir().unwrap();
+        let identity = gateway(dir.path());
+        let attached = identity.attach_certificates(ENTITY).unwrap();
+        assert!(attached.starts_with(b"Content-Type: multipart/mixed;"));
+        assert!(attached.windows(ENTITY.len()).any(|part| part == ENTITY));
+
+        let certs_only = last_part(&attached);
+        assert!(Pkcs7::from_der(&certs_only).is_ok());
+        let certs = smime::extract_certificates_from_p7s(&certs_only).unwrap();
+        assert!(smime::find_cert_for_email(&certs, "postmaster@example.com").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_protect() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = self_signed_identity("b@example.org");
+        smime::write_pem_stack([&cert], &dir.path().join("b@example.org.pem"))
+            .await
+            .unwrap();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec![]);
+        let from = "postmaster@example.com";
+        let plain = protect(&settings, from, "b@example.org", ENTITY.to_vec());
+        assert_eq!(plain.await.unwrap(), ENTITY);
+
+        settings.gateway_identity = Some(gateway(dir.path()));
+        settings.encrypt_notifications = true;
+        let unknown = protect(&settings, from, "c@example.org", ENTITY.to_vec());
+        assert!(unknown
+            .await
+            .unwrap()
+            .starts_with(b"Content-Type: multipart/signed;"));
+
+        let encrypted = protect(&settings, from, "b@example.org", ENTITY.to_vec())
+            .await
+            .unwrap();
+        assert!(encrypted.starts_with(b"Content-Type: application/pkcs7-mime;"));
+        let decrypted = smime::decrypt_data(&last_part(&encrypted), &cert, &key).unwrap();
+        assert!(decrypted.starts_with(b"Content-Type: multipart/signed;"));
+
+        settings.publish_gateway_certificate = true;
+        let published = protect(&settings, from, "c@example.org", ENTITY.to_vec())
+            .await
+            .unwrap();
+        let published = String::from_utf8_lossy(&published);
+        assert!(published.contains("Content-Type: multipart/mixed;"));
+        assert!(published.contains("smime-type=certs-only"));
+        settings.publish_gateway_certificate = false;
+
+        settings.crypto_profiles =
+            vec![crypto_profile::parse_profile("mangler.example=signature=opaque").unwrap()];
+        let opaque = protect(&settings, from, "c@mangler.example", ENTITY.to_vec())
+            .await
+            .unwrap();
+        assert!(opaque.starts_with(
+            b"Content-Type: application/pkcs7-mime; name=smime.p7m; smime-type=signed-data\r\n"
+        ));
+        let mut store = openssl::x509::store::X509StoreBuilder::new().unwrap();
+        store
+            .add_cert(settings.gateway_identity.as_ref().unwrap().cert.clone())
+            .unwrap();
+        let mut content = Vec::new();
+        Pkcs7::from_der(&last_part(&opaque))
+            .unwrap()
+            .verify(
+                &Stack::new().unwrap(),
+                &store.build(),
+                None,
+                Some(&mut content),
+                Pkcs7Flags::BINARY,
+            )
+            .unwrap();
+        assert_eq!(content, ENTITY);
+    }
+}
@@ -0,0 +1,150 @@
+//! Spool of harvested certificate chains that could not be stored as their certificate
+//! directory was unavailable, so the signed message still goes through. The chains are
+//! stored in the background once the directory is back.
+//!
+//! Each chain is kept as `<id>.json`, with its certificate directory, owner and metadata.
+
+use anyhow::{Context, Result};
+use openssl::x509::X509;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tracing::{error, info, warn};
+
+use crate::cert_store;
+use crate::metrics;
+use crate::pipeline;
+use crate::settings::Settings;
+use crate::smime_attributes::CertMetadata;
+
+/// How often storing the spooled chains is retried.
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A chain waiting for its certificate directory.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    cert_dir: PathBuf,
+    sender: String,
+    /// The chain in PEM.
+    chain: String,
+    metadata: CertMetadata,
+}
+
+/// Spool the `certs` harvested from `sender` for `cert_dir`. The entry is renamed into place
+/// once written, so the retries only see complete ones.
+pub async fn enqueue(
+    dir: &Path,
+    cert_dir: &Path,
+    sender: &str,
+    certs: &[X509],
+    metadata: &CertMetadata,
+) -> Result<()> {
+    tokio::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .await
+        .with_context(|| format!("Failed to create harvest spool {:?}", dir))?;
+    let mut chain = String::new();
+    for cert in certs {
+        chain.push_str(std::str::from_utf8(&cert.to_pem()?)?);
+    }
+    let entry = Entry {
+        cert_dir: cert_dir.to_path_buf(),
+        sender: sender.to_string(),
+        chain,
+        metadata: metadata.clone(),
+    };
+    let id = uuid::Uuid::new_v4();
+    let tmp = dir.join(format!(".{}.tmp", id));
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp)
+        .await
+        .with_context(|| format!("Failed to create {:?}", tmp))?;
+    file.write_all(&serde_json::to_vec(&entry)?).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&tmp, dir.join(format!("{}.json", id))).await?;
+    metrics::HARVEST_SPOOL_DEPTH.inc();
+    Ok(())
+}
+
+/// The spooled entries, oldest first.
+async fn entries(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries = Vec::new();
+    let mut read_dir = match tokio::fs::read_dir(dir).await {
+        Ok(read_dir) => read_dir,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read harvest spool {:?}", dir)),
+    };
+    while let Some(entry) = read_dir.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_some_and(|e| e == "json") {
+            entries.push((entry.metadata().await?.modified()?, path));
+        }
+    }
+    entries.sort();
+    Ok(entries.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Store the chain of the entry at `path`.
+async fn store(settings: &Settings, path: &Path) -> Result<()> {
+    let mut entry: Entry = serde_json::from_slice(&tokio::fs::read(path).await?)
+        .with_context(|| format!("Failed to parse spooled chain {:?}", path))?;
+    settings
+        .store_health
+        .check(&entry.cert_dir)
+        .await
+        .map_err(anyhow::Error::from)?;
+    let certs = X509::stack_from_pem(entry.chain.as_bytes())?;
+    pipeline::store_chain(
+        settings,
+        &entry.cert_dir,
+        &entry.sender,
+        &certs,
+        &mut entry.metadata,
+    )
+    .await
+    .inspect_err(|error| {
+        if cert_store::is_store_failure(error) {
+            settings.store_health.trip(&entry.cert_dir, error);
+        }
+    })?;
+    info!(cert_dir = ?entry.cert_dir, sender = entry.sender, "Stored spooled certificate chain");
+    Ok(())
+}
+
+/// Try storing every spooled chain, keeping the ones that still fail. Returns how many were
+/// stored.
+pub async fn retry(settings: &Settings, dir: &Path) -> Result<usize> {
+    let entries = entries(dir).await?;
+    let mut stored = 0;
+    for path in &entries {
+        match store(settings, path).await {
+            Ok(()) => {
+                tokio::fs::remove_file(path).await?;
+                stored += 1;
+            }
+            Err(error) => warn!(?path, ?error, "Spooled certificate chain not stored yet"),
+        }
+    }
+    metrics::HARVEST_SPOOL_DEPTH.set((entries.len() - stored) as i64);
+    Ok(stored)
+}
+
+/// Retry the spooled chains every [`RETRY_INTERVAL`], starting right away for the ones left
+/// over from before a restart.
+pub async fn run_periodically(settings: Arc<Settings>, dir: PathBuf) {
+    let mut interval = tokio::time::interval(RETRY_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if let Err(error) = retry(&settings, &dir).await {
+            error!(?dir, ?error, "Retrying the harvest spool failed");
+        }
+    }
+}
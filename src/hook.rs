@@ -0,0 +1,224 @@
+//! Site-specific automation after each encryption or harvest, like updating a ticket or
+//! tracking keys in a CRM: a command gets the event report of the message as JSON on its
+//! standard input, or the report is POSTed to an `http://` webhook. The message itself is
+//! never passed on.
+//!
+//! Hooks run in the background with a timeout; failures are logged and never hold up mail.
+
+use anyhow::{bail, Context, Result};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+use crate::event_report::MessageReport;
+
+/// How long a hook may take before it is killed or the webhook request is given up.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where reports of successful messages go.
+#[derive(Debug, PartialEq)]
+pub enum Hook {
+    /// An executable, run without arguments.
+    Command(PathBuf),
+    Webhook {
+        /// `<HOST>:<PORT>` to connect to.
+        address: String,
+        host: String,
+        path: String,
+    },
+}
+
+impl Hook {
+    /// Parse the path of an executable or an `http://<HOST>[:<PORT>]/<PATH>` URL.
+    pub fn parse(target: &str) -> Result<Self> {
+        let Some(url) = target.strip_prefix("http://") else {
+            if target.contains("://") {
+                bail!(
+                    "Unsupported hook {:?}, use a command like curl for other protocols",
+                    target
+                );
+            }
+            return Ok(Self::Command(PathBuf::from(target)));
+        };
+        let (host, path) = match url.find('/') {
+            Some(slash) => url.split_at(slash),
+            None => (url, "/"),
+        };
+        if host.is_empty() {
+            bail!("No host in hook {:?}", target);
+        }
+        let address = match host.rsplit_once(':') {
+            Some((_, port)) if !port.contains(']') => host.to_string(),
+            _ => format!("{}:80", host),
+        };
+        Ok(Self::Webhook {
+            address,
+            host: host.to_string(),
+            path: path.to_string(),
+        })
+    }
+
+    /// Check that a command is an executable file; webhooks are only known to work once used.
+    pub fn check(&self) -> Result<()> {
+        let Self::Command(path) = self else {
+            return Ok(());
+        };
+        let metadata =
+            std::fs::metadata(path).with_context(|| format!("Failed to read {:?}", path))?;
+        if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+            bail!("{:?} is not an executable file", path);
+        }
+        Ok(())
+    }
+
+    async fn run(&self, json: &str) -> Result<()> {
+        match self {
+            Self::Command(path) => {
+                let mut child = Command::new(path)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::piped())
+                    .kill_on_drop(true)
+                    .spawn()
+                    .with_context(|| format!("Failed to run hook {:?}", path))?;
+                // A hook not reading its input must not fail.
+                if let Some(mut stdin) = child.stdin.take() {
+                    let _ = stdin.write_all(json.as_bytes()).await;
+                }
+                let output = child.wait_with_output().await?;
+                if !output.status.success() {
+                    bail!(
+                        "Hook {:?} failed with {}: {}",
+                        path,
+                        output.status,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    );
+                }
+            }
+            Self::Webhook {
+                address,
+                host,
+                path,
+            } => {
+                let mut stream = TcpStream::connect(address)
+                    .await
+                    .with_context(|| format!("Failed to connect to webhook {}", address))?;
+                let request = format!(
+                    "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\n\r\n{}",
+                    path,
+                    host,
+                    json.len(),
+                    json
+                );
+                stream.write_all(request.as_bytes()).await?;
+                let mut response = Vec::new();
+                stream.read_to_end(&mut response).await?;
+                let response = String::from_utf8_lossy(&response);
+                let status = response.lines().next().unwrap_or_default();
+                if !status
+                    .split_whitespace()
+                    .nth(1)
+                    .is_some_and(|code| code.starts_with('2'))
+                {
+                    bail!("Webhook {} answered {:?}", address, status);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the hook for the report of a message in the background, if it was encrypted or
+    /// certificates were harvested from it.
+    pub fn notify(self: &Arc<Self>, report: &MessageReport) {
+        if report.outcome != "accept" || (report.cipher.is_none() && report.harvested.is_empty()) {
+            return;
+        }
+        let json = match serde_json::to_string(report) {
+            Ok(json) => json,
+            Err(error) => {
+                warn!(?error, "Failed to serialize report for hook");
+                return;
+            }
+        };
+        let hook = self.clone();
+        let queue_id = report.queue_id.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(TIMEOUT, hook.run(&json)).await {
+                Ok(Ok(())) => debug!(%queue_id, "Ran post-processing hook"),
+                Ok(Err(error)) => warn!(%queue_id, ?error, "Post-processing hook failed"),
+                Err(_) => warn!(%queue_id, "Post-processing hook timed out"),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Hook::parse("/usr/local/bin/crm-update").unwrap(),
+            Hook::Command(PathBuf::from("/usr/local/bin/crm-update"))
+        );
+        assert_eq!(
+            Hook::parse("http://tickets.example:8080/hooks/smime").unwrap(),
+            Hook::Webhook {
+                address: "tickets.example:8080".into(),
+                host: "tickets.example:8080".into(),
+                path: "/hooks/smime".into(),
+            }
+        );
+        assert_eq!(
+            Hook::parse("http://[::1]").unwrap(),
+            Hook::Webhook {
+                address: "[::1]:80".into(),
+                host: "[::1]".into(),
+                path: "/".into(),
+            }
+        );
+        assert!(Hook::parse("https://tickets.example/").is_err());
+        assert!(Hook::parse("http:///hooks").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let (script, output) = (dir.path().join("hook.sh"), dir.path().join("report.json"));
+        std::fs::write(&script, format!("#!/bin/sh\ncat > {:?}\n", output)).unwrap();
+        let hook = Hook::Command(script.clone());
+        assert!(hook.check().is_err());
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        hook.check().unwrap();
+        hook.run("{}").await.unwrap();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "{}");
+        let failing = Hook::Command(PathBuf::from("/bin/false"));
+        assert!(failing.run("{}").await.is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hooks/smime", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 1024];
+            let len = stream.read(&mut request).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.0 204 No Content\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&request[..len]).to_string()
+        });
+        Hook::parse(&url).unwrap().run("{}").await.unwrap();
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /hooks/smime HTTP/1.0\r\n"));
+        assert!(request.ends_with("Content-Length: 2\r\n\r\n{}"));
+    }
+}
@@ -0,0 +1,301 @@
+//! Drop-in provisioning of certificates through the `import/` subdirectory of
+//! each certificate directory.
+//!
+//! Files copied there, as PEM, DER, PKCS#7 or PKCS#12 (without password), are
+//! validated and stored under the addresses their certificates are issued for,
+//! unless a certificate expiring no earlier is stored already.
+//! Imported files are removed, invalid ones moved to `import/rejected/`.
+
+use anyhow::{bail, Context, Result};
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
+use openssl::nid::Nid;
+use openssl::pkcs12::Pkcs12;
+use openssl::x509::X509;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing::{error, info, warn};
+
+use crate::address;
+use crate::cert_store::{CertStore, DirectoryStore};
+use crate::settings::Settings;
+use crate::smime;
+
+/// Subdirectory of a certificate directory that is scanned for new files.
+const IMPORT_DIR: &str = "import";
+/// Subdirectory of the import directory receiving files that failed to import.
+const REJECTED_DIR: &str = "rejected";
+/// Files modified more recently are assumed to still be in transfer.
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// Decode the certificates of a PEM, DER, PKCS#7 or PKCS#12 file.
+fn decode_file(data: &[u8]) -> Result<Vec<X509>> {
+    if data.windows(10).any(|w| w == b"-----BEGIN") {
+        return X509::stack_from_pem(data).with_context(|| "Failed to parse PEM");
+    }
+    if let Ok(cert) = X509::from_der(data) {
+        return Ok(vec![cert]);
+    }
+    if let Ok(certs) = smime::extract_certificates_from_p7s(data) {
+        return Ok(certs);
+    }
+    let pkcs12 = Pkcs12::from_der(data)
+        .with_context(|| "Neither PEM, X.509, PKCS#7 nor PKCS#12 data")?
+        .parse2("")
+        .with_context(|| "Failed to open PKCS#12 file without password")?;
+    let mut certs: Vec<X509> = pkcs12.cert.into_iter().collect();
+    certs.extend(pkcs12.ca.into_iter().flatten());
+    Ok(certs)
+}
+
+/// All addresses a certificate is issued for.
+fn cert_emails(cert: &X509) -> Vec<String> {
+    let mut emails: Vec<String> = cert
+        .subject_alt_names()
+        .map(|san| {
+            san.iter()
+                .filter_map(|name| name.email().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default();
+    emails.extend(
+        cert.subject_name()
+            .entries_by_nid(Nid::PKCS9_EMAILADDRESS)
+            .filter_map(|entry| entry.data().to_string().ok()),
+    );
+    let mut emails: Vec<String> = emails.iter().map(|e| address::normalize(e)).collect();
+    emails.sort();
+    emails.dedup();
+    emails
+}
+
+/// What became of the certificates of an imported file, by address.
+#[derive(Debug, Default)]
+struct Imported {
+    stored: Vec<String>,
+    /// Addresses with a certificate on file expiring no earlier, which is kept.
+    kept: Vec<String>,
+    /// Addresses whose certificate in the file has expired.
+    expired: Vec<String>,
+}
+
+/// Whether a certificate expiring at `not_after` should replace the one stored for `email`:
+/// unless that is valid for as long or longer, e.g. a renewed one harvested meanwhile.
+async fn replaces_stored(store: &DirectoryStore, email: &str, not_after: &Asn1TimeRef) -> bool {
+    let Ok(chain) = store.load(email).await else {
+        return true;
+    };
+    let stored = smime::find_cert_for_email(&chain, email)
+        .ok()
+        .or_else(|| chain.into_iter().next());
+    stored.is_none_or(|stored| stored.not_after() < not_after)
+}
+
+/// Validate a dropped file and store its certificates in `cert_dir`. Expired certificates
+/// are skipped, and ones stored already are only replaced by certificates expiring later.
+/// Fails if the file has no usable certificate issued for an email address.
+async fn import_file(cert_dir: &Path, path: &Path) -> Result<Imported> {
+    let data = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("Failed to read {:?}", path))?;
+    let certs = decode_file(&data)?;
+    let now = Asn1Time::days_from_now(0)?;
+    let store = DirectoryStore::new(cert_dir);
+
+    let mut imported = Imported::default();
+    for cert in &certs {
+        let emails = cert_emails(cert);
+        if emails.is_empty() {
+            continue;
+        }
+        if cert.not_after() < now {
+            imported.expired.extend(emails);
+            continue;
+        }
+        // Store the chain with the certificate of the address first.
+        let chain = std::iter::once(cert).chain(certs.iter().filter(|c| *c != cert));
+        let chain: Vec<X509> = chain.cloned().collect();
+        for email in emails {
+            if !replaces_stored(&store, &email, cert.not_after()).await {
+                imported.kept.push(email);
+                continue;
+            }
+            store.store(&email, &chain).await?;
+            imported.stored.push(email);
+        }
+    }
+    if imported.stored.is_empty() && imported.kept.is_empty() {
+        match imported.expired.first() {
+            Some(email) => bail!("Certificate for {} expired", email),
+            None => bail!("No certificate is issued for an email address"),
+        }
+    }
+    Ok(imported)
+}
+
+/// Import all settled files from the import directory of `cert_dir`. Returns
+/// the number of imported files.
+pub async fn scan(cert_dir: &Path) -> Result<usize> {
+    let import_dir = cert_dir.join(IMPORT_DIR);
+    let mut entries = match tokio::fs::read_dir(&import_dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", import_dir)),
+    };
+
+    let mut imported = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let metadata = entry.metadata().await?;
+        // Skip hidden files, which is how scp and rsync name partial transfers.
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        let settling = metadata
+            .modified()
+            .ok()
+            .and_then(|m| SystemTime::now().duration_since(m).ok())
+            .is_some_and(|age| age < SETTLE_TIME);
+        if !metadata.is_file() || hidden || settling {
+            continue;
+        }
+
+        match import_file(cert_dir, &path).await {
+            Ok(Imported {
+                stored,
+                kept,
+                expired,
+            }) => {
+                info!(
+                    ?path,
+                    ?stored,
+                    ?kept,
+                    ?expired,
+                    "Imported dropped certificate"
+                );
+                tokio::fs::remove_file(&path)
+                    .await
+                    .with_context(|| format!("Failed to remove {:?}", path))?;
+                imported += 1;
+            }
+            Err(error) => {
+                warn!(?path, ?error, "Rejected dropped certificate");
+                let rejected = import_dir.join(REJECTED_DIR);
+                tokio::fs::create_dir_all(&rejected).await?;
+                tokio::fs::rename(&path, rejected.join(entry.file_name()))
+                    .await
+                    .with_context(|| format!("Failed to move {:?} to {:?}", path, rejected))?;
+            }
+        }
+    }
+    Ok(imported)
+}
+
+/// Scan the import directories of all certificate directories periodically, forever.
+pub async fn run_periodically(settings: Arc<Settings>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        for cert_dir in settings.all_cert_dirs() {
+            if let Err(error) = scan(cert_dir).await {
+                error!(?cert_dir, ?error, "Scanning import directory failed");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_pki::{self_signed_identity, self_signed_identity_until, TestCa};
+    use std::fs;
+
+    fn drop_file(cert_dir: &Path, name: &str, data: &[u8]) {
+        let path = cert_dir.join(IMPORT_DIR).join(name);
+        fs::write(&path, data).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() - SETTLE_TIME * 2)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_scan() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join(IMPORT_DIR)).unwrap();
+        let (alice, _) = self_signed_identity("alice@Example.COM");
+        let ca = TestCa::new("Test CA");
+        let (bob, bob_key) = ca.issue("bob@example.com");
+        let pkcs12 = Pkcs12::builder()
+            .name("bob")
+            .pkey(&bob_key)
+            .cert(&bob)
+            .ca({
+                let mut stack = openssl::stack::Stack::new().unwrap();
+                stack.push(ca.cert.clone()).unwrap();
+                stack
+            })
+            .build2("")
+            .unwrap();
+
+        drop_file(dir.path(), "alice.pem", &alice.to_pem().unwrap());
+        drop_file(dir.path(), "bob.p12", &pkcs12.to_der().unwrap());
+        drop_file(dir.path(), "garbage.cer", b"not a certificate");
+        drop_file(dir.path(), ".partial.der", &alice.to_der().unwrap());
+
+        assert_eq!(scan(dir.path()).await.unwrap(), 2);
+        let store = DirectoryStore::new(dir.path());
+        assert_eq!(store.load("alice@example.com").await.unwrap(), [alice]);
+        assert_eq!(
+            store.load("bob@example.com").await.unwrap(),
+            [bob, ca.cert.clone()]
+        );
+
+        let import_dir = dir.path().join(IMPORT_DIR);
+        assert!(!import_dir.join("alice.pem").exists());
+        assert!(!import_dir.join("bob.p12").exists());
+        assert!(import_dir.join(REJECTED_DIR).join("garbage.cer").exists());
+        assert!(import_dir.join(".partial.der").exists());
+    }
+
+    #[tokio::test]
+    async fn test_import_file_keeps_newer() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join(IMPORT_DIR)).unwrap();
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let (renewed, _) = self_signed_identity_until("alice@example.com", now + 365 * 86400);
+        let (older, _) = self_signed_identity_until("alice@example.com", now + 30 * 86400);
+        let (expired, _) = self_signed_identity_until("carol@example.com", now - 86400);
+        let (dave, _) = self_signed_identity("dave@example.com");
+        let store = DirectoryStore::new(dir.path());
+        store
+            .store("alice@example.com", std::slice::from_ref(&renewed))
+            .await
+            .unwrap();
+
+        // A bundle with an older certificate for a stored address, an expired one and a
+        // valid one only has the expired one skipped, and keeps the stored certificate.
+        let mut bundle = older.to_pem().unwrap();
+        bundle.extend(expired.to_pem().unwrap());
+        bundle.extend(dave.to_pem().unwrap());
+        drop_file(dir.path(), "bundle.pem", &bundle);
+        drop_file(dir.path(), "expired.pem", &expired.to_pem().unwrap());
+
+        assert_eq!(scan(dir.path()).await.unwrap(), 1);
+        assert_eq!(store.load("alice@example.com").await.unwrap()[0], renewed);
+        assert_eq!(store.load("dave@example.com").await.unwrap()[0], dave);
+        assert!(store.load("carol@example.com").await.is_err());
+        let rejected = dir.path().join(IMPORT_DIR).join(REJECTED_DIR);
+        assert!(rejected.join("expired.pem").exists());
+
+        // A later expiring certificate replaces the stored one.
+        let (longer, _) = self_signed_identity_until("alice@example.com", now + 730 * 86400);
+        drop_file(dir.path(), "longer.pem", &longer.to_pem().unwrap());
+        assert_eq!(scan(dir.path()).await.unwrap(), 1);
+        assert_eq!(store.load("alice@example.com").await.unwrap(), [longer]);
+    }
+}
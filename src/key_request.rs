@@ -0,0 +1,182 @@
+//! Requests to recipients without a certificate to reply with a signed message, so their
+//! certificate gets harvested, automating how S/MIME is usually bootstrapped with externals.
+//!
+//! Each recipient is asked at most once per interval, recorded in `.key-requested` in the
+//! certificate directory.
+
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::cert_usage;
+use crate::expiry;
+use crate::settings::Settings;
+use crate::templates::{self, Templates};
+
+/// Record of the requests sent, one `<recipient>\t<seconds since the epoch>` line each.
+const REQUESTED: &str = ".key-requested";
+
+/// Longest time to wait for the SMTP server, as the message waits for the request.
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How to ask recipients for their certificate.
+pub struct KeyRequest {
+    /// Sender address of the requests.
+    pub from: String,
+    /// Text with `{sender}` and `{recipient}` placeholders, optionally starting with a
+    /// `Subject:` line, used instead of the `key-request` templates.
+    pub template: Option<String>,
+    /// Seconds before asking a recipient again.
+    pub interval: u64,
+    /// Serializes updates of the records.
+    lock: Mutex<()>,
+}
+
+impl KeyRequest {
+    pub fn new(from: String, template: Option<&Path>, interval_days: u32) -> Result<Self> {
+        let template = template
+            .map(|path| {
+                std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read key request template {:?}", path))
+            })
+            .transpose()?;
+        Ok(Self {
+            from,
+            template,
+            interval: interval_days as u64 * 86400,
+            lock: Mutex::default(),
+        })
+    }
+
+    /// Subject and text of the request.
+    fn render(&self, templates: &Templates, sender: &str, recipient: &str) -> (String, String) {
+        let values = [("sender", sender), ("recipient", recipient)];
+        match &self.template {
+            Some(template) => {
+                let (subject, text) = templates::render_text(template, &values);
+                let subject = subject.unwrap_or_else(|| "Please send a signed message".into());
+                (subject, text)
+            }
+            None => templates.render(&templates::KEY_REQUEST, recipient, &values),
+        }
+    }
+
+    /// Ask `recipient` to send a signed message to `sender`, unless they were asked within the
+    /// interval. Failures are only logged, the message is refused anyway.
+    pub async fn send(&self, settings: &Settings, cert_dir: &Path, sender: &str, recipient: &str) {
+        let _guard = self.lock.lock().await;
+        let now = cert_usage::now();
+        let mut requested = read_requested(cert_dir);
+        if requested
+            .get(recipient)
+            .is_some_and(|last| now.saturating_sub(*last) < self.interval)
+        {
+            return;
+        }
+        let (subject, text) = self.render(&settings.templates, sender, recipient);
+        let headers = [("Reply-To", sender)];
+        let sent = tokio::time::timeout(
+            SEND_TIMEOUT,
+            expiry::send_mail_with_headers(
+                settings, &self.from, recipient, &subject, &headers, &text,
+            ),
+        )
+        .await
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("Timed out")));
+        if let Err(error) = sent {
+            warn!(%recipient, ?error, "Failed to request certificate");
+            return;
+        }
+        info!(%recipient, "Requested certificate from recipient");
+        requested.insert(recipient.to_string(), now);
+        let content: String = requested
+            .iter()
+            .map(|(recipient, time)| format!("{}\t{}\n", recipient, time))
+            .collect();
+        if let Err(error) = tokio::fs::write(cert_dir.join(REQUESTED), content).await {
+            warn!(?error, "Failed to record certificate request");
+        }
+    }
+}
+
+fn read_requested(cert_dir: &Path) -> BTreeMap<String, u64> {
+    std::fs::read_to_string(cert_dir.join(REQUESTED))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let (recipient, time) = line.split_once('\t')?;
+            Some((recipient.to_string(), time.parse().ok()?))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_render() {
+        let request = KeyRequest::new("postmaster@example.com".into(), None, 30).unwrap();
+        let (subject, text) =
+            request.render(&Templates::default(), "a@example.com", "b@example.org");
+        assert_eq!(subject, "Please send a signed message to a@example.com");
+        assert!(text.starts_with("a@example.com tried to send you"));
+        assert!(text.contains("for b@example.org yet."));
+    }
+
+    /// Accept SMTP sessions, counting the messages.
+    async fn smtp_sink(listener: TcpListener) -> usize {
+        let mut messages = 0;
+        while let Ok(Ok((stream, _))) =
+            tokio::time::timeout(Duration::from_millis(500), listener.accept()).await
+        {
+            let mut stream = BufReader::new(stream);
+            stream.get_mut().write_all(b"220 ok\r\n").await.unwrap();
+            let mut line = String::new();
+            while stream.read_line(&mut line).await.unwrap() > 0 {
+                let reply: &[u8] = match line.trim_end() {
+                    "DATA" => b"354 go\r\n",
+                    "." => {
+                        messages += 1;
+                        b"250 ok\r\n"
+                    }
+                    "QUIT" => b"221 bye\r\n",
+                    _ if line.starts_with("EHLO")
+                        || line.starts_with("MAIL")
+                        || line.starts_with("RCPT") =>
+                    {
+                        b"250 ok\r\n"
+                    }
+                    _ => b"",
+                };
+                stream.get_mut().write_all(reply).await.unwrap();
+                line.clear();
+            }
+        }
+        messages
+    }
+
+    #[tokio::test]
+    async fn test_send_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        let sink = tokio::spawn(smtp_sink(listener));
+
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec![]);
+        settings.smtp_server = server;
+        let request = KeyRequest::new("postmaster@example.com".into(), None, 30).unwrap();
+        for _ in 0..2 {
+            request
+                .send(&settings, dir.path(), "a@example.com", "b@example.org")
+                .await;
+        }
+        assert_eq!(sink.await.unwrap(), 1);
+        assert!(read_requested(dir.path()).contains_key("b@example.org"));
+    }
+}
@@ -0,0 +1,355 @@
+//! Synchronization of user certificates from an LDAP directory, like Active
+//! Directory or another global address list, into the certificate directory.
+//!
+//! The addresses written by a sync are recorded in a manifest file in the
+//! certificate directory. Only those are removed again once their users leave
+//! the directory, harvested and imported certificates are never touched unless
+//! `--ldap-overwrite` is given.
+
+use anyhow::{bail, Context, Result};
+use ldap3::{adapters::PagedResults, LdapConnAsync, Scope, SearchEntry};
+use openssl::x509::X509;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+
+use crate::address;
+use crate::cert_command::LDAP_SYNC_MANIFEST as MANIFEST;
+use crate::cert_store::{CertStore, DirectoryStore};
+use crate::contacts::Contact;
+use crate::smime;
+
+/// Connection and schedule of the LDAP synchronization.
+#[derive(clap::Args, Debug, Clone)]
+pub struct LdapArgs {
+    /// LDAP server to synchronize certificates from, e.g. `ldaps://dc.example.com`.
+    #[arg(long)]
+    pub ldap_url: Option<String>,
+
+    /// DN to bind as, binds anonymously if not given.
+    #[arg(long)]
+    pub ldap_bind_dn: Option<String>,
+
+    /// File containing the bind password.
+    #[arg(long)]
+    pub ldap_bind_password_file: Option<PathBuf>,
+
+    /// Base DN to search for users.
+    #[arg(long, default_value = "")]
+    pub ldap_base_dn: String,
+
+    /// Filter selecting the users to synchronize.
+    #[arg(long, default_value = "(&(mail=*)(userCertificate;binary=*))")]
+    pub ldap_filter: String,
+
+    /// Attributes holding the addresses of a user. `smtp:` prefixes, as used by
+    /// `proxyAddresses`, are stripped.
+    #[arg(long, default_value = "mail")]
+    pub ldap_mail_attribute: Vec<String>,
+
+    /// Attribute holding the DER encoded certificates of a user.
+    #[arg(long, default_value = "userCertificate;binary")]
+    pub ldap_cert_attribute: String,
+
+    /// Synchronize every this many seconds while running as daemon, 0 disables it.
+    #[arg(long, default_value_t = 3600)]
+    pub ldap_sync_interval: u64,
+
+    /// Replace harvested or imported certificates of addresses found in the directory,
+    /// which then count as synchronized.
+    #[arg(long)]
+    pub ldap_overwrite: bool,
+}
+
+/// Counts of a synchronization run.
+#[derive(Debug, Default, PartialEq)]
+pub struct SyncSummary {
+    pub added: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub removed: usize,
+    /// Addresses left alone, as their certificates were harvested or imported.
+    pub skipped: usize,
+}
+
+fn attribute_values<'a, T>(
+    values: &'a std::collections::HashMap<String, Vec<T>>,
+    name: &'a str,
+) -> impl Iterator<Item = &'a T> {
+    values
+        .iter()
+        .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+        .flat_map(|(_, v)| v)
+}
+
+/// Search the directory for all users with certificates.
+pub async fn fetch_entries(args: &LdapArgs) -> Result<Vec<Contact>> {
+    let Some(url) = &args.ldap_url else {
+        bail!("No LDAP server configured, see --ldap-url");
+    };
+    let (conn, mut ldap) = LdapConnAsync::new(url)
+        .await
+        .with_context(|| format!("Failed to connect to {}", url))?;
+    ldap3::drive!(conn);
+
+    if let Some(dn) = &args.ldap_bind_dn {
+        let password = match &args.ldap_bind_password_file {
+            Some(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read LDAP password from {:?}", path))?
+                .trim_end_matches(['\r', '\n'])
+                .to_string(),
+            None => String::new(),
+        };
+        ldap.simple_bind(dn, &password)
+            .await?
+            .success()
+            .with_context(|| format!("Failed to bind as {}", dn))?;
+    }
+
+    let mut attrs = args.ldap_mail_attribute.clone();
+    attrs.push(args.ldap_cert_attribute.clone());
+    let mut search = ldap
+        .streaming_search_with(
+            PagedResults::new(500),
+            &args.ldap_base_dn,
+            Scope::Subtree,
+            &args.ldap_filter,
+            attrs,
+        )
+        .await
+        .with_context(|| "LDAP search failed")?;
+
+    let mut entries = Vec::new();
+    while let Some(entry) = search.next().await? {
+        let entry = SearchEntry::construct(entry);
+        let emails = args
+            .ldap_mail_attribute
+            .iter()
+            .flat_map(|name| attribute_values(&entry.attrs, name))
+            .map(|value| match value.get(..5) {
+                Some(prefix) if prefix.eq_ignore_ascii_case("smtp:") => &value[5..],
+                _ => value.as_str(),
+            })
+            .filter(|email| email.contains('@'))
+            .map(str::to_string)
+            .collect();
+        let mut certificates = Vec::new();
+        for der in attribute_values(&entry.bin_attrs, &args.ldap_cert_attribute) {
+            match X509::from_der(der) {
+                Ok(cert) => certificates.push(cert),
+                Err(error) => warn!(?error, dn = entry.dn, "Skipping invalid certificate"),
+            }
+        }
+        entries.push(Contact {
+            emails,
+            certificates,
+        });
+    }
+    search
+        .finish()
+        .await
+        .success()
+        .with_context(|| "LDAP search failed")?;
+    ldap.unbind().await?;
+    Ok(entries)
+}
+
+fn read_manifest(cert_dir: &Path) -> Result<BTreeSet<String>> {
+    match std::fs::read_to_string(cert_dir.join(MANIFEST)) {
+        Ok(content) => Ok(content
+            .lines()
+            .filter(|l| !l.is_empty())
+            .map(str::to_string)
+            .collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+        Err(e) => Err(e).with_context(|| "Failed to read LDAP sync manifest"),
+    }
+}
+
+/// Bring `cert_dir` in line with the directory entries: store the certificates
+/// issued for each address, newest first, and remove the ones of addresses
+/// synchronized before but gone now. Certificates not synchronized before are
+/// only replaced with `overwrite`.
+pub async fn apply_sync(
+    cert_dir: &Path,
+    entries: &[Contact],
+    overwrite: bool,
+) -> Result<SyncSummary> {
+    let previous = read_manifest(cert_dir)?;
+    let store = DirectoryStore::new(cert_dir);
+
+    let mut wanted: BTreeMap<String, Vec<&X509>> = BTreeMap::new();
+    for entry in entries {
+        for email in &entry.emails {
+            let email = address::normalize(email);
+            let certs: Vec<&X509> = entry
+                .certificates
+                .iter()
+                .filter(|cert| smime::find_cert_for_email([*cert], &email).is_ok())
+                .collect();
+            if certs.is_empty() {
+                debug!(%email, "No certificate in the directory is issued for this address");
+                continue;
+            }
+            wanted.entry(email).or_default().extend(certs);
+        }
+    }
+    if wanted.is_empty() && !previous.is_empty() {
+        bail!("Directory returned no certificates, refusing to remove all synchronized ones");
+    }
+
+    let mut summary = SyncSummary::default();
+    let mut synced = BTreeSet::new();
+    for (email, certs) in wanted.iter_mut() {
+        certs.sort_by(|a, b| b.not_after().compare(a.not_after()).unwrap());
+        certs.dedup();
+        let chain: Vec<X509> = certs.iter().map(|cert| (*cert).clone()).collect();
+
+        match store.load(email).await {
+            Ok(_) if !previous.contains(email) && !overwrite => {
+                debug!(%email, "Leaving harvested or imported certificate alone");
+                summary.skipped += 1;
+                continue;
+            }
+            Ok(stored) if stored == chain => {
+                summary.unchanged += 1;
+                synced.insert(email.clone());
+                continue;
+            }
+            Ok(_) => summary.updated += 1,
+            Err(_) => summary.added += 1,
+        }
+        store.store(email, &chain).await?;
+        synced.insert(email.clone());
+        info!(%email, "Synchronized certificate from directory");
+    }
+
+    for email in previous.iter().filter(|e| !wanted.contains_key(*e)) {
+        if store.remove(email).await? {
+            info!(%email, "Removed certificate of address gone from the directory");
+            summary.removed += 1;
+        }
+    }
+
+    let manifest: String = synced.iter().map(|email| format!("{}\n", email)).collect();
+    tokio::fs::write(cert_dir.join(MANIFEST), manifest)
+        .await
+        .with_context(|| "Failed to write LDAP sync manifest")?;
+    Ok(summary)
+}
+
+/// Synchronize `cert_dir` with the directory once.
+pub async fn sync(args: &LdapArgs, cert_dir: &Path) -> Result<SyncSummary> {
+    let entries = fetch_entries(args).await?;
+    apply_sync(cert_dir, &entries, args.ldap_overwrite).await
+}
+
+/// Synchronize `cert_dir` periodically, forever.
+pub async fn run_periodically(args: LdapArgs, cert_dir: PathBuf) {
+    let mut interval = tokio::time::interval(Duration::from_secs(args.ldap_sync_interval));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        match sync(&args, &cert_dir).await {
+            Ok(summary) => info!(?summary, "LDAP synchronization done"),
+            Err(error) => error!(?error, "LDAP synchronization failed"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_pki::self_signed_identity;
+
+    fn entry(emails: &[&str], certificates: Vec<X509>) -> Contact {
+        Contact {
+            emails: emails.iter().map(|e| e.to_string()).collect(),
+            certificates,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_apply_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let (alice, _) = self_signed_identity("alice@example.com");
+        let (bob, _) = self_signed_identity("bob@example.com");
+        let (harvested, _) = self_signed_identity("carol@example.com");
+        smime::write_pem_stack([&harvested], &dir.path().join("carol@example.com.pem"))
+            .await
+            .unwrap();
+
+        let entries = [
+            entry(&["alice@example.com", "a@example.com"], vec![alice.clone()]),
+            entry(&["bob@example.com"], vec![bob.clone()]),
+        ];
+        let summary = apply_sync(dir.path(), &entries, false).await.unwrap();
+        assert_eq!(
+            summary,
+            SyncSummary {
+                added: 2,
+                ..Default::default()
+            }
+        );
+        assert!(!dir.path().join("a@example.com.pem").exists());
+
+        let summary = apply_sync(dir.path(), &entries, false).await.unwrap();
+        assert_eq!(summary.unchanged, 2);
+
+        // Bob leaves, Alice gets a new certificate.
+        let (renewed, _) = self_signed_identity("alice@example.com");
+        let entries = [entry(&["alice@example.com"], vec![alice, renewed])];
+        let summary = apply_sync(dir.path(), &entries, false).await.unwrap();
+        assert_eq!(
+            summary,
+            SyncSummary {
+                updated: 1,
+                removed: 1,
+                ..Default::default()
+            }
+        );
+        assert!(!dir.path().join("bob@example.com.pem").exists());
+        assert!(dir.path().join("carol@example.com.pem").exists());
+        let stored = smime::load_pem_stack(dir.path().join("alice@example.com.pem"))
+            .await
+            .unwrap();
+        assert_eq!(stored.len(), 2);
+
+        assert!(apply_sync(dir.path(), &[], false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_apply_sync_harvested() {
+        let dir = tempfile::tempdir().unwrap();
+        let carol = dir.path().join("carol@example.com.pem");
+        let (harvested, _) = self_signed_identity("carol@example.com");
+        smime::write_pem_stack([&harvested], &carol).await.unwrap();
+        let (published, _) = self_signed_identity("carol@example.com");
+        let entries = [entry(&["carol@example.com"], vec![published.clone()])];
+
+        let summary = apply_sync(dir.path(), &entries, false).await.unwrap();
+        assert_eq!(
+            summary,
+            SyncSummary {
+                skipped: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            smime::load_pem_stack(&carol).await.unwrap(),
+            vec![harvested]
+        );
+        assert!(read_manifest(dir.path()).unwrap().is_empty());
+
+        let summary = apply_sync(dir.path(), &entries, true).await.unwrap();
+        assert_eq!(summary.updated, 1);
+        assert_eq!(
+            smime::load_pem_stack(&carol).await.unwrap(),
+            vec![published]
+        );
+        assert!(read_manifest(dir.path())
+            .unwrap()
+            .contains("carol@example.com"));
+    }
+}
@@ -0,0 +1,124 @@
+//! S/MIME gateway logic of pantosmime: encrypting mail for recipients with a certificate on
+//! file, and harvesting the certificates of senders from their signatures. The `pantosmimed`
+//! daemon is a thin binary on top of this crate.
+//!
+//! Other programs can embed the gateway, or test against it without spawning the daemon:
+//!
+//! - [`Pantosmime::builder()`] configures the milter, optionally with certificates from any
+//!   [`CertStore`], to serve it on a listener, hand its callbacks to an own [`indymilter`]
+//!   server, or run single messages through it.
+//! - [`encrypt_data()`] and [`extract_certificates()`] do the cryptography for one message,
+//!   with certificates from any [`CertStore`].
+//!
+//! Only the items named here are meant to stay stable. The modules the daemon, benchmarks and
+//! fuzz targets share are public, as they are separate crates, but hidden from the
+//! documentation.
+
+pub(crate) mod cert_store;
+pub(crate) mod contacts;
+pub(crate) mod dead_letter;
+pub(crate) mod diagnostics;
+pub(crate) mod embed;
+#[cfg(any(test, feature = "replay"))]
+pub(crate) mod milter_client;
+pub(crate) mod result_header;
+pub(crate) mod smime_attributes;
+#[cfg(test)]
+mod test_pki;
+
+// Shared with the daemon, the benchmarks and the fuzz targets.
+#[doc(hidden)]
+pub mod address;
+#[doc(hidden)]
+pub mod address_list;
+#[doc(hidden)]
+pub mod authentication_results;
+#[doc(hidden)]
+pub mod backpressure;
+#[doc(hidden)]
+pub mod body_normalization;
+#[doc(hidden)]
+pub mod capabilities;
+#[doc(hidden)]
+pub mod cert_command;
+#[doc(hidden)]
+pub mod cert_usage;
+#[cfg(feature = "chaos")]
+#[doc(hidden)]
+pub mod chaos;
+#[doc(hidden)]
+pub mod compat;
+#[doc(hidden)]
+pub mod config_reload;
+#[doc(hidden)]
+pub mod crypto_profile;
+#[doc(hidden)]
+pub mod decision_cache;
+#[doc(hidden)]
+pub mod der;
+#[doc(hidden)]
+pub mod deterministic;
+#[doc(hidden)]
+pub mod escrow;
+#[doc(hidden)]
+pub mod event_report;
+#[doc(hidden)]
+pub mod expiry;
+#[doc(hidden)]
+pub mod explain;
+#[doc(hidden)]
+pub mod gateway_identity;
+#[doc(hidden)]
+pub mod harvest_spool;
+#[doc(hidden)]
+pub mod hook;
+#[doc(hidden)]
+pub mod import_dir;
+#[doc(hidden)]
+pub mod key_request;
+#[cfg(feature = "ldap")]
+#[doc(hidden)]
+pub mod ldap_sync;
+#[doc(hidden)]
+#[cfg_attr(not(feature = "metrics"), path = "metrics_noop.rs")]
+pub mod metrics;
+#[doc(hidden)]
+pub mod milter_callbacks;
+#[doc(hidden)]
+pub mod mime_parser;
+#[doc(hidden)]
+pub mod network;
+#[doc(hidden)]
+pub mod pipeline;
+#[cfg(feature = "lua")]
+#[doc(hidden)]
+pub mod policy_script;
+#[doc(hidden)]
+pub mod reinjection;
+#[cfg(feature = "replay")]
+#[doc(hidden)]
+pub mod replay;
+#[doc(hidden)]
+pub mod replication;
+#[doc(hidden)]
+pub mod schedule;
+#[doc(hidden)]
+pub mod settings;
+#[doc(hidden)]
+pub mod smime;
+#[cfg(feature = "archive")]
+#[doc(hidden)]
+pub mod store_archive;
+#[doc(hidden)]
+pub mod templates;
+#[doc(hidden)]
+pub mod transfer_encoding;
+#[doc(hidden)]
+pub mod trust;
+
+pub use cert_store::{CertStore, DirectoryStore};
+pub use embed::{extract_certificates, Builder, Pantosmime};
+#[cfg(feature = "replay")]
+pub use milter_client::{Action, Outcome, Response};
+pub use settings::{Mode, Settings};
+pub use smime::encrypt_data;
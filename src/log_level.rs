@@ -0,0 +1,64 @@
+//! Switching the log level at runtime: SIGUSR2 toggles between the configured filter and
+//! DEBUG, to capture verbose traces of a problem message without restarting and losing the
+//! reproduction.
+
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::{reload, Registry};
+
+/// Replaces the filter of the running subscriber.
+pub type Handle = reload::Handle<EnvFilter, Registry>;
+
+/// The filter from `RUST_LOG`, INFO by default.
+pub fn configured_filter() -> EnvFilter {
+    EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env_lossy()
+}
+
+/// Switch to DEBUG, or back to the configured filter.
+fn set_debug(handle: &Handle, debug: bool) -> Result<(), reload::Error> {
+    let filter = match debug {
+        true => EnvFilter::new("debug"),
+        false => configured_filter(),
+    };
+    handle.reload(filter)
+}
+
+/// Toggle the log level on every SIGUSR2, forever.
+pub async fn toggle_on_signal(handle: Handle) {
+    let mut signals = match signal(SignalKind::user_defined2()) {
+        Ok(signals) => signals,
+        Err(error) => {
+            warn!(?error, "Cannot listen for SIGUSR2; the log level is fixed");
+            return;
+        }
+    };
+    let mut verbose = false;
+    while signals.recv().await.is_some() {
+        verbose = !verbose;
+        match set_debug(&handle, verbose) {
+            Ok(()) => info!(verbose, "Switched log level"),
+            Err(error) => error!(?error, "Failed to switch log level"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::Level;
+    use tracing_subscriber::prelude::*;
+
+    #[test]
+    fn test_set_debug() {
+        let (filter, handle) = reload::Layer::new(EnvFilter::new("info"));
+        let subscriber = tracing_subscriber::registry().with(filter);
+        tracing::subscriber::with_default(subscriber, || {
+            assert!(!tracing::enabled!(Level::DEBUG));
+            set_debug(&handle, true).unwrap();
+            assert!(tracing::enabled!(Level::DEBUG));
+        });
+    }
+}
@@ -1,16 +1,34 @@
-mod milter_callbacks;
-mod mime_parser;
-mod smime;
+mod check_config;
+mod log_level;
+mod privileges;
+mod sandbox;
+mod systemd;
 
-use clap::Parser;
-use std::{path::PathBuf, sync::Arc};
-use tokio::{net::TcpListener, signal};
-use tracing::info;
-use tracing_subscriber::{
-    filter::{EnvFilter, LevelFilter},
-    fmt,
-    prelude::*,
+use anyhow::Context;
+use clap::{CommandFactory, Parser, Subcommand};
+#[cfg(feature = "chaos")]
+use pantosmime::chaos;
+#[cfg(feature = "ldap")]
+use pantosmime::ldap_sync;
+#[cfg(feature = "metrics")]
+use pantosmime::metrics;
+#[cfg(feature = "replay")]
+use pantosmime::replay;
+#[cfg(feature = "archive")]
+use pantosmime::store_archive;
+use pantosmime::{
+    address, authentication_results, backpressure, body_normalization, capabilities, cert_command,
+    cert_usage, compat, config_reload, crypto_profile, decision_cache, deterministic, escrow,
+    event_report, expiry, explain, gateway_identity, harvest_spool, hook, import_dir, key_request,
+    milter_callbacks, network, pipeline, reinjection, replication, schedule, settings, templates,
+    transfer_encoding, trust, Pantosmime,
+};
+use std::{
+    io::Write, net::SocketAddr, num::NonZeroUsize, path::PathBuf, sync::Arc, time::Duration,
 };
+use tokio::{net::TcpListener, signal};
+use tracing::{error, info, warn};
+use tracing_subscriber::{fmt, prelude::*, reload};
 
 #[derive(Parser)]
 #[command(name = "pantosmime")]
@@ -18,42 +36,1214 @@ use tracing_subscriber::{
 #[command(about = "S/MIME Encrypting Milter Daemon", long_about = None)]
 #[clap(version)]
 struct Cli {
-    #[arg(short, long, default_value = "127.0.0.1:22666")]
-    listen: String,
+    /// Milter socket, optionally bound to a mode, like `127.0.0.1:22667=harvest-only`. The
+    /// address may be a host name, like `localhost:22666`, or IPv6, like `[::1]:22666`. Can be
+    /// given multiple times, e.g. for the inbound and the submission MTA.
+    #[arg(short, long, default_value = "127.0.0.1:22666", value_parser = parse_listen)]
+    listen: Vec<Listen>,
+
+    /// Bind `all` addresses a `--listen` host name resolves to, or only the `first`.
+    #[arg(long, default_value = "all", value_parser = parse_listen_resolve)]
+    listen_resolve: ListenResolve,
 
     #[arg(short, long)]
     certificate_directory: PathBuf,
 
+    /// Address to encrypt mail from and harvest certificates sent to, or a pattern: `*` is a
+    /// wildcard, like in `noreply-*@example.com`, `@example.com` stands for every address at
+    /// the domain, and `/^sales-.*@corp\.example$/` is a regular expression.
     #[arg(short, long, num_args(0..))]
     address: Vec<String>,
+
+    /// File with one address, wildcard pattern or regular expression per line, like
+    /// `*@example.com`.
+    #[arg(long)]
+    address_file: Option<PathBuf>,
+
+    /// Check the address file and its includes for changes every this many seconds, and
+    /// reload the addresses when they do, 0 disables it.
+    #[arg(long, default_value_t = 10)]
+    address_file_check_interval: u64,
+
+    /// Processing done by this instance: `both`, `encrypt-only` or `harvest-only`, for
+    /// dedicated instances sharing a certificate store.
+    #[arg(long, default_value = "both", value_parser = settings::parse_mode)]
+    mode: settings::Mode,
+
+    /// Ask the MTA to leave out a milter protocol step pantosmime ignores: `connect`, `helo`,
+    /// `data` or `unknown`. Can be given multiple times.
+    #[arg(long, value_parser = settings::parse_milter_step)]
+    milter_skip_step: Vec<settings::MilterStep>,
+
+    /// Accept messages to harvest from right after their headers unless they are multipart,
+    /// so the MTA doesn't transfer bodies that can't carry a signature.
+    #[arg(long)]
+    skip_unsigned_bodies: bool,
+
+    /// Clients to consider internal, by network like `10.0.0.0/8` or by verified host name
+    /// pattern like `*.corp.example.com`, told by the MTA with the `{client_addr}` and
+    /// `{client_name}` macros. Can be given multiple times.
+    #[arg(long = "internal-network", value_parser = network::parse_internal_network)]
+    internal_networks: Vec<network::InternalNetwork>,
+
+    /// Leave mail from clients outside the internal networks unencrypted.
+    #[arg(long, requires = "internal_networks")]
+    encrypt_internal_only: bool,
+
+    /// Don't harvest certificates from mail of clients in the internal networks.
+    #[arg(long, requires = "internal_networks")]
+    harvest_external_only: bool,
+
+    /// Classify messages by the client in the `Received` headers the MTA added, when it
+    /// doesn't pass the `{client_addr}` and `{client_name}` macros.
+    #[arg(long, requires = "internal_networks")]
+    origin_from_received: bool,
+
+    /// Normalize local parts of addresses at a domain before matching and certificate lookups,
+    /// e.g. `gmail.com=lowercase,strip-dots` or `*=lowercase`. Can be given multiple times,
+    /// first matching domain wins.
+    #[arg(long, value_parser = address::parse_rule)]
+    address_normalization: Vec<(String, address::LocalPartRule)>,
+
+    /// Handle an envelope address as another one for matching and certificate lookups, e.g.
+    /// `old.example=new.example` or `alice@old.example=alice.smith@new.example` while users
+    /// move to a new domain. Can be given multiple times, first match wins.
+    #[arg(long = "address-rewrite", value_parser = address::parse_rewrite)]
+    address_rewrites: Vec<(String, String)>,
+
+    /// Handle bare local usernames, like from sendmail command line submissions, and address
+    /// literals like `user@[192.0.2.1]` as addresses at this domain. Without it, messages from
+    /// or to them are accepted unchanged.
+    #[arg(long)]
+    local_domain: Option<String>,
+
+    /// Use another certificate directory for the addresses matching a pattern,
+    /// e.g. `*@tenant.example=/srv/certs/tenant`. Can be given multiple times, first match wins.
+    #[arg(long = "certificate-directory-override", value_parser = parse_cert_dir_override)]
+    cert_dir_overrides: Vec<(String, PathBuf)>,
+
+    /// Only harvest and enroll certificates of senders at a domain that are issued by a CA in a
+    /// PEM bundle, e.g. `partner.example=/etc/pantosmime/partner-ca.pem` or
+    /// `*=/etc/ssl/certs/ca-bundle.crt`. Can be given multiple times, first matching domain
+    /// wins. Senders at other domains are trusted on first use.
+    #[arg(long, value_parser = trust::parse_trust_anchors)]
+    trust_anchors: Vec<(String, PathBuf)>,
+
+    /// Only harvest certificates from messages with a single `From` header address that is at
+    /// the domain of the envelope sender, `same-domain`, or is the envelope sender,
+    /// `same-address`. With `ignore`, only the envelope sender counts.
+    #[arg(long, default_value = "ignore", value_parser = settings::parse_from_header_match)]
+    harvest_from_header: settings::FromHeaderMatch,
+
+    /// Only harvest certificates from messages that passed `dmarc`, `dkim` or `spf` for a
+    /// domain aligned with the sender, by the `Authentication-Results` headers of an
+    /// `--authserv-id`. Can be given multiple times, one of the methods must pass.
+    #[arg(long, requires = "authserv_ids", value_parser = authentication_results::parse_method)]
+    harvest_require_auth: Vec<authentication_results::Method>,
+
+    /// Authentication service identifier, like the host name of the MTA, whose
+    /// `Authentication-Results` headers are trusted. The MTA must remove ones with it that came
+    /// with the message. Can be given multiple times.
+    #[arg(long = "authserv-id")]
+    authserv_ids: Vec<String>,
+
+    /// Don't store chains of more certificates than this from a signature or enrollment.
+    #[arg(long, default_value_t = 10)]
+    max_chain_certs: usize,
+
+    /// Don't store chains larger than this many bytes, DER encoded, from a signature or
+    /// enrollment.
+    #[arg(long, default_value_t = 65_536)]
+    max_chain_bytes: usize,
+
+    /// Scan the `import/` subdirectory of each certificate directory for dropped certificates
+    /// every this many seconds, 0 disables it.
+    #[arg(long, default_value_t = 10)]
+    import_scan_interval: u64,
+
+    /// Content transfer encoding of encrypted messages. `binary` saves a third of the size, but
+    /// must only be used if every hop supports BINARYMIME (RFC 3030).
+    #[arg(long, default_value = "base64", value_parser = ["base64", "binary"])]
+    envelope_encoding: String,
+
+    /// Line length of base64 encoded messages, a multiple of 4 up to 76.
+    #[arg(long, default_value_t = 76, value_parser = transfer_encoding::parse_line_length)]
+    base64_line_length: usize,
+
+    /// Crypto settings for recipients at a domain, e.g. `legacy.example=cipher=3des` or
+    /// `*=aead,key-transport=rsa-oaep`, with the options `cipher=<3des|aes-128|aes-192|aes-256>`,
+    /// `aead`, `aead=auto` for recipients announcing AES-GCM, `key-transport=<rsa-pkcs1|rsa-oaep>`
+    /// and `compress`. Can be given multiple times, first matching domain wins.
+    #[arg(long = "crypto-profile", value_parser = crypto_profile::parse_profile)]
+    crypto_profiles: Vec<(String, crypto_profile::CryptoProfile)>,
+
+    /// Stages an action runs, in order, e.g.
+    /// `encrypt=build-entity,encrypt,emit-envelope,add-result`, the action being `encrypt`,
+    /// `harvest` or `enroll`. Stages left out don't run, and stages working on the result of
+    /// another one have to come after it. The last one given for an action applies.
+    #[arg(long = "pipeline", value_parser = pipeline::parse_pipeline)]
+    pipelines: Vec<(milter_callbacks::MilterAction, Vec<String>)>,
+
+    /// Workaround for picky receiving clients: `recipient-id=<issuer-serial|key-id>` to name
+    /// recipients in the envelope, `attachment-name=<NAME>` instead of `smime.p7m`,
+    /// `disposition=<attachment|inline|none>` or `legacy-content-type` for
+    /// `application/x-pkcs7-mime`. Can be given multiple times.
+    #[arg(long = "compat", value_parser = compat::parse_toggle)]
+    compat: Vec<compat::Toggle>,
+
+    /// Let senders choose the handling of a message with a recipient subaddress, e.g.
+    /// `nocrypt=plain` for `user+nocrypt@example.com`, the action being `plain` or `encrypt`.
+    /// The tag is removed before delivery. Can be given multiple times.
+    #[arg(long = "subaddress", value_parser = settings::parse_subaddress)]
+    subaddress_actions: Vec<(String, settings::SubaddressAction)>,
+
+    /// Remove this header from encrypted messages, e.g. `X-Mailer`. Can be given multiple times.
+    #[arg(long = "strip-header")]
+    strip_headers: Vec<String>,
+
+    /// Keep this address header with the message, one of `From`, `Reply-To`, `To` and `Cc`,
+    /// and copy it into the encrypted entity, so the recipient can tell it wasn't changed in
+    /// transit. Can be given multiple times.
+    #[arg(long = "capture-header", value_parser = settings::parse_address_header)]
+    capture_headers: Vec<String>,
+
+    /// Leave calendar invitations unencrypted, like delivery and read reports, so the
+    /// recipient's calendar can process them.
+    #[arg(long)]
+    exempt_calendar: bool,
+
+    /// What to do with messages already encrypted with inline PGP: `warn` and encrypt them
+    /// anyway, or `skip` to leave them as they are.
+    #[arg(long, default_value = "warn", value_parser = settings::parse_inline_pgp_action)]
+    inline_pgp_action: settings::InlinePgpAction,
+
+    /// Encrypt only this percentage of the messages to encrypt, chosen by queue ID, and mark
+    /// the others with a header instead, to roll out encryption gradually.
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u8).range(0..=100))]
+    encrypt_percent: u8,
+
+    /// Change the policy within a time window, as `<window>=<change>`: `2025-01-01` from then
+    /// on, `2025-01-01..2025-02-01`, or weekly like `daily 02:00-04:00` or `sat,sun 22:00-06:00`,
+    /// in local time unless given with an offset; `encrypt:<domain>` to require encryption to
+    /// a domain, or `tempfail` to defer messages instead of rejecting them when they fail. Can
+    /// be given multiple times.
+    #[arg(long = "schedule", value_parser = schedule::parse_scheduled)]
+    schedules: Vec<schedule::Scheduled>,
+
+    /// What to do with messages to recipients without a usable certificate: `reject`,
+    /// `tempfail` or `exclude` them, encrypting for the others.
+    #[arg(long, default_value = "reject", value_parser = settings::parse_cert_failure_action)]
+    missing_cert_action: settings::CertFailureAction,
+
+    /// What to do with messages to recipients whose certificate expired, once the grace period
+    /// is over: `reject`, `tempfail` or `exclude` them.
+    #[arg(long, default_value = "reject", value_parser = settings::parse_cert_failure_action)]
+    expired_cert_action: settings::CertFailureAction,
+
+    /// Tempfail messages to recipients whose certificate expired less than this many days ago,
+    /// giving them time to send a signed message with their renewed one.
+    #[arg(long, default_value_t = 0)]
+    expired_cert_grace_days: u32,
+
+    /// Leave messages unencrypted that TLS protects: `always` encrypts regardless,
+    /// `unprotected-submission` only encrypts messages submitted without TLS, and
+    /// `skip-verified-tls` skips messages whose recipients are all at a `--verified-tls-domain`.
+    #[arg(long, default_value = "always", value_parser = settings::parse_tls_policy)]
+    tls_policy: settings::TlsPolicy,
+
+    /// Domain we deliver to with verified TLS only, e.g. `partner.example` or `*.example`, for
+    /// `--tls-policy skip-verified-tls`. Can be given multiple times.
+    #[arg(long = "verified-tls-domain", value_parser = settings::parse_domain_pattern)]
+    verified_tls_domains: Vec<String>,
+
+    /// Service address importing the certificates of certs-only messages sent to it, e.g.
+    /// `smime-keys@example.com`. Can be given multiple times.
+    #[arg(long = "enrollment-address")]
+    enrollment_addresses: Vec<String>,
+
+    /// Confirm imported certificates with a reply to the sender, submitted to `--smtp-server`.
+    #[arg(long)]
+    enrollment_reply: bool,
+
+    /// Ask recipients without a certificate to reply with a signed message, in a request from
+    /// this address submitted to `--smtp-server`.
+    #[arg(long)]
+    key_request_from: Option<String>,
+
+    /// Text of the requests, with `{sender}` and `{recipient}` placeholders and optionally a
+    /// `Subject:` first line.
+    #[arg(long, requires = "key_request_from")]
+    key_request_template: Option<PathBuf>,
+
+    /// Ask each recipient at most once within this many days.
+    #[arg(long, default_value_t = 30)]
+    key_request_interval_days: u32,
+
+    /// Sign the notifications and replies sent with this PEM certificate, followed by its
+    /// chain.
+    #[arg(long, requires = "gateway_key")]
+    gateway_certificate: Option<PathBuf>,
+
+    /// PEM key of `--gateway-certificate`.
+    #[arg(long, requires = "gateway_certificate")]
+    gateway_key: Option<PathBuf>,
+
+    /// Encrypt the notifications and replies sent to recipients with a certificate on file.
+    #[arg(long)]
+    encrypt_notifications: bool,
+
+    /// Attach the chain of `--gateway-certificate` as a certs-only part to the signed
+    /// notifications and replies, for gateways harvesting certificates from mail.
+    #[arg(long, requires = "gateway_certificate")]
+    publish_gateway_certificate: bool,
+
+    /// Directory with templates replacing the texts of notifications and replies, per
+    /// recipient domain and language.
+    #[arg(long)]
+    template_dir: Option<PathBuf>,
+
+    /// Language of the templates for recipients in matching domains, as
+    /// `<DOMAIN>=<LANGUAGE>`, e.g. `*.de=de`. The first match wins.
+    #[arg(long = "template-language", value_parser = templates::parse_language)]
+    template_languages: Vec<(String, String)>,
+
+    /// Most headers accepted per message.
+    #[arg(long, default_value_t = 10_000)]
+    max_headers: usize,
+
+    /// Largest total size of the header names and values of a message, in bytes.
+    #[arg(long, default_value_t = 1024 * 1024)]
+    max_header_bytes: usize,
+
+    /// What to do with messages exceeding the header limits: `tempfail`, or `pass-through` to
+    /// leave them alone unless they are to be encrypted.
+    #[arg(long, default_value = "tempfail", value_parser = settings::parse_header_overflow_action)]
+    header_overflow_action: settings::HeaderOverflowAction,
+
+    /// Undo an artifact the MTA leaves in message bodies before encrypting them:
+    /// `unescape-from` for mbox escaped `>From ` lines or `unstuff-dots` for leftover SMTP
+    /// dot-stuffing. Can be given multiple times.
+    #[arg(long = "normalize-body", value_parser = body_normalization::parse_normalization)]
+    body_normalizations: Vec<body_normalization::Normalization>,
+
+    /// Save messages rejected because processing failed to this directory, with their envelope
+    /// and error in a JSON file next to them, to replay them once the problem is fixed.
+    #[arg(long)]
+    dead_letter_dir: Option<PathBuf>,
+
+    /// Keep certificate chains harvested while their certificate directory is unavailable in
+    /// this directory and store them once it is back, rather than deferring the signed message.
+    #[arg(long)]
+    harvest_spool_dir: Option<PathBuf>,
+
+    /// Reject messages larger than this many bytes, as announced with the ESMTP SIZE parameter
+    /// or once the body exceeds it.
+    #[arg(long)]
+    max_message_size: Option<u64>,
+
+    /// What to do with messages exceeding `--max-message-size` once encrypted: `reject`,
+    /// `compress` or `proceed`.
+    #[arg(long, default_value = "reject", value_parser = settings::parse_oversize_action)]
+    oversize_action: settings::OversizeAction,
+
+    /// Reject messages to encrypt to more than this many recipients, as each one grows the
+    /// message and some clients fail to open messages with many.
+    #[arg(long)]
+    max_cms_recipients: Option<usize>,
+
+    /// Close new milter connections while this many messages are being processed, so the MTA
+    /// applies its milter default action to them.
+    #[arg(long)]
+    max_in_flight: Option<usize>,
+
+    /// Close new milter connections while the process uses this many bytes of memory.
+    #[arg(long)]
+    max_memory: Option<u64>,
+
+    /// File with a secret shared by all pantosmime hosts, to mark processed messages with an
+    /// HMAC so they are never processed twice, e.g. after reinjection from a content filter.
+    #[arg(long)]
+    reinjection_secret_file: Option<PathBuf>,
+
+    /// File with a secret shared with downstream filters, to add an `X-Pantosmime-Result`
+    /// header telling them what was done, authenticated with an HMAC.
+    #[arg(long)]
+    result_secret_file: Option<PathBuf>,
+
+    /// Standby host to copy harvested certificates to, as `<HOST>:<PORT>` of its
+    /// `--replication-listen`. Repeat for several.
+    #[arg(
+        long = "replicate-to",
+        value_name = "HOST:PORT",
+        requires = "replication_secret_file"
+    )]
+    replication_peers: Vec<String>,
+
+    /// Take certificates replicated by the active host on this address, e.g. `0.0.0.0:9467`.
+    #[arg(long, requires = "replication_secret_file")]
+    replication_listen: Option<String>,
+
+    /// File with a secret shared by the replicating hosts, to authenticate the certificates.
+    #[arg(long)]
+    replication_secret_file: Option<PathBuf>,
+
+    /// Send all certificates to the standby hosts every this many seconds, 0 disables it.
+    #[arg(long, default_value_t = 3600)]
+    replication_interval: u64,
+
+    /// Also encrypt every message to this PEM certificate, so it can be recovered with
+    /// `escrow decrypt` and the escrow key.
+    #[arg(long)]
+    escrow_certificate: Option<PathBuf>,
+
+    /// Write a JSON event report per processed message to a file, `udp://<HOST>:<PORT>` or
+    /// `tcp://<HOST>:<PORT>`.
+    #[arg(long)]
+    event_report: Option<String>,
+
+    /// Run an executable with the event report of each encrypted or harvested message as JSON
+    /// on its standard input, or POST the report to an `http://<HOST>[:<PORT>]/<PATH>` webhook.
+    /// The message itself is never passed on.
+    #[arg(long, value_parser = parse_hook)]
+    post_process_hook: Option<Arc<hook::Hook>>,
+
+    /// Serve Prometheus metrics on `http://<ADDRESS>/metrics`, e.g. `127.0.0.1:9466`.
+    #[cfg(feature = "metrics")]
+    #[arg(long)]
+    metrics_listen: Option<String>,
+
+    /// Label metrics with at most this many recipient domains, counting further ones as
+    /// `other`.
+    #[cfg(feature = "metrics")]
+    #[arg(long, default_value_t = 100)]
+    metrics_max_domains: usize,
+
+    /// Drop root privileges to this user once the sockets are bound and the keys and secrets
+    /// are read.
+    #[arg(long)]
+    user: Option<String>,
+
+    /// Group to drop root privileges to, the primary group of `--user` by default.
+    #[arg(long, requires = "user")]
+    group: Option<String>,
+
+    /// Confine the process to the certificate directories, the files named on the command line
+    /// and what the C library needs, with Landlock, or by chrooting into the directory holding
+    /// all files used while running where Landlock is unavailable.
+    #[arg(long)]
+    sandbox: bool,
+
+    /// Close milter connections after this many seconds without activity.
+    #[arg(long, default_value_t = 7210, value_parser = clap::value_parser!(u64).range(1..))]
+    idle_timeout: u64,
+
+    /// Remember the policy decision and the recipient certificates of an envelope for this many
+    /// seconds, so bulk mailings to the same recipients skip them. Off with 0.
+    #[arg(long, default_value_t = 0)]
+    decision_cache_ttl: u64,
+
+    /// Threads handling milter connections, by default one per CPU core.
+    #[arg(long)]
+    worker_threads: Option<NonZeroUsize>,
+
+    /// Largest number of threads for blocking work, like file access and OpenSSL.
+    #[arg(long, default_value_t = NonZeroUsize::new(512).unwrap())]
+    max_blocking_threads: NonZeroUsize,
+
+    /// Largest number of messages encrypted at once, by default as many as there are blocking
+    /// threads.
+    #[arg(long)]
+    crypto_jobs: Option<NonZeroUsize>,
+
+    /// Lua script deciding what to do with each message.
+    #[cfg(feature = "lua")]
+    #[arg(long)]
+    policy_script: Option<PathBuf>,
+
+    #[command(flatten)]
+    expiry: expiry::ExpiryArgs,
+
+    #[cfg(feature = "ldap")]
+    #[command(flatten)]
+    ldap: ldap_sync::LdapArgs,
+
+    #[cfg(feature = "chaos")]
+    #[command(flatten)]
+    chaos: chaos::ChaosArgs,
+
+    /// Derive MIME boundaries and all OpenSSL randomness from this seed, for golden-file
+    /// tests. Makes the encryption worthless.
+    #[arg(long, hide = true)]
+    deterministic_seed: Option<u64>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[cfg(feature = "replay")]
+    /// Run a captured message from stdin through the milter in-process and print the decisions
+    /// and the resulting message. Harvested certificates are written to the certificate
+    /// directory, like the daemon would.
+    Replay {
+        /// Envelope sender (MAIL FROM).
+        #[arg(long)]
+        from: String,
+
+        /// Envelope recipients (RCPT TO).
+        #[arg(long, required = true, num_args(1..))]
+        to: Vec<String>,
+
+        /// Queue ID to report in the logs.
+        #[arg(long, default_value = "REPLAY")]
+        queue_id: String,
+    },
+
+    /// Manage the certificate directory.
+    #[command(subcommand)]
+    Cert(CertCommand),
+
+    /// Recover messages encrypted to the `--escrow-certificate`.
+    #[command(subcommand)]
+    Escrow(EscrowCommand),
+
+    /// Debug the policy with the other options given.
+    #[command(subcommand)]
+    Policy(PolicyCommand),
+
+    /// Check the options and the files they name without starting, listing every problem.
+    /// Exits with 1 if there are errors, warnings alone don't fail.
+    CheckConfig,
+
+    /// Print the compiled features, supported ciphers and backends, what is negotiated with
+    /// the MTA and the configuration schema version as JSON.
+    Capabilities,
+}
+
+#[derive(Subcommand)]
+enum CertCommand {
+    /// Import certificates from a vCard file or an Outlook CSV contact export.
+    ImportContacts {
+        /// The .vcf or .csv file.
+        file: PathBuf,
+
+        /// Replace certificates already stored for an address.
+        #[arg(long)]
+        overwrite: bool,
+    },
+
+    /// Write the certificate directory to a zstd compressed tar archive, with a manifest of hashes.
+    #[cfg(feature = "archive")]
+    Export {
+        /// Archive to write, like `backup.tar.zst`.
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+
+    /// Restore the certificate directory from an archive written by `cert export`.
+    #[cfg(feature = "archive")]
+    Import {
+        /// The archive.
+        input: PathBuf,
+
+        /// Replace files already in the certificate directory.
+        #[arg(long)]
+        overwrite: bool,
+    },
+
+    /// List the stored certificates with when they were last used for encryption and how
+    /// often, least recently used first.
+    Usage {
+        /// Only list certificates unused for at least this many days, to prune them.
+        #[arg(long)]
+        unused_days: Option<u32>,
+    },
+
+    /// Write a certificate and its chain as a certs-only message (`smime.p7c`), to attach to
+    /// mail so gateways and clients harvesting certificates learn them.
+    Publish {
+        /// PEM file with the certificate followed by its chain.
+        certificate: PathBuf,
+
+        /// File to write, like `smime.p7c`.
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+
+    /// Synchronize certificates from the LDAP directory once.
+    #[cfg(feature = "ldap")]
+    Sync,
+}
+
+#[derive(Subcommand)]
+enum EscrowCommand {
+    /// Decrypt a message with the escrow key and print its content. Who decrypted which
+    /// message and why is appended to the audit log first.
+    Decrypt {
+        /// The encrypted message, as delivered.
+        message: PathBuf,
+
+        /// PEM private key of the escrow certificate.
+        #[arg(long)]
+        key: PathBuf,
+
+        /// Why the message is decrypted, like a case or ticket number.
+        #[arg(long)]
+        reason: String,
+
+        /// Audit log to append to, `.escrow-audit.log` in the certificate directory by default.
+        #[arg(long)]
+        audit_log: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum PolicyCommand {
+    /// Print step by step which rules match an envelope, the action chosen and the
+    /// certificates it would use.
+    Explain {
+        /// Envelope sender (MAIL FROM).
+        #[arg(long)]
+        from: String,
+
+        /// Envelope recipients (RCPT TO).
+        #[arg(long, required = true, num_args(1..))]
+        to: Vec<String>,
+    },
+}
+
+/// Milter socket, with the mode of its connections if it differs from `--mode`.
+#[derive(Debug, Clone)]
+struct Listen {
+    address: String,
+    mode: Option<settings::Mode>,
+}
+
+fn parse_listen(s: &str) -> Result<Listen, String> {
+    match s.split_once('=') {
+        Some((address, mode)) if !address.is_empty() => Ok(Listen {
+            address: address.to_string(),
+            mode: Some(settings::parse_mode(mode)?),
+        }),
+        Some(_) => Err(format!("expected <ADDRESS>[=<MODE>], got {:?}", s)),
+        None => Ok(Listen {
+            address: s.to_string(),
+            mode: None,
+        }),
+    }
+}
+
+/// Which addresses of a `--listen` host name to bind.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ListenResolve {
+    All,
+    First,
+}
+
+fn parse_listen_resolve(s: &str) -> Result<ListenResolve, String> {
+    match s {
+        "all" => Ok(ListenResolve::All),
+        "first" => Ok(ListenResolve::First),
+        other => Err(format!("unknown value {:?}, expected all or first", other)),
+    }
 }
 
-#[tokio::main]
-async fn main() {
+/// Bind the addresses `address` resolves to, e.g. both `127.0.0.1` and `::1` for `localhost`.
+async fn bind(address: &str, resolve: ListenResolve) -> std::io::Result<Vec<TcpListener>> {
+    let mut addresses: Vec<SocketAddr> = Vec::new();
+    for resolved in tokio::net::lookup_host(address).await? {
+        if !addresses.contains(&resolved) {
+            addresses.push(resolved);
+        }
+    }
+    if resolve == ListenResolve::First {
+        addresses.truncate(1);
+    }
+    let mut listeners = Vec::with_capacity(addresses.len());
+    for resolved in addresses {
+        listeners.push(TcpListener::bind(resolved).await?);
+    }
+    Ok(listeners)
+}
+
+fn parse_cert_dir_override(s: &str) -> Result<(String, PathBuf), String> {
+    match s.split_once('=') {
+        Some((pattern, dir)) if !pattern.is_empty() && !dir.is_empty() => {
+            Ok((pattern.to_string(), PathBuf::from(dir)))
+        }
+        _ => Err(format!("expected <PATTERN>=<DIRECTORY>, got {:?}", s)),
+    }
+}
+
+fn parse_hook(s: &str) -> Result<Arc<hook::Hook>, String> {
+    hook::Hook::parse(s)
+        .map(Arc::new)
+        .map_err(|error| format!("{:#}", error))
+}
+
+fn main() {
     let cli = Cli::parse();
+    // The sandbox would confine the command too, leaving it little it could do.
+    if cli.sandbox
+        && matches!(
+            cli.post_process_hook.as_deref(),
+            Some(hook::Hook::Command(_))
+        )
+    {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--post-process-hook can only be a webhook with --sandbox",
+            )
+            .exit();
+    }
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime
+        .enable_all()
+        .max_blocking_threads(cli.max_blocking_threads.get());
+    if let Some(threads) = cli.worker_threads {
+        runtime.worker_threads(threads.get());
+    }
+    let notifier = systemd::Notifier::from_env().expect("cannot connect to the service manager");
+    // Landlock only confines threads created afterwards, so it must precede the runtime.
+    let landlock_abi = match cli.sandbox && cli.command.is_none() {
+        true => sandbox::landlock(&sandbox::paths(&cli)).expect("cannot set up the sandbox"),
+        false => None,
+    };
+    runtime
+        .build()
+        .expect("cannot start runtime")
+        .block_on(run(cli, landlock_abi, notifier));
+}
 
+async fn run(cli: Cli, landlock_abi: Option<i32>, notifier: Option<systemd::Notifier>) {
+    let (filter, log_level) = reload::Layer::new(log_level::configured_filter());
     tracing_subscriber::registry()
-        .with(fmt::layer())
-        .with(
-            EnvFilter::builder()
-                .with_default_directive(LevelFilter::INFO.into())
-                .from_env_lossy(),
-        )
+        .with(filter)
+        .with(fmt::layer().with_writer(std::io::stderr))
         .init();
 
-    let listener = TcpListener::bind(cli.listen.clone())
-        .await
-        .expect("cannot open milter socket");
+    #[cfg(feature = "chaos")]
+    chaos::configure(cli.chaos.clone());
+    if let Some(seed) = cli.deterministic_seed {
+        warn!("Deterministic randomness for tests enabled; encryption is worthless");
+        deterministic::enable(seed);
+    }
+
+    if let Some(Command::CheckConfig) = cli.command {
+        let findings = check_config::check(&cli);
+        for finding in &findings {
+            println!("{}: {}", finding.severity, finding.message);
+        }
+        let errors = findings
+            .iter()
+            .filter(|finding| finding.severity == check_config::Severity::Error)
+            .count();
+        println!("{} errors, {} warnings", errors, findings.len() - errors);
+        std::process::exit(match errors {
+            0 => 0,
+            _ => 1,
+        });
+    }
+
+    if let Some(abi) = landlock_abi {
+        info!(abi, "Confined with Landlock");
+    }
+    let chroot_root = match cli.sandbox && cli.command.is_none() && landlock_abi.is_none() {
+        true => Some(sandbox::chroot_root(&cli).expect("cannot set up the sandbox")),
+        false => None,
+    };
+    // Looked up ahead of a chroot hiding the user database.
+    let credentials = match (&cli.command, &cli.user) {
+        (None, Some(user)) => {
+            Some(privileges::lookup(user, cli.group.as_deref()).expect("cannot look up user"))
+        }
+        _ => None,
+    };
+
+    address::set_rules(cli.address_normalization);
+    #[cfg(feature = "metrics")]
+    metrics::set_max_domain_labels(cli.metrics_max_domains);
+
+    let mut sources = config_reload::Sources {
+        addresses: cli.address.clone(),
+        address_file: cli.address_file.clone(),
+        trust_anchors: cli.trust_anchors.clone(),
+        #[cfg(feature = "lua")]
+        policy_script: cli.policy_script.clone(),
+    };
+    let addresses = sources.responsible().expect("cannot load address file");
+    info!(count = addresses.len(), "Loaded responsible addresses");
+
+    let mut report_sink = match &cli.event_report {
+        Some(target) => Some(
+            event_report::ReportSink::open(target)
+                .await
+                .expect("cannot open event report sink"),
+        ),
+        None => None,
+    };
+    #[cfg(feature = "ldap")]
+    let mut ldap = cli.ldap.clone();
+    let gateway = Pantosmime::builder(cli.certificate_directory)
+        .responsible(addresses)
+        .mode(cli.mode)
+        .configure(|settings| {
+            settings.milter_skip_steps = cli.milter_skip_step;
+            settings.skip_unsigned_bodies = cli.skip_unsigned_bodies;
+            settings.internal_networks = cli.internal_networks;
+            settings.encrypt_internal_only = cli.encrypt_internal_only;
+            settings.harvest_external_only = cli.harvest_external_only;
+            settings.origin_from_received = cli.origin_from_received;
+            settings.harvest_from_header = cli.harvest_from_header;
+            settings.harvest_require_auth = cli.harvest_require_auth;
+            settings.authserv_ids = cli.authserv_ids;
+            settings.cert_dir_overrides = cli
+                .cert_dir_overrides
+                .into_iter()
+                .map(|(pattern, dir)| (address::normalize(&pattern), dir))
+                .collect();
+            settings
+                .trust_anchors
+                .set(sources.trust_anchors().expect("cannot load trust anchors"));
+            settings.max_chain_certs = cli.max_chain_certs;
+            settings.max_chain_bytes = cli.max_chain_bytes;
+            settings.envelope_encoding = match cli.envelope_encoding.as_str() {
+                "binary" => {
+                    warn!("Using binary transfer encoding, every hop must support BINARYMIME");
+                    transfer_encoding::EnvelopeEncoding::Binary
+                }
+                _ => transfer_encoding::EnvelopeEncoding::Base64 {
+                    line_length: cli.base64_line_length,
+                },
+            };
+            settings.compat = compat::Compatibility::from_toggles(cli.compat);
+            settings.crypto_profiles = cli.crypto_profiles;
+            for (action, names) in &cli.pipelines {
+                let stages = pipeline::Pipeline::select(action, names)
+                    .expect("cannot select pipeline stages");
+                match action {
+                    milter_callbacks::MilterAction::Encrypt => settings.encrypt_pipeline = stages,
+                    milter_callbacks::MilterAction::ExtractKeys => {
+                        settings.harvest_pipeline = stages
+                    }
+                    milter_callbacks::MilterAction::Enroll => settings.enroll_pipeline = stages,
+                }
+            }
+            settings.address_rewrites = cli.address_rewrites;
+            settings.local_domain = cli.local_domain;
+            settings.subaddress_actions = cli.subaddress_actions;
+            settings.strip_headers = cli.strip_headers;
+            settings.capture_headers = cli.capture_headers;
+            settings.exempt_calendar = cli.exempt_calendar;
+            settings.inline_pgp_action = cli.inline_pgp_action;
+            settings.encrypt_percent = cli.encrypt_percent;
+            settings.schedules = cli.schedules;
+            settings.missing_cert_action = cli.missing_cert_action;
+            settings.expired_cert_action = cli.expired_cert_action;
+            settings.expired_cert_grace_days = cli.expired_cert_grace_days;
+            settings.tls_policy = cli.tls_policy;
+            settings.verified_tls_domains = cli.verified_tls_domains;
+            settings.enrollment_addresses = cli
+                .enrollment_addresses
+                .iter()
+                .map(|address| address::normalize(address))
+                .collect();
+            settings.enrollment_reply = cli.enrollment_reply;
+            settings.smtp_server = cli.expiry.smtp_server.clone();
+            if let Some(from) = cli.key_request_from {
+                settings.key_request = Some(
+                    key_request::KeyRequest::new(
+                        from,
+                        cli.key_request_template.as_deref(),
+                        cli.key_request_interval_days,
+                    )
+                    .expect("cannot load key request template"),
+                );
+            }
+            if let (Some(cert), Some(key)) = (&cli.gateway_certificate, &cli.gateway_key) {
+                settings.gateway_identity = Some(
+                    gateway_identity::GatewayIdentity::load(cert, key)
+                        .expect("cannot load gateway identity"),
+                );
+            }
+            settings.encrypt_notifications = cli.encrypt_notifications;
+            settings.publish_gateway_certificate = cli.publish_gateway_certificate;
+            settings.templates = templates::Templates {
+                dir: cli.template_dir,
+                languages: cli.template_languages,
+            };
+            settings.max_message_size = cli.max_message_size;
+            settings.oversize_action = cli.oversize_action;
+            settings.max_cms_recipients = cli.max_cms_recipients;
+            settings.max_headers = cli.max_headers;
+            settings.max_header_bytes = cli.max_header_bytes;
+            settings.header_overflow_action = cli.header_overflow_action;
+            settings.body_normalizations = cli.body_normalizations;
+            settings.dead_letter_dir = cli.dead_letter_dir;
+            settings.harvest_spool_dir = cli.harvest_spool_dir;
+            let decision_cache_ttl = Duration::from_secs(cli.decision_cache_ttl);
+            settings.recipient_certs = decision_cache::DecisionCache::new(decision_cache_ttl);
+            settings.crypto_jobs = cli
+                .crypto_jobs
+                .map(|jobs| tokio::sync::Semaphore::new(jobs.get()));
+            if let Some(path) = &cli.reinjection_secret_file {
+                settings.reinjection_secret =
+                    Some(reinjection::load_secret(path).expect("cannot load reinjection secret"));
+            }
+            if let Some(path) = &cli.result_secret_file {
+                settings.result_secret =
+                    Some(reinjection::load_secret(path).expect("cannot load result secret"));
+            }
+            if let Some(path) = &cli.escrow_certificate {
+                settings.escrow_certificate =
+                    Some(escrow::load_certificate(path).expect("cannot load escrow certificate"));
+            }
+            if let Some(path) = &cli.replication_secret_file {
+                settings.replication = Some(replication::Replication {
+                    peers: cli.replication_peers.clone(),
+                    secret: reinjection::load_secret(path).expect("cannot load replication secret"),
+                });
+            }
+            settings.post_process_hook = cli.post_process_hook.clone();
+            #[cfg(feature = "lua")]
+            if cli.policy_script.is_some() {
+                settings
+                    .policy_script
+                    .set(sources.policy_script().expect("cannot load policy script"));
+                settings.policy_decisions = decision_cache::DecisionCache::new(decision_cache_ttl);
+            }
+            if let Some(root) = &chroot_root {
+                let mut paths = vec![&mut settings.cert_dir];
+                paths.extend(settings.cert_dir_overrides.iter_mut().map(|(_, dir)| dir));
+                paths.extend(settings.dead_letter_dir.as_mut());
+                paths.extend(settings.harvest_spool_dir.as_mut());
+                paths.extend(settings.templates.dir.as_mut());
+                if let Some(event_report::ReportSink::File(path)) = &mut report_sink {
+                    paths.push(path);
+                }
+                paths.extend(sources.address_file.as_mut());
+                paths.extend(sources.trust_anchors.iter_mut().map(|(_, path)| path));
+                #[cfg(feature = "lua")]
+                paths.extend(sources.policy_script.as_mut());
+                #[cfg(feature = "ldap")]
+                paths.extend(ldap.ldap_bind_password_file.as_mut());
+                sandbox::chroot(root, paths).expect("cannot chroot");
+                info!(?root, "Confined with chroot, Landlock is unavailable");
+            }
+            settings.report_sink = report_sink.map(event_report::ReportSink::spawn);
+        })
+        .build();
+    let settings = gateway.settings().clone();
+
+    match cli.command {
+        #[cfg(feature = "replay")]
+        Some(Command::Replay { from, to, queue_id }) => {
+            let result = replay::run(Arc::clone(&settings), &queue_id, &from, &to).await;
+            if let Some(sink) = &settings.report_sink {
+                sink.flush().await;
+            }
+            if let Err(error) = result {
+                eprintln!("replay failed: {:?}", error);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Cert(CertCommand::ImportContacts { file, overwrite })) => {
+            match cert_command::import_contacts(&settings.cert_dir, &file, overwrite).await {
+                Ok(summary) => println!(
+                    "Imported {} certificates, skipped {} already stored and {} not matching the contact's address",
+                    summary.imported, summary.skipped_existing, summary.skipped_mismatch
+                ),
+                Err(error) => {
+                    eprintln!("import failed: {:?}", error);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        #[cfg(feature = "archive")]
+        Some(Command::Cert(CertCommand::Export { output })) => {
+            match store_archive::export_store(&settings.cert_dir, &output) {
+                Ok(count) => println!("Exported {} certificates to {:?}", count, output),
+                Err(error) => {
+                    eprintln!("export failed: {:?}", error);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        #[cfg(feature = "archive")]
+        Some(Command::Cert(CertCommand::Import { input, overwrite })) => {
+            match store_archive::import_store(&settings.cert_dir, &input, overwrite) {
+                Ok(summary) => println!(
+                    "Restored {} files, skipped {} already existing",
+                    summary.restored, summary.skipped_existing
+                ),
+                Err(error) => {
+                    eprintln!("import failed: {:?}", error);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(Command::Cert(CertCommand::Usage { unused_days })) => {
+            match cert_usage::list(&settings.cert_dir, unused_days, cert_usage::now()) {
+                Ok(list) => {
+                    for (name, usage) in list {
+                        let last_used = match usage.last_used {
+                            0 => "never".to_string(),
+                            secs => event_report::rfc3339(
+                                std::time::UNIX_EPOCH + Duration::from_secs(secs),
+                            ),
+                        };
+                        println!("{}\t{}\t{}", name, last_used, usage.count);
+                    }
+                }
+                Err(error) => {
+                    eprintln!("usage failed: {:?}", error);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(Command::Cert(CertCommand::Publish {
+            certificate,
+            output,
+        })) => {
+            match cert_command::publish(&certificate, &output).await {
+                Ok(count) => println!("Wrote {} certificates to {:?}", count, output),
+                Err(error) => {
+                    eprintln!("publish failed: {:?}", error);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        #[cfg(feature = "ldap")]
+        Some(Command::Cert(CertCommand::Sync)) => {
+            match ldap_sync::sync(&cli.ldap, &settings.cert_dir).await {
+                Ok(summary) => println!(
+                    "Added {} certificates, updated {}, removed {}, {} unchanged, {} skipped",
+                    summary.added,
+                    summary.updated,
+                    summary.removed,
+                    summary.unchanged,
+                    summary.skipped
+                ),
+                Err(error) => {
+                    eprintln!("sync failed: {:?}", error);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(Command::CheckConfig) => unreachable!("checked before loading the configuration"),
+        Some(Command::Capabilities) => {
+            let report = serde_json::to_string_pretty(&capabilities::report())
+                .expect("cannot serialize capabilities");
+            println!("{}", report);
+            return;
+        }
+        Some(Command::Policy(PolicyCommand::Explain { from, to })) => {
+            for line in explain::explain(&settings, &from, &to).await {
+                println!("{}", line);
+            }
+            return;
+        }
+        Some(Command::Escrow(EscrowCommand::Decrypt {
+            message,
+            key,
+            reason,
+            audit_log,
+        })) => {
+            let result = (|| {
+                let cert = settings
+                    .escrow_certificate
+                    .as_ref()
+                    .context("--escrow-certificate is required")?;
+                let key = std::fs::read(&key)
+                    .with_context(|| format!("Failed to read escrow key {:?}", key))?;
+                let key = openssl::pkey::PKey::private_key_from_pem(&key)
+                    .context("Failed to parse escrow key")?;
+                let audit_log =
+                    audit_log.unwrap_or_else(|| settings.cert_dir.join(escrow::AUDIT_LOG));
+                let content = escrow::decrypt(&message, cert, &key, &reason, &audit_log)?;
+                std::io::stdout().write_all(&content)?;
+                anyhow::Ok(())
+            })();
+            if let Err(error) = result {
+                eprintln!("escrow decryption failed: {:?}", error);
+                std::process::exit(1);
+            }
+            return;
+        }
+        None => {}
+    }
+
+    let mut listeners = Vec::new();
+    for listen in &cli.listen {
+        let mode = listen.mode.unwrap_or(settings.mode);
+        for listener in bind(&listen.address, cli.listen_resolve)
+            .await
+            .expect("cannot open milter socket")
+        {
+            let local_addr = listener.local_addr().expect("cannot get socket address");
+            info!(address = listen.address, %local_addr, ?mode, "Started listening");
+            listeners.push((listener, mode));
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    let metrics_listener = match &cli.metrics_listen {
+        Some(listen) => {
+            let listener = TcpListener::bind(listen)
+                .await
+                .expect("cannot open metrics socket");
+            info!(listen, "Serving metrics");
+            Some(listener)
+        }
+        None => None,
+    };
+    let replication_listener = match &cli.replication_listen {
+        Some(listen) => {
+            let listener = TcpListener::bind(listen)
+                .await
+                .expect("cannot open replication socket");
+            info!(listen, "Taking replicated certificates");
+            Some(listener)
+        }
+        None => None,
+    };
 
-    info!(cli.listen, "Started listening");
+    // Everything needing root is done: the sockets are bound, keys and secrets are read.
+    if let (Some(user), Some(credentials)) = (&cli.user, credentials) {
+        privileges::drop_to(credentials).expect("cannot drop privileges");
+        for dir in settings
+            .all_cert_dirs()
+            .into_iter()
+            .chain(settings.dead_letter_dir.as_deref())
+            .chain(settings.harvest_spool_dir.as_deref())
+        {
+            privileges::check_access(dir).expect("cannot access directory as unprivileged user");
+        }
+        info!(
+            user,
+            uid = credentials.uid,
+            gid = credentials.gid,
+            "Dropped privileges"
+        );
+    } else if privileges::is_root() {
+        warn!("Running as root, use --user to drop privileges after binding the sockets");
+    }
 
-    // TODO: drop privileges, only keep r/w to certificate directory
+    #[cfg(feature = "metrics")]
+    if let Some(listener) = metrics_listener {
+        let settings = settings.clone();
+        tokio::spawn(async move {
+            if let Err(error) = metrics::serve(listener, settings).await {
+                error!(?error, "Metrics endpoint failed");
+            }
+        });
+    }
+    if let Some(listener) = replication_listener {
+        let settings = settings.clone();
+        tokio::spawn(async move {
+            if let Err(error) = replication::serve(listener, settings).await {
+                error!(?error, "Replication endpoint failed");
+            }
+        });
+    }
+    if !cli.replication_peers.is_empty() && cli.replication_interval > 0 {
+        tokio::spawn(replication::run_periodically(
+            settings.clone(),
+            Duration::from_secs(cli.replication_interval),
+        ));
+    }
+    if cli.import_scan_interval > 0 {
+        tokio::spawn(import_dir::run_periodically(
+            settings.clone(),
+            Duration::from_secs(cli.import_scan_interval),
+        ));
+    }
+    if let Some(dir) = &settings.harvest_spool_dir {
+        tokio::spawn(harvest_spool::run_periodically(
+            settings.clone(),
+            dir.clone(),
+        ));
+    }
+    tokio::spawn(log_level::toggle_on_signal(log_level));
+    if cli.address_file_check_interval > 0 {
+        tokio::spawn(config_reload::reload_on_change(
+            settings.clone(),
+            sources.clone(),
+            Duration::from_secs(cli.address_file_check_interval),
+        ));
+    }
+    tokio::spawn(config_reload::reload_on_signal(settings.clone(), sources));
+    tokio::spawn(cert_usage::run_periodically(
+        settings.clone(),
+        Duration::from_secs(60),
+    ));
+    if cli.expiry.enabled() {
+        tokio::spawn(expiry::run_periodically(
+            cli.expiry.clone(),
+            settings.clone(),
+        ));
+    }
+    #[cfg(feature = "ldap")]
+    if ldap.ldap_url.is_some() && ldap.ldap_sync_interval > 0 {
+        tokio::spawn(ldap_sync::run_periodically(ldap, settings.cert_dir.clone()));
+    }
 
-    let callbacks =
-        milter_callbacks::assemble_callbacks(cli.certificate_directory, Arc::new(cli.address));
-    let config = Default::default();
+    let config = indymilter::Config {
+        connection_timeout: Duration::from_secs(cli.idle_timeout),
+        ..Default::default()
+    };
 
-    indymilter::run(listener, callbacks, config, signal::ctrl_c())
-        .await
-        .expect("milter execution failed");
+    let watermarks = backpressure::Watermarks {
+        max_in_flight: cli.max_in_flight,
+        max_memory: cli.max_memory,
+    };
+    let mut servers = tokio::task::JoinSet::new();
+    for (listener, mode) in listeners {
+        let callbacks = milter_callbacks::assemble_callbacks_with_mode(settings.clone(), mode);
+        let listener = backpressure::BackpressureListener::new(listener, watermarks);
+        servers.spawn(indymilter::run(
+            listener,
+            callbacks,
+            config.clone(),
+            signal::ctrl_c(),
+        ));
+    }
+    // The watchdog is pinged from here, so it notices this task being stuck as well.
+    let watchdog = notifier
+        .as_ref()
+        .and_then(systemd::Notifier::watchdog_interval);
+    let mut pings = tokio::time::interval(watchdog.unwrap_or(Duration::from_secs(60)));
+    if let Some(notifier) = &notifier {
+        notifier.notify("READY=1");
+    }
+    loop {
+        tokio::select! {
+            result = servers.join_next() => match result {
+                Some(result) => result
+                    .expect("milter listener panicked")
+                    .expect("milter execution failed"),
+                None => break,
+            },
+            _ = pings.tick(), if watchdog.is_some() => {
+                if let Some(notifier) = &notifier {
+                    notifier.notify("WATCHDOG=1");
+                }
+            }
+        }
+    }
+    if let Some(notifier) = &notifier {
+        notifier.notify("STOPPING=1");
+    }
+    if let Err(error) = settings.cert_usage.flush() {
+        error!(?error, "Updating certificate usage failed");
+    }
 }
@@ -0,0 +1,269 @@
+//! Prometheus metrics of the certificate store, served over HTTP. Builds without the `metrics`
+//! feature count nothing, see `metrics_noop.rs`.
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram, register_int_counter, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, Encoder, Histogram, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+    TextEncoder,
+};
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tracing::{debug, warn};
+
+use crate::cert_usage;
+use crate::settings::Settings;
+
+lazy_static! {
+    pub static ref CERT_CACHE_HITS: IntCounter = register_int_counter!(
+        "pantosmime_cert_cache_hits_total",
+        "Certificate lookups answered from the cache"
+    )
+    .unwrap();
+    pub static ref CERT_CACHE_MISSES: IntCounter = register_int_counter!(
+        "pantosmime_cert_cache_misses_total",
+        "Certificate lookups that had to read the store"
+    )
+    .unwrap();
+    pub static ref CERT_LOOKUP_SECONDS: Histogram = register_histogram!(
+        "pantosmime_cert_lookup_duration_seconds",
+        "Duration of certificate lookups",
+        vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0]
+    )
+    .unwrap();
+    pub static ref CERT_NEGATIVE_LOOKUPS: IntCounterVec = register_int_counter_vec!(
+        "pantosmime_cert_negative_lookups_total",
+        "Certificate lookups finding no certificate, by recipient domain",
+        &["domain"]
+    )
+    .unwrap();
+    static ref ENCRYPTION_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "pantosmime_encryption_failures_total",
+        "Recipients a message could not be encrypted for, by recipient domain and reason",
+        &["domain", "reason"]
+    )
+    .unwrap();
+    static ref ENCRYPTION_FALLBACKS: IntCounterVec = register_int_counter_vec!(
+        "pantosmime_encryption_fallbacks_total",
+        "Recipients of messages from responsible senders left unencrypted, by recipient domain and reason",
+        &["domain", "reason"]
+    )
+    .unwrap();
+    /// Domains used as label values so far.
+    static ref DOMAIN_LABELS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    pub static ref CERT_USES: IntCounter = register_int_counter!(
+        "pantosmime_cert_uses_total",
+        "Recipient certificates messages were encrypted for"
+    )
+    .unwrap();
+    pub static ref MESSAGES_IN_FLIGHT: IntGauge = register_int_gauge!(
+        "pantosmime_messages_in_flight",
+        "Messages currently being processed"
+    )
+    .unwrap();
+    pub static ref HARVEST_SPOOL_DEPTH: IntGauge = register_int_gauge!(
+        "pantosmime_harvest_spool_depth",
+        "Harvested certificate chains in the spool, waiting for their certificate directory"
+    )
+    .unwrap();
+    pub static ref CONNECTIONS_SHED: IntCounter = register_int_counter!(
+        "pantosmime_connections_shed_total",
+        "Milter connections closed right away while overloaded"
+    )
+    .unwrap();
+    static ref STORE_CERTIFICATES: IntGaugeVec = register_int_gauge_vec!(
+        "pantosmime_cert_store_certificates",
+        "Number of certificate files in the certificate directory",
+        &["cert_dir"]
+    )
+    .unwrap();
+    static ref STORE_USED_CERTIFICATES: IntGaugeVec = register_int_gauge_vec!(
+        "pantosmime_cert_store_used_certificates",
+        "Number of certificates in the certificate directory used within the last 30 days",
+        &["cert_dir"]
+    )
+    .unwrap();
+    static ref STORE_UNAVAILABLE: IntGaugeVec = register_int_gauge_vec!(
+        "pantosmime_cert_store_unavailable",
+        "Whether the certificate directory is unavailable, deferring messages to encrypt",
+        &["cert_dir"]
+    )
+    .unwrap();
+    static ref STORE_BYTES: IntGaugeVec = register_int_gauge_vec!(
+        "pantosmime_cert_store_bytes",
+        "Size of the certificate files in the certificate directory",
+        &["cert_dir"]
+    )
+    .unwrap();
+}
+
+/// Label value for domains beyond the limit.
+const OTHER_DOMAINS: &str = "other";
+
+/// Most distinct domains used as label values.
+static MAX_DOMAIN_LABELS: AtomicUsize = AtomicUsize::new(100);
+
+/// Limit the distinct domains used as label values, so mail to ever new domains can't make
+/// the number of series grow without bounds.
+pub fn set_max_domain_labels(max: usize) {
+    MAX_DOMAIN_LABELS.store(max, Ordering::Relaxed);
+}
+
+/// The label of a domain, `other` once `max` other domains are labeled.
+fn label_for(labels: &mut HashSet<String>, max: usize, domain: &str) -> String {
+    let domain = domain.to_ascii_lowercase();
+    if labels.contains(&domain) || (labels.len() < max && labels.insert(domain.clone())) {
+        domain
+    } else {
+        OTHER_DOMAINS.to_string()
+    }
+}
+
+/// The domain label for `email`.
+fn domain_label(email: &str) -> String {
+    let domain = email.rsplit_once('@').map_or("", |(_, d)| d);
+    let max = MAX_DOMAIN_LABELS.load(Ordering::Relaxed);
+    label_for(&mut DOMAIN_LABELS.lock().unwrap(), max, domain)
+}
+
+/// Count a lookup that found no certificate for `email`.
+pub fn negative_lookup(email: &str) {
+    CERT_NEGATIVE_LOOKUPS
+        .with_label_values(&[&domain_label(email)])
+        .inc();
+}
+
+/// Count a recipient a message could not be encrypted for, e.g. for a `missing` certificate.
+pub fn encryption_failure(email: &str, reason: &str) {
+    ENCRYPTION_FAILURES
+        .with_label_values(&[&domain_label(email), reason])
+        .inc();
+}
+
+/// Count a recipient a message of a responsible sender is left unencrypted for, e.g. as the
+/// `tls-policy` deems the connection to its domain good enough.
+pub fn encryption_fallback(email: &str, reason: &str) {
+    ENCRYPTION_FALLBACKS
+        .with_label_values(&[&domain_label(email), reason])
+        .inc();
+}
+
+/// Record whether `cert_dir` is available.
+pub fn store_available(cert_dir: &Path, available: bool) {
+    STORE_UNAVAILABLE
+        .with_label_values(&[&cert_dir.to_string_lossy()])
+        .set(i64::from(!available));
+}
+
+async fn update_store_size(cert_dir: &Path) -> Result<()> {
+    let (mut count, mut bytes) = (0, 0);
+    let mut entries = tokio::fs::read_dir(cert_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_name().to_string_lossy().ends_with(".pem") {
+            count += 1;
+            bytes += entry.metadata().await?.len() as i64;
+        }
+    }
+    let now = cert_usage::now();
+    let used = cert_usage::list(cert_dir, None, now)?
+        .iter()
+        .filter(|(_, usage)| now.saturating_sub(usage.last_used) < 30 * 86400)
+        .count();
+    let label = cert_dir.to_string_lossy();
+    STORE_CERTIFICATES.with_label_values(&[&label]).set(count);
+    STORE_USED_CERTIFICATES
+        .with_label_values(&[&label])
+        .set(used as i64);
+    STORE_BYTES.with_label_values(&[&label]).set(bytes);
+    Ok(())
+}
+
+/// Render all metrics in the Prometheus text format, measuring the store first. An
+/// unavailable store keeps its last measurements, so its alert still gets scraped.
+pub async fn render(settings: &Settings) -> Result<String> {
+    for cert_dir in settings.all_cert_dirs() {
+        if let Err(error) = update_store_size(cert_dir).await {
+            warn!(?cert_dir, ?error, "Failed to measure certificate directory");
+        }
+    }
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
+
+/// Serve the metrics on `/metrics` over plain HTTP.
+pub async fn serve(listener: TcpListener, settings: Arc<Settings>) -> Result<()> {
+    loop {
+        let (mut stream, peer) = listener.accept().await?;
+        let settings = settings.clone();
+        tokio::spawn(async move {
+            let mut request = [0; 1024];
+            let len = stream.read(&mut request).await.unwrap_or_default();
+            let request = String::from_utf8_lossy(&request[..len]);
+            let response = match request.split_whitespace().nth(1) {
+                Some("/metrics") => match render(&settings).await {
+                    Ok(body) => format!(
+                        "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+                        body.len(),
+                        body
+                    ),
+                    Err(error) => {
+                        debug!(?error, "Failed to render metrics");
+                        "HTTP/1.0 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n".into()
+                    }
+                },
+                _ => "HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n".into(),
+            };
+            if let Err(error) = stream.write_all(response.as_bytes()).await {
+                debug!(?error, ?peer, "Failed to send metrics");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_render() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a@example.com.pem"), "0123456789").unwrap();
+        std::fs::write(dir.path().join(".ldap-sync"), "a@example.com\n").unwrap();
+        let settings = Settings::new(dir.path().to_path_buf(), vec![]);
+        negative_lookup("nobody@Unknown.example");
+        encryption_failure("nobody@unknown.example", "missing");
+
+        let text = render(&settings).await.unwrap();
+        let label = format!("cert_dir=\"{}\"", dir.path().to_string_lossy());
+        assert!(text.contains(&format!(
+            "pantosmime_cert_store_certificates{{{}}} 1",
+            label
+        )));
+        assert!(text.contains(&format!("pantosmime_cert_store_bytes{{{}}} 10", label)));
+        assert!(text.contains(&format!(
+            "pantosmime_cert_store_used_certificates{{{}}} 0",
+            label
+        )));
+        assert!(text.contains("pantosmime_cert_negative_lookups_total{domain=\"unknown.example\"}"));
+        assert!(text.contains("pantosmime_cert_lookup_duration_seconds_bucket"));
+        assert!(text.contains(
+            "pantosmime_encryption_failures_total{domain=\"unknown.example\",reason=\"missing\"}"
+        ));
+    }
+
+    #[test]
+    fn test_label_for() {
+        let mut labels = HashSet::new();
+        assert_eq!(label_for(&mut labels, 2, "A.example"), "a.example");
+        assert_eq!(label_for(&mut labels, 2, "b.example"), "b.example");
+        assert_eq!(label_for(&mut labels, 2, "c.example"), "other");
+        assert_eq!(label_for(&mut labels, 2, "a.example"), "a.example");
+        assert_eq!(labels.len(), 2);
+    }
+}
@@ -0,0 +1,40 @@
+//! Stand-ins for the Prometheus metrics in builds without the `metrics` feature, recording
+//! nothing, so the code counting doesn't need to care.
+
+use std::path::Path;
+
+/// A metric that isn't recorded.
+pub struct Noop;
+
+/// A duration that isn't measured.
+pub struct Timer;
+
+impl Noop {
+    pub fn inc(&self) {}
+
+    pub fn dec(&self) {}
+
+    pub fn set(&self, _value: i64) {}
+
+    pub fn start_timer(&self) -> Timer {
+        Timer
+    }
+}
+
+pub static CERT_CACHE_HITS: Noop = Noop;
+pub static CERT_CACHE_MISSES: Noop = Noop;
+pub static CERT_LOOKUP_SECONDS: Noop = Noop;
+pub static CERT_USES: Noop = Noop;
+pub static MESSAGES_IN_FLIGHT: Noop = Noop;
+pub static HARVEST_SPOOL_DEPTH: Noop = Noop;
+pub static CONNECTIONS_SHED: Noop = Noop;
+
+pub fn set_max_domain_labels(_max: usize) {}
+
+pub fn negative_lookup(_email: &str) {}
+
+pub fn encryption_failure(_email: &str, _reason: &str) {}
+
+pub fn encryption_fallback(_email: &str, _reason: &str) {}
+
+pub fn store_available(_cert_dir: &Path, _available: bool) {}
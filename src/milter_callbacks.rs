@@ -1,43 +1,118 @@
-use anyhow::Result;
-use base64::{prelude::BASE64_STANDARD, Engine};
 use bytes::{Bytes, BytesMut};
 use indymilter::{
-    Actions, Callbacks, Context, ContextActions, EomActions, EomContext, IntoCString, MacroStage,
-    Macros, NegotiateContext, Status,
+    Actions, Callbacks, Context, ContextActions, EomContext, MacroStage, Macros, NegotiateContext,
+    ProtoOpts, SetErrorReply, Status,
 };
 use lazy_static::lazy_static;
 use regex::Regex;
 use std::borrow::Cow;
 use std::ffi::CString;
-use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Instant, SystemTime};
 use tracing::{debug, error, info, warn};
 
-use crate::mime_parser::MimeContainer;
-use crate::smime;
+use crate::address;
+use crate::address_list::Pattern;
+use crate::backpressure::InFlight;
+use crate::body_normalization;
+use crate::dead_letter;
+use crate::event_report::{self, MessageReport, RecipientReport};
+use crate::metrics;
+use crate::network::{self, Origin};
+use crate::pipeline::Message;
+#[cfg(feature = "lua")]
+use crate::policy_script::{Overrun, PolicyDecision, PolicyInput};
+use crate::reinjection;
+use crate::result_header;
+use crate::settings::{HeaderOverflowAction, MilterStep, Mode, Settings, SubaddressAction};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum MilterAction {
     Encrypt,
     ExtractKeys,
+    /// Import the certificates of a certs-only message to an enrollment address.
+    Enroll,
+}
+
+impl MilterAction {
+    /// Name in reports and policy scripts.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MilterAction::Encrypt => "encrypt",
+            MilterAction::ExtractKeys => "harvest",
+            MilterAction::Enroll => "enroll",
+        }
+    }
 }
 
 /// Context to carry across the steps.
 #[derive(std::default::Default)]
 pub struct MilterContext<'a> {
     action: Option<MilterAction>,
-    sender: String,
-    recipients: Vec<String>,
-    queue_id: Option<String>,
+    decided: bool,
+    /// Processing done by the listener the message came in on.
+    mode: Mode,
+    pub sender: String,
+    pub recipients: Vec<String>,
+    /// Recipients as the MTA delivers to them, to remove excluded ones.
+    pub envelope_recipients: Vec<String>,
+    pub queue_id: Option<String>,
+    /// Message-ID header, to trace harvested certificates back to their message.
+    pub message_id: Option<String>,
+    /// Message size announced with the ESMTP SIZE parameter.
+    declared_size: Option<u64>,
+    /// TLS protocol version of the submission, from the `{tls_version}` macro.
+    inbound_tls: Option<String>,
+    /// Whether the client is in an internal network, if known.
+    origin: Option<Origin>,
+    /// `Received` headers, topmost first, collected only to classify the origin without macros.
+    received: Vec<String>,
+    /// Address headers, collected only as far as they are captured.
+    pub address_headers: Vec<(String, String)>,
+    /// `Authentication-Results` headers, collected only to harvest from authenticated senders.
+    pub authentication_results: Vec<String>,
+    started: Option<Instant>,
+    /// Counts the message as in flight until the context is dropped.
+    _in_flight: Option<InFlight>,
+    pub report: MessageReport,
 
-    headers: Vec<(Cow<'a, str>, Cow<'a, str>)>,
-    body: BytesMut,
+    pub headers: Vec<(Cow<'a, str>, Cow<'a, str>)>,
+    /// Handling chosen with the subaddress of a recipient.
+    subaddress_action: Option<SubaddressAction>,
+    /// Recipients as given and without their subaddress, to restore before delivery.
+    retagged: Vec<(String, String)>,
+    /// Reinjection markers the message came with, checked against its body at the end.
+    markers: Vec<String>,
+    /// Headers received so far, and the size of their names and values.
+    header_count: usize,
+    pub header_bytes: usize,
+    /// Result headers the message came with, to replace.
+    pub result_headers: usize,
+    /// Names of the headers to strip after encryption, once per occurrence.
+    pub stripped_headers: Vec<String>,
+    /// All headers, collected only for the policy script.
+    #[cfg(feature = "lua")]
+    all_headers: Vec<(String, String)>,
+    pub body: BytesMut,
+    /// All headers as received, collected only to save failed messages.
+    pub raw_headers: Vec<u8>,
+    /// Incomplete last line of the body received so far, if it is normalized.
+    partial_line: Vec<u8>,
 }
 
-/// Extracts the email address from a sender/recipient field.
+impl MilterContext<'_> {
+    /// What is done with the message, once decided.
+    pub fn action(&self) -> Option<&MilterAction> {
+        self.action.as_ref()
+    }
+}
+
+/// Extracts the email address from a sender/recipient field, which may also be a bare local
+/// username or have an address literal as domain. The null reverse-path `<>` gives an empty
+/// address.
 pub fn extract_email(input: &str) -> Option<&str> {
     lazy_static! {
-        static ref RE: Regex = Regex::new(r#"(?i)<([^>]+)>|^([^<>\s]+@[^<>\s]+)$"#).unwrap();
+        static ref RE: Regex = Regex::new(r#"(?i)<([^>]*)>|^([^<>\s]+)$"#).unwrap();
     }
 
     let input = input.trim();
@@ -47,11 +122,91 @@ pub fn extract_email(input: &str) -> Option<&str> {
         .map(|m| m.as_str())
 }
 
+/// The address of a header with a single one, normalized and rewritten like the envelope
+/// addresses. A second `@`, be it another address or one in the display name, is ambiguous.
+pub fn header_address(settings: &Settings, value: &str) -> Option<String> {
+    extract_email(value.trim())
+        .filter(|_| value.matches('@').count() == 1)
+        .map(|email| rewrite_address(settings, address::normalize(email)))
+}
+
+/// Decide on the action from the envelope alone, if we are responsible at all.
+fn decide_action(
+    sender: &str,
+    recipients: &[String],
+    responsible: &[Pattern],
+) -> Option<MilterAction> {
+    responsible_match(sender, recipients, responsible).map(|(action, _, _)| action)
+}
+
+/// The action from the first responsible pattern matching the sender or a recipient, with the
+/// pattern and the address it matched.
+pub fn responsible_match<'a>(
+    sender: &'a str,
+    recipients: &'a [String],
+    responsible: &'a [Pattern],
+) -> Option<(MilterAction, &'a str, &'a str)> {
+    responsible.iter().find_map(|e| {
+        if e.matches(sender) {
+            Some((MilterAction::Encrypt, e.as_str(), sender))
+        } else {
+            recipients
+                .iter()
+                .find(|r| e.matches(r))
+                .map(|r| (MilterAction::ExtractKeys, e.as_str(), r.as_str()))
+        }
+    })
+}
+
+/// Largest body buffer allocated up front for the declared message size.
+const MAX_PREALLOCATION: u64 = 32 * 1024 * 1024;
+
+/// Parse the value of a `SIZE=<BYTES>` ESMTP parameter of MAIL FROM.
+fn parse_size_param(param: &str) -> Option<u64> {
+    let (name, value) = param.split_once('=')?;
+    if !name.eq_ignore_ascii_case("SIZE") {
+        return None;
+    }
+    value.parse().ok()
+}
+
+/// Reject a message for being too large, with the reply of RFC 1870.
+fn reject_size(reply: &mut impl SetErrorReply, size: u64, max: u64) -> Status {
+    info!(size, max, "Message exceeds the maximum size; rejecting");
+    if let Err(error) = reply.set_error_reply(
+        "552",
+        Some("5.3.4"),
+        ["Message size exceeds fixed maximum message size"],
+    ) {
+        error!(?error, "Failed to set reply");
+    }
+    Status::Reject
+}
+
+/// Refuse a local user or address literal as recipient of a responsible sender while a
+/// schedule requires encryption, as no certificate can be looked up without a domain.
+fn refuse_unqualified(
+    reply: &mut impl SetErrorReply,
+    settings: &Settings,
+    recipient: &str,
+) -> Status {
+    warn!(%recipient, "Encryption required for a recipient without a domain; refusing");
+    let (code, status, result) = match settings.failures_tempfail() {
+        true => ("451", "4.7.1", Status::Tempfail),
+        false => ("550", "5.7.1", Status::Reject),
+    };
+    let text = "Encryption required, but the recipient has no domain to find a certificate for";
+    if let Err(error) = reply.set_error_reply(code, Some(status), [text]) {
+        error!(?error, "Failed to set reply");
+    }
+    result
+}
+
 /// Try to get Queue ID from the macros of the current context.
-fn get_queue_id_macro<'a>(macros: &Macros) -> Option<String> {
+fn get_queue_id_macro(macros: &Macros) -> Option<String> {
     macros
         .get(c"i")
-        .and_then(|cstr| Some(cstr.to_string_lossy().into_owned()))
+        .map(|cstr| cstr.to_string_lossy().into_owned())
 }
 
 fn try_get_queue_id<'a>(macros: &Macros, context: &mut Option<MilterContext<'a>>) -> String {
@@ -68,16 +223,56 @@ fn try_get_queue_id<'a>(macros: &Macros, context: &mut Option<MilterContext<'a>>
     ctx.queue_id.clone().unwrap_or(String::from("<none>"))
 }
 
+/// Actions requested from the MTA, with their names.
+pub const REQUESTED_ACTIONS: [(&str, Actions); 5] = [
+    ("add-header", Actions::ADD_HEADER),
+    ("change-header", Actions::CHANGE_HEADER),
+    ("replace-body", Actions::REPLACE_BODY),
+    ("add-rcpt", Actions::ADD_RCPT),
+    ("delete-rcpt", Actions::DELETE_RCPT),
+];
+
 /// Negotiate the required actions for the signing/encrypting dance.
-#[tracing::instrument(skip(context))]
-async fn on_negotiate<'a>(context: &mut NegotiateContext<MilterContext<'a>>) -> Status {
+#[tracing::instrument(skip(context, settings))]
+async fn on_negotiate<'a>(
+    context: &mut NegotiateContext<MilterContext<'a>>,
+    offered: ProtoOpts,
+    settings: Arc<Settings>,
+) -> Status {
     // We need a few special actions.
-    context.requested_actions |=
-        Actions::ADD_HEADER | Actions::CHANGE_HEADER | Actions::REPLACE_BODY;
-    info!("Negotiating actions: added ADD_HEADER, CHANGE_HEADER, and REPLACE_BODY");
+    for (_, action) in REQUESTED_ACTIONS {
+        context.requested_actions |= action;
+    }
+    info!("Negotiating actions: added ADD_HEADER, CHANGE_HEADER, REPLACE_BODY, ADD_RCPT, and DELETE_RCPT");
+
+    let mut skipped = ProtoOpts::empty();
+    for step in &settings.milter_skip_steps {
+        skipped |= match step {
+            MilterStep::Connect => ProtoOpts::NO_CONNECT,
+            MilterStep::Helo => ProtoOpts::NO_HELO,
+            MilterStep::Data => ProtoOpts::NO_DATA,
+            MilterStep::Unknown => ProtoOpts::NO_UNKNOWN,
+        };
+    }
+    if !offered.contains(skipped) {
+        warn!(
+            unsupported = ?skipped.difference(offered),
+            "MTA can't leave out all of the requested protocol steps"
+        );
+    }
+    context.requested_opts |= skipped & offered;
 
     let macros = &mut context.requested_macros;
-    macros.insert(MacroStage::Mail, c"i".into());
+    // Without the connect step, its macros come along with MAIL FROM.
+    if context.requested_opts.contains(ProtoOpts::NO_CONNECT) {
+        macros.insert(
+            MacroStage::Mail,
+            c"i {tls_version} {cipher} {client_addr} {client_name}".into(),
+        );
+    } else {
+        macros.insert(MacroStage::Connect, c"{client_addr} {client_name}".into());
+        macros.insert(MacroStage::Mail, c"i {tls_version} {cipher}".into());
+    }
     macros.insert(MacroStage::Rcpt, c"i".into());
     macros.insert(MacroStage::Eoh, c"i".into());
     macros.insert(MacroStage::Data, c"i".into());
@@ -87,9 +282,15 @@ async fn on_negotiate<'a>(context: &mut NegotiateContext<MilterContext<'a>>) ->
 }
 
 /// Check if sender is in whitelist.
-#[tracing::instrument(skip(context, args), fields(queue = try_get_queue_id(&context.macros, &mut context.data)))]
-async fn on_mail<'a>(context: &mut Context<MilterContext<'a>>, args: Vec<CString>) -> Status {
-    if let Some(sender) = args.into_iter().next() {
+#[tracing::instrument(skip(context, args, settings, mode), fields(queue = try_get_queue_id(&context.macros, &mut context.data)))]
+async fn on_mail<'a>(
+    context: &mut Context<MilterContext<'a>>,
+    args: Vec<CString>,
+    settings: Arc<Settings>,
+    mode: Mode,
+) -> Status {
+    let mut args = args.into_iter();
+    if let Some(sender) = args.next() {
         let sender_email = match extract_email(&sender.to_string_lossy()) {
             Some(mail) => mail.to_string(),
             None => {
@@ -97,10 +298,51 @@ async fn on_mail<'a>(context: &mut Context<MilterContext<'a>>, args: Vec<CString
                 return Status::Reject;
             }
         };
-        debug!(%sender_email, "Sender accepted and context initialized");
+        // Reports like DSNs and MDNs come from the null reverse-path, which is never responsible
+        // but may still be harvested from when sent to a responsible recipient.
+        let sender = match sender_email.is_empty() {
+            true => String::new(),
+            false => {
+                let Some(sender_email) =
+                    address::qualify(&sender_email, settings.local_domain.as_deref())
+                else {
+                    info!(%sender_email, "Sender is a local user or address literal; accepting unchanged");
+                    return Status::Accept;
+                };
+                rewrite_address(&settings, address::normalize(&sender_email))
+            }
+        };
+        let declared_size = args.find_map(|arg| parse_size_param(&arg.to_string_lossy()));
+        if let (Some(size), Some(max)) = (declared_size, settings.max_message_size) {
+            if size > max {
+                return reject_size(&mut context.reply, size, max);
+            }
+        }
+        let macro_value = |name: &std::ffi::CStr| {
+            context
+                .macros
+                .get(name)
+                .map(|value| value.to_string_lossy().into_owned())
+        };
+        let inbound_tls = macro_value(c"{tls_version}");
+        let cipher = macro_value(c"{cipher}");
+        let client_addr = macro_value(c"{client_addr}");
+        let client_name = macro_value(c"{client_name}");
+        let origin = network::classify(
+            &settings.internal_networks,
+            client_addr.as_deref(),
+            client_name.as_deref(),
+        );
+        debug!(%sender, ?declared_size, ?inbound_tls, ?cipher, ?client_addr, ?client_name, ?origin, "Sender accepted and context initialized");
         context.data = Some(MilterContext {
-            sender: sender_email,
+            sender,
             recipients: Vec::new(),
+            mode,
+            declared_size,
+            inbound_tls,
+            origin,
+            started: Some(Instant::now()),
+            _in_flight: Some(InFlight::start()),
             ..Default::default()
         });
         Status::Continue
@@ -110,21 +352,50 @@ async fn on_mail<'a>(context: &mut Context<MilterContext<'a>>, args: Vec<CString
     }
 }
 
-/// Check if keys are available for recipient.
-/// If yes, add to recipients, otherwise reject
-#[tracing::instrument(skip(context, args), fields(queue = try_get_queue_id(&context.macros, &mut context.data)))]
-async fn on_rcpt<'a>(context: &mut Context<MilterContext<'a>>, args: Vec<CString>) -> Status {
+/// Add the recipient to the context, taking note of a subaddress choosing the handling.
+#[tracing::instrument(skip(context, args, settings), fields(queue = try_get_queue_id(&context.macros, &mut context.data)))]
+async fn on_rcpt<'a>(
+    context: &mut Context<MilterContext<'a>>,
+    args: Vec<CString>,
+    settings: Arc<Settings>,
+) -> Status {
     if let Some(recipient) = args.into_iter().next() {
         if let Some(ctx) = &mut context.data {
             let recipient_email = match extract_email(&recipient.to_string_lossy()) {
-                Some(mail) => mail.to_string(),
-                None => {
+                Some(mail) if !mail.is_empty() => mail.to_string(),
+                _ => {
                     error!(?recipient, "Could not extract recipient email");
                     return Status::Reject;
                 }
             };
+            let Some(recipient_email) =
+                address::qualify(&recipient_email, settings.local_domain.as_deref())
+            else {
+                let responsible = settings.is_responsible(&ctx.sender);
+                if responsible && settings.encryption_required(&recipient_email) {
+                    return refuse_unqualified(&mut context.reply, &settings, &recipient_email);
+                }
+                info!(%recipient_email, "Recipient is a local user or address literal; leaving it out");
+                if responsible {
+                    metrics::encryption_fallback(&recipient_email, "unqualified");
+                }
+                return Status::Continue;
+            };
+            let mut recipient_email = address::normalize(&recipient_email);
+            let mut envelope = recipient.to_string_lossy().into_owned();
+            if let Some((untagged, tag)) = address::split_tag(&recipient_email) {
+                if let Some(action) = settings.subaddress_action(tag) {
+                    info!(%recipient_email, ?action, "Recipient subaddress chooses handling");
+                    ctx.subaddress_action = Some(action);
+                    ctx.retagged.push((envelope, untagged.clone()));
+                    envelope = untagged.clone();
+                    recipient_email = untagged;
+                }
+            }
+            let recipient_email = rewrite_address(&settings, recipient_email);
             debug!(%recipient_email, "Added recipient to context");
             ctx.recipients.push(recipient_email);
+            ctx.envelope_recipients.push(envelope);
             Status::Continue
         } else {
             error!("Context data is missing in on_rcpt; rejecting message");
@@ -136,13 +407,46 @@ async fn on_rcpt<'a>(context: &mut Context<MilterContext<'a>>, args: Vec<CString
     }
 }
 
+/// Apply the first matching `--address-rewrite` to a normalized envelope address.
+pub fn rewrite_address(settings: &Settings, email: String) -> String {
+    match address::rewrite(&settings.address_rewrites, &email) {
+        Some(rewritten) => {
+            let rewritten = address::normalize(&rewritten);
+            debug!(from = %email, to = %rewritten, "Rewrote address");
+            rewritten
+        }
+        None => email,
+    }
+}
+
+/// Whether the origin of a message rules out the action, as configured.
+fn excluded_by_origin(
+    settings: &Settings,
+    action: Option<&MilterAction>,
+    origin: Option<Origin>,
+) -> bool {
+    match (action, origin) {
+        (Some(MilterAction::Encrypt), Some(Origin::External)) if settings.encrypt_internal_only => {
+            info!("Message comes from an external network; not encrypting");
+            true
+        }
+        (Some(MilterAction::ExtractKeys), Some(Origin::Internal))
+            if settings.harvest_external_only =>
+        {
+            info!("Message comes from an internal network; not harvesting");
+            true
+        }
+        _ => false,
+    }
+}
+
 /// Process headers
-#[tracing::instrument(skip(context, name, value, responsible), fields(queue = try_get_queue_id(&context.macros, &mut context.data)))]
+#[tracing::instrument(skip(context, name, value, settings), fields(queue = try_get_queue_id(&context.macros, &mut context.data)))]
 async fn on_header<'a>(
     context: &mut Context<MilterContext<'a>>,
     name: CString,
     value: CString,
-    responsible: Arc<Vec<String>>,
+    settings: Arc<Settings>,
 ) -> Status {
     let ctx = match context.data.as_mut() {
         Some(ctx) => ctx,
@@ -152,22 +456,74 @@ async fn on_header<'a>(
         }
     };
 
-    // Decide on action if not already set.
-    if ctx.action.is_none() {
-        match responsible.as_ref().iter().find_map(|e| {
-            let e = e.as_str();
-            if e.eq_ignore_ascii_case(&ctx.sender) {
-                Some(MilterAction::Encrypt)
-            } else if ctx.recipients.iter().any(|r| r.eq_ignore_ascii_case(e)) {
-                Some(MilterAction::ExtractKeys)
-            } else {
-                None
+    // Decide on action if not already done.
+    if !ctx.decided {
+        ctx.decided = true;
+        let enrollment = !ctx.recipients.is_empty()
+            && ctx
+                .recipients
+                .iter()
+                .all(|r| settings.is_enrollment_address(r));
+        let mut action = match enrollment {
+            true => Some(MilterAction::Enroll),
+            false => decide_action(&ctx.sender, &ctx.recipients, &settings.responsible.get()),
+        };
+        match ctx.subaddress_action {
+            Some(SubaddressAction::Plain)
+                if action == Some(MilterAction::Encrypt)
+                    && !ctx
+                        .recipients
+                        .iter()
+                        .any(|r| settings.encryption_required(r)) =>
+            {
+                info!("Subaddress asks for no encryption");
+                for recipient in &ctx.recipients {
+                    metrics::encryption_fallback(recipient, "subaddress");
+                }
+                action = None;
+            }
+            Some(SubaddressAction::Encrypt) => action = Some(MilterAction::Encrypt),
+            _ if action == Some(MilterAction::Encrypt) => {
+                if let Some(reason) =
+                    settings.tls_suffices(ctx.inbound_tls.as_deref(), &ctx.recipients)
+                {
+                    info!(reason, "TLS policy asks for no encryption");
+                    for recipient in &ctx.recipients {
+                        metrics::encryption_fallback(recipient, "tls-policy");
+                    }
+                    action = None;
+                }
             }
-        }) {
+            _ => {}
+        }
+        if excluded_by_origin(&settings, action.as_ref(), ctx.origin) {
+            action = None;
+        }
+        if action == Some(MilterAction::Encrypt) && ctx.recipients.is_empty() {
+            debug!("Only local users or address literals as recipients; not encrypting");
+            action = None;
+        }
+        if action
+            .as_ref()
+            .is_some_and(|action| !ctx.mode.allows(action))
+        {
+            debug!(?action, mode = ?ctx.mode, "Action is left to another instance");
+            action = None;
+        }
+        match action {
             Some(action) => {
                 info!("Need to perform {:?} on message", action);
                 ctx.action = Some(action);
             }
+            #[cfg(feature = "lua")]
+            None if settings.policy_script.get().is_some() => {
+                debug!(
+                    "Not responsible for neither sender nor recipients; deferring to policy script"
+                );
+            }
+            None if !ctx.retagged.is_empty() => {
+                debug!("No processing needed; only restoring the subaddressed recipients");
+            }
             None => {
                 debug!("Not responsible for neither sender nor recipients; no further processing");
                 return Status::Accept;
@@ -175,14 +531,80 @@ async fn on_header<'a>(
         };
     };
 
+    // Bound what a single message can make us buffer before its end.
+    ctx.header_count += 1;
+    ctx.header_bytes += name.as_bytes().len() + value.as_bytes().len();
+    if ctx.header_count > settings.max_headers || ctx.header_bytes > settings.max_header_bytes {
+        let (count, bytes) = (ctx.header_count, ctx.header_bytes);
+        let encrypt = ctx.action == Some(MilterAction::Encrypt);
+        return match settings.header_overflow_action {
+            HeaderOverflowAction::PassThrough if !encrypt => {
+                warn!(
+                    count,
+                    bytes, "Message exceeds the header limits; accepting unchanged"
+                );
+                Status::Accept
+            }
+            _ => {
+                warn!(count, bytes, "Message exceeds the header limits; deferring");
+                if let Err(error) = context.reply.set_error_reply(
+                    "451",
+                    Some("4.3.0"),
+                    ["Message header exceeds the limits of the encryption gateway"],
+                ) {
+                    error!(?error, "Failed to set reply");
+                }
+                Status::Tempfail
+            }
+        };
+    }
+
+    if settings.dead_letter_dir.is_some() {
+        ctx.raw_headers.extend_from_slice(name.to_bytes());
+        ctx.raw_headers.extend_from_slice(b": ");
+        ctx.raw_headers.extend_from_slice(value.to_bytes());
+        ctx.raw_headers.extend_from_slice(b"\r\n");
+    }
     let name_str = name.to_string_lossy();
     let value_str = value.to_string_lossy();
-    let interesting_headers = vec![
+    #[cfg(feature = "lua")]
+    if settings.policy_script.get().is_some() {
+        ctx.all_headers
+            .push((name_str.to_string(), value_str.to_string()));
+    }
+    let interesting_headers = [
         "MIME-Version",
         "Content-Type",
         "Content-Transfer-Encoding",
         "Content-Disposition",
+        "Content-ID",
     ];
+    if settings.reinjection_secret.is_some()
+        && name_str.eq_ignore_ascii_case(reinjection::MARKER_HEADER)
+    {
+        ctx.markers.push(value_str.to_string());
+    }
+    if settings.origin_from_received
+        && ctx.origin.is_none()
+        && name_str.eq_ignore_ascii_case("Received")
+    {
+        ctx.received.push(value_str.to_string());
+    }
+    if settings.captures_header(&name_str) {
+        ctx.address_headers
+            .push((name_str.to_string(), value_str.to_string()));
+    }
+    if !settings.harvest_require_auth.is_empty()
+        && name_str.eq_ignore_ascii_case("Authentication-Results")
+    {
+        ctx.authentication_results.push(value_str.to_string());
+    }
+    if name_str.eq_ignore_ascii_case(result_header::HEADER) {
+        ctx.result_headers += 1;
+    }
+    if name_str.eq_ignore_ascii_case("Message-ID") {
+        ctx.message_id = Some(value_str.trim().to_string());
+    }
     if interesting_headers
         .iter()
         .any(|h| h.eq_ignore_ascii_case(&name_str))
@@ -192,18 +614,136 @@ async fn on_header<'a>(
             Cow::Owned(value_str.to_string()),
         ));
         debug!(header = %name_str, value = %value_str, "Added custom header");
+    } else if settings
+        .strip_headers
+        .iter()
+        .any(|h| h.eq_ignore_ascii_case(&name_str))
+    {
+        ctx.stripped_headers.push(name_str.to_string());
     }
     Status::Continue
 }
 
 /// Check if Headers are complete enough to encrypt content.
-#[tracing::instrument(skip(context), fields(queue = try_get_queue_id(&context.macros, &mut context.data)))]
-async fn on_eoh<'a>(context: &mut Context<MilterContext<'a>>) -> Status {
+#[tracing::instrument(skip(context, settings), fields(queue = try_get_queue_id(&context.macros, &mut context.data)))]
+async fn on_eoh<'a>(context: &mut Context<MilterContext<'a>>, settings: Arc<Settings>) -> Status {
     if let Some(ctx) = &mut context.data {
+        if settings.origin_from_received && ctx.origin.is_none() {
+            ctx.origin = network::classify_received(&settings.internal_networks, &ctx.received);
+            debug!(origin = ?ctx.origin, "Classified message by its Received headers");
+            if excluded_by_origin(&settings, ctx.action.as_ref(), ctx.origin) {
+                ctx.action = None;
+            }
+        }
+        #[cfg(feature = "lua")]
+        if let Some(script) = settings.policy_script.get().as_ref() {
+            let cached = settings.policy_decisions.get(&ctx.sender, &ctx.recipients);
+            let decision = match cached {
+                Some(decision) => {
+                    debug!("Reusing the policy decision for the same envelope");
+                    Ok(decision)
+                }
+                None => {
+                    let input = PolicyInput {
+                        sender: &ctx.sender,
+                        recipients: &ctx.recipients,
+                        headers: &ctx.all_headers,
+                        action: ctx.action.as_ref(),
+                        origin: ctx.origin,
+                    };
+                    let decision = script
+                        .decide(&input, settings.cert_dir_for(&ctx.sender))
+                        .await;
+                    if let Ok(decision) = &decision {
+                        settings.policy_decisions.insert(
+                            &ctx.sender,
+                            &ctx.recipients,
+                            decision.clone(),
+                        );
+                    }
+                    decision
+                }
+            };
+            match decision {
+                Ok(PolicyDecision::Process(action)) if !ctx.mode.allows(&action) => {
+                    info!(
+                        mode = ?ctx.mode,
+                        "Policy script decided to perform {:?}, which is left to another instance; accepting message",
+                        action
+                    );
+                    return Status::Accept;
+                }
+                Ok(PolicyDecision::Process(action)) => {
+                    info!("Policy script decided to perform {:?} on message", action);
+                    ctx.action = Some(action);
+                }
+                Ok(PolicyDecision::Accept) => {
+                    info!("Policy script accepted message; no further processing");
+                    return Status::Accept;
+                }
+                Ok(PolicyDecision::Reject) => {
+                    info!("Policy script rejected message");
+                    return Status::Reject;
+                }
+                Ok(PolicyDecision::Tempfail) => {
+                    info!("Policy script deferred message");
+                    return Status::Tempfail;
+                }
+                Err(error) if error.is::<Overrun>() => {
+                    warn!(
+                        ?error,
+                        "Policy script ran out of its limits; deferring message"
+                    );
+                    return Status::Tempfail;
+                }
+                Err(error) => {
+                    error!(?error, "Policy script failed; rejecting message");
+                    return Status::Reject;
+                }
+            }
+        }
+        if ctx.action.is_none() {
+            // Only the recipients are restored at the end of the message, if any.
+            return match ctx.retagged.is_empty() {
+                true => Status::Accept,
+                false => Status::Continue,
+            };
+        }
         if ctx.headers.is_empty() {
             warn!("Headers are empty in on_eoh; rejecting message");
             return Status::Reject;
         }
+        if ctx.action == Some(MilterAction::ExtractKeys) && settings.skip_unsigned_bodies {
+            let multipart = ctx
+                .headers
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case("Content-Type"))
+                .is_some_and(|(_, value)| {
+                    value
+                        .trim_start()
+                        .to_ascii_lowercase()
+                        .starts_with("multipart/")
+                });
+            if !multipart {
+                info!("Message can't be signed, nothing to harvest; accepting without its body");
+                return Status::Accept;
+            }
+        }
+        if ctx.action == Some(MilterAction::Encrypt) {
+            if let Some((_, content_type)) = ctx
+                .headers
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case("Content-Type"))
+                .filter(|(_, value)| settings.is_exempt(value))
+            {
+                info!(%content_type, "Message is exempt from encryption; accepting unchanged");
+                return Status::Accept;
+            }
+        }
+        // We are going to process the message, make room for all of it at once.
+        if let Some(size) = ctx.declared_size {
+            ctx.body.reserve(size.min(MAX_PREALLOCATION) as usize);
+        }
         info!("Headers are complete");
         Status::Continue
     } else {
@@ -213,10 +753,27 @@ async fn on_eoh<'a>(context: &mut Context<MilterContext<'a>>) -> Status {
 }
 
 /// Parse body
-#[tracing::instrument(skip(context, data), fields(queue = try_get_queue_id(&context.macros, &mut context.data)))]
-async fn on_body<'a>(context: &mut Context<MilterContext<'a>>, data: Bytes) -> Status {
+#[tracing::instrument(skip(context, data, settings), fields(queue = try_get_queue_id(&context.macros, &mut context.data)))]
+async fn on_body<'a>(
+    context: &mut Context<MilterContext<'a>>,
+    data: Bytes,
+    settings: Arc<Settings>,
+) -> Status {
     if let Some(ctx) = &mut context.data {
-        ctx.body.extend_from_slice(&data);
+        if ctx.action.is_none() {
+            return Status::Continue;
+        }
+        // The declared size is only a hint, enforce the maximum on what arrives.
+        let size = (ctx.body.len() + ctx.partial_line.len() + data.len()) as u64;
+        if let Some(max) = settings.max_message_size.filter(|max| size > *max) {
+            return reject_size(&mut context.reply, size, max);
+        }
+        body_normalization::push(
+            &mut ctx.body,
+            &mut ctx.partial_line,
+            &data,
+            &settings.body_normalizations,
+        );
         debug!(body_len = %ctx.body.len(), "Accumulated body data");
         Status::Continue
     } else {
@@ -225,63 +782,66 @@ async fn on_body<'a>(context: &mut Context<MilterContext<'a>>, data: Bytes) -> S
     }
 }
 
-#[tracing::instrument(skip(ctx, actions, new_headers))]
-async fn update_headers<'a>(
-    ctx: &mut MilterContext<'a>,
-    actions: &EomActions,
-    new_headers: Vec<(Cow<'a, str>, Cow<'a, str>)>,
-) -> Result<()> {
-    for (updated_key, updated_value) in new_headers.iter() {
-        if let Some((current_key, current_value)) = ctx
-            .headers
-            .iter()
-            .find(|t| t.0.as_ref().eq_ignore_ascii_case(updated_key.as_ref()))
-        {
-            if current_value != updated_value {
-                debug!(
-                    key = %current_key,
-                    old_value = %current_value,
-                    new_value = %updated_value,
-                    "Changing header"
-                );
-                actions
-                    .change_header(
-                        current_key.into_c_string(),
-                        1,
-                        Some(updated_value.into_c_string()),
-                    )
-                    .await?;
-            }
-        } else {
-            debug!(
-                key = %updated_key,
-                value = %updated_value,
-                "Adding new header"
-            );
-            actions
-                .add_header(updated_key.into_c_string(), updated_value.into_c_string())
-                .await?;
+/// Process the message and send the event report and run the post-processing hook, if
+/// configured.
+#[tracing::instrument(skip(context, settings), fields(queue = try_get_queue_id(&context.macros, &mut context.data)))]
+async fn on_eom<'a>(
+    context: &mut EomContext<MilterContext<'a>>,
+    settings: Arc<Settings>,
+) -> Status {
+    // The span fields are not evaluated if its level is disabled, so don't rely on them.
+    try_get_queue_id(&context.macros, &mut context.data);
+    let status = process_eom(context, &settings).await;
+    let reported = settings.report_sink.is_some() || settings.post_process_hook.is_some();
+    if let Some(ctx) = context.data.as_mut().filter(|_| reported) {
+        let report = &mut ctx.report;
+        report.timestamp = event_report::rfc3339(SystemTime::now());
+        report.queue_id = ctx.queue_id.clone().unwrap_or_default();
+        report.sender = ctx.sender.clone();
+        report.origin = ctx.origin.map(|origin| origin.as_str().to_string());
+        report.decision = ctx
+            .action
+            .as_ref()
+            .map_or("none", MilterAction::as_str)
+            .to_string();
+        if report.recipients.is_empty() {
+            report.recipients = ctx
+                .recipients
+                .iter()
+                .map(|address| RecipientReport {
+                    address: address.clone(),
+                    ..Default::default()
+                })
+                .collect();
+        }
+        report.outcome = match status {
+            Status::Accept | Status::Continue => "accept",
+            Status::Reject => "reject",
+            Status::Tempfail => "tempfail",
+            Status::Discard => "discard",
+            Status::Skip => "skip",
+            Status::Noreply => "noreply",
+            Status::AllOpts => "all-opts",
+        }
+        .to_string();
+        if let Some(started) = ctx.started {
+            report.durations.total_ms = started.elapsed().as_secs_f64() * 1000.0;
+        }
+        if let Some(sink) = &settings.report_sink {
+            sink.send(report);
+        }
+        if let Some(hook) = &settings.post_process_hook {
+            hook.notify(report);
         }
     }
-    // TODO: If a key exists in current headers, but not the new ones, it should be deleted. But is
-    // this necessary?
-    Ok(())
-}
-
-fn wrap_bytes_crlf(buf: &mut BytesMut, wrap_at: usize) {
-    let line_ending = line_wrap::crlf();
-    let len = buf.len();
-    let mut additional_len = (len / wrap_at) * 2;
-    if len % wrap_at == 0 {
-        additional_len -= 2;
-    }
-    buf.resize(len + additional_len, 0);
-    line_wrap::line_wrap(buf, len, wrap_at, &line_ending);
+    status
 }
 
-/// Actually rewrite the content!
-#[tracing::instrument(skip(context, cert_dir), fields(queue = try_get_queue_id(&context.macros, &mut context.data)))]
-async fn on_eom<'a>(context: &mut EomContext<MilterContext<'a>>, cert_dir: PathBuf) -> Status {
+/// Actually rewrite the content, with the pipeline of the action.
+async fn process_eom<'a>(
+    context: &mut EomContext<MilterContext<'a>>,
+    settings: &Settings,
+) -> Status {
     let ctx = match context.data.as_mut() {
         Some(ctx) => ctx,
         None => {
@@ -290,192 +850,190 @@ async fn on_eom<'a>(context: &mut EomContext<MilterContext<'a>>, cert_dir: PathB
         }
     };
 
-    let action = match &ctx.action {
-        Some(a) => a,
-        None => {
-            error!("No action determined in on_eom; rejecting message");
-            return Status::Reject;
-        }
-    };
-
-    match action {
-        MilterAction::Encrypt => {
-            // Encrypt and encode actual body.
-            let encrypted = match smime::encrypt_data(&ctx.body, &ctx.recipients, &cert_dir).await {
-                Ok(data) => data,
-                Err(e) => {
-                    error!(error = ?e, "Failed to encrypt message body");
-                    return Status::Reject;
-                }
-            };
-            let encoded = BASE64_STANDARD.encode(&encrypted);
-            let mut wrapped = BytesMut::from(encoded.as_bytes());
-            wrap_bytes_crlf(&mut wrapped, 76);
-
-            // Reserialize and replace changed headers and body.
-            let new_headers = vec![
-                (Cow::Borrowed("MIME-Version"), Cow::Borrowed("1.0")),
-                (
-                    Cow::Borrowed("Content-Type"),
-                    Cow::Borrowed(
-                        "application/pkcs7-mime; name=smime.p7m; smime-type=enveloped-data",
-                    ),
-                ),
-                (
-                    Cow::Borrowed("Content-Transfer-Encoding"),
-                    Cow::Borrowed("base64"),
-                ),
-                (
-                    Cow::Borrowed("Content-Disposition"),
-                    Cow::Borrowed("attachment; filename=smime.p7m"),
-                ),
-            ];
-
-            if let Err(e) = update_headers(ctx, &context.actions, new_headers).await {
-                error!(error = ?e, "Failed to update headers in on_eom for encryption");
-                return Status::Reject;
-            }
+    body_normalization::finish(
+        &mut ctx.body,
+        &mut ctx.partial_line,
+        &settings.body_normalizations,
+    );
 
-            if context.actions.replace_body(&wrapped).await.is_err() {
-                error!("Failed to replace body after encryption");
-                return Status::Reject;
-            }
-            if context
+    // A marker only counts for the message it was made for, others must not travel on.
+    if let Some(secret) = settings
+        .reinjection_secret
+        .as_ref()
+        .filter(|_| !ctx.markers.is_empty())
+    {
+        let binding = reinjection::Binding {
+            message_id: ctx.message_id.as_deref(),
+            body: &ctx.body,
+        };
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let verified = ctx.markers.iter().find_map(|marker| {
+            reinjection::verify(secret, marker, &binding, now)
+                .inspect_err(|error| warn!(?error, "Ignoring invalid reinjection marker"))
+                .ok()
+        });
+        if let Some(queue_id) = verified {
+            info!(%queue_id, "Message was processed already; accepting unchanged");
+            ctx.action = None;
+            return Status::Accept;
+        }
+        for index in (1..=ctx.markers.len() as i32).rev() {
+            let removed = context
                 .actions
-                .add_header(
-                    "X-PANTOSMIME",
-                    "Successfully encrypted plain-text message. Yay!",
-                )
-                .await
-                .is_err()
-            {
-                error!("Failed adding X-PANOSMIME header")
-            };
-            info!("Encryption successful, accepting mail");
-            Status::Accept
-        }
-
-        MilterAction::ExtractKeys => {
-            // Parse using MIME Parser.
-            let body_str = String::from_utf8_lossy(&ctx.body);
-            let container = match MimeContainer::parse_mime_container_data(
-                &body_str,
-                ctx.headers.clone(), // TODO: eliminate clone if possible
-            ) {
-                Ok((_, container)) => container,
-                Err(e) => {
-                    error!(error = ?e, "Failed to parse MIME container in on_eom for key extraction");
-                    return Status::Reject;
-                }
-            };
-
-            // Check if smime signed message
-            if !container
-                .find_header_value("Content-Type")
-                .is_some_and(|e| e.to_lowercase().contains("multipart/signed"))
-            {
-                info!("Message does not contain multipart/signed content, moving on");
-                return Status::Accept;
-            }
-
-            // Iterate through message parts to find one with content type "application/pkcs7-signature".
-            let signature_part = match container.parts.iter().find(|p| {
-                p.find_header_value("Content-Type").is_some_and(|e| {
-                    let e = e.to_lowercase();
-                    e.contains("application/pkcs7-signature")
-                        || e.contains("application/x-pkcs7-signature")
-                })
-            }) {
-                Some(sp) => sp,
-                None => {
-                    error!(
-                        "Message is multipart/signed, but didn't find any PKCS#7 signature part"
-                    );
-                    return Status::Reject;
-                }
-            };
-
-            // De-B64 and validate if cert is valid for sender?
-            let mut signature_data = signature_part.body.to_string();
-            signature_data.retain(|c| !c.is_whitespace());
-            let decoded = match BASE64_STANDARD.decode(signature_data.as_bytes()) {
-                Ok(data) => data,
-                Err(error) => {
-                    error!(?error, "Failed to decrypt signature");
-                    return Status::Reject;
-                }
-            };
-
-            // Extract the cert and verify it's got a cert matching the sender.
-            let cert_chain = match smime::extract_certificates_from_p7s(&decoded) {
-                Ok(chain) => chain,
-                Err(error) => {
-                    error!(?error, "Failed to extract signers from signature");
-                    return Status::Reject;
-                }
-            };
-            if let Err(error) = smime::find_cert_for_email(&cert_chain, &ctx.sender) {
+                .change_header(reinjection::MARKER_HEADER, index, None::<CString>)
+                .await;
+            if let Err(error) = removed {
                 error!(
                     ?error,
-                    "Failed to find signature certificate matching sender"
+                    "Failed to remove invalid reinjection marker; rejecting message"
                 );
                 return Status::Reject;
             }
-            info!(sender = ?ctx.sender, cert_count = ?cert_chain.len(), "Found signature for sender");
+        }
+    }
 
-            // Save PEM into <sender>.pem file.
-            let path = cert_dir.join(format!("{}.pem", ctx.sender));
-            if let Err(error) = smime::write_pem_stack(cert_chain, &path).await {
-                error!(
-                    ?error,
-                    "Failed to write signature certificate chain to File"
-                );
-                return Status::Reject;
-            }
-            if context
-                .actions
-                .add_header(
-                    "X-PANTOSMIME",
-                    "Successfully extracted signature and certificate chain. Yay!",
-                )
-                .await
-                .is_err()
-            {
-                error!("Failed adding X-PANOSMIME header")
-            };
-            info!("Successfully extracted certificate chain from Email");
-            Status::Accept
+    // Deliver to the recipients without their subaddress, whatever happens to the content.
+    for (tagged, untagged) in &ctx.retagged {
+        debug!(%tagged, %untagged, "Restoring recipient");
+        let restored = async {
+            context.actions.delete_recipient(tagged.as_str()).await?;
+            context.actions.add_recipient(untagged.as_str()).await
+        };
+        if let Err(error) = restored.await {
+            error!(
+                ?error,
+                "Failed to restore subaddressed recipient; rejecting message"
+            );
+            return Status::Reject;
         }
     }
+
+    let pipeline = match &ctx.action {
+        Some(MilterAction::Encrypt) => &settings.encrypt_pipeline,
+        Some(MilterAction::ExtractKeys) => &settings.harvest_pipeline,
+        Some(MilterAction::Enroll) => &settings.enroll_pipeline,
+        None if !ctx.retagged.is_empty() => return Status::Accept,
+        None => {
+            error!("No action determined in on_eom; rejecting message");
+            return Status::Reject;
+        }
+    };
+
+    let mut message = Message {
+        ctx,
+        settings,
+        actions: &context.actions,
+        reply: &mut context.reply,
+        content: Vec::new(),
+        certs: Vec::new(),
+        cert_metadata: Default::default(),
+        profile: Default::default(),
+        replaced_body: None,
+    };
+    let status = pipeline.run(&mut message).await;
+    if let (Some(_), Some(dir)) = (&ctx.report.failed_stage, &settings.dead_letter_dir) {
+        match dead_letter::save(dir, ctx).await {
+            Ok(path) => info!(?path, "Saved failed message to the dead-letter directory"),
+            Err(error) => error!(
+                ?error,
+                "Failed to save message to the dead-letter directory"
+            ),
+        }
+    }
+    status
 }
 
 async fn skip_this() -> Status {
     Status::Continue
 }
 
-pub fn assemble_callbacks<'a>(
-    cert_dir: PathBuf,
-    responsible: Arc<Vec<String>>,
+pub fn assemble_callbacks<'a>(settings: Arc<Settings>) -> Callbacks<MilterContext<'a>> {
+    let mode = settings.mode;
+    assemble_callbacks_with_mode(settings, mode)
+}
+
+/// Callbacks only doing the processing of `mode`, for a listener bound to it.
+pub fn assemble_callbacks_with_mode<'a>(
+    settings: Arc<Settings>,
+    mode: Mode,
 ) -> Callbacks<MilterContext<'a>> {
+    let negotiate_settings = Arc::clone(&settings);
+    let mail_settings = Arc::clone(&settings);
+    let rcpt_settings = Arc::clone(&settings);
+    let header_settings = Arc::clone(&settings);
+    let eoh_settings = Arc::clone(&settings);
+    let body_settings = Arc::clone(&settings);
     Callbacks::new()
-        .on_negotiate(|context, _, _| Box::pin(on_negotiate(context)))
+        .on_negotiate(move |context, _, offered| {
+            Box::pin(on_negotiate(
+                context,
+                offered,
+                Arc::clone(&negotiate_settings),
+            ))
+        })
         .on_connect(|_, _, _| Box::pin(skip_this()))
         .on_helo(|_, _| Box::pin(skip_this()))
-        .on_mail(|context, args| Box::pin(on_mail(context, args)))
-        .on_rcpt(|context, args| Box::pin(on_rcpt(context, args)))
+        .on_mail(move |context, args| {
+            Box::pin(on_mail(context, args, Arc::clone(&mail_settings), mode))
+        })
+        .on_rcpt(move |context, args| Box::pin(on_rcpt(context, args, Arc::clone(&rcpt_settings))))
         .on_data(|_| Box::pin(skip_this()))
         .on_header(move |context, name, value| {
-            Box::pin(on_header(context, name, value, Arc::clone(&responsible)))
+            Box::pin(on_header(
+                context,
+                name,
+                value,
+                Arc::clone(&header_settings),
+            ))
         })
-        .on_eoh(|context| Box::pin(on_eoh(context)))
-        .on_body(|context, data| Box::pin(on_body(context, data)))
-        .on_eom(move |context| Box::pin(on_eom(context, cert_dir.clone())))
+        .on_eoh(move |context| Box::pin(on_eoh(context, Arc::clone(&eoh_settings))))
+        .on_body(move |context, data| Box::pin(on_body(context, data, Arc::clone(&body_settings))))
+        .on_eom(move |context| Box::pin(on_eom(context, Arc::clone(&settings))))
         .on_unknown(|_, _| Box::pin(skip_this()))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::authentication_results;
+    use crate::cert_store;
+    use crate::crypto_profile;
+    use crate::harvest_spool;
+    use crate::milter_client::{spawn_milter, split_message, MilterClient, Outcome, Response};
+    use crate::mime_parser::MimeContainer;
+    use crate::settings::{FromHeaderMatch, InlinePgpAction, OversizeAction, TlsPolicy};
+    use crate::smime;
+    use crate::smime_attributes::CertMetadata;
+    use crate::test_pki::{certs_only_message, self_signed_identity, signed_message, TestCa};
+    use base64::{prelude::BASE64_STANDARD, Engine};
+    use openssl::cms::CmsContentInfo;
+    use std::path::Path;
+
+    const SINGLE_EMAIL: &[u8] = include_bytes!("../data/mime/simple_email.eml");
+    const MULTIPART_EXAMPLE: &[u8] = include_bytes!("../data/mime/multipart_example.eml");
+    const MULTIPART_MATRYOSHKA: &[u8] = include_bytes!("../data/mime/multipart_matryoshka.eml");
+    const MULTIPART_RELATED: &[u8] = include_bytes!("../data/mime/multipart_related.eml");
+    const DELIVERY_REPORT: &[u8] = include_bytes!("../data/mime/delivery_report.eml");
+    const CALENDAR_INVITATION: &[u8] = include_bytes!("../data/mime/calendar_invitation.eml");
+
+    async fn connect_milter(cert_dir: &Path, responsible: &[&str]) -> MilterClient {
+        let responsible = responsible.iter().map(|s| s.to_string()).collect();
+        let settings = Settings::new(cert_dir.to_path_buf(), responsible);
+        let callbacks = assemble_callbacks(Arc::new(settings));
+        MilterClient::connect(spawn_milter(callbacks).await)
+            .await
+            .expect("failed to connect to milter")
+    }
+
+    /// Undo the transfer encoding of a replaced S/MIME body.
+    fn smime_body_der(outcome: &Outcome) -> Vec<u8> {
+        let mut encoded = outcome.body().expect("body was not replaced");
+        encoded.retain(|b| !b.is_ascii_whitespace());
+        BASE64_STANDARD.decode(encoded).unwrap()
+    }
 
     #[test]
     fn test_extract_email_variants() {
@@ -484,6 +1042,9 @@ mod tests {
             ("<jane@example.com>", Some("jane@example.com")),
             ("foo@bar.com", Some("foo@bar.com")),
             ("  <baz@example.org> ", Some("baz@example.org")),
+            ("root", Some("root")),
+            ("<>", Some("")),
+            ("<user@[192.168.1.10]>", Some("user@[192.168.1.10]")),
             ("John Doe", None),
             ("John Doe john@example.com", None),
             ("", None),
@@ -500,14 +1061,1919 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_flow_not_responsible() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = connect_milter(dir.path(), &["other@example.com"]).await;
+        let outcome = client
+            .send_message("Q1", "a@example.com", &["b@example.com"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert!(outcome.actions.is_empty());
+        client.quit().await.unwrap();
+    }
+
+    #[cfg(feature = "lua")]
+    #[tokio::test]
+    async fn test_flow_policy_script() {
+        use crate::policy_script::PolicyScript;
+
+        let dir = tempfile::tempdir().unwrap();
+        let script_path = dir.path().join("policy.lua");
+        std::fs::write(
+            &script_path,
+            "function policy(msg) if msg.action == nil then return 'reject' end end",
+        )
+        .unwrap();
+        let settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings
+            .policy_script
+            .set(Some(PolicyScript::load(&script_path).unwrap()));
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+
+        let mut client = MilterClient::connect(addr).await.unwrap();
+        let outcome = client
+            .send_message("Q1", "c@example.com", &["b@example.com"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Reject));
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_encrypt() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, _) = self_signed_identity("b@example.com");
+        smime::write_pem_stack([&cert], &dir.path().join("b@example.com.pem"))
+            .await
+            .unwrap();
+
+        let mut client = connect_milter(dir.path(), &["a@example.com"]).await;
+        let outcome = client
+            .send_message("Q2", "a@example.com", &["b@example.com"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert!(outcome
+            .header("Content-Type")
+            .unwrap()
+            .starts_with("application/pkcs7-mime"));
+        assert_eq!(outcome.header("Content-Transfer-Encoding"), Some("base64"));
+        assert_eq!(outcome.header("X-PANTOSMIME"), Some("ENCRYPTED_OK"));
+
+        let body = outcome.body().expect("body was not replaced");
+        assert!(body.split(|b| *b == b'\n').all(|l| l.len() <= 77));
+        CmsContentInfo::from_der(&smime_body_der(&outcome)).expect("body is not a CMS structure");
+    }
+
+    #[tokio::test]
+    async fn test_flow_encrypt_idn() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, _) = self_signed_identity("b@xn--bcher-kva.example");
+        smime::write_pem_stack([&cert], &dir.path().join("b@xn--bcher-kva.example.pem"))
+            .await
+            .unwrap();
+
+        let mut client = connect_milter(dir.path(), &["a@bücher.example"]).await;
+        let outcome = client
+            .send_message(
+                "Q2",
+                "a@xn--bcher-kva.example",
+                &["b@bücher.example"],
+                SINGLE_EMAIL,
+            )
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert!(outcome.body().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_flow_encrypt_decryptable() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = TestCa::new("Test CA");
+        let (cert, key) = ca.issue("b@example.com");
+        smime::write_pem_stack([&cert, &ca.cert], &dir.path().join("b@example.com.pem"))
+            .await
+            .unwrap();
+
+        let mut client = connect_milter(dir.path(), &["a@example.com"]).await;
+        for message in [SINGLE_EMAIL, MULTIPART_EXAMPLE, MULTIPART_RELATED] {
+            let outcome = client
+                .send_message("Q7", "a@example.com", &["b@example.com"], message)
+                .await
+                .unwrap();
+            assert_eq!(outcome.response, Some(Response::Accept));
+            let (outer_headers, _) = split_message(&outcome.apply(message));
+            assert!(!outer_headers
+                .iter()
+                .any(|(name, _)| name.eq_ignore_ascii_case("Content-ID")));
+
+            let inner = smime::decrypt_data(&smime_body_der(&outcome), &cert, &key).unwrap();
+            let (_, original_body) = split_message(message);
+            assert!(inner.ends_with(original_body));
+
+            let inner = String::from_utf8(inner).unwrap();
+            let original = std::str::from_utf8(message).unwrap();
+            let (_, inner) = MimeContainer::parse_mime_container(&inner).unwrap();
+            let (_, original) = MimeContainer::parse_mime_container(original).unwrap();
+            assert_eq!(
+                inner.find_header_value("Content-Type"),
+                original.find_header_value("Content-Type")
+            );
+            assert_eq!(
+                inner.find_header_value("Content-ID"),
+                original.find_header_value("Content-ID")
+            );
+            assert_eq!(inner.body, original.body);
+            assert_eq!(inner.parts, original.parts);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flow_crypto_profile() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = self_signed_identity("b@example.com");
+        smime::write_pem_stack([&cert], &dir.path().join("b@example.com.pem"))
+            .await
+            .unwrap();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.crypto_profiles =
+            vec![
+                crypto_profile::parse_profile("example.com=aead,key-transport=rsa-oaep,compress")
+                    .unwrap(),
+            ];
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+
+        let outcome = client
+            .send_message("Q1", "a@example.com", &["b@example.com"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        let (headers, _) = split_message(&outcome.apply(SINGLE_EMAIL));
+        assert!(headers.iter().any(|(name, value)| name == "Content-Type"
+            && value.contains("smime-type=authEnveloped-data")));
+        let inner = smime::decrypt_data(&smime_body_der(&outcome), &cert, &key).unwrap();
+        assert!(String::from_utf8(inner)
+            .unwrap()
+            .starts_with("Content-Type: application/pkcs7-mime; smime-type=compressed-data"));
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_aead_auto() {
+        let dir = tempfile::tempdir().unwrap();
+        for email in ["b@example.com", "c@example.com"] {
+            let (cert, _) = self_signed_identity(email);
+            smime::write_pem_stack([&cert], &dir.path().join(format!("{}.pem", email)))
+                .await
+                .unwrap();
+        }
+        let metadata = CertMetadata {
+            capabilities: vec!["id-aes256-GCM".into(), "AES-256-CBC".into()],
+            ..CertMetadata::default()
+        };
+        metadata
+            .store(&CertMetadata::path(dir.path(), "b@example.com"))
+            .await
+            .unwrap();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.crypto_profiles = vec![crypto_profile::parse_profile("*=aead=auto").unwrap()];
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+
+        for (queue_id, recipients, smime_type) in [
+            ("Q1", &["b@example.com"][..], "authEnveloped-data"),
+            ("Q2", &["c@example.com"][..], "enveloped-data"),
+            (
+                "Q3",
+                &["b@example.com", "c@example.com"][..],
+                "enveloped-data",
+            ),
+        ] {
+            let outcome = client
+                .send_message(queue_id, "a@example.com", recipients, SINGLE_EMAIL)
+                .await
+                .unwrap();
+            assert_eq!(outcome.response, Some(Response::Accept));
+            let content_type = outcome.header("Content-Type").unwrap();
+            assert!(content_type.contains(&format!("smime-type={}", smime_type)));
+        }
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_decision_cache() {
+        use crate::decision_cache::DecisionCache;
+
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, _) = self_signed_identity("b@example.com");
+        let path = dir.path().join("b@example.com.pem");
+        smime::write_pem_stack([&cert], &path).await.unwrap();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.recipient_certs = DecisionCache::new(std::time::Duration::from_secs(60));
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+
+        for queue_id in ["Q1", "Q2"] {
+            let outcome = client
+                .send_message(queue_id, "a@example.com", &["b@example.com"], SINGLE_EMAIL)
+                .await
+                .unwrap();
+            assert_eq!(outcome.response, Some(Response::Accept));
+            assert!(outcome.body().is_some());
+            // The next message of the batch doesn't even look at the store.
+            std::fs::remove_file(&path).ok();
+        }
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_encrypt_missing_cert() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = connect_milter(dir.path(), &["a@example.com"]).await;
+        let outcome = client
+            .send_message("Q3", "a@example.com", &["b@example.com"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert!(matches!(
+            &outcome.response,
+            Some(Response::ReplyCode(reply))
+                if reply.starts_with("550 5.7.5 No usable S/MIME certificate for b@example.com")
+        ));
+        assert!(outcome.body().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_flow_encrypt_expired_cert() {
+        use crate::settings::CertFailureAction;
+        use crate::test_pki::self_signed_identity_until;
+
+        let dir = tempfile::tempdir().unwrap();
+        let expired_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+            - 2 * 86400;
+        let (cert, _) = self_signed_identity_until("b@example.com", expired_at);
+        smime::write_pem_stack([&cert], &dir.path().join("b@example.com.pem"))
+            .await
+            .unwrap();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.missing_cert_action = CertFailureAction::Tempfail;
+        settings.expired_cert_grace_days = 7;
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+
+        // Within the grace period, the sender's MTA retries.
+        let outcome = client
+            .send_message("Q3", "a@example.com", &["b@example.com"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert!(matches!(
+            &outcome.response,
+            Some(Response::ReplyCode(reply))
+                if reply.starts_with("451 4.7.5 S/MIME certificate for b@example.com expired on")
+        ));
+        assert!(outcome.body().is_none());
+
+        // Once over, the expired action applies, and rejecting wins.
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.missing_cert_action = CertFailureAction::Tempfail;
+        settings.expired_cert_grace_days = 2;
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+        let outcome = client
+            .send_message(
+                "Q4",
+                "a@example.com",
+                &["b@example.com", "c@example.com"],
+                SINGLE_EMAIL,
+            )
+            .await
+            .unwrap();
+        let Some(Response::ReplyCode(reply)) = &outcome.response else {
+            panic!("unexpected response {:?}", outcome.response);
+        };
+        assert!(reply.starts_with("550-5.7.5 S/MIME certificate for b@example.com expired"));
+        assert!(reply.contains("550 5.7.5 No usable S/MIME certificate for c@example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_flow_exclude_recipients() {
+        use crate::milter_client::Action;
+        use crate::pipeline;
+        use crate::settings::CertFailureAction;
+
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, _) = self_signed_identity("b@example.com");
+        smime::write_pem_stack([&cert], &dir.path().join("b@example.com.pem"))
+            .await
+            .unwrap();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.missing_cert_action = CertFailureAction::Exclude;
+        settings.result_secret = Some(b"0123456789abcdef".to_vec());
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+
+        let outcome = client
+            .send_message(
+                "Q1",
+                "a@example.com",
+                &["b@example.com", "c@example.net"],
+                SINGLE_EMAIL,
+            )
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert!(outcome.body().is_some());
+        assert!(outcome
+            .actions
+            .contains(&Action::DeleteRecipient("<c@example.net>".into())));
+        assert_eq!(
+            outcome.header(pipeline::EXCLUDED_HEADER),
+            Some("c@example.net (missing)")
+        );
+        assert!(outcome
+            .header(result_header::HEADER)
+            .unwrap()
+            .contains("recipients=1/2"));
+
+        // Without anyone left to encrypt for, the message is refused.
+        let outcome = client
+            .send_message("Q2", "a@example.com", &["c@example.net"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert!(matches!(outcome.response, Some(Response::ReplyCode(_))));
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_rollout() {
+        use crate::pipeline;
+
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, _) = self_signed_identity("b@example.com");
+        smime::write_pem_stack([&cert], &dir.path().join("b@example.com.pem"))
+            .await
+            .unwrap();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.encrypt_percent = 0;
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+
+        let outcome = client
+            .send_message("Q1", "a@example.com", &["b@example.com"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert!(outcome.body().is_none());
+        assert_eq!(
+            outcome.header(pipeline::WOULD_ENCRYPT_HEADER),
+            Some("b@example.com")
+        );
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_schedule() {
+        use crate::schedule::parse_scheduled;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.schedules = vec![parse_scheduled("2000-01-01T00:00Z=tempfail").unwrap()];
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+
+        let outcome = client
+            .send_message("Q1", "a@example.com", &["c@example.net"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        let Some(Response::ReplyCode(reply)) = &outcome.response else {
+            panic!("unexpected response {:?}", outcome.response);
+        };
+        assert!(reply.starts_with("451 4.7.5"), "{}", reply);
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_local_domain() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = connect_milter(dir.path(), &["a@example.com"]).await;
+        let outcome = client
+            .send_message("Q1", "a@example.com", &["root"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert!(outcome.body().is_none());
+
+        // A local user doesn't leave the other recipients of the message unencrypted.
+        let (cert, _) = self_signed_identity("b@example.com");
+        smime::write_pem_stack([&cert], &dir.path().join("b@example.com.pem"))
+            .await
+            .unwrap();
+        let outcome = client
+            .send_message(
+                "Q2",
+                "a@example.com",
+                &["root", "b@example.com"],
+                SINGLE_EMAIL,
+            )
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert!(outcome.body().is_some());
+        client.quit().await.unwrap();
+
+        let (cert, _) = self_signed_identity("user@example.com");
+        smime::write_pem_stack([&cert], &dir.path().join("user@example.com.pem"))
+            .await
+            .unwrap();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.local_domain = Some("example.com".into());
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+        let outcome = client
+            .send_message(
+                "Q3",
+                "a@example.com",
+                &["user@[192.168.1.10]"],
+                SINGLE_EMAIL,
+            )
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert!(outcome.body().is_some());
+        client.quit().await.unwrap();
+
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.schedules =
+            vec![crate::schedule::parse_scheduled("2000-01-01T00:00Z=encrypt:*").unwrap()];
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+        let outcome = client
+            .send_message(
+                "Q4",
+                "a@example.com",
+                &["root", "b@example.com"],
+                SINGLE_EMAIL,
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            outcome.response,
+            Some(Response::ReplyCode(reply)) if reply.starts_with("550 5.7.1")
+        ));
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_store_unavailable() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert_dir = dir.path().join("certs");
+        std::fs::create_dir(&cert_dir).unwrap();
+        let (cert, _) = self_signed_identity("b@example.com");
+        smime::write_pem_stack([&cert], &cert_dir.join("b@example.com.pem"))
+            .await
+            .unwrap();
+        let settings = Settings::new(cert_dir.clone(), vec!["a@example.com".into()]);
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+
+        // Like an unmounted share, deferring rather than refusing the message.
+        std::fs::rename(&cert_dir, dir.path().join("moved")).unwrap();
+        let outcome = client
+            .send_message("Q1", "a@example.com", &["b@example.com"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert!(matches!(
+            outcome.response,
+            Some(Response::ReplyCode(reply)) if reply.starts_with("451 4.3.0")
+        ));
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_result_header() {
+        use crate::milter_client::Action;
+
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, _) = self_signed_identity("b@example.com");
+        smime::write_pem_stack([&cert], &dir.path().join("b@example.com.pem"))
+            .await
+            .unwrap();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.result_secret = Some(b"0123456789abcdef".to_vec());
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+
+        // Result headers from the sender are replaced.
+        let forged = [
+            format!("{}: action=encrypt; mac=00\r\n", result_header::HEADER).as_bytes(),
+            SINGLE_EMAIL,
+        ]
+        .concat();
+        let outcome = client
+            .send_message("Q1", "a@example.com", &["b@example.com"], &forged)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert!(outcome.actions.contains(&Action::ChangeHeader(
+            1,
+            result_header::HEADER.into(),
+            None
+        )));
+        let result = outcome.header(result_header::HEADER).unwrap();
+        assert!(result.starts_with("action=encrypt; recipients=1/1; cipher=aes-256-cbc; q=Q1; t="));
+        assert!(result.contains("; mac="));
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_address_rewrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = self_signed_identity("b@example.com");
+        smime::write_pem_stack([&cert], &dir.path().join("b@example.com.pem"))
+            .await
+            .unwrap();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.address_rewrites =
+            vec![address::parse_rewrite("old.example=example.com").unwrap()];
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+
+        // Both addresses at the old domain are handled as the new ones.
+        let outcome = client
+            .send_message("Q1", "a@old.example", &["b@old.example"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        smime::decrypt_data(&smime_body_der(&outcome), &cert, &key).unwrap();
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_inline_pgp() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, _) = self_signed_identity("b@example.com");
+        smime::write_pem_stack([&cert], &dir.path().join("b@example.com.pem"))
+            .await
+            .unwrap();
+        let message = b"From: a@example.com\r\nContent-Type: text/plain\r\n\r\n\
+                        Hi,\r\n\r\n-----BEGIN PGP MESSAGE-----\r\n\r\nhQEMA0+Zz\r\n\
+                        -----END PGP MESSAGE-----\r\n";
+
+        // Encrypted anyway by default.
+        let mut client = connect_milter(dir.path(), &["a@example.com"]).await;
+        let outcome = client
+            .send_message("Q1", "a@example.com", &["b@example.com"], message)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert!(outcome.body().is_some());
+
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.inline_pgp_action = InlinePgpAction::Skip;
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+        let outcome = client
+            .send_message("Q2", "a@example.com", &["b@example.com"], message)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert!(outcome.body().is_none());
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_escrow() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = self_signed_identity("b@example.com");
+        smime::write_pem_stack([&cert], &dir.path().join("b@example.com.pem"))
+            .await
+            .unwrap();
+        let (escrow, escrow_key) = self_signed_identity("escrow@example.com");
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.escrow_certificate = Some(escrow.clone());
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+
+        let outcome = client
+            .send_message("Q1", "a@example.com", &["b@example.com"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        let der = smime_body_der(&outcome);
+        let inner = smime::decrypt_data(&der, &cert, &key).unwrap();
+        assert_eq!(
+            smime::decrypt_data(&der, &escrow, &escrow_key).unwrap(),
+            inner
+        );
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_reinjection_marker() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, _) = self_signed_identity("b@example.com");
+        smime::write_pem_stack([&cert], &dir.path().join("b@example.com.pem"))
+            .await
+            .unwrap();
+        let secret = b"0123456789abcdef".to_vec();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.reinjection_secret = Some(secret.clone());
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+
+        let outcome = client
+            .send_message("Q1", "a@example.com", &["b@example.com"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        let marker = outcome.header(reinjection::MARKER_HEADER).unwrap();
+        assert!(marker.starts_with("q=Q1; "));
+
+        // Coming back, e.g. from a content filter, the message is left alone.
+        let reinjected = outcome.apply(SINGLE_EMAIL);
+        let outcome = client
+            .send_message("Q2", "a@example.com", &["b@example.com"], &reinjected)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert!(outcome.actions.is_empty());
+
+        // Neither forged markers nor ones copied into another message count, and both are
+        // removed.
+        let stripped = crate::milter_client::Action::ChangeHeader(
+            1,
+            reinjection::MARKER_HEADER.to_string(),
+            None,
+        );
+        for marker in [marker, "q=Q1; t=1; mac=00"] {
+            let copied = [
+                format!("{}: {}\r\n", reinjection::MARKER_HEADER, marker).as_bytes(),
+                SINGLE_EMAIL,
+            ]
+            .concat();
+            let outcome = client
+                .send_message("Q3", "a@example.com", &["b@example.com"], &copied)
+                .await
+                .unwrap();
+            assert!(outcome.body().is_some());
+            assert!(outcome.actions.contains(&stripped));
+            let (headers, _) = split_message(&outcome.apply(&copied));
+            let markers = headers
+                .iter()
+                .filter(|(name, _)| name == reinjection::MARKER_HEADER)
+                .count();
+            assert_eq!(markers, 1);
+        }
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_tls_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.tls_policy = TlsPolicy::UnprotectedSubmission;
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+
+        // Without a certificate for the recipient, encryption would be refused.
+        client
+            .macros(
+                b'H',
+                &[
+                    ("{tls_version}", "TLSv1.3"),
+                    ("{cipher}", "TLS_AES_256_GCM_SHA384"),
+                ],
+            )
+            .await
+            .unwrap();
+        let outcome = client
+            .send_message("Q1", "a@example.com", &["b@example.com"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert!(outcome.body().is_none());
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_internal_network() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, _) = self_signed_identity("b@example.com");
+        smime::write_pem_stack([&cert], &dir.path().join("b@example.com.pem"))
+            .await
+            .unwrap();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.internal_networks = vec![network::parse_internal_network("10.0.0.0/8").unwrap()];
+        settings.encrypt_internal_only = true;
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+
+        for (client_addr, encrypted) in [("10.1.2.3", true), ("192.0.2.1", false)] {
+            let mut client = MilterClient::connect(addr).await.unwrap();
+            client
+                .macros(
+                    b'C',
+                    &[("{client_addr}", client_addr), ("{client_name}", "unknown")],
+                )
+                .await
+                .unwrap();
+            let outcome = client
+                .send_message("Q1", "a@example.com", &["b@example.com"], SINGLE_EMAIL)
+                .await
+                .unwrap();
+            assert_eq!(outcome.response, Some(Response::Accept));
+            assert_eq!(outcome.body().is_some(), encrypted, "{}", client_addr);
+            client.quit().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flow_negotiation() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, _) = self_signed_identity("b@example.com");
+        smime::write_pem_stack([&cert], &dir.path().join("b@example.com.pem"))
+            .await
+            .unwrap();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.internal_networks = vec![network::parse_internal_network("10.0.0.0/8").unwrap()];
+        settings.encrypt_internal_only = true;
+        settings.milter_skip_steps = vec![MilterStep::Connect, MilterStep::Helo, MilterStep::Data];
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+
+        let mut client = MilterClient::connect(addr).await.unwrap();
+        let skipped = ProtoOpts::NO_CONNECT | ProtoOpts::NO_HELO | ProtoOpts::NO_DATA;
+        assert_eq!(client.protocol_opts & skipped.bits(), skipped.bits());
+        // The client address comes along with MAIL FROM instead.
+        client
+            .macros(b'M', &[("{client_addr}", "10.1.2.3")])
+            .await
+            .unwrap();
+        let outcome = client
+            .send_message("Q1", "a@example.com", &["b@example.com"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert!(outcome.body().is_some());
+        client.quit().await.unwrap();
+
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["c@example.com".into()]);
+        settings.skip_unsigned_bodies = true;
+        // Any body transferred for harvesting exceeds the limit.
+        settings.max_message_size = Some(16);
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+        let outcome = client
+            .send_message("Q2", "x@example.org", &["c@example.com"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        let outcome = client
+            .send_message("Q3", "x@example.org", &["c@example.com"], MULTIPART_EXAMPLE)
+            .await
+            .unwrap();
+        assert!(matches!(outcome.response, Some(Response::ReplyCode(_))));
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_origin_from_received() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, _) = self_signed_identity("b@example.com");
+        smime::write_pem_stack([&cert], &dir.path().join("b@example.com.pem"))
+            .await
+            .unwrap();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.internal_networks = vec![network::parse_internal_network("10.0.0.0/8").unwrap()];
+        settings.encrypt_internal_only = true;
+        settings.origin_from_received = true;
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+
+        for (client_addr, encrypted) in [("10.1.2.3", true), ("192.0.2.1", false)] {
+            let mut client = MilterClient::connect(addr).await.unwrap();
+            let message = [
+                format!(
+                    "Received: from client (unknown [{}])\r\n\tby mx.example.com (Postfix)\r\n",
+                    client_addr
+                )
+                .as_bytes(),
+                SINGLE_EMAIL,
+            ]
+            .concat();
+            let outcome = client
+                .send_message("Q1", "a@example.com", &["b@example.com"], &message)
+                .await
+                .unwrap();
+            assert_eq!(outcome.response, Some(Response::Accept));
+            assert_eq!(outcome.body().is_some(), encrypted, "{}", client_addr);
+            client.quit().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flow_strip_headers() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, _) = self_signed_identity("b@example.com");
+        smime::write_pem_stack([&cert], &dir.path().join("b@example.com.pem"))
+            .await
+            .unwrap();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.strip_headers = vec!["x-mailer".into(), "X-Internal".into()];
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+
+        let mut client = MilterClient::connect(addr).await.unwrap();
+        let message = [
+            b"X-Mailer: Notifier 1.0\r\nX-Internal: ticket-42\r\nX-MAILER: Relay 2.0\r\n"
+                .as_slice(),
+            SINGLE_EMAIL,
+        ]
+        .concat();
+        let outcome = client
+            .send_message("Q3", "a@example.com", &["b@example.com"], &message)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        let (headers, _) = split_message(&outcome.apply(&message));
+        assert!(!headers.iter().any(|(name, _)| {
+            name.eq_ignore_ascii_case("X-Mailer") || name.eq_ignore_ascii_case("X-Internal")
+        }));
+        assert!(headers.iter().any(|(name, _)| name == "From"));
+    }
+
+    #[tokio::test]
+    async fn test_flow_capture_headers() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = self_signed_identity("b@example.com");
+        smime::write_pem_stack([&cert], &dir.path().join("b@example.com.pem"))
+            .await
+            .unwrap();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.capture_headers = vec!["From".into(), "Reply-To".into()];
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+
+        let mut client = MilterClient::connect(addr).await.unwrap();
+        let message = [
+            b"To: b@example.com\r\nreply-to: <help@example.com>\r\n".as_slice(),
+            SINGLE_EMAIL,
+        ]
+        .concat();
+        let outcome = client
+            .send_message("Q1", "a@example.com", &["b@example.com"], &message)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        let inner = smime::decrypt_data(&smime_body_der(&outcome), &cert, &key).unwrap();
+        let (inner_headers, _) = split_message(&inner);
+        assert_eq!(
+            inner_headers,
+            [
+                ("reply-to".to_string(), "<help@example.com>".to_string()),
+                ("From".to_string(), "test@example.com".to_string()),
+                ("Content-Type".to_string(), "text/plain".to_string()),
+            ]
+        );
+        let (headers, _) = split_message(&outcome.apply(&message));
+        assert!(headers.iter().any(|(name, _)| name == "From"));
+        assert!(headers.iter().any(|(name, _)| name == "reply-to"));
+    }
+
+    #[tokio::test]
+    async fn test_flow_subaddress() {
+        use crate::milter_client::Action;
+        use crate::settings::SubaddressAction;
+
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, _) = self_signed_identity("c@example.com");
+        smime::write_pem_stack([&cert], &dir.path().join("c@example.com.pem"))
+            .await
+            .unwrap();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.subaddress_actions = vec![
+            ("nocrypt".into(), SubaddressAction::Plain),
+            ("secure".into(), SubaddressAction::Encrypt),
+        ];
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+
+        // Without a certificate for b, encrypting would fail.
+        let outcome = client
+            .send_message(
+                "Q1",
+                "a@example.com",
+                &["b+nocrypt@example.com"],
+                SINGLE_EMAIL,
+            )
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert_eq!(
+            outcome.actions,
+            vec![
+                Action::DeleteRecipient("<b+nocrypt@example.com>".into()),
+                Action::AddRecipient("b@example.com".into()),
+            ]
+        );
+
+        let outcome = client
+            .send_message(
+                "Q2",
+                "x@example.com",
+                &["c+secure@example.com"],
+                SINGLE_EMAIL,
+            )
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert!(outcome
+            .actions
+            .contains(&Action::AddRecipient("c@example.com".into())));
+        assert!(outcome.body().is_some());
+
+        // Unknown tags are left alone.
+        let outcome = client
+            .send_message("Q3", "x@example.com", &["c+news@example.com"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert!(outcome.actions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_flow_exempt() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = connect_milter(dir.path(), &["a@example.com"]).await;
+        // Without a certificate, anything encrypted would be rejected.
+        let outcome = client
+            .send_message("Q3", "a@example.com", &["b@example.com"], DELIVERY_REPORT)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert!(outcome.actions.is_empty());
+        let outcome = client
+            .send_message(
+                "Q3",
+                "a@example.com",
+                &["b@example.com"],
+                CALENDAR_INVITATION,
+            )
+            .await
+            .unwrap();
+        assert!(
+            matches!(&outcome.response, Some(Response::ReplyCode(reply)) if reply.starts_with("550 "))
+        );
+
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.exempt_calendar = true;
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+        let outcome = client
+            .send_message(
+                "Q3",
+                "a@example.com",
+                &["b@example.com"],
+                CALENDAR_INVITATION,
+            )
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert_eq!(outcome.actions.len(), 1);
+        assert_eq!(outcome.header("X-PANTOSMIME"), Some("SKIPPED_EXEMPT"));
+    }
+
     #[test]
-    fn test_line_wrap() {
-        let mut data = BytesMut::from("testtest".as_bytes());
-        wrap_bytes_crlf(&mut data, 4);
-        assert_eq!(data, BytesMut::from("test\r\ntest"));
-
-        data = BytesMut::from("testtest".as_bytes());
-        wrap_bytes_crlf(&mut data, 6);
-        assert_eq!(data, BytesMut::from("testte\r\nst"));
+    fn test_parse_size_param() {
+        assert_eq!(parse_size_param("SIZE=1234"), Some(1234));
+        assert_eq!(parse_size_param("size=0"), Some(0));
+        assert_eq!(parse_size_param("BODY=8BITMIME"), None);
+        assert_eq!(parse_size_param("SIZE=-1"), None);
+        assert_eq!(parse_size_param("SIZE"), None);
+    }
+
+    #[tokio::test]
+    async fn test_flow_max_message_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.max_message_size = Some(100);
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+
+        // Declared too large, rejected before any body is sent.
+        client.mail_args = vec!["BODY=8BITMIME".into(), "SIZE=5000".into()];
+        let outcome = client
+            .send_message("Q1", "a@example.com", &["b@example.com"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert!(
+            matches!(&outcome.response, Some(Response::ReplyCode(reply)) if reply.starts_with("552 5.3.4")),
+            "{:?}",
+            outcome.response
+        );
+
+        // Declared small, but the body turns out larger.
+        client.mail_args = vec!["SIZE=50".into()];
+        let outcome = client
+            .send_message("Q2", "a@example.com", &["b@example.com"], MULTIPART_EXAMPLE)
+            .await
+            .unwrap();
+        assert!(matches!(outcome.response, Some(Response::ReplyCode(_))));
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_oversize_action() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, _) = self_signed_identity("b@example.com");
+        smime::write_pem_stack([&cert], &dir.path().join("b@example.com.pem"))
+            .await
+            .unwrap();
+        let message = format!(
+            "From: a@example.com\r\nTo: b@example.com\r\nSubject: Hi\r\n\
+             Content-Type: text/plain\r\n\r\n{}",
+            "hello world\r\n".repeat(300)
+        );
+
+        for (action, encrypted) in [
+            (OversizeAction::Reject, false),
+            (OversizeAction::Compress, true),
+            (OversizeAction::Proceed, true),
+        ] {
+            let mut settings =
+                Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+            // Fits as it is, but not once encrypted.
+            settings.max_message_size = Some(4500);
+            settings.oversize_action = action;
+            let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+            let mut client = MilterClient::connect(addr).await.unwrap();
+            let outcome = client
+                .send_message(
+                    "Q1",
+                    "a@example.com",
+                    &["b@example.com"],
+                    message.as_bytes(),
+                )
+                .await
+                .unwrap();
+            match encrypted {
+                true => assert_eq!(outcome.response, Some(Response::Accept), "{:?}", action),
+                false => assert!(
+                    matches!(&outcome.response, Some(Response::ReplyCode(reply)) if reply.starts_with("552 5.3.4")),
+                    "{:?}",
+                    outcome.response
+                ),
+            }
+            assert_eq!(outcome.body().is_some(), encrypted, "{:?}", action);
+            client.quit().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flow_max_cms_recipients() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.max_cms_recipients = Some(1);
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+
+        let outcome = client
+            .send_message(
+                "Q1",
+                "a@example.com",
+                &["b@example.com", "c@example.com"],
+                SINGLE_EMAIL,
+            )
+            .await
+            .unwrap();
+        assert!(
+            matches!(&outcome.response, Some(Response::ReplyCode(reply)) if reply.starts_with("550 5.5.3")),
+            "{:?}",
+            outcome.response
+        );
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_encrypt_unsupported_key() {
+        use crate::test_pki::self_signed_ed25519_identity;
+
+        let dir = tempfile::tempdir().unwrap();
+        let (rsa, _) = self_signed_identity("b@example.com");
+        smime::write_pem_stack([&rsa], &dir.path().join("b@example.com.pem"))
+            .await
+            .unwrap();
+        let (ed25519, _) = self_signed_ed25519_identity("c@example.com");
+        smime::write_pem_stack([&ed25519], &dir.path().join("c@example.com.pem"))
+            .await
+            .unwrap();
+        let report_path = dir.path().join("events.json");
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.report_sink = Some(
+            event_report::ReportSink::open(report_path.to_str().unwrap())
+                .await
+                .unwrap()
+                .spawn(),
+        );
+        let settings = Arc::new(settings);
+        let addr = spawn_milter(assemble_callbacks(Arc::clone(&settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+
+        let outcome = client
+            .send_message(
+                "Q1",
+                "a@example.com",
+                &["b@example.com", "c@example.com"],
+                SINGLE_EMAIL,
+            )
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Reject));
+        client.quit().await.unwrap();
+        settings.report_sink.as_ref().unwrap().flush().await;
+
+        let content = std::fs::read_to_string(&report_path).unwrap();
+        let report: serde_json::Value = serde_json::from_str(content.trim()).unwrap();
+        assert_eq!(report["error_code"], "unsupported-key-type");
+        let error = report["error"].as_str().unwrap();
+        assert!(error.contains("for c@example.com"), "{}", error);
+        assert!(error.contains("c@example.com.pem"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_flow_dead_letter() {
+        use crate::test_pki::self_signed_ed25519_identity;
+
+        let dir = tempfile::tempdir().unwrap();
+        let (ed25519, _) = self_signed_ed25519_identity("b@example.com");
+        smime::write_pem_stack([&ed25519], &dir.path().join("b@example.com.pem"))
+            .await
+            .unwrap();
+        let dead_letters = dir.path().join("dead-letters");
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.dead_letter_dir = Some(dead_letters.clone());
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+
+        // Refused on purpose, not because processing failed.
+        let outcome = client
+            .send_message("Q1", "a@example.com", &["c@example.com"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert!(matches!(outcome.response, Some(Response::ReplyCode(_))));
+        assert!(!dead_letters.exists());
+
+        let outcome = client
+            .send_message("Q2", "a@example.com", &["b@example.com"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Reject));
+        client.quit().await.unwrap();
+
+        let mut files: Vec<_> = std::fs::read_dir(&dead_letters)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        files.sort();
+        let [eml, json] = &files[..] else {
+            panic!("{:?}", files);
+        };
+        let saved = std::fs::read(eml).unwrap();
+        let (headers, body) = split_message(&saved);
+        let (sent_headers, sent_body) = split_message(SINGLE_EMAIL);
+        assert_eq!(headers, sent_headers);
+        assert_eq!(body, sent_body);
+        let metadata: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(json).unwrap()).unwrap();
+        assert_eq!(metadata["queue_id"], "Q2");
+        assert_eq!(metadata["recipients"][0], "b@example.com");
+        assert_eq!(metadata["error_code"], "unsupported-key-type");
+        assert_eq!(metadata["stage"], "encrypt");
+    }
+
+    #[tokio::test]
+    async fn test_flow_header_limits() {
+        use crate::settings::HeaderOverflowAction;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.max_headers = 2;
+        settings.header_overflow_action = HeaderOverflowAction::PassThrough;
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+        let message = b"From: a@example.com\r\nTo: b@example.com\r\nSubject: Hi\r\n\
+            Content-Type: text/plain\r\n\r\nHello.\r\n";
+
+        // Never passed through in plain text if it is to be encrypted.
+        let outcome = client
+            .send_message("Q1", "a@example.com", &["b@example.com"], message)
+            .await
+            .unwrap();
+        assert!(
+            matches!(&outcome.response, Some(Response::ReplyCode(reply)) if reply.starts_with("451 4.3.0")),
+            "{:?}",
+            outcome.response
+        );
+
+        let outcome = client
+            .send_message("Q2", "b@example.com", &["a@example.com"], message)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert!(outcome.actions.is_empty());
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_event_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, _) = self_signed_identity("b@example.com");
+        smime::write_pem_stack([&cert], &dir.path().join("b@example.com.pem"))
+            .await
+            .unwrap();
+        let report_path = dir.path().join("events.json");
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.report_sink = Some(
+            event_report::ReportSink::open(report_path.to_str().unwrap())
+                .await
+                .unwrap()
+                .spawn(),
+        );
+        let settings = Arc::new(settings);
+        let addr = spawn_milter(assemble_callbacks(Arc::clone(&settings))).await;
+
+        let mut client = MilterClient::connect(addr).await.unwrap();
+        let recipients = ["b@example.com", "c@example.com"];
+        for recipients in [&recipients[..1], &recipients[..]] {
+            client
+                .send_message("Q5", "a@example.com", recipients, SINGLE_EMAIL)
+                .await
+                .unwrap();
+        }
+        client.quit().await.unwrap();
+        settings.report_sink.as_ref().unwrap().flush().await;
+
+        let content = std::fs::read_to_string(&report_path).unwrap();
+        let reports: Vec<serde_json::Value> = content
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0]["decision"], "encrypt");
+        assert_eq!(reports[0]["outcome"], "accept");
+        assert_eq!(reports[0]["cipher"], "aes-256-cbc");
+        assert_eq!(
+            reports[0]["recipients"][0]["fingerprint"],
+            event_report::fingerprint(&cert)
+        );
+        assert_eq!(reports[1]["outcome"], "reject");
+        assert_eq!(reports[1]["recipients"][0]["certificate"], true);
+        assert_eq!(reports[1]["recipients"][1]["certificate"], false);
+        assert!(reports[1]["error"]
+            .as_str()
+            .unwrap()
+            .contains("c@example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_flow_post_process_hook() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, _) = self_signed_identity("b@example.com");
+        smime::write_pem_stack([&cert], &dir.path().join("b@example.com.pem"))
+            .await
+            .unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.post_process_hook = Some(Arc::new(crate::hook::Hook::parse(&url).unwrap()));
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+
+        // Only the encrypted message, not the rejected one, is reported.
+        let mut client = MilterClient::connect(addr).await.unwrap();
+        for (queue_id, recipients) in [
+            ("Q1", &["c@example.com"][..]),
+            ("Q2", &["b@example.com"][..]),
+        ] {
+            client
+                .send_message(queue_id, "a@example.com", recipients, SINGLE_EMAIL)
+                .await
+                .unwrap();
+        }
+        client.quit().await.unwrap();
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buffer = [0; 4096];
+        while !request.ends_with(b"}") {
+            let len = stream.read(&mut buffer).await.unwrap();
+            assert!(len > 0);
+            request.extend_from_slice(&buffer[..len]);
+        }
+        stream.write_all(b"HTTP/1.0 200 OK\r\n\r\n").await.unwrap();
+        let request = String::from_utf8(request).unwrap();
+        let (_, body) = request.split_once("\r\n\r\n").unwrap();
+        let report: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(report["queue_id"], "Q2");
+        assert_eq!(report["cipher"], "aes-256-cbc");
+        assert!(!body.contains("Hello"));
+    }
+
+    #[tokio::test]
+    async fn test_flow_harvest() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = self_signed_identity("a@example.com");
+        let message = signed_message(&cert, &key, "a@example.com", "Hello there.");
+
+        let mut client = connect_milter(dir.path(), &["b@example.com"]).await;
+        let outcome = client
+            .send_message("Q4", "a@example.com", &["b@example.com"], &message)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert_eq!(outcome.header("X-PANTOSMIME"), Some("HARVESTED_OK"));
+        assert!(outcome.body().is_none());
+
+        let stored = smime::load_pem_stack(dir.path().join("a@example.com.pem"))
+            .await
+            .unwrap();
+        assert_eq!(stored, vec![cert]);
+    }
+
+    #[tokio::test]
+    async fn test_flow_harvest_spooled() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert_dir = dir.path().join("certs");
+        let spool_dir = dir.path().join("spool");
+        let (cert, key) = self_signed_identity("a@example.com");
+        let message = signed_message(&cert, &key, "a@example.com", "Hello there.");
+
+        let mut settings = Settings::new(cert_dir.clone(), vec!["b@example.com".to_string()]);
+        settings.harvest_spool_dir = Some(spool_dir.clone());
+        settings.store_health = cert_store::StoreHealth::new(std::time::Duration::ZERO);
+        let settings = Arc::new(settings);
+        let addr = spawn_milter(assemble_callbacks(settings.clone())).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+        let outcome = client
+            .send_message("Q4", "a@example.com", &["b@example.com"], &message)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert_eq!(outcome.header("X-PANTOSMIME"), Some("HARVESTED_QUEUED"));
+        assert_eq!(std::fs::read_dir(&spool_dir).unwrap().count(), 1);
+
+        // Still unavailable, the chain stays spooled.
+        assert_eq!(
+            harvest_spool::retry(&settings, &spool_dir).await.unwrap(),
+            0
+        );
+        assert_eq!(std::fs::read_dir(&spool_dir).unwrap().count(), 1);
+
+        std::fs::create_dir(&cert_dir).unwrap();
+        assert_eq!(
+            harvest_spool::retry(&settings, &spool_dir).await.unwrap(),
+            1
+        );
+        assert_eq!(std::fs::read_dir(&spool_dir).unwrap().count(), 0);
+        let stored = smime::load_pem_stack(cert_dir.join("a@example.com.pem"))
+            .await
+            .unwrap();
+        assert_eq!(stored, vec![cert]);
+        let metadata = CertMetadata::load(&CertMetadata::path(&cert_dir, "a@example.com"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(metadata.harvested_from.unwrap().queue_id, "Q4");
+    }
+
+    #[tokio::test]
+    async fn test_flow_harvest_key_types() {
+        use crate::test_pki::{
+            rsa_pss_key, self_signed_ed25519_identity, self_signed_identity_with_key, TestCa,
+        };
+        use openssl::pkey::PKey;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = connect_milter(dir.path(), &["b@example.com"]).await;
+        let stored = dir.path().join("a@example.com.pem");
+
+        // Certificates signed with RSASSA-PSS or EdDSA by the CA work like any other.
+        for ca_key in [rsa_pss_key(), PKey::generate_ed25519().unwrap()] {
+            let ca = TestCa::with_key("Partner CA", ca_key);
+            let (cert, key) = ca.issue("a@example.com");
+            let message = signed_message(&cert, &key, "a@example.com", "Hello there.");
+            let outcome = client
+                .send_message("Q1", "a@example.com", &["b@example.com"], &message)
+                .await
+                .unwrap();
+            assert_eq!(outcome.response, Some(Response::Accept));
+            assert_eq!(smime::load_pem_stack(&stored).await.unwrap(), [cert]);
+            let outcome = client
+                .send_message("Q2", "b@example.com", &["a@example.com"], SINGLE_EMAIL)
+                .await
+                .unwrap();
+            assert!(outcome.body().is_some(), "{:?}", outcome.response);
+        }
+
+        // Keys that only sign don't replace them.
+        let before = std::fs::read(&stored).unwrap();
+        for (cert, key) in [
+            self_signed_ed25519_identity("a@example.com"),
+            self_signed_identity_with_key("a@example.com", PKey::generate_ed448().unwrap()),
+            self_signed_identity_with_key("a@example.com", rsa_pss_key()),
+        ] {
+            let message = signed_message(&cert, &key, "a@example.com", "Hello there.");
+            let outcome = client
+                .send_message("Q3", "a@example.com", &["b@example.com"], &message)
+                .await
+                .unwrap();
+            assert_eq!(outcome.response, Some(Response::Accept));
+            assert_eq!(std::fs::read(&stored).unwrap(), before);
+        }
+        client.quit().await.unwrap();
+    }
+
+    /// A BINARYMIME body with NUL and non-UTF-8 bytes.
+    fn binary_message(content: &[u8], signature: &[u8]) -> Vec<u8> {
+        [
+            b"From: a@example.com\r\nMIME-Version: 1.0\r\n\
+              Content-Type: multipart/signed; protocol=\"application/pkcs7-signature\"; \
+              micalg=sha-256; boundary=\"bin\"\r\n\r\n--bin\r\n"
+                .as_slice(),
+            content,
+            b"\r\n--bin\r\nContent-Type: application/pkcs7-signature; name=smime.p7s\r\n\
+              Content-Transfer-Encoding: binary\r\n\r\n",
+            signature,
+            b"\r\n--bin--\r\n",
+        ]
+        .concat()
+    }
+
+    #[tokio::test]
+    async fn test_flow_binary_body() {
+        use openssl::pkcs7::{Pkcs7, Pkcs7Flags};
+        use openssl::stack::Stack;
+
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = self_signed_identity("a@example.com");
+        let content = [
+            b"Content-Type: application/octet-stream\r\n\
+              Content-Transfer-Encoding: binary\r\n\r\n"
+                .as_slice(),
+            &[0x00, 0xff, 0xfe, 0x0d, 0x00, 0x80, 0x2d, 0x2d],
+        ]
+        .concat();
+        let signature = Pkcs7::sign(
+            &cert,
+            &key,
+            &Stack::new().unwrap(),
+            &content,
+            Pkcs7Flags::DETACHED | Pkcs7Flags::BINARY,
+        )
+        .unwrap()
+        .to_der()
+        .unwrap();
+        let message = binary_message(&content, &signature);
+
+        // Harvested from the raw signature.
+        let mut client = connect_milter(dir.path(), &["b@example.com"]).await;
+        client.mail_args = vec!["BODY=BINARYMIME".into()];
+        let outcome = client
+            .send_message("Q1", "a@example.com", &["b@example.com"], &message)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        let stored = smime::load_pem_stack(dir.path().join("a@example.com.pem"))
+            .await
+            .unwrap();
+        assert_eq!(stored, vec![cert.clone()]);
+
+        // Encrypted byte for byte.
+        let mut client = connect_milter(dir.path(), &["b@example.com"]).await;
+        client.mail_args = vec!["BODY=BINARYMIME".into()];
+        let outcome = client
+            .send_message("Q2", "b@example.com", &["a@example.com"], &message)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        let inner = smime::decrypt_data(&smime_body_der(&outcome), &cert, &key).unwrap();
+        let (_, body) = split_message(&message);
+        assert!(inner.ends_with(body));
+    }
+
+    #[tokio::test]
+    async fn test_flow_harvest_trust_anchors() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = TestCa::new("Partner CA");
+        let ca_path = dir.path().join("partner-ca.pem");
+        std::fs::write(&ca_path, ca.cert.to_pem().unwrap()).unwrap();
+        let settings = Settings::new(dir.path().to_path_buf(), vec!["b@example.com".into()]);
+        settings
+            .trust_anchors
+            .set(vec![crate::trust::TrustAnchors::load(
+                "partner.example".into(),
+                &ca_path,
+            )
+            .unwrap()]);
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+
+        let (cert, key) = self_signed_identity("a@partner.example");
+        let message = signed_message(&cert, &key, "a@partner.example", "Hello there.");
+        let outcome = client
+            .send_message("Q1", "a@partner.example", &["b@example.com"], &message)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert!(!dir.path().join("a@partner.example.pem").exists());
+
+        let (cert, key) = ca.issue("a@partner.example");
+        let message = signed_message(&cert, &key, "a@partner.example", "Hello there.");
+        client
+            .send_message("Q2", "a@partner.example", &["b@example.com"], &message)
+            .await
+            .unwrap();
+        assert!(dir.path().join("a@partner.example.pem").exists());
+
+        // Other domains are trusted on first use.
+        let (cert, key) = self_signed_identity("c@other.example");
+        let message = signed_message(&cert, &key, "c@other.example", "Hello there.");
+        client
+            .send_message("Q3", "c@other.example", &["b@example.com"], &message)
+            .await
+            .unwrap();
+        assert!(dir.path().join("c@other.example.pem").exists());
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_harvest_require_auth() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["b@example.com".into()]);
+        settings.harvest_require_auth = vec![authentication_results::Method::Dmarc];
+        settings.authserv_ids = vec!["mx.example.com".into()];
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+
+        let (cert, key) = self_signed_identity("a@partner.example");
+        let signed = signed_message(&cert, &key, "a@partner.example", "Hello there.");
+        let with_results = |value: &str| {
+            [
+                format!("Authentication-Results: {}\r\n", value).as_bytes(),
+                &signed,
+            ]
+            .concat()
+        };
+        let stored = dir.path().join("a@partner.example.pem");
+
+        for (queue_id, message) in [
+            ("Q1", signed.clone()),
+            (
+                "Q2",
+                with_results("other.example; dmarc=pass header.from=partner.example"),
+            ),
+            (
+                "Q3",
+                with_results("mx.example.com; dmarc=fail header.from=partner.example"),
+            ),
+        ] {
+            let outcome = client
+                .send_message(queue_id, "a@partner.example", &["b@example.com"], &message)
+                .await
+                .unwrap();
+            assert_eq!(outcome.response, Some(Response::Accept));
+            assert!(!stored.exists(), "{}", queue_id);
+        }
+
+        let message = with_results("mx.example.com; dmarc=pass header.from=partner.example");
+        client
+            .send_message("Q4", "a@partner.example", &["b@example.com"], &message)
+            .await
+            .unwrap();
+        assert!(stored.exists());
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_harvest_from_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["b@example.com".into()]);
+        settings.harvest_from_header = FromHeaderMatch::SameAddress;
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+
+        let (cert, key) = self_signed_identity("a@partner.example");
+        let stored = dir.path().join("a@partner.example.pem");
+        for (queue_id, from) in [
+            ("Q1", "ceo@partner.example"),
+            ("Q2", "\"a@partner.example\" <ceo@partner.example>"),
+        ] {
+            let message = signed_message(&cert, &key, from, "Hello there.");
+            let outcome = client
+                .send_message(queue_id, "a@partner.example", &["b@example.com"], &message)
+                .await
+                .unwrap();
+            assert_eq!(outcome.response, Some(Response::Accept));
+            assert!(!stored.exists(), "{}", queue_id);
+        }
+
+        let message = signed_message(&cert, &key, "A <a@Partner.Example>", "Hello there.");
+        client
+            .send_message("Q3", "a@partner.example", &["b@example.com"], &message)
+            .await
+            .unwrap();
+        assert!(stored.exists());
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = self_signed_identity("a@example.com");
+        let signed = signed_message(&cert, &key, "a@example.com", "Hello there.");
+        let (cert, _) = self_signed_identity("b@example.com");
+        smime::write_pem_stack([&cert], &dir.path().join("b@example.com.pem"))
+            .await
+            .unwrap();
+        let connect = |mode| {
+            let mut settings =
+                Settings::new(dir.path().to_path_buf(), vec!["c@example.com".into()]);
+            settings.mode = mode;
+            async move {
+                let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+                MilterClient::connect(addr).await.unwrap()
+            }
+        };
+
+        let mut client = connect(Mode::EncryptOnly).await;
+        let outcome = client
+            .send_message("Q1", "a@example.com", &["c@example.com"], &signed)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert!(outcome.actions.is_empty());
+        assert!(!dir.path().join("a@example.com.pem").exists());
+        let outcome = client
+            .send_message("Q2", "c@example.com", &["b@example.com"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert!(outcome.body().is_some());
+        client.quit().await.unwrap();
+
+        let mut client = connect(Mode::HarvestOnly).await;
+        let outcome = client
+            .send_message("Q3", "c@example.com", &["b@example.com"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert!(outcome.actions.is_empty());
+        let outcome = client
+            .send_message("Q4", "a@example.com", &["c@example.com"], &signed)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert!(dir.path().join("a@example.com.pem").exists());
+        client.quit().await.unwrap();
+
+        // A listener bound to a mode overrides the one of the instance.
+        let settings = Settings::new(dir.path().to_path_buf(), vec!["c@example.com".into()]);
+        let callbacks = assemble_callbacks_with_mode(Arc::new(settings), Mode::HarvestOnly);
+        let mut client = MilterClient::connect(spawn_milter(callbacks).await)
+            .await
+            .unwrap();
+        let outcome = client
+            .send_message("Q5", "c@example.com", &["b@example.com"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert!(outcome.actions.is_empty());
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_empty_body() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = TestCa::new("Test CA");
+        let (cert, key) = ca.issue("b@example.com");
+        smime::write_pem_stack([&cert, &ca.cert], &dir.path().join("b@example.com.pem"))
+            .await
+            .unwrap();
+        let header_only = b"From: a@example.com\r\nTo: b@example.com\r\n\
+            Subject: Canceled: Meeting\r\nContent-Type: text/plain\r\n";
+
+        let mut client = connect_milter(dir.path(), &["a@example.com"]).await;
+        let outcome = client
+            .send_message("Q1", "a@example.com", &["b@example.com"], header_only)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        let inner = smime::decrypt_data(&smime_body_der(&outcome), &cert, &key).unwrap();
+        assert_eq!(inner, b"Content-Type: text/plain\r\n\r\n");
+        client.quit().await.unwrap();
+
+        // Announcing a signature, but without any body to find it in.
+        let header_only = b"From: a@example.com\r\nTo: b@example.com\r\n\
+            Content-Type: multipart/signed; protocol=\"application/pkcs7-signature\"; \
+            boundary=x\r\n\r\n";
+        let mut client = connect_milter(dir.path(), &["b@example.com"]).await;
+        let outcome = client
+            .send_message("Q2", "a@example.com", &["b@example.com"], header_only)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert_eq!(outcome.actions.len(), 1);
+        assert_eq!(outcome.header("X-PANTOSMIME"), Some("SKIPPED_NOT_SIGNED"));
+        assert!(!dir.path().join("a@example.com.pem").exists());
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_harvest_key_preference() {
+        use crate::cert_store::CertCache;
+        use crate::smime_attributes::CertMetadata;
+        use crate::test_pki::{
+            key_preference_attribute, self_signed_signing_identity, signed_message_with_attributes,
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let (signing, _) = self_signed_signing_identity("a@example.com");
+        let (encryption, _) = self_signed_identity("a@example.com");
+        let message = signed_message_with_attributes(
+            &[&signing, &encryption],
+            &signing,
+            &key_preference_attribute(&encryption),
+            "a@example.com",
+            "Hello there.",
+        );
+
+        let mut client = connect_milter(dir.path(), &["b@example.com"]).await;
+        let outcome = client
+            .send_message("Q4", "a@example.com", &["b@example.com"], &message)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+
+        let metadata = CertMetadata::load(&CertMetadata::path(dir.path(), "a@example.com"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            metadata.encryption_certificate,
+            Some(event_report::fingerprint(&encryption))
+        );
+        assert_eq!(
+            metadata.usage[&event_report::fingerprint(&signing)],
+            ["signing"]
+        );
+        let harvested_from = metadata.harvested_from.unwrap();
+        assert_eq!(harvested_from.queue_id, "Q4");
+        assert_eq!(
+            harvested_from.message_id.as_deref(),
+            Some("<signed@test.example>")
+        );
+        let cert = CertCache::default()
+            .lookup(dir.path(), "a@example.com")
+            .await
+            .unwrap()
+            .0;
+        assert_eq!(cert, encryption);
+    }
+
+    #[tokio::test]
+    async fn test_flow_cert_dir_override() {
+        let dir = tempfile::tempdir().unwrap();
+        let tenant_dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings::new(
+            dir.path().to_path_buf(),
+            vec!["b@example.com".into(), "*@tenant.test".into()],
+        );
+        settings.cert_dir_overrides = vec![("*@tenant.test".into(), tenant_dir.path().into())];
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+
+        // Harvested for a tenant only ends up in the tenant directory.
+        let (cert, key) = self_signed_identity("a@example.com");
+        let message = signed_message(&cert, &key, "a@example.com", "Hello there.");
+        let outcome = client
+            .send_message("Q1", "a@example.com", &["t@tenant.test"], &message)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert!(tenant_dir.path().join("a@example.com.pem").exists());
+        assert!(!dir.path().join("a@example.com.pem").exists());
+
+        // And is used when the tenant sends mail.
+        let outcome = client
+            .send_message("Q2", "t@tenant.test", &["a@example.com"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert!(outcome.body().is_some());
+
+        // Other senders don't get to use it.
+        let outcome = client
+            .send_message("Q3", "b@example.com", &["a@example.com"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert!(
+            matches!(&outcome.response, Some(Response::ReplyCode(reply)) if reply.starts_with("550 "))
+        );
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_enroll() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["b@example.com".into()]);
+        settings.enrollment_addresses = vec!["smime-keys@example.com".into()];
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+        let mut client = MilterClient::connect(addr).await.unwrap();
+
+        let (cert, _) = self_signed_identity("a@example.org");
+        let (other, _) = self_signed_identity("c@example.org");
+        let message = certs_only_message(&[&other], "a@example.org", "smime-keys@example.com");
+        let outcome = client
+            .send_message("Q1", "a@example.org", &["smime-keys@example.com"], &message)
+            .await
+            .unwrap();
+        assert!(
+            matches!(&outcome.response, Some(Response::ReplyCode(reply)) if reply.starts_with("550 5.7.0 No S/MIME encryption certificate"))
+        );
+        assert!(!dir.path().join("a@example.org.pem").exists());
+
+        let message = certs_only_message(&[&cert], "a@example.org", "smime-keys@example.com");
+        let outcome = client
+            .send_message("Q2", "a@example.org", &["smime-keys@example.com"], &message)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Discard));
+        let stored = smime::load_pem_stack(dir.path().join("a@example.org.pem"))
+            .await
+            .unwrap();
+        assert_eq!(stored, [cert]);
+        client.quit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_harvest_unsigned() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = connect_milter(dir.path(), &["b@example.com"]).await;
+        let outcome = client
+            .send_message("Q5", "a@example.com", &["b@example.com"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert_eq!(outcome.actions.len(), 1);
+        assert_eq!(outcome.header("X-PANTOSMIME"), Some("SKIPPED_NOT_SIGNED"));
+        assert!(!dir.path().join("a@example.com.pem").exists());
+    }
+
+    #[tokio::test]
+    async fn test_flow_null_sender() {
+        let dir = tempfile::tempdir().unwrap();
+        // The client wraps the empty sender into the null reverse-path `<>`, which not even a
+        // pattern matching anything makes responsible.
+        let mut client = connect_milter(dir.path(), &["*"]).await;
+        let outcome = client
+            .send_message("Q7", "", &["b@example.com"], DELIVERY_REPORT)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert_eq!(outcome.header("X-PANTOSMIME"), Some("SKIPPED_NOT_SIGNED"));
+
+        let (cert, key) = self_signed_identity("a@example.com");
+        let message = signed_message(&cert, &key, "a@example.com", "Delivered.");
+        let outcome = client
+            .send_message("Q7", "", &["b@example.com"], &message)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+        assert_eq!(outcome.header("X-PANTOSMIME"), Some("SKIPPED_NULL_SENDER"));
+        assert!(std::fs::read_dir(dir.path()).unwrap().next().is_none());
+
+        let outcome = client
+            .send_message("Q7", "a@example.com", &[""], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Reject));
+    }
+
+    #[tokio::test]
+    async fn test_flow_harvest_broken_signature() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = connect_milter(dir.path(), &["b@example.com"]).await;
+        let outcome = client
+            .send_message(
+                "Q6",
+                "a@example.com",
+                &["b@example.com"],
+                MULTIPART_MATRYOSHKA,
+            )
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Reject));
+        assert!(outcome.actions.is_empty());
     }
 }
@@ -0,0 +1,413 @@
+//! Minimal milter protocol client for driving the callbacks in-process.
+//!
+//! Speaks just enough of the sendmail milter protocol (version 6) to play the
+//! MTA side of a single connection: negotiation, macros, the SMTP stages and
+//! collection of the modification actions sent back at end of message. Used by
+//! the `replay` subcommand and the end-to-end tests.
+
+#![cfg_attr(not(feature = "replay"), allow(dead_code))]
+
+use anyhow::{anyhow, bail, Context, Result};
+use indymilter::Callbacks;
+use std::fmt;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Protocol version we claim to speak.
+const MILTER_VERSION: u32 = 6;
+/// All actions an MTA usually offers.
+const ALL_ACTIONS: u32 = 0x1ff;
+/// All protocol steps an MTA usually offers.
+const ALL_PROTOCOL_OPTS: u32 = 0x1f_ffff;
+/// Protocol steps the milter can ask to leave out.
+const NO_CONNECT: u32 = 0x1;
+const NO_HELO: u32 = 0x2;
+const NO_DATA: u32 = 0x200;
+/// Maximum size of a single body chunk.
+const MAX_BODY_CHUNK: usize = 65535;
+
+/// Final response of the milter to a command.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    Continue,
+    Accept,
+    Reject,
+    Tempfail,
+    Discard,
+    Skip,
+    ReplyCode(String),
+}
+
+/// Modification requested by the milter at end of message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    AddHeader(String, String),
+    InsertHeader(u32, String, String),
+    ChangeHeader(u32, String, Option<String>),
+    ReplaceBody(Vec<u8>),
+    AddRecipient(String),
+    DeleteRecipient(String),
+    ChangeSender(String),
+    Quarantine(String),
+}
+
+/// Everything the milter answered for a single message.
+#[derive(Debug, Default)]
+pub struct Outcome {
+    pub response: Option<Response>,
+    pub actions: Vec<Action>,
+}
+
+impl Outcome {
+    /// Value of the last header added or changed with the given name.
+    #[cfg(test)]
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.actions.iter().rev().find_map(|a| match a {
+            Action::AddHeader(n, v) | Action::InsertHeader(_, n, v)
+                if n.eq_ignore_ascii_case(name) =>
+            {
+                Some(v.as_str())
+            }
+            Action::ChangeHeader(_, n, v) if n.eq_ignore_ascii_case(name) => v.as_deref(),
+            _ => None,
+        })
+    }
+
+    /// Concatenation of all replaced body chunks, if the body was replaced.
+    pub fn body(&self) -> Option<Vec<u8>> {
+        let chunks: Vec<&Vec<u8>> = self
+            .actions
+            .iter()
+            .filter_map(|a| match a {
+                Action::ReplaceBody(chunk) => Some(chunk),
+                _ => None,
+            })
+            .collect();
+        (!chunks.is_empty()).then(|| chunks.into_iter().flatten().copied().collect())
+    }
+
+    /// Apply the header and body modifications to the original message the
+    /// way an MTA would, returning the message as it would be delivered.
+    pub fn apply(&self, raw: &[u8]) -> Vec<u8> {
+        let (mut headers, body) = split_message(raw);
+        for action in &self.actions {
+            match action {
+                Action::AddHeader(name, value) => headers.push((name.clone(), value.clone())),
+                Action::InsertHeader(index, name, value) => {
+                    let index = (*index as usize).min(headers.len());
+                    headers.insert(index, (name.clone(), value.clone()));
+                }
+                Action::ChangeHeader(index, name, value) => {
+                    let position = headers
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, (n, _))| n.eq_ignore_ascii_case(name))
+                        .nth((*index as usize).saturating_sub(1))
+                        .map(|(i, _)| i);
+                    match (position, value) {
+                        (Some(i), Some(value)) => headers[i].1 = value.clone(),
+                        (Some(i), None) => {
+                            headers.remove(i);
+                        }
+                        (None, Some(value)) => headers.push((name.clone(), value.clone())),
+                        (None, None) => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut message = Vec::with_capacity(raw.len());
+        for (name, value) in &headers {
+            message.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        message.extend_from_slice(b"\r\n");
+        match self.body() {
+            Some(replaced) => message.extend_from_slice(&replaced),
+            None => message.extend_from_slice(body),
+        }
+        message
+    }
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Response::Continue => write!(f, "continue"),
+            Response::Accept => write!(f, "accept"),
+            Response::Reject => write!(f, "reject"),
+            Response::Tempfail => write!(f, "tempfail"),
+            Response::Discard => write!(f, "discard"),
+            Response::Skip => write!(f, "skip"),
+            Response::ReplyCode(reply) => write!(f, "reply {}", reply),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::AddHeader(name, value) => write!(f, "add header {}: {}", name, value),
+            Action::InsertHeader(index, name, value) => {
+                write!(f, "insert header #{} {}: {}", index, name, value)
+            }
+            Action::ChangeHeader(index, name, Some(value)) => {
+                write!(f, "change header {}[{}]: {}", name, index, value)
+            }
+            Action::ChangeHeader(index, name, None) => {
+                write!(f, "delete header {}[{}]", name, index)
+            }
+            Action::ReplaceBody(chunk) => write!(f, "replace body ({} bytes)", chunk.len()),
+            Action::AddRecipient(rcpt) => write!(f, "add recipient {}", rcpt),
+            Action::DeleteRecipient(rcpt) => write!(f, "delete recipient {}", rcpt),
+            Action::ChangeSender(sender) => write!(f, "change sender {}", sender),
+            Action::Quarantine(reason) => write!(f, "quarantine: {}", reason),
+        }
+    }
+}
+
+/// Bind a milter with the given callbacks to an ephemeral local port.
+pub async fn spawn_milter<T: Send + 'static>(callbacks: Callbacks<T>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("cannot bind test milter");
+    let addr = listener.local_addr().expect("no local address");
+    tokio::spawn(indymilter::run(
+        listener,
+        callbacks,
+        Default::default(),
+        std::future::pending::<()>(),
+    ));
+    addr
+}
+
+fn cstrings(parts: &[&str]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for part in parts {
+        out.extend_from_slice(part.as_bytes());
+        out.push(0);
+    }
+    out
+}
+
+fn split_cstrings(data: &[u8]) -> Vec<String> {
+    data.strip_suffix(b"\0")
+        .unwrap_or(data)
+        .split(|b| *b == 0)
+        .map(|p| String::from_utf8_lossy(p).into_owned())
+        .collect()
+}
+
+fn read_u32(data: &[u8]) -> Result<u32> {
+    Ok(u32::from_be_bytes(
+        data.get(..4)
+            .ok_or_else(|| anyhow!("Packet too short"))?
+            .try_into()?,
+    ))
+}
+
+/// Split a raw RFC 5322 message into unfolded headers and the body.
+pub fn split_message(raw: &[u8]) -> (Vec<(String, String)>, &[u8]) {
+    let mut headers: Vec<(String, String)> = Vec::new();
+    let mut rest = raw;
+    while !rest.is_empty() {
+        let end = rest
+            .iter()
+            .position(|b| *b == b'\n')
+            .map_or(rest.len(), |p| p + 1);
+        let (line, remaining) = rest.split_at(end);
+        rest = remaining;
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push_str("\r\n");
+                value.push_str(line);
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.to_string(), value.trim_start().to_string()));
+        }
+    }
+    (headers, rest)
+}
+
+/// MTA side of a milter connection.
+pub struct MilterClient {
+    stream: TcpStream,
+    /// ESMTP parameters sent along with MAIL FROM, like `SIZE=1234`.
+    pub mail_args: Vec<String>,
+    /// Protocol options the milter asked for in the negotiation.
+    pub protocol_opts: u32,
+}
+
+impl MilterClient {
+    /// Connect and negotiate with the milter at the given address.
+    pub async fn connect(addr: SocketAddr) -> Result<Self> {
+        let stream = TcpStream::connect(addr)
+            .await
+            .with_context(|| format!("Failed to connect to milter at {}", addr))?;
+        let mut client = Self {
+            stream,
+            mail_args: Vec::new(),
+            protocol_opts: 0,
+        };
+
+        let mut negotiate = Vec::new();
+        negotiate.extend(MILTER_VERSION.to_be_bytes());
+        negotiate.extend(ALL_ACTIONS.to_be_bytes());
+        negotiate.extend(ALL_PROTOCOL_OPTS.to_be_bytes());
+        client.send(b'O', &negotiate).await?;
+        let (cmd, reply) = client.recv().await?;
+        if cmd != b'O' {
+            bail!("Unexpected negotiation reply {:?}", cmd as char);
+        }
+        client.protocol_opts = read_u32(reply.get(8..).unwrap_or_default())?;
+
+        if client.protocol_opts & NO_CONNECT == 0 {
+            client
+                .macros(b'C', &[("j", "mx.test"), ("{daemon_name}", "test")])
+                .await?;
+            let mut connect = cstrings(&["client.test"]);
+            connect.push(b'4');
+            connect.extend(25u16.to_be_bytes());
+            connect.extend(cstrings(&["127.0.0.1"]));
+            client.command(b'C', &connect).await?;
+        }
+        if client.protocol_opts & NO_HELO == 0 {
+            client.command(b'H', &cstrings(&["client.test"])).await?;
+        }
+        Ok(client)
+    }
+
+    async fn send(&mut self, cmd: u8, data: &[u8]) -> Result<()> {
+        let mut packet = Vec::with_capacity(data.len() + 5);
+        packet.extend(((data.len() + 1) as u32).to_be_bytes());
+        packet.push(cmd);
+        packet.extend_from_slice(data);
+        self.stream
+            .write_all(&packet)
+            .await
+            .with_context(|| "Failed to send milter packet")
+    }
+
+    async fn recv(&mut self) -> Result<(u8, Vec<u8>)> {
+        let len = self.stream.read_u32().await? as usize;
+        if len == 0 {
+            bail!("Received empty milter packet");
+        }
+        let mut data = vec![0; len];
+        self.stream.read_exact(&mut data).await?;
+        let cmd = data.remove(0);
+        Ok((cmd, data))
+    }
+
+    /// Read packets until a final response, collecting actions on the way.
+    async fn response(&mut self, actions: &mut Vec<Action>) -> Result<Response> {
+        loop {
+            let (cmd, data) = self.recv().await?;
+            let strings = || split_cstrings(&data);
+            match cmd {
+                b'c' => return Ok(Response::Continue),
+                b'a' => return Ok(Response::Accept),
+                b'r' => return Ok(Response::Reject),
+                b't' => return Ok(Response::Tempfail),
+                b'd' => return Ok(Response::Discard),
+                b's' => return Ok(Response::Skip),
+                b'y' => return Ok(Response::ReplyCode(strings().join(""))),
+                b'p' => continue,
+                b'h' => {
+                    let s = strings();
+                    actions.push(Action::AddHeader(s[0].clone(), s[1].clone()));
+                }
+                b'i' | b'm' => {
+                    let index = read_u32(&data)?;
+                    let mut s = split_cstrings(&data[4..]).into_iter();
+                    let name = s.next().unwrap_or_default();
+                    let value = s.next().filter(|v| !v.is_empty());
+                    actions.push(if cmd == b'i' {
+                        Action::InsertHeader(index, name, value.unwrap_or_default())
+                    } else {
+                        Action::ChangeHeader(index, name, value)
+                    });
+                }
+                b'b' => actions.push(Action::ReplaceBody(data)),
+                b'+' => actions.push(Action::AddRecipient(strings().remove(0))),
+                b'-' => actions.push(Action::DeleteRecipient(strings().remove(0))),
+                b'e' => actions.push(Action::ChangeSender(strings().remove(0))),
+                b'q' => actions.push(Action::Quarantine(strings().remove(0))),
+                other => bail!("Unexpected milter reply {:?}", other as char),
+            }
+        }
+    }
+
+    /// Send a command and wait for its response.
+    pub async fn command(&mut self, cmd: u8, data: &[u8]) -> Result<Response> {
+        self.send(cmd, data).await?;
+        self.response(&mut Vec::new()).await
+    }
+
+    /// Define macros for the given stage command.
+    pub async fn macros(&mut self, stage: u8, macros: &[(&str, &str)]) -> Result<()> {
+        let mut data = vec![stage];
+        for (name, value) in macros {
+            data.extend(cstrings(&[name, value]));
+        }
+        self.send(b'D', &data).await
+    }
+
+    /// Run a complete message transaction, stopping at the first response
+    /// other than Continue just like an MTA would.
+    pub async fn send_message(
+        &mut self,
+        queue_id: &str,
+        sender: &str,
+        recipients: &[&str],
+        raw: &[u8],
+    ) -> Result<Outcome> {
+        let mut outcome = Outcome::default();
+        let (headers, body) = split_message(raw);
+
+        macro_rules! step {
+            ($cmd:expr, $data:expr) => {
+                match self.command($cmd, $data).await? {
+                    Response::Continue => {}
+                    other => {
+                        outcome.response = Some(other);
+                        return Ok(outcome);
+                    }
+                }
+            };
+        }
+
+        self.macros(b'M', &[("i", queue_id)]).await?;
+        let sender = format!("<{}>", sender);
+        let mut mail = vec![sender.as_str()];
+        mail.extend(self.mail_args.iter().map(String::as_str));
+        step!(b'M', &cstrings(&mail));
+        for rcpt in recipients {
+            step!(b'R', &cstrings(&[&format!("<{}>", rcpt)]));
+        }
+        if self.protocol_opts & NO_DATA == 0 {
+            step!(b'T', &[]);
+        }
+        for (name, value) in &headers {
+            step!(b'L', &cstrings(&[name, value]));
+        }
+        step!(b'N', &[]);
+        for chunk in body.chunks(MAX_BODY_CHUNK) {
+            step!(b'B', chunk);
+        }
+        self.send(b'E', &[]).await?;
+        outcome.response = Some(self.response(&mut outcome.actions).await?);
+        Ok(outcome)
+    }
+
+    /// Politely close the connection.
+    pub async fn quit(mut self) -> Result<()> {
+        self.send(b'Q', &[]).await
+    }
+}
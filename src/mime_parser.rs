@@ -7,7 +7,25 @@ use nom::{
     IResult,
 };
 use std::borrow::Cow;
-use uuid::Uuid;
+
+use crate::deterministic;
+
+/// Most multiparts nested in each other, deeper structures are refused instead of exhausting
+/// the stack. Real clients stay well below it.
+pub const MAX_DEPTH: usize = 32;
+
+/// Most parameters of a header value that are looked at. Clients have been seen sending
+/// hundreds; later ones are ignored rather than searched.
+pub const MAX_PARAMETERS: usize = 1024;
+
+/// Longest line a header is written with, as RFC 5322 requires. Longer ones are folded.
+pub const MAX_LINE_LENGTH: usize = 998;
+
+/// Length lines of a folded header are kept to, where its whitespace allows.
+const FOLD_LENGTH: usize = 78;
+
+/// A single header as (name, value) pair.
+pub type Header<'a> = (Cow<'a, str>, Cow<'a, str>);
 
 /// A MIME container holds a list of headers (in order), a body (preamble or full body)
 /// and, in the case of multipart messages, a list of parts.
@@ -49,7 +67,7 @@ fn line_ending_custom(input: &str) -> IResult<&str, &str> {
 }
 
 /// Parse all headers until an empty line is encountered.
-fn parse_headers(input: &str) -> IResult<&str, Vec<(Cow<'_, str>, Cow<'_, str>)>> {
+fn parse_headers(input: &str) -> IResult<&str, Vec<Header<'_>>> {
     let mut headers = Vec::new();
     let mut input = input;
     loop {
@@ -58,6 +76,10 @@ fn parse_headers(input: &str) -> IResult<&str, Vec<(Cow<'_, str>, Cow<'_, str>)>
             input = remaining;
             break;
         }
+        // So does the end of input, e.g. for a part with an empty body.
+        if input.is_empty() {
+            break;
+        }
         let (remaining, header) = parse_header(input)?;
         headers.push(header);
         input = remaining;
@@ -66,38 +88,197 @@ fn parse_headers(input: &str) -> IResult<&str, Vec<(Cow<'_, str>, Cow<'_, str>)>
 }
 
 /// Retrieve the Content-Type header value (case-insensitive).
-fn get_content_type<'a>(headers: &'a [(Cow<str>, Cow<str>)]) -> Option<String> {
+fn get_content_type(headers: &[(Cow<str>, Cow<str>)]) -> Option<String> {
     headers
         .iter()
         .find(|(name, _)| name.eq_ignore_ascii_case("Content-Type"))
         .map(|(_, value)| value.to_string())
 }
 
+/// The parameters of a structured header value like `multipart/mixed; boundary="b1"`, in
+/// order, with quoted values unquoted. Made in a single pass, so huge values cost no more
+/// than reading them.
+fn parameters(value: &str) -> impl Iterator<Item = (&str, Cow<'_, str>)> {
+    let mut rest = value.find(';').map_or("", |start| &value[start..]);
+    std::iter::from_fn(move || loop {
+        rest = rest.trim_start_matches(|c: char| c == ';' || c.is_whitespace());
+        if rest.is_empty() {
+            return None;
+        }
+        let end = rest.find(['=', ';']).unwrap_or(rest.len());
+        let name = rest[..end].trim();
+        rest = &rest[end..];
+        let Some(after) = rest.strip_prefix('=') else {
+            continue;
+        };
+        let after = after.trim_start();
+        let Some(quoted) = after.strip_prefix('"') else {
+            let end = after.find(';').unwrap_or(after.len());
+            rest = &after[end..];
+            return Some((name, Cow::Borrowed(after[..end].trim())));
+        };
+        let mut escaped = false;
+        let end = quoted
+            .find(|c| {
+                let closing = c == '"' && !escaped;
+                escaped = c == '\\' && !escaped;
+                closing
+            })
+            .unwrap_or(quoted.len());
+        rest = quoted.get(end + 1..).unwrap_or("");
+        let value = &quoted[..end];
+        return Some((
+            name,
+            match value.contains('\\') {
+                true => Cow::Owned(unescape(value)),
+                false => Cow::Borrowed(value),
+            },
+        ));
+    })
+    .take(MAX_PARAMETERS)
+}
+
+/// Remove the backslashes of quoted pairs.
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.extend(chars.next()),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Decode the `%XX` escapes of an RFC 2231 extended value, after its charset and language.
+fn decode_extended(value: &str, first: bool) -> String {
+    let value = match first {
+        true => value.splitn(3, '\'').nth(2).unwrap_or(value),
+        false => value,
+    };
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let hex = tail.get(..2).and_then(|hex| std::str::from_utf8(hex).ok());
+        match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(decoded) if b == b'%' => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// The value of parameter `name` of a structured header value, case-insensitively, also when
+/// split into RFC 2231 continuations like `boundary*0="..."; boundary*1="..."`.
+pub fn parameter<'v>(value: &'v str, name: &str) -> Option<Cow<'v, str>> {
+    let mut sections: Vec<(usize, bool, Cow<'v, str>)> = Vec::new();
+    for (key, value) in parameters(value) {
+        let Some(suffix) = key
+            .get(..name.len())
+            .filter(|prefix| prefix.eq_ignore_ascii_case(name))
+            .map(|_| &key[name.len()..])
+        else {
+            continue;
+        };
+        if suffix.is_empty() {
+            return Some(value);
+        }
+        if suffix == "*" {
+            return Some(Cow::Owned(decode_extended(&value, true)));
+        }
+        let Some(section) = suffix.strip_prefix('*') else {
+            continue;
+        };
+        let (section, extended) = match section.strip_suffix('*') {
+            Some(section) => (section, true),
+            None => (section, false),
+        };
+        if let Ok(number) = section.parse() {
+            sections.push((number, extended, value));
+        }
+    }
+    sections.sort_by_key(|(number, _, _)| *number);
+    let contiguous = sections
+        .iter()
+        .enumerate()
+        .take_while(|(index, (number, _, _))| index == number);
+    let mut joined = String::new();
+    for (index, (_, extended, value)) in contiguous {
+        match extended {
+            true => joined.push_str(&decode_extended(value, index == 0)),
+            false => joined.push_str(value),
+        }
+    }
+    (!sections.is_empty() && sections[0].0 == 0).then_some(Cow::Owned(joined))
+}
+
 /// Extract the boundary parameter from a Content-Type header value.
-fn extract_boundary(content_type: &str) -> Option<&str> {
-    let lower = content_type.to_ascii_lowercase();
-    if let Some(pos) = lower.find("boundary=") {
-        // TODO: this is probably way too naive.
-        let after = &content_type[pos + "boundary=".len()..];
-        let boundary = after.trim().trim_matches(|c| c == '"' || c == '\'');
-        let boundary = boundary
-            .split(|c| c == '"' || c == ';' || c == ' ')
-            .next()
-            .unwrap_or(boundary);
-        Some(boundary)
-    } else {
-        None
+fn extract_boundary(content_type: &str) -> Option<Cow<'_, str>> {
+    parameter(content_type, "boundary").filter(|boundary| !boundary.is_empty())
+}
+
+/// Write a header, turning bare LF line endings into CRLF and folding lines longer than
+/// [`MAX_LINE_LENGTH`] at their whitespace. Other lines are kept as they are.
+pub fn write_header(out: &mut String, name: &str, value: &str) {
+    out.push_str(name);
+    out.push_str(": ");
+    for (index, line) in value.split('\n').enumerate() {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        let indent = match index {
+            0 => name.len() + 2,
+            _ => {
+                out.push_str("\r\n");
+                0
+            }
+        };
+        if indent + line.len() <= MAX_LINE_LENGTH {
+            out.push_str(line);
+            continue;
+        }
+        // Break before whitespace following other characters, so unfolding restores the line.
+        let mut start = 0;
+        let mut column = indent;
+        let mut fold = None;
+        let breaks = line
+            .char_indices()
+            .filter(|(position, c)| {
+                c.is_whitespace()
+                    && *position > 0
+                    && !line[..*position].ends_with(char::is_whitespace)
+            })
+            .map(|(position, _)| position)
+            .chain([line.len()]);
+        for position in breaks {
+            if column + position - start > FOLD_LENGTH {
+                if let Some(at) = fold.take() {
+                    out.push_str(&line[start..at]);
+                    out.push_str("\r\n");
+                    start = at;
+                    column = 0;
+                }
+            }
+            fold = Some(position);
+        }
+        out.push_str(&line[start..]);
     }
+    out.push_str("\r\n");
 }
 
 /// Returns the boundary from the headers or generates a new one using a UUID.
 fn get_or_generate_boundary(headers: &[(Cow<str>, Cow<str>)]) -> String {
     if let Some(ct) = get_content_type(headers) {
         if let Some(boundary) = extract_boundary(&ct) {
-            return boundary.to_string();
+            return boundary.into_owned();
         }
     }
-    Uuid::new_v4().to_string()
+    deterministic::uuid().to_string()
 }
 
 fn trim_newline(input: &str) -> &str {
@@ -113,7 +294,14 @@ fn parse_multipart_container<'a>(
     input: &'a str,
     boundary: &str,
     headers: Vec<(Cow<'a, str>, Cow<'a, str>)>,
+    depth: usize,
 ) -> IResult<&'a str, MimeContainer<'a>> {
+    if depth >= MAX_DEPTH {
+        return Err(nom::Err::Failure(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::TooLarge,
+        )));
+    }
     let boundary_marker_string = &format!("\r\n--{}", boundary);
     let boundary_marker = boundary_marker_string.as_str();
     let boundary_marker_only = &boundary_marker[2..];
@@ -155,7 +343,7 @@ fn parse_multipart_container<'a>(
         part_content = trim_newline(part_content);
 
         // Parse the part content as an independent MIME container.
-        let (_, part) = MimeContainer::parse_mime_container(part_content)?;
+        let (_, part) = MimeContainer::parse_mime_container_at(part_content, depth + 1)?;
         parts.push(part);
 
         buf = i;
@@ -184,11 +372,19 @@ impl<'a> MimeContainer<'a> {
     pub fn parse_mime_container_data(
         input: &'a str,
         headers: Vec<(Cow<'a, str>, Cow<'a, str>)>,
+    ) -> IResult<&'a str, MimeContainer<'a>> {
+        Self::parse_mime_container_data_at(input, headers, 0)
+    }
+
+    fn parse_mime_container_data_at(
+        input: &'a str,
+        headers: Vec<(Cow<'a, str>, Cow<'a, str>)>,
+        depth: usize,
     ) -> IResult<&'a str, MimeContainer<'a>> {
         if let Some(ct) = get_content_type(&headers) {
             if ct.to_ascii_lowercase().starts_with("multipart/") {
                 if let Some(boundary) = extract_boundary(&ct) {
-                    return parse_multipart_container(input, boundary, headers);
+                    return parse_multipart_container(input, &boundary, headers, depth);
                 }
             }
         }
@@ -206,8 +402,15 @@ impl<'a> MimeContainer<'a> {
     /// Parse a complete MIME container: headers, then body.
     /// If the message is multipart, delegate to the multipart parser.
     pub fn parse_mime_container(input: &'a str) -> IResult<&'a str, MimeContainer<'a>> {
+        Self::parse_mime_container_at(input, 0)
+    }
+
+    fn parse_mime_container_at(
+        input: &'a str,
+        depth: usize,
+    ) -> IResult<&'a str, MimeContainer<'a>> {
         let (input, headers) = parse_headers(input)?;
-        Self::parse_mime_container_data(input, headers)
+        Self::parse_mime_container_data_at(input, headers, depth)
     }
 
     /// Convert the Container back into MIME message form
@@ -215,10 +418,7 @@ impl<'a> MimeContainer<'a> {
         let mut out = String::new();
         // Serialize headers.
         for (name, value) in &self.headers {
-            out.push_str(name);
-            out.push_str(": ");
-            out.push_str(value);
-            out.push_str("\r\n");
+            write_header(&mut out, name, value);
         }
         out.push_str("\r\n");
 
@@ -298,6 +498,21 @@ mod tests {
         assert_eq!(part2.headers.len(), 2);
         assert!(part2.body.contains("PGh0bWw+CiAgPGhlYWQ+CiAgPC9oZWFkPg"));
     }
+    #[test]
+    fn test_parse_depth_limit() {
+        let nested = |depth: usize| {
+            let mut message = "Content-Type: text/plain\r\n\r\nHello.".to_string();
+            for level in 0..depth {
+                message = format!(
+                    "Content-Type: multipart/mixed; boundary=\"b{level}\"\r\n\r\n--b{level}\r\n{message}\r\n--b{level}--\r\n"
+                );
+            }
+            message
+        };
+        assert!(MimeContainer::parse_mime_container(&nested(MAX_DEPTH)).is_ok());
+        assert!(MimeContainer::parse_mime_container(&nested(MAX_DEPTH + 1)).is_err());
+    }
+
     #[test]
     fn test_parse_matryoshka() {
         let (_remaining, container) =
@@ -391,6 +606,71 @@ mod tests {
             "Serialization does not match original"
         );
     }
+    #[test]
+    fn test_parameter() {
+        let ct = "multipart/mixed; x-boundary=decoy; note=\"boundary=quoted\"; Boundary=\"b\\\"1\"";
+        assert_eq!(parameter(ct, "boundary").as_deref(), Some("b\"1"));
+        assert_eq!(parameter(ct, "note").as_deref(), Some("boundary=quoted"));
+        assert_eq!(parameter(ct, "charset"), None);
+
+        let ct =
+            "multipart/mixed; boundary*1=\"-part\"; boundary*0=\"first\"; boundary*2*=%2Dthird";
+        assert_eq!(
+            parameter(ct, "boundary").as_deref(),
+            Some("first-part-third")
+        );
+        let ct = "text/plain; name*=utf-8'de'Gr%C3%BC%C3%9Fe.txt";
+        assert_eq!(parameter(ct, "name").as_deref(), Some("Grüße.txt"));
+        assert_eq!(parameter("text/plain; name*1=late", "name"), None);
+        assert_eq!(extract_boundary("multipart/mixed; boundary=\"\""), None);
+    }
+
+    #[test]
+    fn test_parse_huge_content_type() {
+        let mut content_type = "multipart/mixed".to_string();
+        for index in 0..MAX_PARAMETERS - 2 {
+            content_type.push_str(&format!(
+                ";\r\n\tx-param-{index}=\"boundary=decoy-{index}\""
+            ));
+        }
+        content_type.push_str(";\r\n\tboundary*0=\"real\";\r\n\tboundary*1=\"-boundary\"");
+        let message = format!(
+            "Content-Type: {content_type}\r\n\r\n--real-boundary\r\nContent-Type: text/plain\r\n\r\nHello.\r\n--real-boundary--\r\n"
+        );
+        let (_remaining, container) = MimeContainer::parse_mime_container(&message).unwrap();
+        assert_eq!(container.parts.len(), 1);
+        assert_eq!(container.parts[0].body, "Hello.");
+        let serialized = container.to_mime_string();
+        assert!(serialized
+            .split("\r\n")
+            .all(|line| line.len() <= MAX_LINE_LENGTH));
+        let (_remaining, reparsed) = MimeContainer::parse_mime_container(&serialized).unwrap();
+        assert_eq!(container, reparsed);
+    }
+
+    #[test]
+    fn test_write_header() {
+        let mut out = String::new();
+        write_header(&mut out, "Subject", "folded\n  before");
+        assert_eq!(out, "Subject: folded\r\n  before\r\n");
+
+        let value = (0..500)
+            .map(|n| format!("word{n}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mut out = String::new();
+        write_header(&mut out, "X-Long", &value);
+        let lines: Vec<&str> = out.strip_suffix("\r\n").unwrap().split("\r\n").collect();
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|line| line.len() <= FOLD_LENGTH));
+        assert_eq!(lines.concat(), format!("X-Long: {value}"));
+
+        // Without whitespace to fold at, the line is kept whole rather than broken up.
+        let mut out = String::new();
+        write_header(&mut out, "X-Token", &"a".repeat(2000));
+        assert_eq!(out.len(), "X-Token: ".len() + 2000 + 2);
+    }
+
     #[ignore]
     #[test]
     fn test_matryoshka_against_original() {
@@ -402,4 +682,128 @@ mod tests {
             "Serialization does not match original"
         );
     }
+
+    mod proptests {
+        use super::*;
+        use proptest::prelude::*;
+
+        fn header_name() -> impl Strategy<Value = String> {
+            "[A-Za-z][A-Za-z0-9_-]{0,20}".prop_filter("Content-Type is set explicitly", |n| {
+                !n.eq_ignore_ascii_case("Content-Type")
+            })
+        }
+
+        /// Header values as the parser yields them: single line, trimmed.
+        fn header_value() -> impl Strategy<Value = String> {
+            "([!-~]([ -~]{0,40}[!-~])?)?"
+        }
+
+        fn headers() -> impl Strategy<Value = Vec<Header<'static>>> {
+            prop::collection::vec(
+                (header_name(), header_value())
+                    .prop_map(|(name, value)| (Cow::Owned(name), Cow::Owned(value))),
+                0..6,
+            )
+        }
+
+        /// Body text that can't be mistaken for a boundary delimiter.
+        fn text() -> impl Strategy<Value = String> {
+            prop::collection::vec("[A-Za-z0-9 .,;:!?]{0,60}", 0..5)
+                .prop_map(|lines| lines.join("\r\n"))
+                .prop_filter("trailing newlines belong to the delimiter", |t| {
+                    !t.ends_with("\r\n")
+                })
+        }
+
+        fn with_content_type(
+            mut headers: Vec<Header<'static>>,
+            position: usize,
+            content_type: String,
+        ) -> Vec<Header<'static>> {
+            let position = position % (headers.len() + 1);
+            headers.insert(
+                position,
+                (Cow::Borrowed("Content-Type"), Cow::Owned(content_type)),
+            );
+            headers
+        }
+
+        /// Give every multipart container a unique boundary that is no prefix of another.
+        fn assign_boundaries(container: &mut MimeContainer<'static>, counter: &mut usize) {
+            if container.parts.is_empty() {
+                return;
+            }
+            *counter += 1;
+            let boundary = format!("=_boundary_{:04}", counter);
+            for (name, value) in container.headers.iter_mut() {
+                if name.eq_ignore_ascii_case("Content-Type") {
+                    *value = Cow::Owned(format!("{}; boundary=\"{}\"", value, boundary));
+                }
+            }
+            for part in container.parts.iter_mut() {
+                assign_boundaries(part, counter);
+            }
+        }
+
+        fn container() -> impl Strategy<Value = MimeContainer<'static>> {
+            let leaf = (
+                headers(),
+                any::<usize>(),
+                "(text|image|application)/[a-z]{1,10}",
+                text(),
+            )
+                .prop_map(|(headers, position, content_type, body)| MimeContainer {
+                    headers: with_content_type(headers, position, content_type),
+                    body: Cow::Owned(body),
+                    parts: Vec::new(),
+                });
+            leaf.prop_recursive(3, 24, 4, |inner| {
+                (
+                    headers(),
+                    any::<usize>(),
+                    "(mixed|alternative|related|signed)",
+                    text(),
+                    prop::collection::vec(inner, 1..4),
+                )
+                    .prop_map(|(headers, position, subtype, preamble, parts)| {
+                        MimeContainer {
+                            headers: with_content_type(
+                                headers,
+                                position,
+                                format!("multipart/{}", subtype),
+                            ),
+                            body: Cow::Owned(preamble),
+                            parts,
+                        }
+                    })
+            })
+            .prop_map(|mut container| {
+                assign_boundaries(&mut container, &mut 0);
+                container
+            })
+        }
+
+        proptest! {
+            #[test]
+            fn prop_serialize_parse_round_trip(container in container()) {
+                let serialized = container.to_mime_string();
+                let (_, parsed) = MimeContainer::parse_mime_container(&serialized)
+                    .map_err(|e| TestCaseError::fail(format!("{:?}", e)))?;
+                prop_assert_eq!(&parsed, &container);
+            }
+
+            #[test]
+            fn prop_parse_serialize_byte_exact(container in container()) {
+                let serialized = container.to_mime_string();
+                let (_, parsed) = MimeContainer::parse_mime_container(&serialized)
+                    .map_err(|e| TestCaseError::fail(format!("{:?}", e)))?;
+                let reserialized = parsed.to_mime_string();
+                prop_assert_eq!(&reserialized, &serialized);
+
+                let (_, reparsed) = MimeContainer::parse_mime_container(&reserialized)
+                    .map_err(|e| TestCaseError::fail(format!("{:?}", e)))?;
+                prop_assert_eq!(reparsed, parsed);
+            }
+        }
+    }
 }
@@ -0,0 +1,219 @@
+//! Telling connections from internal networks apart from external ones, by the
+//! `{client_addr}` and `{client_name}` macros of the MTA, or by the `Received` headers it added
+//! where the macros are unavailable.
+//!
+//! Postfix only sets `{client_name}` to a name whose address resolves back to the client, so
+//! matching it is as safe as matching the address. The same name is in parentheses in its
+//! `Received` header, unlike the HELO name in front of it, which the client chooses.
+
+use std::net::IpAddr;
+
+use crate::address;
+use crate::settings;
+
+/// Where a connection comes from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Origin {
+    Internal,
+    External,
+}
+
+impl Origin {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Origin::Internal => "internal",
+            Origin::External => "external",
+        }
+    }
+}
+
+/// Clients considered internal.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InternalNetwork {
+    /// Addresses sharing the first bits with the network address.
+    Cidr(IpAddr, u8),
+    /// Verified client host names matching a domain pattern.
+    Hosts(String),
+}
+
+impl InternalNetwork {
+    fn contains(&self, addr: Option<IpAddr>, name: Option<&str>) -> bool {
+        match (self, addr, name) {
+            (InternalNetwork::Cidr(network, prefix), Some(addr), _) => {
+                match (network, addr.to_canonical()) {
+                    (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                        prefix_matches(network.to_bits().into(), addr.to_bits().into(), 32, *prefix)
+                    }
+                    (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                        prefix_matches(network.to_bits(), addr.to_bits(), 128, *prefix)
+                    }
+                    _ => false,
+                }
+            }
+            (InternalNetwork::Hosts(pattern), _, Some(name)) => {
+                address::domain_matches(pattern, name.trim_end_matches('.'))
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether the first `prefix` of `bits` bits of both addresses are equal.
+fn prefix_matches(network: u128, addr: u128, bits: u8, prefix: u8) -> bool {
+    let shift = bits - prefix;
+    shift == bits || network >> shift == addr >> shift
+}
+
+/// Parse a network like `10.0.0.0/8` or `2001:db8::/32`, a single address, or a host name
+/// pattern like `*.corp.example.com`.
+pub fn parse_internal_network(s: &str) -> Result<InternalNetwork, String> {
+    let (addr, prefix) = match s.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (s, None),
+    };
+    let Ok(addr) = addr.parse::<IpAddr>() else {
+        return match prefix {
+            Some(_) => Err(format!("invalid network {:?}", s)),
+            None => settings::parse_domain_pattern(s).map(InternalNetwork::Hosts),
+        };
+    };
+    let bits = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => match prefix.parse::<u8>() {
+            Ok(prefix) if prefix <= bits => prefix,
+            _ => return Err(format!("invalid prefix length in {:?}", s)),
+        },
+        None => bits,
+    };
+    Ok(InternalNetwork::Cidr(addr, prefix))
+}
+
+/// Classify a client by the values of its `{client_addr}` and `{client_name}` macros. Without
+/// internal networks, or without either macro, the origin is unknown.
+pub fn classify(
+    internal: &[InternalNetwork],
+    client_addr: Option<&str>,
+    client_name: Option<&str>,
+) -> Option<Origin> {
+    // Sendmail prefixes IPv6 addresses.
+    let addr = client_addr.and_then(|addr| addr.trim_start_matches("IPv6:").parse::<IpAddr>().ok());
+    if internal.is_empty() || (addr.is_none() && client_name.is_none()) {
+        return None;
+    }
+    match internal
+        .iter()
+        .any(|network| network.contains(addr, client_name))
+    {
+        true => Some(Origin::Internal),
+        false => Some(Origin::External),
+    }
+}
+
+/// The client address and verified name in the `from` clause of a `Received` header, like
+/// `from helo.example (mx.example.net [192.0.2.1]) by ...`.
+fn received_client(value: &str) -> Option<(Option<String>, Option<String>)> {
+    let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+    let lower = value.to_ascii_lowercase();
+    if !lower.starts_with("from ") {
+        return None;
+    }
+    let clause = &value[..lower.find(" by ").unwrap_or(value.len())];
+    let addr = clause
+        .split_once('[')
+        .and_then(|(_, rest)| rest.split_once(']'))
+        .map(|(addr, _)| addr.to_string());
+    let name = clause
+        .split_once('(')
+        .and_then(|(_, rest)| rest.split_whitespace().next())
+        .map(|name| name.trim_end_matches(')'))
+        .filter(|name| !name.starts_with('[') && !name.eq_ignore_ascii_case("unknown"))
+        .map(str::to_string);
+    Some((addr, name))
+}
+
+/// Classify a message by its `Received` headers, topmost first, for MTAs not passing the
+/// macros. The first hop not from a loopback address counts, skipping reinjection by local
+/// content filters.
+pub fn classify_received(internal: &[InternalNetwork], received: &[String]) -> Option<Origin> {
+    for value in received {
+        let (addr, name) = received_client(value)?;
+        let loopback = addr
+            .as_deref()
+            .and_then(|addr| addr.trim_start_matches("IPv6:").parse::<IpAddr>().ok())
+            .is_some_and(|addr| addr.to_canonical().is_loopback());
+        if !loopback {
+            return classify(internal, addr.as_deref(), name.as_deref());
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let internal: Vec<InternalNetwork> = ["10.0.0.0/8", "2001:db8::/32", "*.corp.example.com"]
+            .into_iter()
+            .map(|s| parse_internal_network(s).unwrap())
+            .collect();
+        let classify = |addr, name| classify(&internal, addr, name);
+
+        assert_eq!(classify(Some("10.1.2.3"), None), Some(Origin::Internal));
+        assert_eq!(
+            classify(Some("::ffff:10.1.2.3"), None),
+            Some(Origin::Internal)
+        );
+        assert_eq!(
+            classify(Some("IPv6:2001:db8::1"), None),
+            Some(Origin::Internal)
+        );
+        assert_eq!(
+            classify(Some("192.0.2.1"), Some("mail.corp.example.com")),
+            Some(Origin::Internal)
+        );
+        assert_eq!(
+            classify(Some("192.0.2.1"), Some("unknown")),
+            Some(Origin::External)
+        );
+        assert_eq!(classify(Some("11.0.0.1"), None), Some(Origin::External));
+        assert_eq!(classify(None, None), None);
+        assert_eq!(super::classify(&[], Some("10.1.2.3"), None), None);
+
+        assert_eq!(
+            parse_internal_network("0.0.0.0/0"),
+            Ok(InternalNetwork::Cidr("0.0.0.0".parse().unwrap(), 0))
+        );
+        assert!(parse_internal_network("10.0.0.0/33").is_err());
+        assert!(parse_internal_network("corp/8").is_err());
+    }
+
+    #[test]
+    fn test_classify_received() {
+        let internal = [parse_internal_network("10.0.0.0/8").unwrap()];
+        let received = |values: &[&str]| {
+            let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+            classify_received(&internal, &values)
+        };
+
+        let postfix = "from client.corp (client.corp [10.1.2.3])\r\n\tby mx.example.com \
+                       (Postfix) with ESMTPS id 4Bc1x20kLz for <b@example.com>";
+        assert_eq!(received(&[postfix]), Some(Origin::Internal));
+        // A forged HELO name doesn't count.
+        assert_eq!(
+            received(&["from 10.1.2.3 (unknown [192.0.2.1]) by mx.example.com"]),
+            Some(Origin::External)
+        );
+        // Reinjected by a local content filter.
+        assert_eq!(
+            received(&[
+                "from localhost (localhost [127.0.0.1]) by mx.example.com",
+                postfix
+            ]),
+            Some(Origin::Internal)
+        );
+        assert_eq!(received(&["by mx.example.com with local"]), None);
+        assert_eq!(received(&[]), None);
+    }
+}
@@ -0,0 +1,1997 @@
+//! End of message processing as a pipeline of stages.
+//!
+//! Each action runs its own list of stages, usually deciding whether to go on at all,
+//! transforming the MIME content, doing the cryptography and finally emitting the milter
+//! actions. Further features, like signing or archiving, are added as stages of their own.
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use indymilter::{ContextActions, EomActions, IntoCString, SetErrorReply, SmtpReply, Status};
+use openssl::asn1::Asn1Time;
+use openssl::x509::X509;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, info, warn};
+
+use crate::address;
+use crate::authentication_results;
+use crate::cert_store::{self, Expired, StoreLock};
+use crate::cert_usage;
+#[cfg(feature = "chaos")]
+use crate::chaos::{self, Fault};
+use crate::crypto_profile::{self, CryptoProfile, KeyTransport};
+use crate::diagnostics::{self, Diagnosis};
+use crate::event_report::{self, RecipientReport};
+use crate::expiry;
+use crate::harvest_spool;
+use crate::metrics;
+use crate::milter_callbacks::{self, MilterAction, MilterContext};
+use crate::mime_parser::{self, MimeContainer};
+use crate::reinjection;
+use crate::result_header::{self, Reason};
+use crate::settings::{
+    CertFailureAction, FromHeaderMatch, InlinePgpAction, OversizeAction, Settings,
+};
+use crate::smime;
+use crate::smime_attributes::{self, CertMetadata, HarvestedFrom};
+use crate::templates;
+use crate::trust;
+
+/// Whether the next stage runs.
+pub enum Flow {
+    Continue,
+    /// Skip the remaining stages and finish the message with this status.
+    Finish(Status),
+}
+
+/// A message passing through the stages.
+pub struct Message<'m, 'a> {
+    pub ctx: &'m mut MilterContext<'a>,
+    pub settings: &'m Settings,
+    pub actions: &'m EomActions,
+    /// SMTP reply sent along a reject or tempfail status.
+    pub reply: &'m mut SmtpReply,
+    /// Content handed from one stage to the next, like the entity to encrypt.
+    pub content: Vec<u8>,
+    /// Certificates of the recipients, or the harvested chain.
+    pub certs: Vec<X509>,
+    /// What the signature tells about the harvested certificates.
+    pub cert_metadata: CertMetadata,
+    /// Crypto settings the message was encrypted with.
+    pub profile: CryptoProfile,
+    /// Body the message was given in place of the one received, if any.
+    pub replaced_body: Option<Vec<u8>>,
+}
+
+/// One step of processing a message.
+#[async_trait]
+pub trait Stage: Send + Sync {
+    /// Name of the stage, for logging.
+    fn name(&self) -> &'static str;
+
+    /// Stages one of which has to run earlier, as this one works on what it leaves in the
+    /// message.
+    fn needs(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    async fn run(&self, message: &mut Message<'_, '_>) -> Result<Flow>;
+}
+
+/// The stages run in order for one action.
+pub struct Pipeline {
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl Pipeline {
+    pub fn new(stages: Vec<Box<dyn Stage>>) -> Self {
+        Self { stages }
+    }
+
+    /// Encrypt messages from responsible senders.
+    pub fn encrypt() -> Self {
+        Self::new(vec![
+            Box::new(SkipExempt),
+            Box::new(InlinePgp),
+            Box::new(Rollout),
+            Box::new(BuildEntity),
+            Box::new(Encrypt),
+            Box::new(EmitEnvelope),
+            Box::new(MarkProcessed),
+            Box::new(AddResult),
+        ])
+    }
+
+    /// Harvest certificates from signed messages to responsible recipients.
+    pub fn harvest() -> Self {
+        Self::new(vec![
+            Box::new(RequireSignature),
+            Box::new(RequireAuthentication),
+            Box::new(MatchFromHeader),
+            Box::new(ExtractSigners),
+            Box::new(VerifyTrust),
+            Box::new(StoreCertificates),
+            Box::new(MarkProcessed),
+            Box::new(AddResult),
+        ])
+    }
+
+    /// Import the certificates of certs-only messages to enrollment addresses.
+    pub fn enroll() -> Self {
+        Self::new(vec![
+            Box::new(RequireCertsOnly),
+            Box::new(ExtractEnrolled),
+            Box::new(VerifyTrust),
+            Box::new(StoreCertificates),
+            Box::new(AnswerEnrollment),
+        ])
+    }
+
+    /// The stages `action` runs unless configured otherwise.
+    pub fn default_for(action: &MilterAction) -> Self {
+        match action {
+            MilterAction::Encrypt => Self::encrypt(),
+            MilterAction::ExtractKeys => Self::harvest(),
+            MilterAction::Enroll => Self::enroll(),
+        }
+    }
+
+    /// Names of the stages, in the order they run.
+    pub fn stage_names(&self) -> Vec<&'static str> {
+        self.stages.iter().map(|stage| stage.name()).collect()
+    }
+
+    /// The default stages of `action` named in `names`, in that order. Stages can be left out
+    /// or reordered, but not repeated, and only those of `action` are available. A stage
+    /// another one needs can't be left out or come after it.
+    pub fn select(action: &MilterAction, names: &[String]) -> Result<Self, String> {
+        let mut available = Self::default_for(action).stages;
+        let expected = available
+            .iter()
+            .map(|stage| stage.name())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut stages = Vec::new();
+        for name in names {
+            let index = available
+                .iter()
+                .position(|stage| stage.name() == name)
+                .ok_or_else(|| {
+                    format!(
+                        "unknown or repeated {} stage {:?}, expected one of {}",
+                        action.as_str(),
+                        name,
+                        expected
+                    )
+                })?;
+            stages.push(available.remove(index));
+        }
+        for (position, stage) in stages.iter().enumerate() {
+            let needs = stage.needs();
+            if needs.is_empty()
+                || names[..position]
+                    .iter()
+                    .any(|n| needs.contains(&n.as_str()))
+            {
+                continue;
+            }
+            return Err(format!(
+                "{} stage {:?} needs {} before it",
+                action.as_str(),
+                stage.name(),
+                needs.join(" or ")
+            ));
+        }
+        Ok(Self::new(stages))
+    }
+
+    /// Run all stages, rejecting the message if one fails, or deferring it if a schedule asks
+    /// to.
+    pub async fn run(&self, message: &mut Message<'_, '_>) -> Status {
+        for stage in &self.stages {
+            debug!(stage = stage.name(), "Running stage");
+            match stage.run(message).await {
+                Ok(Flow::Continue) => {}
+                Ok(Flow::Finish(status)) => return status,
+                Err(error) => {
+                    let diagnosis = diagnostics::diagnose(&error);
+                    let tempfail = message.settings.failures_tempfail();
+                    error!(
+                        stage = stage.name(),
+                        error_code = diagnosis.map(Diagnosis::code),
+                        hint = diagnosis.map(Diagnosis::hint),
+                        tempfail,
+                        ?error,
+                        "Stage failed; refusing message"
+                    );
+                    message.ctx.report.error = Some(format!("{:#}", error));
+                    message.ctx.report.error_code = diagnosis.map(|d| d.code().to_string());
+                    message.ctx.report.failed_stage = Some(stage.name().to_string());
+                    return match tempfail {
+                        true => Status::Tempfail,
+                        false => Status::Reject,
+                    };
+                }
+            }
+        }
+        Status::Accept
+    }
+}
+
+/// Parse an `<ACTION>=<STAGE>[,<STAGE>...]` pipeline, the action being `encrypt`, `harvest` or
+/// `enroll`, into the action and the names of its stages.
+pub fn parse_pipeline(s: &str) -> Result<(MilterAction, Vec<String>), String> {
+    let (action, stages) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <ACTION>=<STAGE>[,<STAGE>...], got {:?}", s))?;
+    let action = match action {
+        "encrypt" => MilterAction::Encrypt,
+        "harvest" => MilterAction::ExtractKeys,
+        "enroll" => MilterAction::Enroll,
+        other => {
+            return Err(format!(
+                "unknown action {:?}, expected encrypt, harvest or enroll",
+                other
+            ))
+        }
+    };
+    let names: Vec<String> = stages
+        .split(',')
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect();
+    Pipeline::select(&action, &names)?;
+    Ok((action, names))
+}
+
+/// The body as text for the MIME parser. Bodies that are not UTF-8, like binary parts sent
+/// with BINARYMIME, have each byte mapped to the character of the same code point rather than
+/// replaced, so decoding the parts gets the original bytes back.
+pub fn body_text(body: &[u8]) -> Cow<'_, str> {
+    match std::str::from_utf8(body) {
+        Ok(text) => Cow::Borrowed(text),
+        Err(_) => Cow::Owned(body.iter().map(|&b| char::from(b)).collect()),
+    }
+}
+
+/// The content of a part, which is base64 unless it is declared `binary`. `bytewise` tells
+/// whether [`body_text`] mapped the body byte by byte.
+fn decode_part(part: &MimeContainer<'_>, bytewise: bool) -> Result<Vec<u8>> {
+    let binary = part
+        .find_header_value("Content-Transfer-Encoding")
+        .is_some_and(|encoding| encoding.trim().eq_ignore_ascii_case("binary"));
+    if binary {
+        return Ok(match bytewise {
+            true => part.body.chars().map(|c| c as u8).collect(),
+            false => part.body.as_bytes().to_vec(),
+        });
+    }
+    let mut data = part.body.to_string();
+    data.retain(|c| !c.is_whitespace());
+    Ok(BASE64_STANDARD.decode(data.as_bytes())?)
+}
+
+/// Find a part nested anywhere in the message that is exempt from encryption.
+fn find_exempt_part<'c>(
+    container: &'c MimeContainer<'c>,
+    settings: &Settings,
+) -> Option<Cow<'c, str>> {
+    container.parts.iter().find_map(|part| {
+        part.find_header_value("Content-Type")
+            .filter(|content_type| settings.is_exempt(content_type))
+            .or_else(|| find_exempt_part(part, settings))
+    })
+}
+
+/// Tell downstream filters why the message went through as it did, in the `X-PANTOSMIME`
+/// header.
+async fn add_reason(message: &mut Message<'_, '_>, reason: Reason) {
+    if let Err(error) = message
+        .actions
+        .add_header(result_header::REASON_HEADER, reason.code())
+        .await
+    {
+        error!(?error, "Failed adding X-PANTOSMIME header");
+    }
+}
+
+/// Leave messages with parts the recipient processes automatically unencrypted.
+pub struct SkipExempt;
+
+#[async_trait]
+impl Stage for SkipExempt {
+    fn name(&self) -> &'static str {
+        "skip-exempt"
+    }
+
+    async fn run(&self, message: &mut Message<'_, '_>) -> Result<Flow> {
+        // Invitations usually come as an alternative to a readable text.
+        let ctx = &message.ctx;
+        let body_str = body_text(&ctx.body);
+        if let Ok((_, container)) =
+            MimeContainer::parse_mime_container_data(&body_str, ctx.headers.clone())
+        {
+            if let Some(content_type) = find_exempt_part(&container, message.settings) {
+                info!(%content_type, "Message contains a part exempt from encryption; accepting unchanged");
+                add_reason(message, Reason::SkippedExempt).await;
+                return Ok(Flow::Finish(Status::Accept));
+            }
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+/// Armor line starting a classic inline PGP message.
+const PGP_MESSAGE_ARMOR: &[u8] = b"-----BEGIN PGP MESSAGE-----";
+
+/// Whether a line of the body starts an inline PGP message.
+fn contains_inline_pgp(body: &[u8]) -> bool {
+    body.split(|&b| b == b'\n')
+        .any(|line| line.starts_with(PGP_MESSAGE_ARMOR))
+}
+
+/// Apply the configured handling to messages their sender already encrypted with inline PGP,
+/// which an S/MIME envelope around them only makes harder to read.
+pub struct InlinePgp;
+
+#[async_trait]
+impl Stage for InlinePgp {
+    fn name(&self) -> &'static str {
+        "inline-pgp"
+    }
+
+    async fn run(&self, message: &mut Message<'_, '_>) -> Result<Flow> {
+        if !contains_inline_pgp(&message.ctx.body) {
+            return Ok(Flow::Continue);
+        }
+        match message.settings.inline_pgp_action {
+            InlinePgpAction::Skip => {
+                info!("Message is encrypted with inline PGP; accepting unchanged");
+                for recipient in &message.ctx.recipients {
+                    metrics::encryption_fallback(recipient, "inline-pgp");
+                }
+                add_reason(message, Reason::SkippedAlreadyEncrypted).await;
+                Ok(Flow::Finish(Status::Accept))
+            }
+            InlinePgpAction::Warn => {
+                warn!("Message is already encrypted with inline PGP; encrypting it anyway");
+                Ok(Flow::Continue)
+            }
+        }
+    }
+}
+
+/// Header marking a message left unencrypted as it is outside the rollout, naming the
+/// recipients it would have been encrypted for.
+pub const WOULD_ENCRYPT_HEADER: &str = "X-Pantosmime-Would-Encrypt";
+
+/// Bucket from 0 to 99 of a queue ID, the same on every host and run.
+fn rollout_bucket(queue_id: &str) -> u8 {
+    let digest = openssl::sha::sha256(queue_id.as_bytes());
+    (u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) % 100) as u8
+}
+
+/// Leave messages outside `--encrypt-percent` unencrypted, marking them with a header, so
+/// encryption can be rolled out gradually. Messages without a queue ID are always encrypted.
+pub struct Rollout;
+
+#[async_trait]
+impl Stage for Rollout {
+    fn name(&self) -> &'static str {
+        "rollout"
+    }
+
+    async fn run(&self, message: &mut Message<'_, '_>) -> Result<Flow> {
+        let percent = message.settings.encrypt_percent;
+        let Some(queue_id) = message.ctx.queue_id.as_deref().filter(|_| percent < 100) else {
+            return Ok(Flow::Continue);
+        };
+        let required = message
+            .ctx
+            .recipients
+            .iter()
+            .any(|r| message.settings.encryption_required(r));
+        if required || rollout_bucket(queue_id) < percent {
+            return Ok(Flow::Continue);
+        }
+        info!(
+            percent,
+            "Message is outside the rollout; accepting unchanged"
+        );
+        for recipient in &message.ctx.recipients {
+            metrics::encryption_fallback(recipient, "rollout");
+        }
+        message.ctx.report.outside_rollout = true;
+        message
+            .actions
+            .add_header(WOULD_ENCRYPT_HEADER, message.ctx.recipients.join(", "))
+            .await
+            .context("Failed to add the would-encrypt header")?;
+        add_reason(message, Reason::SkippedRollout).await;
+        Ok(Flow::Finish(Status::Accept))
+    }
+}
+
+/// Assemble the MIME entity to be encrypted from the captured headers and the body.
+/// Without a body, the entity is just the headers and the empty line ending them.
+fn build_inner_entity<'h>(
+    headers: impl IntoIterator<Item = (&'h str, &'h str)>,
+    body: &[u8],
+) -> Vec<u8> {
+    let mut head = String::with_capacity(256);
+    for (name, value) in headers
+        .into_iter()
+        .filter(|(name, _)| !name.eq_ignore_ascii_case("MIME-Version"))
+    {
+        mime_parser::write_header(&mut head, name, value);
+    }
+    head.push_str("\r\n");
+    let mut entity = Vec::with_capacity(head.len() + body.len());
+    entity.extend_from_slice(head.as_bytes());
+    entity.extend_from_slice(body);
+    entity
+}
+
+/// Put the content headers together with the body, so the recipient gets back the complete
+/// original MIME entity.
+pub struct BuildEntity;
+
+#[async_trait]
+impl Stage for BuildEntity {
+    fn name(&self) -> &'static str {
+        "build-entity"
+    }
+
+    async fn run(&self, message: &mut Message<'_, '_>) -> Result<Flow> {
+        if message.ctx.body.is_empty() {
+            debug!("Message has no body; encrypting its content headers only");
+        }
+        // Captured address headers go along, for MUAs to show or compare with the outer ones.
+        let ctx = &message.ctx;
+        let protected = ctx
+            .address_headers
+            .iter()
+            .filter(|(name, _)| {
+                message
+                    .settings
+                    .capture_headers
+                    .iter()
+                    .any(|header| header.eq_ignore_ascii_case(name))
+            })
+            .map(|(name, value)| (name.as_str(), value.as_str()));
+        let content = ctx.headers.iter().map(|(name, value)| (&**name, &**value));
+        message.content = build_inner_entity(protected.chain(content), &ctx.body);
+        Ok(Flow::Continue)
+    }
+}
+
+/// Run OpenSSL work on the blocking thread pool, so it never stalls the milter connections,
+/// with at most as many jobs at once as configured.
+async fn run_crypto<T: Send + 'static>(
+    settings: &Settings,
+    work: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    let _permit = match &settings.crypto_jobs {
+        Some(jobs) => Some(jobs.acquire().await?),
+        None => None,
+    };
+    tokio::task::spawn_blocking(work)
+        .await
+        .context("Crypto job failed")?
+}
+
+/// An envelope, or why encryption failed and the recipient it fails for, if any.
+type Encrypted = Result<Vec<u8>, (anyhow::Error, Option<usize>)>;
+
+/// Encrypt `content` on the crypto workers.
+async fn encrypt_content(
+    settings: &Settings,
+    content: &Arc<Vec<u8>>,
+    recipients: &Arc<Vec<(X509, KeyTransport)>>,
+    profile: &CryptoProfile,
+) -> Result<Encrypted> {
+    let content = Arc::clone(content);
+    let recipients = Arc::clone(recipients);
+    let recipient_id = settings.compat.recipient_id;
+    let profile = profile.clone();
+    run_crypto(settings, move || {
+        let compressed;
+        let content = match profile.compress {
+            true => {
+                compressed = crypto_profile::compress_entity(&content)?;
+                &compressed
+            }
+            false => &*content,
+        };
+        Ok(
+            crypto_profile::encrypt(content, &recipients, &profile, recipient_id).map_err(
+                |error| {
+                    // Point at the certificate the encryption fails for on its own.
+                    let culprit = recipients.iter().position(|recipient| {
+                        let to = std::slice::from_ref(recipient);
+                        crypto_profile::encrypt(b"", to, &profile, recipient_id).is_err()
+                    });
+                    (error, culprit)
+                },
+            ),
+        )
+    })
+    .await
+    .context("Failed to encrypt message body")
+}
+
+/// Size of the message once its body is replaced with the envelope, 0 if there is none.
+fn encrypted_size(settings: &Settings, header_bytes: usize, encrypted: &Encrypted) -> u64 {
+    match encrypted {
+        Ok(envelope) => {
+            (header_bytes + settings.envelope_encoding.encoded_len(envelope.len())) as u64
+        }
+        Err(_) => 0,
+    }
+}
+
+/// Encrypt the content for the certificates of all recipients.
+pub struct Encrypt;
+
+#[async_trait]
+impl Stage for Encrypt {
+    fn name(&self) -> &'static str {
+        "encrypt"
+    }
+
+    fn needs(&self) -> &'static [&'static str] {
+        &["build-entity"]
+    }
+
+    async fn run(&self, message: &mut Message<'_, '_>) -> Result<Flow> {
+        #[cfg(feature = "chaos")]
+        chaos::inject(&[Fault::SlowBackend, Fault::CertRead, Fault::OpenSsl]).await?;
+        let ctx = &mut *message.ctx;
+        // Every recipient adds to the envelope, and some clients choke on too many.
+        if let Some(max) = message
+            .settings
+            .max_cms_recipients
+            .filter(|max| ctx.recipients.len() > *max)
+        {
+            let count = ctx.recipients.len();
+            info!(count, max, "Too many recipients to encrypt to; rejecting");
+            ctx.report.error = Some(format!(
+                "{} recipients exceed the maximum of {} to encrypt to",
+                count, max
+            ));
+            let reply = format!(
+                "Encrypted messages may have at most {} recipients, please send it in smaller batches",
+                max
+            );
+            if let Err(error) = message.reply.set_error_reply("550", Some("5.5.3"), [reply]) {
+                error!(?error, "Failed to set reply");
+            }
+            return Ok(Flow::Finish(Status::Reject));
+        }
+        let cert_dir = message.settings.cert_dir_for(&ctx.sender);
+        debug!(?cert_dir, "Using certificate directory of sender");
+        if let Err(unavailable) = message.settings.store_health.check(cert_dir).await {
+            return Ok(Flow::Finish(defer_unavailable(message, unavailable)));
+        }
+        // Look up all recipients, so the report tells every one lacking a certificate.
+        let started = Instant::now();
+        let mut failures = Vec::new();
+        let cached = message
+            .settings
+            .recipient_certs
+            .get(&ctx.sender, &ctx.recipients);
+        if let Some(certs) = cached {
+            debug!("Reusing the certificates looked up for the same envelope");
+            for (recipient, (_, fingerprint)) in ctx.recipients.iter().zip(&certs) {
+                ctx.report.recipients.push(RecipientReport {
+                    address: recipient.clone(),
+                    certificate: true,
+                    fingerprint: Some(fingerprint.clone()),
+                    problem: None,
+                    excluded: false,
+                });
+            }
+            message.certs = certs.into_iter().map(|(cert, _)| cert).collect();
+        } else {
+            let mut fingerprinted = Vec::new();
+            for recipient in &ctx.recipients {
+                let lookup = match &message.settings.store {
+                    Some(store) => cert_store::lookup_in(store.as_ref(), recipient).await,
+                    None => {
+                        message
+                            .settings
+                            .cert_cache
+                            .lookup(cert_dir, recipient)
+                            .await
+                    }
+                };
+                ctx.report.recipients.push(RecipientReport {
+                    address: recipient.clone(),
+                    certificate: lookup.is_ok(),
+                    fingerprint: lookup.as_ref().ok().map(|(_, f)| f.clone()),
+                    problem: lookup.as_ref().err().map(|error| {
+                        match error.downcast_ref::<Expired>() {
+                            Some(_) => "expired",
+                            None => "missing",
+                        }
+                        .to_string()
+                    }),
+                    excluded: false,
+                });
+                match lookup {
+                    Ok((cert, fingerprint)) => {
+                        message.certs.push(cert.clone());
+                        fingerprinted.push((cert, fingerprint));
+                    }
+                    Err(error) if cert_store::is_store_failure(&error) => {
+                        message.settings.store_health.trip(cert_dir, &error);
+                        let unavailable = cert_store::Unavailable {
+                            cert_dir: cert_dir.to_path_buf(),
+                        };
+                        return Ok(Flow::Finish(defer_unavailable(message, unavailable)));
+                    }
+                    Err(error) => {
+                        if let Some(diagnosis) = diagnostics::diagnose(&error) {
+                            warn!(
+                                %recipient,
+                                error_code = diagnosis.code(),
+                                hint = diagnosis.hint(),
+                                ?error,
+                                "Stored certificate is unusable"
+                            );
+                        }
+                        failures.push((recipient.clone(), error));
+                    }
+                }
+            }
+            if failures.is_empty() {
+                message.settings.recipient_certs.insert(
+                    &ctx.sender,
+                    &ctx.recipients,
+                    fingerprinted,
+                );
+            }
+        }
+        ctx.report.durations.lookup_ms = started.elapsed().as_secs_f64() * 1000.0;
+        if !failures.is_empty() {
+            if let Some(key_request) = &message.settings.key_request {
+                let missing = failures
+                    .iter()
+                    .filter(|(_, error)| error.downcast_ref::<Expired>().is_none());
+                for (recipient, _) in missing {
+                    key_request
+                        .send(message.settings, cert_dir, &ctx.sender, recipient)
+                        .await;
+                }
+            }
+            let exclude = failures.len() < ctx.recipients.len()
+                && failures.iter().all(|(_, error)| {
+                    failure_action(message.settings, error) == CertFailureAction::Exclude
+                });
+            if !exclude {
+                return Ok(Flow::Finish(refuse(message, &failures)));
+            }
+            exclude_recipients(message, &failures).await?;
+        }
+        let ctx = &mut *message.ctx;
+
+        // Content encryption is shared, so it has to suit every recipient's profile.
+        let mut profiles: Vec<CryptoProfile> = Vec::with_capacity(ctx.recipients.len());
+        for recipient in &ctx.recipients {
+            let mut profile =
+                crypto_profile::profile_for(&message.settings.crypto_profiles, recipient).clone();
+            if profile.aead_auto && !profile.aead {
+                profile.aead = announces_aead(cert_dir, recipient).await;
+            }
+            profiles.push(profile);
+        }
+        let profile = crypto_profile::negotiate(&profiles);
+        let (_, cipher) = profile.cipher();
+        debug!(
+            cipher,
+            compress = profile.compress,
+            "Negotiated crypto profile"
+        );
+        let mut recipients: Vec<(X509, KeyTransport)> = message
+            .certs
+            .iter()
+            .cloned()
+            .zip(profiles.iter().map(|p| p.key_transport))
+            .collect();
+        if let Some(escrow) = &message.settings.escrow_certificate {
+            recipients.push((escrow.clone(), profile.key_transport));
+        }
+
+        let started = Instant::now();
+        let content = Arc::new(std::mem::take(&mut message.content));
+        let recipients = Arc::new(recipients);
+        let mut profile = profile;
+        let mut encrypted =
+            encrypt_content(message.settings, &content, &recipients, &profile).await?;
+
+        // Base64 adds a third, which can push the message past what the MTA takes.
+        let settings = message.settings;
+        if let Some(max) = settings.max_message_size {
+            let mut size = encrypted_size(settings, ctx.header_bytes, &encrypted);
+            if size > max
+                && settings.oversize_action == OversizeAction::Compress
+                && !profile.compress
+            {
+                info!(size, max, "Encrypted message is too large; compressing it");
+                profile.compress = true;
+                encrypted = encrypt_content(settings, &content, &recipients, &profile).await?;
+                size = encrypted_size(settings, ctx.header_bytes, &encrypted);
+            }
+            if size > max {
+                if settings.oversize_action == OversizeAction::Proceed {
+                    warn!(size, max, "Encrypted message exceeds the maximum size");
+                } else {
+                    info!(
+                        size,
+                        max, "Encrypted message exceeds the maximum size; rejecting"
+                    );
+                    ctx.report.error = Some(format!(
+                        "Encrypted message of {} bytes exceeds the maximum of {}",
+                        size, max
+                    ));
+                    if let Err(error) = message.reply.set_error_reply(
+                        "552",
+                        Some("5.3.4"),
+                        ["Message size exceeds fixed maximum message size once encrypted"],
+                    ) {
+                        error!(?error, "Failed to set reply");
+                    }
+                    return Ok(Flow::Finish(Status::Reject));
+                }
+            }
+        }
+        message.content = match encrypted {
+            Ok(content) => content,
+            Err((error, Some(culprit))) if culprit == ctx.recipients.len() => {
+                return Err(error.context("Failed to encrypt message body for the key escrow"));
+            }
+            Err((error, Some(culprit))) => {
+                let recipient = &ctx.recipients[culprit];
+                metrics::encryption_failure(recipient, "unusable");
+                let path =
+                    cert_dir.join(format!("{}.pem", address::cert_name(cert_dir, recipient)));
+                return Err(error.context(format!(
+                    "Failed to encrypt message body for {} with the certificate in {:?}",
+                    recipient, path
+                )));
+            }
+            Err((error, None)) => return Err(error.context("Failed to encrypt message body")),
+        };
+        ctx.report.durations.crypto_ms = started.elapsed().as_secs_f64() * 1000.0;
+        ctx.report.cipher = Some(cipher.to_string());
+        message.profile = profile;
+        let now = cert_usage::now();
+        for recipient in &ctx.recipients {
+            let name = address::cert_name(cert_dir, recipient);
+            message.settings.cert_usage.record(cert_dir, &name, now);
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+/// Header naming the recipients removed from a message encrypted for the others.
+pub const EXCLUDED_HEADER: &str = "X-Pantosmime-Excluded";
+
+/// Handling of a recipient the certificate lookup failed for.
+fn failure_action(settings: &Settings, error: &anyhow::Error) -> CertFailureAction {
+    let action = match error.downcast_ref::<Expired>() {
+        Some(expired) => settings.action_for_expired(expired.days_ago),
+        None => settings.missing_cert_action,
+    };
+    match action {
+        CertFailureAction::Reject if settings.failures_tempfail() => CertFailureAction::Tempfail,
+        action => action,
+    }
+}
+
+/// Remove the recipients without a usable certificate from the message, so it is encrypted
+/// for the others, and name them in a header for the sender's organization to follow up.
+async fn exclude_recipients(
+    message: &mut Message<'_, '_>,
+    failures: &[(String, anyhow::Error)],
+) -> Result<()> {
+    let ctx = &mut *message.ctx;
+    let mut excluded = Vec::new();
+    for (recipient, error) in failures {
+        let problem = match error.downcast_ref::<Expired>() {
+            Some(_) => "expired",
+            None => "missing",
+        };
+        warn!(%recipient, problem, ?error, "Excluding recipient without a usable certificate");
+        metrics::encryption_failure(recipient, problem);
+        if let Some(index) = ctx.recipients.iter().position(|r| r == recipient) {
+            ctx.recipients.remove(index);
+            let envelope = ctx.envelope_recipients.remove(index);
+            message
+                .actions
+                .delete_recipient(envelope)
+                .await
+                .context("Failed to remove an excluded recipient")?;
+        }
+        if let Some(report) = ctx
+            .report
+            .recipients
+            .iter_mut()
+            .find(|r| r.address == *recipient)
+        {
+            report.excluded = true;
+        }
+        excluded.push(format!("{} ({})", recipient, problem));
+    }
+    message
+        .actions
+        .add_header(EXCLUDED_HEADER, excluded.join(", "))
+        .await
+        .context("Failed to add the excluded recipients header")
+}
+
+/// Defer a message while its certificate directory is unavailable, as whether its
+/// recipients have a certificate can't be told.
+fn defer_unavailable(
+    message: &mut Message<'_, '_>,
+    unavailable: cert_store::Unavailable,
+) -> Status {
+    debug!(cert_dir = ?unavailable.cert_dir, "Deferring message");
+    message.ctx.report.error = Some(unavailable.to_string());
+    let reply = "Certificate store temporarily unavailable, please try again later";
+    if let Err(error) = message.reply.set_error_reply("451", Some("4.3.0"), [reply]) {
+        error!(?error, "Failed to set reply");
+    }
+    Status::Tempfail
+}
+
+/// Whether `recipient` announced AES-GCM when their certificate was harvested.
+async fn announces_aead(cert_dir: &Path, recipient: &str) -> bool {
+    let name = address::cert_name(cert_dir, recipient);
+    match CertMetadata::load(&CertMetadata::path(cert_dir, &name)).await {
+        Ok(metadata) => metadata.is_some_and(|m| crypto_profile::announces_aead(&m.capabilities)),
+        Err(error) => {
+            warn!(?error, "Ignoring unreadable certificate metadata");
+            false
+        }
+    }
+}
+
+/// Refuse a message to recipients without a usable certificate, as configured for whether
+/// their certificate is missing or expired. Rejecting wins, as retrying can't help then.
+fn refuse(message: &mut Message<'_, '_>, failures: &[(String, anyhow::Error)]) -> Status {
+    let settings = message.settings;
+    let mut tempfail = true;
+    let mut lines = Vec::new();
+    for (recipient, error) in failures {
+        match error.downcast_ref::<Expired>() {
+            Some(expired) => {
+                warn!(
+                    %recipient,
+                    not_after = %expired.not_after,
+                    days_ago = expired.days_ago,
+                    "Certificate of recipient expired"
+                );
+                lines.push(format!(
+                    "{}, a signed message with the renewed one is needed",
+                    expired
+                ));
+                metrics::encryption_failure(recipient, "expired");
+            }
+            None => {
+                warn!(%recipient, ?error, "No usable certificate for recipient");
+                metrics::encryption_failure(recipient, "missing");
+                lines.push(format!("No usable S/MIME certificate for {}", recipient));
+            }
+        };
+        tempfail &= failure_action(settings, error) == CertFailureAction::Tempfail;
+    }
+    message.ctx.report.error = failures
+        .first()
+        .map(|(_, error)| format!("Failed to encrypt message body: {:#}", error));
+
+    let (status, rcode, xcode) = match tempfail {
+        true => (Status::Tempfail, "451", "4.7.5"),
+        false => (Status::Reject, "550", "5.7.5"),
+    };
+    info!(
+        ?status,
+        "Refusing message to recipients without a usable certificate"
+    );
+    if let Err(error) = message.reply.set_error_reply(rcode, Some(xcode), lines) {
+        error!(?error, "Failed to set reply");
+    }
+    status
+}
+
+#[tracing::instrument(skip(ctx, actions, new_headers))]
+async fn update_headers<'a>(
+    ctx: &MilterContext<'a>,
+    actions: &EomActions,
+    new_headers: Vec<(Cow<'a, str>, Cow<'a, str>)>,
+) -> Result<()> {
+    for (updated_key, updated_value) in new_headers.iter() {
+        if let Some((current_key, current_value)) = ctx
+            .headers
+            .iter()
+            .find(|t| t.0.as_ref().eq_ignore_ascii_case(updated_key.as_ref()))
+        {
+            if current_value != updated_value {
+                debug!(
+                    key = %current_key,
+                    old_value = %current_value,
+                    new_value = %updated_value,
+                    "Changing header"
+                );
+                actions
+                    .change_header(
+                        current_key.into_c_string(),
+                        1,
+                        Some(updated_value.into_c_string()),
+                    )
+                    .await?;
+            }
+        } else {
+            debug!(
+                key = %updated_key,
+                value = %updated_value,
+                "Adding new header"
+            );
+            actions
+                .add_header(updated_key.into_c_string(), updated_value.into_c_string())
+                .await?;
+        }
+    }
+    // Content headers moved into the envelope must not leak, e.g. the Content-ID of
+    // multipart/related mail.
+    for (current_key, _) in ctx.headers.iter().filter(|(current_key, _)| {
+        !new_headers
+            .iter()
+            .any(|(updated_key, _)| updated_key.eq_ignore_ascii_case(current_key))
+    }) {
+        debug!(key = %current_key, "Deleting header");
+        actions
+            .change_header(current_key.into_c_string(), 1, None::<CString>)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Delete every occurrence of the headers to strip, last first so the indices stay valid.
+async fn strip_headers(ctx: &MilterContext<'_>, actions: &EomActions) -> Result<()> {
+    let mut names: Vec<&String> = Vec::new();
+    for name in &ctx.stripped_headers {
+        if !names.iter().any(|n| n.eq_ignore_ascii_case(name)) {
+            names.push(name);
+        }
+    }
+    for name in names {
+        let count = ctx
+            .stripped_headers
+            .iter()
+            .filter(|n| n.eq_ignore_ascii_case(name))
+            .count();
+        for index in (1..=count as i32).rev() {
+            debug!(key = %name, index, "Stripping header");
+            actions
+                .change_header(name.as_str(), index, None::<CString>)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Replace the message with the encrypted S/MIME envelope.
+pub struct EmitEnvelope;
+
+#[async_trait]
+impl Stage for EmitEnvelope {
+    fn name(&self) -> &'static str {
+        "emit-envelope"
+    }
+
+    fn needs(&self) -> &'static [&'static str] {
+        &["encrypt"]
+    }
+
+    async fn run(&self, message: &mut Message<'_, '_>) -> Result<Flow> {
+        let encoding = &message.settings.envelope_encoding;
+        let wrapped = encoding.encode(&message.content);
+
+        // Reserialize and replace changed headers and body.
+        let compat = &message.settings.compat;
+        let content_type = compat.content_type(message.profile.smime_type());
+        let mut new_headers = vec![
+            (Cow::Borrowed("MIME-Version"), Cow::Borrowed("1.0")),
+            (Cow::Borrowed("Content-Type"), Cow::Owned(content_type)),
+            (
+                Cow::Borrowed("Content-Transfer-Encoding"),
+                Cow::Borrowed(encoding.header_value()),
+            ),
+        ];
+        if let Some(disposition) = compat.content_disposition() {
+            new_headers.push((
+                Cow::Borrowed("Content-Disposition"),
+                Cow::Owned(disposition),
+            ));
+        }
+        update_headers(message.ctx, message.actions, new_headers)
+            .await
+            .context("Failed to update headers for encryption")?;
+        strip_headers(message.ctx, message.actions)
+            .await
+            .context("Failed to strip headers after encryption")?;
+        message
+            .actions
+            .replace_body(&wrapped)
+            .await
+            .context("Failed to replace body after encryption")?;
+        message.replaced_body = Some(wrapped.to_vec());
+        let reason = match message.ctx.report.recipients.iter().any(|r| r.excluded) {
+            true => Reason::EncryptedPartial,
+            false => Reason::EncryptedOk,
+        };
+        add_reason(message, reason).await;
+        info!("Encryption successful, accepting mail");
+        Ok(Flow::Continue)
+    }
+}
+
+/// Only go on with S/MIME signed messages, keeping their signature as content.
+pub struct RequireSignature;
+
+#[async_trait]
+impl Stage for RequireSignature {
+    fn name(&self) -> &'static str {
+        "require-signature"
+    }
+
+    async fn run(&self, message: &mut Message<'_, '_>) -> Result<Flow> {
+        let ctx = &message.ctx;
+        // Header-only messages, like some calendar cancellations, cannot carry a signature.
+        if ctx.body.is_empty() {
+            info!("Message has no body, nothing to harvest; moving on");
+            add_reason(message, Reason::SkippedNotSigned).await;
+            return Ok(Flow::Finish(Status::Accept));
+        }
+
+        // Parse using MIME Parser.
+        let body_str = body_text(&ctx.body);
+        let (_, container) =
+            MimeContainer::parse_mime_container_data(&body_str, ctx.headers.clone())
+                .map_err(|e| anyhow!("{:?}", e))
+                .context("Failed to parse MIME container for key extraction")?;
+
+        let Some(signature) = find_signature(&container, matches!(body_str, Cow::Owned(_)))? else {
+            info!("Message does not contain multipart/signed content, moving on");
+            add_reason(message, Reason::SkippedNotSigned).await;
+            return Ok(Flow::Finish(Status::Accept));
+        };
+        message.content = signature;
+        Ok(Flow::Continue)
+    }
+}
+
+fn content_type(container: &MimeContainer<'_>) -> String {
+    container
+        .find_header_value("Content-Type")
+        .map(|value| value.to_lowercase())
+        .unwrap_or_default()
+}
+
+/// Find the `multipart/signed` entity: the message itself, or one nested in other multiparts,
+/// like signed content inside an alternative inside a mixed part as mobile clients send it.
+/// The parser limits the depth.
+fn find_signed<'c>(container: &'c MimeContainer<'c>) -> Option<&'c MimeContainer<'c>> {
+    match content_type(container).starts_with("multipart/signed") {
+        true => Some(container),
+        false => container.parts.iter().find_map(find_signed),
+    }
+}
+
+/// The decoded PKCS#7 signature of the `multipart/signed` entity in `container`, if there is
+/// one. `bytewise` tells whether [`body_text`] mapped the body byte by byte.
+pub fn find_signature(container: &MimeContainer<'_>, bytewise: bool) -> Result<Option<Vec<u8>>> {
+    let Some(signed) = find_signed(container) else {
+        return Ok(None);
+    };
+
+    // Iterate through message parts to find one with content type "application/pkcs7-signature".
+    let signature_part = signed
+        .parts
+        .iter()
+        .find(|p| {
+            p.find_header_value("Content-Type").is_some_and(|e| {
+                let e = e.to_lowercase();
+                e.contains("application/pkcs7-signature")
+                    || e.contains("application/x-pkcs7-signature")
+            })
+        })
+        .context("Message is multipart/signed, but didn't find any PKCS#7 signature part")?;
+    decode_part(signature_part, bytewise)
+        .context("Failed to decode signature")
+        .map(Some)
+}
+
+/// What each of the owner's certificates in a chain is good for, by fingerprint.
+fn usage_tags(chain: &[X509], owner: &str) -> BTreeMap<String, Vec<String>> {
+    chain
+        .iter()
+        .filter(|cert| smime::find_cert_for_email([cert], owner).is_ok())
+        .filter_map(|cert| match smime::cert_usage(cert) {
+            Ok(mut usage) => {
+                usage.encryption &= smime::has_encryption_key(cert);
+                Some((event_report::fingerprint(cert), usage.tags()))
+            }
+            Err(error) => {
+                warn!(?error, "Failed to read certificate usage");
+                None
+            }
+        })
+        .collect()
+}
+
+/// Why none of the certificates of `owner` in `chain` can be encrypted to, if so. Signing
+/// certificates with RSA or EC keys still count, as before.
+fn unencryptable_chain(chain: &[X509], owner: &str) -> Option<String> {
+    let owned: Vec<&X509> = chain
+        .iter()
+        .filter(|cert| smime::find_cert_for_email([cert], owner).is_ok())
+        .collect();
+    if owned.iter().any(|cert| smime::has_encryption_key(cert)) {
+        return None;
+    }
+    let mut algorithms: Vec<String> = owned
+        .iter()
+        .map(|cert| smime::key_algorithm(cert).unwrap_or_else(|_| "unknown".to_string()))
+        .collect();
+    algorithms.dedup();
+    Some(format!(
+        "S/MIME can't encrypt to the {} key of the certificate for {}",
+        algorithms.join(", "),
+        owner
+    ))
+}
+
+/// Why `chain` is too long or too large to store, if it is.
+fn oversized_chain(settings: &Settings, chain: &[X509]) -> Result<Option<String>> {
+    if chain.len() > settings.max_chain_certs {
+        return Ok(Some(format!(
+            "{} certificates exceed the maximum of {} per chain",
+            chain.len(),
+            settings.max_chain_certs
+        )));
+    }
+    let mut bytes = 0;
+    for cert in chain {
+        bytes += cert.to_der()?.len();
+    }
+    Ok((bytes > settings.max_chain_bytes).then(|| {
+        format!(
+            "Certificate chain of {} bytes exceeds the maximum of {}",
+            bytes, settings.max_chain_bytes
+        )
+    }))
+}
+
+/// Only go on with messages whose sender passed one of the required authentication methods,
+/// if any.
+pub struct RequireAuthentication;
+
+#[async_trait]
+impl Stage for RequireAuthentication {
+    fn name(&self) -> &'static str {
+        "require-authentication"
+    }
+
+    async fn run(&self, message: &mut Message<'_, '_>) -> Result<Flow> {
+        let settings = message.settings;
+        if settings.harvest_require_auth.is_empty() {
+            return Ok(Flow::Continue);
+        }
+        let ctx = &mut *message.ctx;
+        match authentication_results::passed(
+            &settings.harvest_require_auth,
+            &settings.authserv_ids,
+            &ctx.authentication_results,
+            &ctx.sender,
+        ) {
+            Some(method) => {
+                debug!(method = method.as_str(), "Sender is authenticated");
+                Ok(Flow::Continue)
+            }
+            None => {
+                info!(sender = ?ctx.sender, "Sender is not authenticated; not harvesting");
+                ctx.report.error = Some("Sender is not authenticated".to_string());
+                add_reason(message, Reason::SkippedUnauthenticated).await;
+                Ok(Flow::Finish(Status::Accept))
+            }
+        }
+    }
+}
+
+/// Why the `From` header addresses don't match the envelope sender as required, if they don't.
+fn from_header_mismatch(
+    required: FromHeaderMatch,
+    from_headers: &[Option<String>],
+    sender: &str,
+) -> Option<String> {
+    let domain = |email: &str| email.rsplit_once('@').map(|(_, domain)| domain.to_string());
+    match (required, from_headers) {
+        (FromHeaderMatch::Ignore, _) => None,
+        (_, []) => Some("Message has no From header".to_string()),
+        (_, [Some(from)]) => {
+            let matches = match required {
+                FromHeaderMatch::SameAddress => from == sender,
+                _ => domain(from).is_some_and(|d| Some(d) == domain(sender)),
+            };
+            (!matches).then(|| format!("From header {} does not match the sender", from))
+        }
+        (_, [None]) => Some("From header has no single address".to_string()),
+        _ => Some("Message has multiple From headers".to_string()),
+    }
+}
+
+/// Only go on with messages whose `From` header matches the envelope sender as required, so a
+/// message with mismatched identities can't store a certificate for the wrong address.
+pub struct MatchFromHeader;
+
+#[async_trait]
+impl Stage for MatchFromHeader {
+    fn name(&self) -> &'static str {
+        "match-from-header"
+    }
+
+    async fn run(&self, message: &mut Message<'_, '_>) -> Result<Flow> {
+        let ctx = &mut *message.ctx;
+        let settings = message.settings;
+        let from_headers: Vec<Option<String>> = ctx
+            .address_headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("From"))
+            .map(|(_, value)| milter_callbacks::header_address(settings, value))
+            .collect();
+        let required = settings.harvest_from_header;
+        let Some(reason) = from_header_mismatch(required, &from_headers, &ctx.sender) else {
+            return Ok(Flow::Continue);
+        };
+        info!(sender = ?ctx.sender, %reason, "Not harvesting");
+        ctx.report.error = Some(reason);
+        add_reason(message, Reason::SkippedFromMismatch).await;
+        Ok(Flow::Finish(Status::Accept))
+    }
+}
+
+/// Extract the certificates from the signature, requiring one matching the sender.
+pub struct ExtractSigners;
+
+#[async_trait]
+impl Stage for ExtractSigners {
+    fn name(&self) -> &'static str {
+        "extract-signers"
+    }
+
+    fn needs(&self) -> &'static [&'static str] {
+        &["require-signature"]
+    }
+
+    async fn run(&self, message: &mut Message<'_, '_>) -> Result<Flow> {
+        // Reports like DSNs and MDNs have no sender a certificate could belong to.
+        if message.ctx.sender.is_empty() {
+            let reason = "Message comes from the null reverse-path".to_string();
+            info!(%reason, "Not harvesting");
+            message.ctx.report.error = Some(reason.clone());
+            if message.ctx.action() == Some(&MilterAction::Enroll) {
+                return Ok(Flow::Finish(refuse_enrollment(message, reason)));
+            }
+            add_reason(message, Reason::SkippedNullSender).await;
+            return Ok(Flow::Finish(Status::Accept));
+        }
+        let started = Instant::now();
+        #[cfg(feature = "chaos")]
+        chaos::inject(&[Fault::OpenSsl]).await?;
+        let cert_chain = smime::extract_certificates_from_p7s(&message.content)
+            .context("Failed to extract signers from signature")?;
+        let ctx = &mut *message.ctx;
+        ctx.report.durations.crypto_ms = started.elapsed().as_secs_f64() * 1000.0;
+        if let Some(reason) = oversized_chain(message.settings, &cert_chain)? {
+            warn!(%reason, "Not storing oversized certificate chain");
+            ctx.report.error = Some(reason);
+            add_reason(message, Reason::SkippedOversizedChain).await;
+            return Ok(Flow::Finish(Status::Accept));
+        }
+        smime::find_cert_for_email(&cert_chain, &ctx.sender)
+            .context("Failed to find signature certificate matching sender")?;
+        info!(sender = ?ctx.sender, cert_count = ?cert_chain.len(), "Found signature for sender");
+        // Storing such a chain would replace a usable one harvested before.
+        if let Some(reason) = unencryptable_chain(&cert_chain, &ctx.sender) {
+            let diagnosis = Diagnosis::UnsupportedKeyType;
+            warn!(
+                %reason,
+                error_code = diagnosis.code(),
+                hint = diagnosis.hint(),
+                "Not storing certificates without a key to encrypt to"
+            );
+            ctx.report.error = Some(reason.clone());
+            ctx.report.error_code = Some(diagnosis.code().to_string());
+            if ctx.action() == Some(&MilterAction::Enroll) {
+                return Ok(Flow::Finish(refuse_enrollment(message, reason)));
+            }
+            add_reason(message, Reason::SkippedUnsupportedKey).await;
+            return Ok(Flow::Finish(Status::Accept));
+        }
+
+        // Senders with separate signing and encryption certificates name the latter.
+        // Tag what each of the sender's certificates is good for, so a signing-only one is
+        // never picked for encryption.
+        let usage = usage_tags(&cert_chain, &ctx.sender);
+        match smime_attributes::parse(&message.content) {
+            Ok(attributes) => {
+                let preferred = attributes.key_preference.as_ref().and_then(|preference| {
+                    cert_chain.iter().find(|cert| {
+                        preference.matches(cert)
+                            && smime::has_encryption_key(cert)
+                            && smime::find_encryption_cert([cert], &ctx.sender).is_ok()
+                    })
+                });
+                if attributes.key_preference.is_some() && preferred.is_none() {
+                    warn!("Preferred encryption certificate of sender is not in the signature");
+                }
+                message.cert_metadata = CertMetadata {
+                    capabilities: attributes.capabilities,
+                    encryption_certificate: preferred.map(|cert| event_report::fingerprint(cert)),
+                    usage,
+                    ..CertMetadata::default()
+                };
+            }
+            Err(error) => {
+                warn!(?error, "Failed to read signed attributes");
+                message.cert_metadata = CertMetadata {
+                    usage,
+                    ..CertMetadata::default()
+                };
+            }
+        }
+        message.certs = cert_chain;
+        Ok(Flow::Continue)
+    }
+}
+
+/// Only go on with chains leading to the trust anchors for the sender's domain, if any.
+pub struct VerifyTrust;
+
+#[async_trait]
+impl Stage for VerifyTrust {
+    fn name(&self) -> &'static str {
+        "verify-trust"
+    }
+
+    fn needs(&self) -> &'static [&'static str] {
+        &["extract-signers", "extract-enrolled"]
+    }
+
+    async fn run(&self, message: &mut Message<'_, '_>) -> Result<Flow> {
+        let sender = &message.ctx.sender;
+        let trust_anchors = message.settings.trust_anchors.get();
+        let Some(anchors) = trust::anchors_for(&trust_anchors, sender) else {
+            return Ok(Flow::Continue);
+        };
+        let Err(error) = anchors.verify(&message.certs, sender) else {
+            debug!(domain = anchors.domain, "Chain leads to a trust anchor");
+            return Ok(Flow::Continue);
+        };
+        warn!(?error, "Not storing untrusted certificates");
+        if message.ctx.action() == Some(&MilterAction::Enroll) {
+            let reason = format!(
+                "The S/MIME certificate for {} is not issued by a trusted CA",
+                sender
+            );
+            return Ok(Flow::Finish(refuse_enrollment(message, reason)));
+        }
+        message.ctx.report.error = Some(format!("{:#}", error));
+        add_reason(message, Reason::SkippedUntrusted).await;
+        Ok(Flow::Finish(Status::Accept))
+    }
+}
+
+/// Save the harvested chain as `<sender>.pem`, in the certificate directory of every
+/// responsible recipient, or in the [`Settings::store`] of an embedding program.
+pub struct StoreCertificates;
+
+#[async_trait]
+impl Stage for StoreCertificates {
+    fn name(&self) -> &'static str {
+        "store-certificates"
+    }
+
+    fn needs(&self) -> &'static [&'static str] {
+        &["verify-trust"]
+    }
+
+    async fn run(&self, message: &mut Message<'_, '_>) -> Result<Flow> {
+        let ctx = &mut *message.ctx;
+        let settings = message.settings;
+        let mut cert_dirs: Vec<&Path> = ctx
+            .recipients
+            .iter()
+            .filter(|r| settings.is_responsible(r) || settings.is_enrollment_address(r))
+            .map(|r| settings.cert_dir_for(r))
+            .collect();
+        cert_dirs.sort();
+        cert_dirs.dedup();
+        #[cfg(feature = "chaos")]
+        chaos::inject(&[Fault::SlowBackend]).await?;
+        message.cert_metadata.harvested_from = Some(HarvestedFrom {
+            queue_id: ctx.queue_id.clone().unwrap_or_default(),
+            message_id: ctx.message_id.clone(),
+            harvested_at: event_report::rfc3339(SystemTime::now()),
+        });
+        let mut spooled = false;
+        if let Some(store) = &settings.store {
+            store
+                .store(&ctx.sender, &message.certs)
+                .await
+                .context("Failed to store the certificate chain")?;
+            cert_dirs.clear();
+        }
+        for cert_dir in cert_dirs {
+            let error = match settings.store_health.check(cert_dir).await {
+                Ok(()) => match store_chain(
+                    settings,
+                    cert_dir,
+                    &ctx.sender,
+                    &message.certs,
+                    &mut message.cert_metadata,
+                )
+                .await
+                {
+                    Ok(()) => continue,
+                    Err(error) if cert_store::is_store_failure(&error) => {
+                        settings.store_health.trip(cert_dir, &error);
+                        error
+                    }
+                    Err(error) => return Err(error),
+                },
+                Err(unavailable) => unavailable.into(),
+            };
+            // The signed message is still wanted by its recipients, so the chain waits in the
+            // spool rather than the message in the MTA's queue.
+            let Some(spool_dir) = &settings.harvest_spool_dir else {
+                return Err(error);
+            };
+            harvest_spool::enqueue(
+                spool_dir,
+                cert_dir,
+                &ctx.sender,
+                &message.certs,
+                &message.cert_metadata,
+            )
+            .await
+            .context("Failed to spool the certificate chain")?;
+            warn!(
+                ?cert_dir,
+                ?error,
+                "Spooled certificate chain to store once the directory is available"
+            );
+            spooled = true;
+        }
+        ctx.report.harvested = message
+            .certs
+            .iter()
+            .map(|cert| event_report::fingerprint(cert))
+            .collect();
+        let reason = match spooled {
+            true => Reason::HarvestedQueued,
+            false => Reason::HarvestedOk,
+        };
+        add_reason(message, reason).await;
+        info!("Successfully extracted certificate chain from Email");
+        Ok(Flow::Continue)
+    }
+}
+
+/// Store the `certs` harvested from `sender` in `cert_dir`, along with their `metadata`,
+/// unless a more recent harvest is on file.
+pub async fn store_chain(
+    settings: &Settings,
+    cert_dir: &Path,
+    sender: &str,
+    certs: &[X509],
+    metadata: &mut CertMetadata,
+) -> Result<()> {
+    // Instances sharing the directory harvesting from the same sender at once must
+    // neither mix their chain and metadata nor undo a more recent harvest.
+    let _lock = StoreLock::acquire(cert_dir).await?;
+    let name = address::cert_name(cert_dir, sender);
+    let metadata_path = CertMetadata::path(cert_dir, &name);
+    let stored = CertMetadata::load(&metadata_path).await.unwrap_or_default();
+    if let Some((stored, ours)) = stored
+        .as_ref()
+        .and_then(|m| m.harvested_from.as_ref())
+        .zip(metadata.harvested_from.as_ref())
+    {
+        if stored.harvested_at > ours.harvested_at {
+            info!(?cert_dir, harvested_at = %stored.harvested_at, "Keeping more recently harvested certificates");
+            return Ok(());
+        }
+    }
+    // The pool and metadata go first, the certificate cache only watches the chain.
+    let (owned, intermediates) = cert_store::pool_intermediates(cert_dir, sender, certs).await?;
+    metadata.intermediates = intermediates;
+    metadata.store(&metadata_path).await?;
+    let path = cert_dir.join(format!("{}.pem", name));
+    smime::write_pem_stack(&owned, &path)
+        .await
+        .context("Failed to write signature certificate chain to File")?;
+    if let Some(replication) = &settings.replication {
+        replication.spawn_push(settings, cert_dir, &name);
+    }
+    Ok(())
+}
+
+/// Refuse a certs-only message, telling the sender why.
+fn refuse_enrollment(message: &mut Message<'_, '_>, reason: String) -> Status {
+    info!(%reason, "Refusing enrollment");
+    if let Err(error) = message
+        .reply
+        .set_error_reply("550", Some("5.7.0"), [reason.as_str()])
+    {
+        error!(?error, "Failed to set reply");
+    }
+    message.ctx.report.error = Some(reason);
+    Status::Reject
+}
+
+/// Find the certs-only entity, being the message itself or one of its parts.
+fn find_certs_only<'c>(container: &'c MimeContainer<'c>) -> Option<&'c MimeContainer<'c>> {
+    let is_certs_only = container
+        .find_header_value("Content-Type")
+        .is_some_and(|content_type| {
+            content_type.to_lowercase().contains("pkcs7-mime")
+                && mime_parser::parameter(&content_type, "smime-type")
+                    .is_some_and(|smime_type| smime_type.eq_ignore_ascii_case("certs-only"))
+        });
+    match is_certs_only {
+        true => Some(container),
+        false => container.parts.iter().find_map(find_certs_only),
+    }
+}
+
+/// Only go on with messages carrying an `application/pkcs7-mime; smime-type=certs-only`
+/// entity, keeping it as content.
+pub struct RequireCertsOnly;
+
+#[async_trait]
+impl Stage for RequireCertsOnly {
+    fn name(&self) -> &'static str {
+        "require-certs-only"
+    }
+
+    async fn run(&self, message: &mut Message<'_, '_>) -> Result<Flow> {
+        let ctx = &message.ctx;
+        let body_str = body_text(&ctx.body);
+        let (_, container) =
+            MimeContainer::parse_mime_container_data(&body_str, ctx.headers.clone())
+                .map_err(|e| anyhow!("{:?}", e))
+                .context("Failed to parse MIME container for enrollment")?;
+        let Some(entity) = find_certs_only(&container) else {
+            let reason = "No application/pkcs7-mime certs-only content in the message";
+            return Ok(Flow::Finish(refuse_enrollment(message, reason.into())));
+        };
+        message.content = decode_part(entity, matches!(body_str, Cow::Owned(_)))
+            .context("Failed to decode certs-only content")?;
+        Ok(Flow::Continue)
+    }
+}
+
+/// Take the certificates of a certs-only message, requiring a valid encryption certificate of
+/// the sender among them.
+pub struct ExtractEnrolled;
+
+#[async_trait]
+impl Stage for ExtractEnrolled {
+    fn name(&self) -> &'static str {
+        "extract-enrolled"
+    }
+
+    fn needs(&self) -> &'static [&'static str] {
+        &["require-certs-only"]
+    }
+
+    async fn run(&self, message: &mut Message<'_, '_>) -> Result<Flow> {
+        let sender = message.ctx.sender.clone();
+        let chain = match smime::extract_certificates_from_p7s(&message.content) {
+            Ok(chain) => chain,
+            Err(error) => {
+                warn!(?error, "Failed to read certs-only content");
+                let reason = "The certs-only content holds no readable certificates";
+                return Ok(Flow::Finish(refuse_enrollment(message, reason.into())));
+            }
+        };
+        if let Some(reason) = oversized_chain(message.settings, &chain)? {
+            return Ok(Flow::Finish(refuse_enrollment(message, reason)));
+        }
+        let cert = match smime::find_encryption_cert(&chain, &sender) {
+            Ok(cert) => cert,
+            Err(_) => {
+                let reason = format!("No S/MIME encryption certificate for {}", sender);
+                return Ok(Flow::Finish(refuse_enrollment(message, reason)));
+            }
+        };
+        let diff = Asn1Time::days_from_now(0)?.diff(cert.not_after())?;
+        if diff.days < 0 || diff.secs < 0 {
+            let reason = format!(
+                "S/MIME certificate for {} expired on {}",
+                sender,
+                cert.not_after()
+            );
+            return Ok(Flow::Finish(refuse_enrollment(message, reason)));
+        }
+        info!(%sender, cert_count = chain.len(), "Found certificate to enroll");
+        message.cert_metadata = CertMetadata {
+            encryption_certificate: Some(event_report::fingerprint(&cert)),
+            usage: usage_tags(&chain, &sender),
+            ..CertMetadata::default()
+        };
+        message.certs = chain;
+        Ok(Flow::Continue)
+    }
+}
+
+/// Confirm the import to the sender, if configured, and discard the message.
+pub struct AnswerEnrollment;
+
+#[async_trait]
+impl Stage for AnswerEnrollment {
+    fn name(&self) -> &'static str {
+        "answer-enrollment"
+    }
+
+    fn needs(&self) -> &'static [&'static str] {
+        &["store-certificates"]
+    }
+
+    async fn run(&self, message: &mut Message<'_, '_>) -> Result<Flow> {
+        let ctx = &message.ctx;
+        let settings = message.settings;
+        if let (true, Some(from)) = (settings.enrollment_reply, ctx.recipients.first()) {
+            let (subject, text) = settings.templates.render(
+                &templates::ENROLLMENT_REPLY,
+                &ctx.sender,
+                &[("sender", &ctx.sender)],
+            );
+            if let Err(error) =
+                expiry::send_mail(settings, from, &ctx.sender, &subject, &text).await
+            {
+                warn!(?error, "Failed to confirm enrollment to sender");
+            }
+        }
+        info!("Enrolled certificate; discarding message");
+        Ok(Flow::Finish(Status::Discard))
+    }
+}
+
+/// Mark the message as processed for the hosts sharing the reinjection secret, if any, bound
+/// to the body it is delivered with.
+pub struct MarkProcessed;
+
+#[async_trait]
+impl Stage for MarkProcessed {
+    fn name(&self) -> &'static str {
+        "mark-processed"
+    }
+
+    async fn run(&self, message: &mut Message<'_, '_>) -> Result<Flow> {
+        let Some(secret) = &message.settings.reinjection_secret else {
+            return Ok(Flow::Continue);
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let ctx = &message.ctx;
+        let queue_id = ctx.queue_id.as_deref().unwrap_or_default();
+        let binding = reinjection::Binding {
+            message_id: ctx.message_id.as_deref(),
+            body: message.replaced_body.as_deref().unwrap_or(&ctx.body),
+        };
+        let value = reinjection::marker(secret, queue_id, now, &binding)?;
+        message
+            .actions
+            .add_header(reinjection::MARKER_HEADER, value)
+            .await
+            .context("Failed to add the reinjection marker")?;
+        Ok(Flow::Continue)
+    }
+}
+
+/// Tell downstream filters what was done with the message, if there is a result secret,
+/// replacing result headers the message came with.
+pub struct AddResult;
+
+#[async_trait]
+impl Stage for AddResult {
+    fn name(&self) -> &'static str {
+        "add-result"
+    }
+
+    async fn run(&self, message: &mut Message<'_, '_>) -> Result<Flow> {
+        let Some(secret) = &message.settings.result_secret else {
+            return Ok(Flow::Continue);
+        };
+        let ctx = &message.ctx;
+        let report = &ctx.report;
+        let Some(action) = ctx.action() else {
+            return Ok(Flow::Continue);
+        };
+        let mut fields = vec![("action", action.as_str().to_string())];
+        if *action == MilterAction::Encrypt {
+            let encrypted = report.recipients.iter().filter(|r| r.certificate).count();
+            fields.push((
+                "recipients",
+                format!("{}/{}", encrypted, report.recipients.len()),
+            ));
+            if let Some(cipher) = &report.cipher {
+                fields.push(("cipher", cipher.clone()));
+            }
+        } else {
+            fields.push(("certificates", report.harvested.len().to_string()));
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let queue_id = ctx.queue_id.as_deref().unwrap_or_default();
+        let value = result_header::value(secret, &fields, queue_id, now)?;
+        for index in (1..=ctx.result_headers as i32).rev() {
+            message
+                .actions
+                .change_header(result_header::HEADER, index, None::<CString>)
+                .await
+                .context("Failed to remove a result header")?;
+        }
+        message
+            .actions
+            .add_header(result_header::HEADER, value)
+            .await
+            .context("Failed to add the result header")?;
+        Ok(Flow::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::milter_callbacks::assemble_callbacks;
+    use crate::milter_client::{spawn_milter, MilterClient, Response};
+    use crate::test_pki::self_signed_identity;
+    use std::sync::Arc;
+
+    const SINGLE_EMAIL: &[u8] = include_bytes!("../data/mime/simple_email.eml");
+
+    /// Tags messages, or rejects them once the content is encrypted.
+    struct Probe;
+
+    #[async_trait]
+    impl Stage for Probe {
+        fn name(&self) -> &'static str {
+            "probe"
+        }
+
+        async fn run(&self, message: &mut Message<'_, '_>) -> Result<Flow> {
+            if message.content.is_empty() {
+                message.actions.add_header("X-Probe", "seen").await?;
+                Ok(Flow::Continue)
+            } else {
+                Ok(Flow::Finish(Status::Reject))
+            }
+        }
+    }
+
+    /// An entity of `content_type` with a sibling text part and a part signed with a dummy
+    /// signature.
+    fn nested_signed(content_type: &str, boundary: &str, sibling: &str) -> String {
+        let signed = "Content-Type: multipart/signed; protocol=\"application/pkcs7-signature\"; boundary=\"inner\"\r\n\
+                      \r\n\
+                      --inner\r\n\
+                      Content-Type: text/plain\r\n\
+                      \r\n\
+                      Accepted.\r\n\
+                      --inner\r\n\
+                      Content-Type: application/pkcs7-signature\r\n\
+                      \r\n\
+                      MAA=\r\n\
+                      --inner--";
+        format!(
+            "Content-Type: {content_type}; boundary=\"{boundary}\"\r\n\
+             \r\n\
+             --{boundary}\r\n\
+             Content-Type: {sibling}\r\n\
+             \r\n\
+             Hello.\r\n\
+             --{boundary}\r\n\
+             {signed}\r\n\
+             --{boundary}--\r\n"
+        )
+    }
+
+    #[test]
+    fn test_find_signed() {
+        let signed_at = |message: &str| {
+            let (_, container) = MimeContainer::parse_mime_container(message).unwrap();
+            find_signed(&container).map(|signed| signed.parts[0].body.to_string())
+        };
+        let report = nested_signed(
+            "multipart/report; report-type=disposition-notification",
+            "outer",
+            "text/plain",
+        );
+        assert_eq!(signed_at(&report).as_deref(), Some("Accepted."));
+        let reply = nested_signed("multipart/mixed", "outer", "text/calendar; method=REPLY");
+        assert_eq!(signed_at(&reply).as_deref(), Some("Accepted."));
+        let mixed = format!(
+            "Content-Type: multipart/mixed; boundary=\"outer\"\r\n\
+             \r\n\
+             --outer\r\n\
+             {}\r\n\
+             --outer\r\n\
+             Content-Type: image/png\r\n\
+             \r\n\
+             iVBORw0KGgo=\r\n\
+             --outer--\r\n",
+            nested_signed("multipart/alternative", "alternative", "text/html")
+        );
+        assert_eq!(signed_at(&mixed).as_deref(), Some("Accepted."));
+        let unsigned = "Content-Type: multipart/mixed; boundary=\"outer\"\r\n\
+                        \r\n\
+                        --outer\r\n\
+                        Content-Type: text/plain\r\n\
+                        \r\n\
+                        Hello.\r\n\
+                        --outer--\r\n";
+        assert_eq!(signed_at(unsigned), None);
+    }
+
+    #[test]
+    fn test_oversized_chain() {
+        let mut settings = Settings::new("/nonexistent".into(), Vec::new());
+        let chain: Vec<X509> = ["a@example.com", "b@example.com", "c@example.com"]
+            .iter()
+            .map(|email| crate::test_pki::self_signed_identity(email).0)
+            .collect();
+        assert_eq!(oversized_chain(&settings, &chain).unwrap(), None);
+        settings.max_chain_certs = 2;
+        assert!(oversized_chain(&settings, &chain)
+            .unwrap()
+            .unwrap()
+            .starts_with("3 certificates exceed"));
+        settings.max_chain_certs = 3;
+        settings.max_chain_bytes = chain[0].to_der().unwrap().len() * 2;
+        assert!(oversized_chain(&settings, &chain)
+            .unwrap()
+            .unwrap()
+            .contains("exceeds the maximum"));
+    }
+
+    #[test]
+    fn test_from_header_mismatch() {
+        let from = |email: &str| vec![Some(email.to_string())];
+        let sender = "a@partner.example";
+        let mismatch =
+            |required, headers: &[Option<String>]| from_header_mismatch(required, headers, sender);
+
+        assert_eq!(mismatch(FromHeaderMatch::Ignore, &[]), None);
+        assert_eq!(mismatch(FromHeaderMatch::SameAddress, &from(sender)), None);
+        assert_eq!(
+            mismatch(FromHeaderMatch::SameDomain, &from("b@partner.example")),
+            None
+        );
+        assert_eq!(
+            mismatch(FromHeaderMatch::SameAddress, &from("b@partner.example")),
+            Some("From header b@partner.example does not match the sender".to_string())
+        );
+        assert!(mismatch(FromHeaderMatch::SameDomain, &from("a@other.example")).is_some());
+        assert!(mismatch(FromHeaderMatch::SameDomain, &from("a@xpartner.example")).is_some());
+        assert!(mismatch(FromHeaderMatch::SameDomain, &[]).is_some());
+        assert!(mismatch(FromHeaderMatch::SameDomain, &[None]).is_some());
+        let twice = [from(sender), from(sender)].concat();
+        assert!(mismatch(FromHeaderMatch::SameDomain, &twice).is_some());
+    }
+
+    #[test]
+    fn test_rollout_bucket() {
+        assert_eq!(rollout_bucket("4Bc1x20kLz"), rollout_bucket("4Bc1x20kLz"));
+        let inside = (0..1000)
+            .filter(|i| rollout_bucket(&format!("Q{}", i)) < 25)
+            .count();
+        assert!((200..300).contains(&inside), "{}", inside);
+    }
+
+    #[test]
+    fn test_parse_pipeline() {
+        let (action, names) =
+            parse_pipeline("encrypt=build-entity,encrypt,emit-envelope,add-result").unwrap();
+        assert_eq!(action, MilterAction::Encrypt);
+        assert_eq!(
+            Pipeline::select(&action, &names).unwrap().stage_names(),
+            ["build-entity", "encrypt", "emit-envelope", "add-result"]
+        );
+        let (action, names) = parse_pipeline("harvest=add-result,require-signature").unwrap();
+        assert_eq!(action, MilterAction::ExtractKeys);
+        assert_eq!(
+            Pipeline::select(&action, &names).unwrap().stage_names(),
+            ["add-result", "require-signature"]
+        );
+        let (_, names) = parse_pipeline(
+            "harvest=require-signature,extract-signers,verify-trust,store-certificates",
+        )
+        .unwrap();
+        assert_eq!(names.len(), 4);
+        assert!(parse_pipeline("enroll=require-certs-only,extract-enrolled,verify-trust").is_ok());
+
+        // Stages can't go without the ones handing them their content, or before them.
+        assert_eq!(
+            parse_pipeline("encrypt=build-entity,emit-envelope").unwrap_err(),
+            "encrypt stage \"emit-envelope\" needs encrypt before it"
+        );
+        assert!(parse_pipeline("encrypt=encrypt,emit-envelope").is_err());
+        assert!(parse_pipeline("encrypt=build-entity,emit-envelope,encrypt").is_err());
+        assert!(
+            parse_pipeline("harvest=require-signature,extract-signers,store-certificates").is_err()
+        );
+        assert!(parse_pipeline("harvest=verify-trust,require-signature,extract-signers").is_err());
+        assert!(parse_pipeline("enroll=extract-enrolled,verify-trust").is_err());
+        assert!(parse_pipeline("enroll=require-certs-only,answer-enrollment").is_err());
+        assert!(parse_pipeline("encrypt=encrypt,encrypt").is_err());
+        assert!(parse_pipeline("encrypt=store-certificates").is_err());
+        assert!(parse_pipeline("sign=encrypt").is_err());
+        assert!(parse_pipeline("encrypt").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_custom_stages() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings::new(dir.path().to_path_buf(), vec!["a@example.com".into()]);
+        settings.encrypt_pipeline = Pipeline::new(vec![
+            Box::new(Probe),
+            Box::new(BuildEntity),
+            Box::new(Probe),
+        ]);
+        let addr = spawn_milter(assemble_callbacks(Arc::new(settings))).await;
+
+        let mut client = MilterClient::connect(addr).await.unwrap();
+        let outcome = client
+            .send_message("Q1", "a@example.com", &["b@example.com"], SINGLE_EMAIL)
+            .await
+            .unwrap();
+        assert_eq!(outcome.response, Some(Response::Reject));
+        assert_eq!(outcome.header("X-Probe"), Some("seen"));
+        assert!(outcome.body().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_store_chain_unicode_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = Settings::new(dir.path().to_path_buf(), vec![]);
+        let (old, _) = self_signed_identity("a@xn--bcher-kva.example");
+        let unicode = dir.path().join("a@bücher.example.pem");
+        smime::write_pem_stack([&old], &unicode).await.unwrap();
+
+        let (cert, _) = self_signed_identity("a@xn--bcher-kva.example");
+        let mut metadata = CertMetadata {
+            capabilities: vec!["aes256-cbc".into()],
+            ..Default::default()
+        };
+        store_chain(
+            &settings,
+            dir.path(),
+            "a@xn--bcher-kva.example",
+            std::slice::from_ref(&cert),
+            &mut metadata,
+        )
+        .await
+        .unwrap();
+        assert_eq!(smime::load_pem_stack(&unicode).await.unwrap(), vec![cert]);
+        assert!(dir.path().join("a@bücher.example.json").exists());
+        assert!(!dir.path().join("a@xn--bcher-kva.example.pem").exists());
+    }
+}
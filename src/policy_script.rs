@@ -0,0 +1,333 @@
+//! Lua policy scripts, for routing rules the command line can't express.
+//!
+//! The script must define a global `policy(msg)` function. `msg` is a table with
+//! the envelope `sender` and `recipients`, all message `headers` as a list of
+//! `{ name = ..., value = ... }` tables, `certificates` mapping each envelope
+//! address to whether a certificate is stored for it, and the built-in decision
+//! as `action` ("encrypt", "harvest" or nil), and the client's `origin` ("internal",
+//! "external" or nil, see `--internal-network`). It returns one of "encrypt",
+//! "harvest", "accept", "reject" or "tempfail", or nil to keep the built-in
+//! decision.
+
+use anyhow::{anyhow, bail, Context, Result};
+use mlua::{ChunkMode, Function, HookTriggers, Lua, LuaOptions, StdLib};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::address;
+use crate::milter_callbacks::MilterAction;
+use crate::network::Origin;
+
+/// Instructions a script may run for a message, including loading it.
+const INSTRUCTION_LIMIT: u64 = 10_000_000;
+/// Instructions between the checks against [`INSTRUCTION_LIMIT`].
+const HOOK_INTERVAL: u32 = 10_000;
+/// Memory a script may allocate for a message.
+const MEMORY_LIMIT: usize = 16 * 1024 * 1024;
+/// How long a decision may take, for scripts blocking outside the interpreter.
+const TIME_LIMIT: Duration = Duration::from_secs(5);
+
+/// What the script wants done with a message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PolicyDecision {
+    /// Process the message with the given action.
+    Process(MilterAction),
+    /// Pass the message on untouched.
+    Accept,
+    Reject,
+    Tempfail,
+}
+
+/// The script ran out of instructions, memory or time before deciding.
+#[derive(Debug, thiserror::Error)]
+#[error("Policy script exceeded its limits")]
+pub struct Overrun;
+
+/// Message metadata handed to the script.
+pub struct PolicyInput<'a> {
+    pub sender: &'a str,
+    pub recipients: &'a [String],
+    pub headers: &'a [(String, String)],
+    pub action: Option<&'a MilterAction>,
+    /// Whether the client is in an internal network, if known.
+    pub origin: Option<Origin>,
+}
+
+/// [`PolicyInput`] owned, to hand it to a blocking task.
+struct OwnedInput {
+    sender: String,
+    recipients: Vec<String>,
+    headers: Vec<(String, String)>,
+    action: Option<MilterAction>,
+    origin: Option<Origin>,
+    cert_dir: PathBuf,
+}
+
+/// A loaded policy script, compiled once.
+#[derive(Clone)]
+pub struct PolicyScript {
+    path: PathBuf,
+    bytecode: Arc<[u8]>,
+}
+
+/// An interpreter with the limits set. Coroutines are left out, as the instruction hook
+/// only sees the main thread.
+fn interpreter() -> mlua::Result<Lua> {
+    let lua = Lua::new_with(StdLib::ALL_SAFE ^ StdLib::COROUTINE, LuaOptions::new())?;
+    lua.set_memory_limit(MEMORY_LIMIT)?;
+    let executed = AtomicU64::new(0);
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(HOOK_INTERVAL),
+        move |lua, _| {
+            let count = executed.fetch_add(HOOK_INTERVAL.into(), Ordering::Relaxed);
+            if count < INSTRUCTION_LIMIT {
+                return Ok(());
+            }
+            // Check every instruction from now on, so a `pcall` can't catch all of them.
+            lua.set_hook(HookTriggers::new().every_nth_instruction(1), |_, _| {
+                Err(mlua::Error::external(Overrun))
+            });
+            Err(mlua::Error::external(Overrun))
+        },
+    );
+    Ok(lua)
+}
+
+/// Whether the interpreter failed for hitting a limit.
+fn is_overrun(error: &mlua::Error) -> bool {
+    match error {
+        mlua::Error::MemoryError(_) => true,
+        mlua::Error::ExternalError(error) => error.is::<Overrun>(),
+        mlua::Error::CallbackError { cause, .. } => is_overrun(cause),
+        _ => false,
+    }
+}
+
+impl PolicyScript {
+    /// Load the script and check that it defines the `policy` function.
+    pub fn load(path: &Path) -> Result<Self> {
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read policy script {:?}", path))?;
+        let bytecode = Lua::new()
+            .load(&source)
+            .set_name(path.to_string_lossy())
+            .into_function()
+            .map(|chunk| chunk.dump(false))
+            .map_err(|e| anyhow!("{}", e))
+            .with_context(|| format!("Policy script {:?} failed", path))?;
+        let script = Self {
+            path: path.to_path_buf(),
+            bytecode: bytecode.into(),
+        };
+        script.with_policy(|_, _| Ok(()))?;
+        Ok(script)
+    }
+
+    /// Run the script in a fresh interpreter, so messages can't influence each
+    /// other, and hand its `policy` function to `f`.
+    fn with_policy<T>(
+        &self,
+        f: impl for<'lua> FnOnce(&'lua Lua, Function<'lua>) -> mlua::Result<T>,
+    ) -> Result<T> {
+        let result = interpreter().and_then(|lua| {
+            lua.load(&*self.bytecode)
+                .set_name(self.path.to_string_lossy())
+                .set_mode(ChunkMode::Binary)
+                .exec()
+                .and_then(|_| lua.globals().get::<_, Function>("policy"))
+                .and_then(|policy| f(&lua, policy))
+        });
+        result
+            .map_err(|e| match is_overrun(&e) {
+                true => anyhow!(Overrun),
+                false => anyhow!("{}", e),
+            })
+            .with_context(|| format!("Policy script {:?} failed", self.path))
+    }
+
+    /// Ask the script what to do with a message. `cert_dir` is consulted for the
+    /// certificate availability. The script runs on a blocking thread; running out of
+    /// its limits fails with [`Overrun`].
+    pub async fn decide(&self, input: &PolicyInput<'_>, cert_dir: &Path) -> Result<PolicyDecision> {
+        let script = self.clone();
+        let input = OwnedInput {
+            sender: input.sender.to_string(),
+            recipients: input.recipients.to_vec(),
+            headers: input.headers.to_vec(),
+            action: input.action.cloned(),
+            origin: input.origin,
+            cert_dir: cert_dir.to_path_buf(),
+        };
+        let decision = tokio::task::spawn_blocking(move || script.run(&input));
+        match tokio::time::timeout(TIME_LIMIT, decision).await {
+            Ok(decision) => decision.context("Policy script panicked")?,
+            Err(_) => Err(anyhow!(Overrun))
+                .with_context(|| format!("Policy script {:?} failed", self.path)),
+        }
+    }
+
+    fn run(&self, input: &OwnedInput) -> Result<PolicyDecision> {
+        let result: Option<String> = self.with_policy(|lua, policy| {
+            let msg = lua.create_table()?;
+            msg.set("sender", input.sender.as_str())?;
+            msg.set("recipients", input.recipients.as_slice())?;
+
+            let headers = lua.create_table()?;
+            for (name, value) in &input.headers {
+                let header = lua.create_table()?;
+                header.set("name", name.as_str())?;
+                header.set("value", value.as_str())?;
+                headers.push(header)?;
+            }
+            msg.set("headers", headers)?;
+
+            let certificates = lua.create_table()?;
+            for email in std::iter::once(&input.sender).chain(&input.recipients) {
+                let name = address::cert_name(&input.cert_dir, email);
+                let available = input.cert_dir.join(format!("{}.pem", name)).is_file();
+                certificates.set(email.as_str(), available)?;
+            }
+            msg.set("certificates", certificates)?;
+
+            msg.set("action", input.action.as_ref().map(MilterAction::as_str))?;
+            msg.set("origin", input.origin.map(Origin::as_str))?;
+            policy.call(msg)
+        })?;
+
+        Ok(match result.as_deref() {
+            None => input
+                .action
+                .clone()
+                .map_or(PolicyDecision::Accept, PolicyDecision::Process),
+            Some("encrypt") => PolicyDecision::Process(MilterAction::Encrypt),
+            Some("harvest") => PolicyDecision::Process(MilterAction::ExtractKeys),
+            Some("enroll") => PolicyDecision::Process(MilterAction::Enroll),
+            Some("accept") => PolicyDecision::Accept,
+            Some("reject") => PolicyDecision::Reject,
+            Some("tempfail") => PolicyDecision::Tempfail,
+            Some(other) => bail!(
+                "Policy script {:?} returned unknown decision {:?}",
+                self.path,
+                other
+            ),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn load_script(source: &str) -> (tempfile::NamedTempFile, PolicyScript) {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(source.as_bytes()).unwrap();
+        let script = PolicyScript::load(file.path()).unwrap();
+        (file, script)
+    }
+
+    async fn decide(
+        script: &PolicyScript,
+        action: Option<&MilterAction>,
+        cert_dir: &Path,
+    ) -> Result<PolicyDecision> {
+        let recipients = ["b@example.com".to_string()];
+        let headers = [("Subject".to_string(), "[secret] Hello".to_string())];
+        let input = PolicyInput {
+            sender: "a@example.com",
+            recipients: &recipients,
+            headers: &headers,
+            action,
+            origin: Some(Origin::External),
+        };
+        script.decide(&input, cert_dir).await
+    }
+
+    #[test]
+    fn test_load_requires_policy_function() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"function other(msg) end").unwrap();
+        assert!(PolicyScript::load(file.path()).is_err());
+        file.write_all(b" this is not lua").unwrap();
+        assert!(PolicyScript::load(file.path()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_decide() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_file, script) = load_script(
+            r#"
+            function policy(msg)
+                if msg.origin ~= "external" then
+                    return "reject"
+                end
+                for _, h in ipairs(msg.headers) do
+                    if h.name == "Subject" and h.value:find("[secret]", 1, true) then
+                        if msg.certificates[msg.recipients[1]] then
+                            return "encrypt"
+                        end
+                        return "tempfail"
+                    end
+                end
+            end
+            "#,
+        );
+
+        assert_eq!(
+            decide(&script, None, dir.path()).await.unwrap(),
+            PolicyDecision::Tempfail
+        );
+        std::fs::write(dir.path().join("b@example.com.pem"), "").unwrap();
+        assert_eq!(
+            decide(&script, None, dir.path()).await.unwrap(),
+            PolicyDecision::Process(MilterAction::Encrypt)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decide_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_file, script) = load_script("function policy(msg) return nil end");
+        assert_eq!(
+            decide(&script, None, dir.path()).await.unwrap(),
+            PolicyDecision::Accept
+        );
+        let (_file, script) = load_script("function policy(msg) return msg.action end");
+        assert_eq!(
+            decide(&script, Some(&MilterAction::ExtractKeys), dir.path())
+                .await
+                .unwrap(),
+            PolicyDecision::Process(MilterAction::ExtractKeys)
+        );
+        let (_file, script) = load_script("function policy(msg) return 'shred' end");
+        assert!(decide(&script, None, dir.path()).await.is_err());
+        let (_file, script) = load_script("function policy(msg) error('boom') end");
+        assert!(decide(&script, None, dir.path()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_decide_overrun() {
+        let dir = tempfile::tempdir().unwrap();
+        for source in [
+            "function policy(msg) while true do end end",
+            "function policy(msg) while true do pcall(function() while true do end end) end end",
+            "function policy(msg) local t = {} while true do t[#t + 1] = string.rep('x', 1024) end end",
+            "function policy(msg) return coroutine.wrap(function() while true do end end)() end",
+        ] {
+            let (_file, script) = load_script(source);
+            let error = decide(&script, None, dir.path()).await.unwrap_err();
+            assert!(
+                error.is::<Overrun>() || source.contains("coroutine"),
+                "{source}: {error:#}"
+            );
+        }
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"while true do end function policy(msg) end")
+            .unwrap();
+        let error = PolicyScript::load(file.path()).err().unwrap();
+        assert!(error.is::<Overrun>(), "{error:#}");
+    }
+}
@@ -0,0 +1,137 @@
+//! Dropping root privileges once the sockets are bound and the keys and secrets are read, so a
+//! parser or OpenSSL bug can't be exploited as root. Afterwards, the process must still be able
+//! to read and write the certificate directories, and must not be able to become root again.
+
+use anyhow::{bail, Context, Result};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// User and group IDs to switch to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Credentials {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+/// Buffer size for the `getpwnam_r` and `getgrnam_r` string fields.
+const ENTRY_BUFFER: usize = 16 * 1024;
+
+/// Look up `user`, and `group` if given, otherwise the user's primary group.
+pub fn lookup(user: &str, group: Option<&str>) -> Result<Credentials> {
+    let name = CString::new(user).context("Invalid user name")?;
+    let mut buffer = vec![0 as libc::c_char; ENTRY_BUFFER];
+    // SAFETY: passwd is plain data, and getpwnam_r only writes within the given buffer.
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+    let error = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut found,
+        )
+    };
+    if error != 0 {
+        return Err(std::io::Error::from_raw_os_error(error))
+            .with_context(|| format!("Failed to look up user {}", user));
+    }
+    if found.is_null() {
+        bail!("No user {}", user);
+    }
+    let mut credentials = Credentials {
+        uid: passwd.pw_uid,
+        gid: passwd.pw_gid,
+    };
+
+    if let Some(group) = group {
+        let name = CString::new(group).context("Invalid group name")?;
+        // SAFETY: as above, for group and getgrnam_r.
+        let mut entry: libc::group = unsafe { std::mem::zeroed() };
+        let mut found = std::ptr::null_mut();
+        let error = unsafe {
+            libc::getgrnam_r(
+                name.as_ptr(),
+                &mut entry,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut found,
+            )
+        };
+        if error != 0 {
+            return Err(std::io::Error::from_raw_os_error(error))
+                .with_context(|| format!("Failed to look up group {}", group));
+        }
+        if found.is_null() {
+            bail!("No group {}", group);
+        }
+        credentials.gid = entry.gr_gid;
+    }
+    Ok(credentials)
+}
+
+/// Fail with the last OS error unless `result` is 0.
+fn check(result: libc::c_int, what: &str) -> Result<()> {
+    match result {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()).with_context(|| format!("Failed to {}", what)),
+    }
+}
+
+/// Switch to `credentials` for good, dropping supplementary groups. This applies to all
+/// threads, as the C library takes care of.
+pub fn drop_to(credentials: Credentials) -> Result<()> {
+    let Credentials { uid, gid } = credentials;
+    // SAFETY: plain system calls, the group list outlives the call.
+    unsafe {
+        check(libc::setgroups(1, &gid), "drop supplementary groups")?;
+        check(libc::setgid(gid), "set the group ID")?;
+        check(libc::setuid(uid), "set the user ID")?;
+    }
+    // SAFETY: plain system calls.
+    let (euid, egid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    if euid != uid || egid != gid {
+        bail!(
+            "Still running as {}:{} after dropping privileges",
+            euid,
+            egid
+        );
+    }
+    // SAFETY: as above, succeeding only if root privileges remain.
+    if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        bail!("Regained root after dropping privileges");
+    }
+    Ok(())
+}
+
+/// Whether the process runs as root.
+pub fn is_root() -> bool {
+    // SAFETY: plain system call.
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Check that the directory can still be read and written.
+pub fn check_access(dir: &Path) -> Result<()> {
+    let path = CString::new(dir.as_os_str().as_bytes()).context("Invalid directory name")?;
+    // SAFETY: the path is a valid C string.
+    let result = unsafe { libc::access(path.as_ptr(), libc::R_OK | libc::W_OK | libc::X_OK) };
+    check(result, &format!("read and write {:?}", dir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let root = lookup("root", None).unwrap();
+        assert_eq!(root, Credentials { uid: 0, gid: 0 });
+        assert!(lookup("no-such-user-pantosmime", None).is_err());
+        assert!(lookup("root", Some("no-such-group-pantosmime")).is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        check_access(dir.path()).unwrap();
+        assert!(check_access(&dir.path().join("missing")).is_err());
+    }
+}
@@ -0,0 +1,153 @@
+//! Marker header for messages processed already, so they are left alone when an MTA
+//! reinjects them after a content filter, or when they pass another pantosmime host.
+//!
+//! The marker carries the queue ID of the processing host and a timestamp, authenticated with
+//! an HMAC-SHA256 with a secret shared by all hosts. The HMAC also covers the Message-ID and
+//! the body, so a marker copied into another message doesn't count. Forged or stale markers
+//! are removed and the message is processed as usual.
+
+use anyhow::{bail, Context, Result};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use std::path::Path;
+
+/// Header carrying the marker.
+pub const MARKER_HEADER: &str = "X-Pantosmime-Processed";
+
+/// Markers older than this are stale, five days like the default queue lifetime of Postfix.
+const MAX_AGE: u64 = 5 * 86400;
+
+/// Tolerated clock skew between hosts, for markers from the future.
+const MAX_SKEW: u64 = 300;
+
+/// Read a secret shared by all hosts, without trailing line breaks.
+pub fn load_secret(path: &Path) -> Result<Vec<u8>> {
+    let secret =
+        std::fs::read(path).with_context(|| format!("Failed to read secret from {:?}", path))?;
+    let len = secret
+        .iter()
+        .rposition(|b| !matches!(b, b'\r' | b'\n'))
+        .map_or(0, |last| last + 1);
+    if len < 16 {
+        bail!("Secret in {:?} is shorter than 16 bytes", path);
+    }
+    Ok(secret[..len].to_vec())
+}
+
+/// The message a marker belongs to.
+pub struct Binding<'b> {
+    pub message_id: Option<&'b str>,
+    /// Body of the message as delivered on. Whitespace doesn't count, as MTAs may change line
+    /// endings on the way back.
+    pub body: &'b [u8],
+}
+
+fn mac(secret: &[u8], queue_id: &str, timestamp: u64, binding: &Binding) -> Result<Vec<u8>> {
+    let key = PKey::hmac(secret)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    let message_id = binding.message_id.unwrap_or_default();
+    signer.update(format!("{}\n{}\n{}\n", queue_id, timestamp, message_id).as_bytes())?;
+    for chunk in binding.body.split(u8::is_ascii_whitespace) {
+        signer.update(chunk)?;
+    }
+    Ok(signer.sign_to_vec()?)
+}
+
+pub fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Value of the marker header for the message of `binding`, processed at `timestamp` (seconds
+/// since the epoch).
+pub fn marker(secret: &[u8], queue_id: &str, timestamp: u64, binding: &Binding) -> Result<String> {
+    Ok(format!(
+        "q={}; t={}; mac={}",
+        queue_id,
+        timestamp,
+        hex(&mac(secret, queue_id, timestamp, binding)?)
+    ))
+}
+
+/// Check a marker header value of the message of `binding`, returning the queue ID of the host
+/// that processed the message.
+pub fn verify(secret: &[u8], value: &str, binding: &Binding, now: u64) -> Result<String> {
+    let (mut queue_id, mut timestamp, mut tag) = (None, None, None);
+    for field in value.split(';') {
+        match field.trim().split_once('=') {
+            Some(("q", v)) => queue_id = Some(v),
+            Some(("t", v)) => timestamp = v.parse::<u64>().ok(),
+            Some(("mac", v)) => tag = Some(v),
+            _ => {}
+        }
+    }
+    let (Some(queue_id), Some(timestamp), Some(tag)) = (queue_id, timestamp, tag) else {
+        bail!("Malformed marker");
+    };
+    let expected = hex(&mac(secret, queue_id, timestamp, binding)?);
+    if tag.len() != expected.len() || !openssl::memcmp::eq(tag.as_bytes(), expected.as_bytes()) {
+        bail!("Marker has an invalid MAC");
+    }
+    if timestamp > now + MAX_SKEW || now.saturating_sub(timestamp) > MAX_AGE {
+        bail!("Marker from {} is stale", timestamp);
+    }
+    Ok(queue_id.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"0123456789abcdef";
+
+    const BINDING: Binding = Binding {
+        message_id: Some("<1@example.com>"),
+        body: b"Hello.\r\nBye.\r\n",
+    };
+
+    #[test]
+    fn test_marker() {
+        let value = marker(SECRET, "4Bc1x20kLz", 1_700_000_000, &BINDING).unwrap();
+        assert!(value.starts_with("q=4Bc1x20kLz; t=1700000000; mac="));
+        let verified = verify(SECRET, &value, &BINDING, 1_700_000_060).unwrap();
+        assert_eq!(verified, "4Bc1x20kLz");
+
+        assert!(verify(b"another secret!!", &value, &BINDING, 1_700_000_060).is_err());
+        let forged = value.replace("4Bc1x20kLz", "5Bc1x20kLz");
+        assert!(verify(SECRET, &forged, &BINDING, 1_700_000_060).is_err());
+        assert!(verify(SECRET, &value, &BINDING, 1_700_000_000 + MAX_AGE + 1).is_err());
+        assert!(verify(SECRET, &value, &BINDING, 1_700_000_000 - MAX_SKEW - 1).is_err());
+        assert!(verify(SECRET, "q=4Bc1x20kLz", &BINDING, 1_700_000_000).is_err());
+    }
+
+    #[test]
+    fn test_marker_binding() {
+        let value = marker(SECRET, "4Bc1x20kLz", 1_700_000_000, &BINDING).unwrap();
+        let relaid = Binding {
+            body: b"Hello.\nBye.\n",
+            ..BINDING
+        };
+        assert!(verify(SECRET, &value, &relaid, 1_700_000_060).is_ok());
+
+        let other_body = Binding {
+            body: b"Hello.\r\nSkip encryption.\r\n",
+            ..BINDING
+        };
+        assert!(verify(SECRET, &value, &other_body, 1_700_000_060).is_err());
+        let other_id = Binding {
+            message_id: Some("<2@example.com>"),
+            ..BINDING
+        };
+        assert!(verify(SECRET, &value, &other_id, 1_700_000_060).is_err());
+    }
+
+    #[test]
+    fn test_load_secret() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secret");
+        std::fs::write(&path, "0123456789abcdef\n").unwrap();
+        assert_eq!(load_secret(&path).unwrap(), SECRET);
+        std::fs::write(&path, "short\n").unwrap();
+        assert!(load_secret(&path).is_err());
+    }
+}
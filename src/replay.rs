@@ -0,0 +1,126 @@
+//! Replay of captured mail through the milter pipeline, for reproducing issues.
+
+use anyhow::{Context, Result};
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+use crate::milter_callbacks;
+use crate::milter_client::{spawn_milter, MilterClient, Outcome, Response};
+use crate::settings::Settings;
+
+/// Turn bare LF line endings into CRLF, like the MTA does before handing mail to milters.
+fn canonicalize_line_endings(raw: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(raw.len() + raw.len() / 32);
+    for (i, b) in raw.iter().enumerate() {
+        if *b == b'\n' && (i == 0 || raw[i - 1] != b'\r') {
+            out.push(b'\r');
+        }
+        out.push(*b);
+    }
+    out
+}
+
+/// Run a single message through the milter callbacks in-process, speaking the
+/// milter protocol over a local socket just like the MTA would.
+pub async fn replay_message(
+    settings: Arc<Settings>,
+    queue_id: &str,
+    sender: &str,
+    recipients: &[&str],
+    raw: &[u8],
+) -> Result<Outcome> {
+    let callbacks = milter_callbacks::assemble_callbacks(settings);
+    let mut client = MilterClient::connect(spawn_milter(callbacks).await).await?;
+    let outcome = client
+        .send_message(queue_id, sender, recipients, raw)
+        .await
+        .with_context(|| "Milter aborted the replayed transaction")?;
+    client.quit().await?;
+    Ok(outcome)
+}
+
+/// Replay the message on stdin, printing the decisions to stderr and the
+/// resulting message to stdout.
+pub async fn run(
+    settings: Arc<Settings>,
+    queue_id: &str,
+    sender: &str,
+    recipients: &[String],
+) -> Result<()> {
+    let mut raw = Vec::new();
+    std::io::stdin()
+        .read_to_end(&mut raw)
+        .with_context(|| "Failed to read message from stdin")?;
+    let raw = canonicalize_line_endings(&raw);
+
+    let recipients: Vec<&str> = recipients.iter().map(String::as_str).collect();
+    let outcome = replay_message(settings, queue_id, sender, &recipients, &raw).await?;
+
+    let mut stderr = std::io::stderr().lock();
+    for action in &outcome.actions {
+        writeln!(stderr, "action: {}", action)?;
+    }
+    let response = outcome.response.clone().unwrap_or(Response::Continue);
+    writeln!(stderr, "response: {}", response)?;
+
+    match response {
+        Response::Reject | Response::Tempfail | Response::Discard | Response::ReplyCode(_) => {}
+        _ => std::io::stdout().lock().write_all(&outcome.apply(&raw))?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::milter_client::split_message;
+    use crate::smime;
+    use crate::test_pki::self_signed_identity;
+
+    const SINGLE_EMAIL: &[u8] = include_bytes!("../data/mime/simple_email.eml");
+
+    #[test]
+    fn test_canonicalize_line_endings() {
+        assert_eq!(
+            canonicalize_line_endings(b"a\nb\r\nc\n"),
+            b"a\r\nb\r\nc\r\n"
+        );
+        assert_eq!(canonicalize_line_endings(b"\n"), b"\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_replay_encrypt() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, _) = self_signed_identity("b@example.com");
+        smime::write_pem_stack([&cert], &dir.path().join("b@example.com.pem"))
+            .await
+            .unwrap();
+
+        let raw = canonicalize_line_endings(SINGLE_EMAIL);
+        let outcome = replay_message(
+            Arc::new(Settings::new(
+                dir.path().to_path_buf(),
+                vec!["a@example.com".to_string()],
+            )),
+            "REPLAY",
+            "a@example.com",
+            &["b@example.com"],
+            &raw,
+        )
+        .await
+        .unwrap();
+        assert_eq!(outcome.response, Some(Response::Accept));
+
+        let message = outcome.apply(&raw);
+        let (headers, body) = split_message(&message);
+        let content_type: Vec<&str> = headers
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case("Content-Type"))
+            .map(|(_, v)| v.as_str())
+            .collect();
+        assert_eq!(content_type.len(), 1);
+        assert!(content_type[0].starts_with("application/pkcs7-mime"));
+        assert!(headers.iter().any(|(n, _)| n == "X-PANTOSMIME"));
+        assert_eq!(body, outcome.body().unwrap());
+    }
+}
@@ -0,0 +1,403 @@
+//! Replication of the certificate store to standby hosts, so a failover doesn't lose the
+//! certificates harvested meanwhile.
+//!
+//! The active host pushes every harvested chain and its metadata to its peers right away, and
+//! all certificate files periodically, to catch up on imports and peers that were down. Each
+//! file is sent as an HTTP `PUT` authenticated with an HMAC-SHA256 over the directory, file
+//! name, a timestamp and the content, with a secret shared by all hosts.
+//!
+//! Directories are named by their `--certificate-directory-override` pattern, or left empty
+//! for the main one, so peers may keep them at other paths. Removals are not replicated.
+//! Pooled intermediates are sent as `intermediates/<fingerprint>.pem`.
+
+use anyhow::{anyhow, bail, Context, Result};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, info, warn};
+
+use crate::cert_store::{self, StoreLock};
+use crate::cert_usage;
+use crate::reinjection::hex;
+use crate::settings::Settings;
+use crate::smime;
+use crate::smime_attributes::CertMetadata;
+
+/// Largest file accepted, certificate chains and metadata are a few KiB.
+const MAX_FILE_SIZE: usize = 1024 * 1024;
+
+/// Tolerated clock skew between hosts, and time to replay a request.
+const MAX_SKEW: u64 = 300;
+
+/// Time for a peer to take a file.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where and how to replicate to.
+#[derive(Debug, Clone, Default)]
+pub struct Replication {
+    /// Standby hosts, as `<HOST>:<PORT>`.
+    pub peers: Vec<String>,
+    /// Secret shared by all hosts to authenticate the files.
+    pub secret: Vec<u8>,
+}
+
+/// Name of `cert_dir` shared with the peers.
+fn dir_key<'s>(settings: &'s Settings, cert_dir: &Path) -> &'s str {
+    match settings
+        .cert_dir_overrides
+        .iter()
+        .find(|(_, dir)| dir == cert_dir)
+    {
+        Some((pattern, _)) if cert_dir != settings.cert_dir => pattern,
+        _ => "",
+    }
+}
+
+/// Local certificate directory of a name from a peer.
+fn dir_by_key<'s>(settings: &'s Settings, key: &str) -> Option<&'s Path> {
+    match key {
+        "" => Some(&settings.cert_dir),
+        key => settings
+            .cert_dir_overrides
+            .iter()
+            .find(|(pattern, _)| pattern == key)
+            .map(|(_, dir)| dir.as_path()),
+    }
+}
+
+/// Whether a file is replicated: certificate chains, their metadata and pooled intermediates.
+fn is_replicated(file_name: &str) -> bool {
+    cert_store::is_pooled(file_name)
+        || (!file_name.starts_with('.')
+            && !file_name.contains(['/', '\\'])
+            && (file_name.ends_with(".pem") || file_name.ends_with(".json")))
+}
+
+fn mac(secret: &[u8], dir: &str, file_name: &str, timestamp: u64, data: &[u8]) -> Result<String> {
+    let key = PKey::hmac(secret)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(format!("{}\n{}\n{}\n", dir, file_name, timestamp).as_bytes())?;
+    signer.update(data)?;
+    Ok(hex(&signer.sign_to_vec()?))
+}
+
+impl Replication {
+    /// Send a file to one peer.
+    async fn push(&self, peer: &str, dir: &str, file_name: &str, data: &[u8]) -> Result<()> {
+        let timestamp = cert_usage::now();
+        let request = format!(
+            "PUT /{} HTTP/1.0\r\nX-Pantosmime-Directory: {}\r\nX-Pantosmime-Timestamp: {}\r\nX-Pantosmime-Mac: {}\r\nContent-Length: {}\r\n\r\n",
+            file_name,
+            dir,
+            timestamp,
+            mac(&self.secret, dir, file_name, timestamp, data)?,
+            data.len()
+        );
+        let status = tokio::time::timeout(TIMEOUT, async {
+            let mut stream = TcpStream::connect(peer).await?;
+            stream.write_all(request.as_bytes()).await?;
+            stream.write_all(data).await?;
+            let mut status = String::new();
+            BufReader::new(stream).read_line(&mut status).await?;
+            anyhow::Ok(status)
+        })
+        .await
+        .map_err(|_| anyhow!("Timed out"))??;
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => bail!("Peer answered {:?}", status.trim_end()),
+        }
+    }
+
+    /// Send the files stored under `name` to all peers, the intermediates and metadata first
+    /// like when storing.
+    async fn push_certificate(self, dir: String, cert_dir: PathBuf, name: String) {
+        let intermediates = match CertMetadata::load(&CertMetadata::path(&cert_dir, &name)).await {
+            Ok(metadata) => metadata.map(|m| m.intermediates).unwrap_or_default(),
+            Err(error) => {
+                warn!(
+                    ?error,
+                    "Not replicating intermediates of unreadable metadata"
+                );
+                Vec::new()
+            }
+        };
+        let file_names = intermediates
+            .iter()
+            .map(|fingerprint| format!("{}/{}.pem", smime::INTERMEDIATES, fingerprint))
+            .chain([format!("{}.json", name), format!("{}.pem", name)]);
+        for file_name in file_names {
+            let data = match tokio::fs::read(cert_dir.join(&file_name)).await {
+                Ok(data) => data,
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(error) => {
+                    error!(?error, file_name, "Failed to read file to replicate");
+                    return;
+                }
+            };
+            for peer in &self.peers {
+                match self.push(peer, &dir, &file_name, &data).await {
+                    Ok(()) => debug!(peer, file_name, "Replicated"),
+                    Err(error) => warn!(?error, peer, file_name, "Replication failed"),
+                }
+            }
+        }
+    }
+
+    /// Send the certificates harvested into `cert_dir` under `name` to all peers, in the
+    /// background.
+    pub fn spawn_push(&self, settings: &Settings, cert_dir: &Path, name: &str) {
+        tokio::spawn(self.clone().push_certificate(
+            dir_key(settings, cert_dir).to_string(),
+            cert_dir.to_path_buf(),
+            name.to_string(),
+        ));
+    }
+
+    /// Send all certificate files to all peers. Returns the number of files sent.
+    async fn push_all(&self, settings: &Settings) -> Result<usize> {
+        let mut pushed = 0;
+        for cert_dir in settings.all_cert_dirs() {
+            let key = dir_key(settings, cert_dir);
+            // The pool first, so peers have the intermediates the metadata refers to.
+            for (dir, prefix) in [
+                (
+                    cert_dir.join(smime::INTERMEDIATES),
+                    format!("{}/", smime::INTERMEDIATES),
+                ),
+                (cert_dir.to_path_buf(), String::new()),
+            ] {
+                let mut entries = match tokio::fs::read_dir(&dir).await {
+                    Ok(entries) => entries,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound && !prefix.is_empty() => {
+                        continue
+                    }
+                    Err(e) => {
+                        return Err(e).with_context(|| {
+                            format!("Failed to read certificate directory {:?}", dir)
+                        })
+                    }
+                };
+                while let Some(entry) = entries.next_entry().await? {
+                    let file_name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+                    if !is_replicated(&file_name) {
+                        continue;
+                    }
+                    let data = tokio::fs::read(entry.path()).await?;
+                    for peer in &self.peers {
+                        self.push(peer, key, &file_name, &data)
+                            .await
+                            .with_context(|| format!("Failed to replicate to {}", peer))?;
+                    }
+                    pushed += 1;
+                }
+            }
+        }
+        Ok(pushed)
+    }
+}
+
+/// Send all certificate files to the peers periodically, forever.
+pub async fn run_periodically(settings: Arc<Settings>, interval: Duration) {
+    let Some(replication) = &settings.replication else {
+        return;
+    };
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        match replication.push_all(&settings).await {
+            Ok(files) => info!(files, "Replicated certificate store"),
+            Err(error) => error!(?error, "Replicating certificate store failed"),
+        }
+    }
+}
+
+/// Take a file from a peer, returning the HTTP status to answer with.
+async fn receive(stream: &mut TcpStream, settings: &Settings) -> Result<&'static str> {
+    let secret = match &settings.replication {
+        Some(replication) => &replication.secret,
+        None => return Ok("404 Not Found"),
+    };
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    let (mut dir, mut timestamp, mut tag, mut length) = (None, None, None, None);
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 || line.len() > 1024 {
+            return Ok("400 Bad Request");
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Ok("400 Bad Request");
+        };
+        let value = value.trim().to_string();
+        match name.to_ascii_lowercase().as_str() {
+            "x-pantosmime-directory" => dir = Some(value),
+            "x-pantosmime-timestamp" => timestamp = value.parse::<u64>().ok(),
+            "x-pantosmime-mac" => tag = Some(value),
+            "content-length" => length = value.parse::<usize>().ok(),
+            _ => {}
+        }
+    }
+    let file_name = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["PUT", path, _] => path.trim_start_matches('/').to_string(),
+        _ => return Ok("405 Method Not Allowed"),
+    };
+    let (Some(dir), Some(timestamp), Some(tag), Some(length)) = (dir, timestamp, tag, length)
+    else {
+        return Ok("400 Bad Request");
+    };
+    if length > MAX_FILE_SIZE {
+        return Ok("413 Payload Too Large");
+    }
+    let mut data = vec![0; length];
+    reader.read_exact(&mut data).await?;
+
+    let expected = mac(secret, &dir, &file_name, timestamp, &data)?;
+    if tag.len() != expected.len() || !openssl::memcmp::eq(tag.as_bytes(), expected.as_bytes()) {
+        warn!(file_name, "Rejecting replicated file with an invalid MAC");
+        return Ok("403 Forbidden");
+    }
+    if cert_usage::now().abs_diff(timestamp) > MAX_SKEW {
+        warn!(file_name, timestamp, "Rejecting stale replicated file");
+        return Ok("403 Forbidden");
+    }
+    if !is_replicated(&file_name) {
+        return Ok("400 Bad Request");
+    }
+    let Some(cert_dir) = dir_by_key(settings, &dir) else {
+        warn!(dir, "Rejecting replicated file for an unknown directory");
+        return Ok("404 Not Found");
+    };
+
+    let path = cert_dir.join(&file_name);
+    let _lock = StoreLock::acquire(cert_dir).await?;
+    if tokio::fs::read(&path)
+        .await
+        .is_ok_and(|stored| stored == data)
+    {
+        return Ok("204 No Content");
+    }
+    if cert_store::is_pooled(&file_name) {
+        tokio::fs::create_dir_all(cert_dir.join(smime::INTERMEDIATES)).await?;
+    }
+    let tmp = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        path.file_name().unwrap_or_default().to_string_lossy(),
+        uuid::Uuid::new_v4()
+    ));
+    tokio::fs::write(&tmp, &data)
+        .await
+        .with_context(|| format!("Failed to write {:?}", tmp))?;
+    tokio::fs::rename(&tmp, &path)
+        .await
+        .with_context(|| format!("Failed to write {:?}", path))?;
+    info!(?path, "Stored replicated file");
+    Ok("204 No Content")
+}
+
+/// Take files replicated by the active host.
+pub async fn serve(listener: TcpListener, settings: Arc<Settings>) -> Result<()> {
+    loop {
+        let (mut stream, peer) = listener.accept().await?;
+        let settings = settings.clone();
+        tokio::spawn(async move {
+            let status = match tokio::time::timeout(TIMEOUT, receive(&mut stream, &settings)).await
+            {
+                Ok(Ok(status)) => status,
+                Ok(Err(error)) => {
+                    error!(?error, ?peer, "Failed to take replicated file");
+                    "500 Internal Server Error"
+                }
+                Err(_) => "408 Request Timeout",
+            };
+            let response = format!("HTTP/1.0 {}\r\nContent-Length: 0\r\n\r\n", status);
+            if let Err(error) = stream.write_all(response.as_bytes()).await {
+                debug!(?error, ?peer, "Failed to answer replication peer");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_replication() {
+        let secret = b"0123456789abcdef".to_vec();
+        let active = tempfile::tempdir().unwrap();
+        let standby = tempfile::tempdir().unwrap();
+        let acme = tempfile::tempdir().unwrap();
+        let mut settings = Settings::new(standby.path().to_path_buf(), vec![]);
+        settings.cert_dir_overrides = vec![("*@acme.example".into(), acme.path().to_path_buf())];
+        settings.replication = Some(Replication {
+            peers: vec![],
+            secret: secret.clone(),
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap().to_string();
+        tokio::spawn(serve(listener, Arc::new(settings)));
+
+        let mut settings = Settings::new(active.path().to_path_buf(), vec![]);
+        let replication = Replication {
+            peers: vec![peer.clone()],
+            secret,
+        };
+        settings.replication = Some(replication.clone());
+        std::fs::write(active.path().join("a@example.com.pem"), "chain").unwrap();
+        std::fs::write(active.path().join("a@example.com.json"), "{}").unwrap();
+        std::fs::write(active.path().join(".cert-usage"), "").unwrap();
+        std::fs::create_dir(active.path().join(smime::INTERMEDIATES)).unwrap();
+        std::fs::write(smime::pooled_path(active.path(), "ab12"), "ca").unwrap();
+        assert_eq!(replication.push_all(&settings).await.unwrap(), 3);
+        assert_eq!(
+            std::fs::read(standby.path().join("a@example.com.pem")).unwrap(),
+            b"chain"
+        );
+        assert!(standby.path().join("a@example.com.json").exists());
+        assert!(!standby.path().join(".cert-usage").exists());
+        assert_eq!(
+            std::fs::read(smime::pooled_path(standby.path(), "ab12")).unwrap(),
+            b"ca"
+        );
+
+        replication
+            .push(&peer, "*@acme.example", "b@acme.example.pem", b"other")
+            .await
+            .unwrap();
+        assert!(acme.path().join("b@acme.example.pem").exists());
+
+        assert!(replication
+            .push(&peer, "*@unknown.example", "c@example.com.pem", b"")
+            .await
+            .is_err());
+        assert!(replication
+            .push(&peer, "", "../c@example.com.pem", b"")
+            .await
+            .is_err());
+        assert!(replication
+            .push(&peer, "", "intermediates/../../c@example.com.pem", b"")
+            .await
+            .is_err());
+        let forged = Replication {
+            peers: vec![],
+            secret: b"another secret!!".to_vec(),
+        };
+        assert!(forged
+            .push(&peer, "", "c@example.com.pem", b"")
+            .await
+            .is_err());
+        assert!(!standby.path().join("c@example.com.pem").exists());
+    }
+}
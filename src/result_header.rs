@@ -0,0 +1,122 @@
+//! Machine-readable header telling downstream filters, archivers or the MDA what was done
+//! with a message, like
+//! `X-Pantosmime-Result: action=encrypt; recipients=2/2; cipher=aes-256-gcm; q=4Bc1x20kLz;
+//! t=1700000000; mac=...`.
+//!
+//! The fields are authenticated with an HMAC-SHA256 over everything before `; mac=`, with a
+//! secret shared with the consumers, so a header forged by the sender is recognized. The queue
+//! ID ties it to the message.
+
+use anyhow::Result;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+
+use crate::reinjection::hex;
+
+/// Header carrying the result.
+pub const HEADER: &str = "X-Pantosmime-Result";
+
+/// Header carrying the [`Reason`] code, unauthenticated, for sieve filters and the like.
+pub const REASON_HEADER: &str = "X-PANTOSMIME";
+
+/// Why a message went through the way it did. Its code is stable, to branch on downstream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reason {
+    /// Encrypted for every recipient.
+    EncryptedOk,
+    /// Encrypted for some recipients, the others were excluded for lack of a certificate.
+    EncryptedPartial,
+    /// Certificates of the sender were stored.
+    HarvestedOk,
+    /// Certificates of the sender were spooled, to store once the directory is available.
+    HarvestedQueued,
+    /// Left unencrypted for a part the recipient processes automatically.
+    SkippedExempt,
+    /// Left unencrypted as the sender already encrypted it with inline PGP.
+    SkippedAlreadyEncrypted,
+    /// Left unencrypted as it is outside `--encrypt-percent`.
+    SkippedRollout,
+    /// Nothing harvested, the message isn't signed.
+    SkippedNotSigned,
+    /// Nothing harvested, the sender isn't authenticated.
+    SkippedUnauthenticated,
+    /// Nothing harvested, the From header doesn't match the sender.
+    SkippedFromMismatch,
+    /// Nothing harvested, the message comes from the null reverse-path.
+    SkippedNullSender,
+    /// Nothing harvested, the certificate chain is too large.
+    SkippedOversizedChain,
+    /// Nothing harvested, no certificate of the sender has a key to encrypt to.
+    SkippedUnsupportedKey,
+    /// Nothing harvested, the chain doesn't lead to the trust anchors.
+    SkippedUntrusted,
+}
+
+impl Reason {
+    pub fn code(self) -> &'static str {
+        match self {
+            Reason::EncryptedOk => "ENCRYPTED_OK",
+            Reason::EncryptedPartial => "ENCRYPTED_PARTIAL",
+            Reason::HarvestedOk => "HARVESTED_OK",
+            Reason::HarvestedQueued => "HARVESTED_QUEUED",
+            Reason::SkippedExempt => "SKIPPED_EXEMPT",
+            Reason::SkippedAlreadyEncrypted => "SKIPPED_ALREADY_ENCRYPTED",
+            Reason::SkippedRollout => "SKIPPED_ROLLOUT",
+            Reason::SkippedNotSigned => "SKIPPED_NOT_SIGNED",
+            Reason::SkippedUnauthenticated => "SKIPPED_UNAUTHENTICATED",
+            Reason::SkippedFromMismatch => "SKIPPED_FROM_MISMATCH",
+            Reason::SkippedNullSender => "SKIPPED_NULL_SENDER",
+            Reason::SkippedOversizedChain => "SKIPPED_OVERSIZED_CHAIN",
+            Reason::SkippedUnsupportedKey => "SKIPPED_UNSUPPORTED_KEY",
+            Reason::SkippedUntrusted => "SKIPPED_UNTRUSTED",
+        }
+    }
+}
+
+/// Value of the header with the given fields, for a message processed at `timestamp` (seconds
+/// since the epoch).
+pub fn value(
+    secret: &[u8],
+    fields: &[(&str, String)],
+    queue_id: &str,
+    timestamp: u64,
+) -> Result<String> {
+    let mut value: Vec<String> = fields
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect();
+    value.push(format!("q={}", queue_id));
+    value.push(format!("t={}", timestamp));
+    let value = value.join("; ");
+    let key = PKey::hmac(secret)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(value.as_bytes())?;
+    Ok(format!("{}; mac={}", value, hex(&signer.sign_to_vec()?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_value() {
+        let secret = b"0123456789abcdef";
+        let fields = [
+            ("action", "encrypt".to_string()),
+            ("recipients", "2/2".to_string()),
+        ];
+        let value = value(secret, &fields, "4Bc1x20kLz", 1_700_000_000).unwrap();
+        let (signed, mac) = value.split_once("; mac=").unwrap();
+        assert_eq!(
+            signed,
+            "action=encrypt; recipients=2/2; q=4Bc1x20kLz; t=1700000000"
+        );
+
+        // What a consumer does, e.g. with `openssl dgst -sha256 -hmac`.
+        let key = PKey::hmac(secret).unwrap();
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+        signer.update(signed.as_bytes()).unwrap();
+        assert_eq!(mac, hex(&signer.sign_to_vec().unwrap()));
+    }
+}
@@ -0,0 +1,405 @@
+//! Opt-in filesystem sandbox, so a parser or OpenSSL bug can't read or write files beyond the
+//! certificate directories, the files named on the command line and what the C library needs.
+//!
+//! Landlock only confines the threads created after it is applied, so it is applied before the
+//! runtime starts its threads, allowing the files read at startup as well. Without Landlock,
+//! the process chroots into the directory holding all paths used while running instead, once
+//! everything is loaded, as that needs root.
+
+use anyhow::{bail, Context, Result};
+use std::path::{Component, Path, PathBuf};
+
+use crate::Cli;
+
+/// How a path is used.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    /// Read at startup only, left out of a chroot.
+    Startup,
+    /// Read while running, e.g. on reload.
+    Read,
+    /// Read and written.
+    Write,
+    /// A file appended to, created if missing.
+    Append,
+}
+
+/// What the C library and OpenSSL read while running: name resolution, the user database,
+/// time zones, OpenSSL's configuration and shared libraries loaded on demand. Missing ones are
+/// left out.
+const SYSTEM_PATHS: &[&str] = &[
+    "/etc/resolv.conf",
+    "/etc/hosts",
+    "/etc/host.conf",
+    "/etc/nsswitch.conf",
+    "/etc/gai.conf",
+    "/etc/services",
+    "/etc/protocols",
+    "/etc/passwd",
+    "/etc/group",
+    "/etc/localtime",
+    "/etc/ssl",
+    "/etc/pki",
+    "/etc/static",
+    "/usr/lib",
+    "/usr/lib64",
+    "/usr/share/zoneinfo",
+    "/lib",
+    "/lib64",
+    "/nix/store",
+    "/proc/self",
+    "/dev/null",
+    "/dev/urandom",
+];
+
+/// The paths named on the command line and how they are used.
+pub fn paths(cli: &Cli) -> Vec<(PathBuf, Access)> {
+    let mut paths = vec![(cli.certificate_directory.clone(), Access::Write)];
+    for (_, dir) in &cli.cert_dir_overrides {
+        paths.push((dir.clone(), Access::Write));
+    }
+    paths.extend(cli.dead_letter_dir.clone().map(|dir| (dir, Access::Write)));
+    paths.extend(
+        cli.harvest_spool_dir
+            .clone()
+            .map(|dir| (dir, Access::Write)),
+    );
+    if let Some(target) = cli.event_report.as_ref().filter(|t| !t.contains("://")) {
+        paths.push((PathBuf::from(target), Access::Append));
+    }
+
+    paths.extend(cli.address_file.clone().map(|path| (path, Access::Read)));
+    for (_, path) in &cli.trust_anchors {
+        paths.push((path.clone(), Access::Read));
+    }
+    paths.extend(cli.template_dir.clone().map(|dir| (dir, Access::Read)));
+    #[cfg(feature = "lua")]
+    paths.extend(cli.policy_script.clone().map(|path| (path, Access::Read)));
+    #[cfg(feature = "ldap")]
+    paths.extend(
+        cli.ldap
+            .ldap_bind_password_file
+            .clone()
+            .map(|path| (path, Access::Read)),
+    );
+
+    let startup = [
+        &cli.key_request_template,
+        &cli.gateway_certificate,
+        &cli.gateway_key,
+        &cli.escrow_certificate,
+        &cli.reinjection_secret_file,
+        &cli.result_secret_file,
+        &cli.replication_secret_file,
+    ];
+    for path in startup.into_iter().flatten() {
+        paths.push((path.clone(), Access::Startup));
+    }
+    paths
+}
+
+/// The absolute path without symbolic links. A missing file is allowed for `Append`.
+fn canonical(path: &Path, access: Access) -> Result<PathBuf> {
+    match std::fs::canonicalize(path) {
+        Ok(path) => Ok(path),
+        Err(_) if access == Access::Append && path.file_name().is_some() => {
+            let parent = path.parent().filter(|p| !p.as_os_str().is_empty());
+            let parent = std::fs::canonicalize(parent.unwrap_or(Path::new(".")))
+                .with_context(|| format!("Failed to resolve {:?}", path))?;
+            Ok(parent.join(path.file_name().unwrap_or_default()))
+        }
+        Err(error) => Err(error).with_context(|| format!("Failed to resolve {:?}", path)),
+    }
+}
+
+/// The deepest directory holding all of `paths`, which must not be the root directory.
+fn common_root(paths: &[PathBuf]) -> Result<PathBuf> {
+    let mut root: Option<PathBuf> = None;
+    for path in paths {
+        let dir = match path.is_dir() {
+            true => path.as_path(),
+            false => path.parent().unwrap_or(path),
+        };
+        root = Some(match root {
+            None => dir.to_path_buf(),
+            Some(root) => root
+                .components()
+                .zip(dir.components())
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| a)
+                .collect(),
+        });
+    }
+    match root {
+        Some(root) if root.components().any(|c| matches!(c, Component::Normal(_))) => Ok(root),
+        _ => bail!("The paths used while running share no directory to chroot into but /"),
+    }
+}
+
+/// The directory to chroot into, holding all paths used while running.
+pub fn chroot_root(cli: &Cli) -> Result<PathBuf> {
+    let mut runtime = Vec::new();
+    for (path, access) in paths(cli) {
+        if access != Access::Startup {
+            runtime.push(canonical(&path, access)?);
+        }
+    }
+    common_root(&runtime)
+}
+
+/// Turn `path` into the same path seen from within the chroot at `root`.
+fn rebase(path: &mut PathBuf, root: &Path) -> Result<()> {
+    let canonical = canonical(path, Access::Append)?;
+    let inner = canonical
+        .strip_prefix(root)
+        .with_context(|| format!("{:?} is outside {:?}", path, root))?;
+    *path = Path::new("/").join(inner);
+    Ok(())
+}
+
+/// Chroot into `root` for good, turning `paths` into the same paths seen from within.
+pub fn chroot(root: &Path, paths: Vec<&mut PathBuf>) -> Result<()> {
+    for path in paths {
+        rebase(path, root)?;
+    }
+    // Load the local time zone of schedules while /etc/localtime is still reachable.
+    // SAFETY: tm is plain data localtime_r writes to.
+    unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&0, &mut tm);
+    }
+    std::os::unix::fs::chroot(root).with_context(|| format!("Failed to chroot into {:?}", root))?;
+    std::env::set_current_dir("/").context("Failed to change into the chroot")
+}
+
+#[cfg(target_os = "linux")]
+mod landlock {
+    use anyhow::{Context, Result};
+    use std::fs::{File, OpenOptions};
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::path::Path;
+
+    use super::Access;
+
+    const CREATE_RULESET_VERSION: libc::c_uint = 1;
+    const RULE_PATH_BENEATH: libc::c_int = 1;
+
+    const EXECUTE: u64 = 1 << 0;
+    const WRITE_FILE: u64 = 1 << 1;
+    const READ_FILE: u64 = 1 << 2;
+    const READ_DIR: u64 = 1 << 3;
+    const REMOVE_DIR: u64 = 1 << 4;
+    const REMOVE_FILE: u64 = 1 << 5;
+    const MAKE_DIR: u64 = 1 << 7;
+    const MAKE_REG: u64 = 1 << 8;
+    const MAKE_SYM: u64 = 1 << 12;
+    /// Everything up to making symbolic links, as handled since ABI 1.
+    const ABI_1: u64 = (1 << 13) - 1;
+    const REFER: u64 = 1 << 13;
+    const TRUNCATE: u64 = 1 << 14;
+    /// Rights that apply to files rather than directories.
+    const FILE_ACCESS: u64 = EXECUTE | WRITE_FILE | READ_FILE | TRUNCATE;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: libc::c_int,
+    }
+
+    /// The Landlock ABI version the kernel supports, if any.
+    pub fn abi() -> Option<i32> {
+        // SAFETY: querying the version takes no attributes.
+        let version = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                CREATE_RULESET_VERSION,
+            )
+        };
+        (version > 0).then_some(version as i32)
+    }
+
+    /// Confine the calling thread, and the threads it creates afterwards, to `paths`.
+    pub fn restrict(abi: i32, paths: &[(&Path, Access)]) -> Result<()> {
+        let mut handled = ABI_1;
+        let mut write = WRITE_FILE
+            | READ_FILE
+            | READ_DIR
+            | REMOVE_DIR
+            | REMOVE_FILE
+            | MAKE_DIR
+            | MAKE_REG
+            | MAKE_SYM;
+        if abi >= 2 {
+            handled |= REFER;
+            write |= REFER;
+        }
+        if abi >= 3 {
+            handled |= TRUNCATE;
+            write |= TRUNCATE;
+        }
+
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        // SAFETY: the attributes outlive the call, which returns a new file descriptor.
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error())
+                .context("Failed to create the Landlock ruleset");
+        }
+        // SAFETY: the descriptor was just created and is owned by nobody else.
+        let ruleset = unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) };
+
+        for &(path, access) in paths {
+            if access == Access::Append {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("Failed to create {:?}", path))?;
+            }
+            let file = File::options()
+                .read(true)
+                .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+                .open(path)
+                .with_context(|| format!("Failed to open {:?}", path))?;
+            let mut allowed = match access {
+                Access::Startup | Access::Read => READ_FILE | READ_DIR,
+                Access::Write => write,
+                Access::Append => WRITE_FILE,
+            };
+            if !file.metadata()?.is_dir() {
+                allowed &= FILE_ACCESS;
+            }
+            let rule = PathBeneathAttr {
+                allowed_access: allowed & handled,
+                parent_fd: file.as_raw_fd(),
+            };
+            // SAFETY: both descriptors are open and the rule outlives the call.
+            let result = unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_add_rule,
+                    ruleset.as_raw_fd(),
+                    RULE_PATH_BENEATH,
+                    &rule,
+                    0,
+                )
+            };
+            if result != 0 {
+                return Err(std::io::Error::last_os_error())
+                    .with_context(|| format!("Failed to allow access to {:?}", path));
+            }
+        }
+
+        // SAFETY: plain system calls; no new privileges is required to restrict unprivileged.
+        unsafe {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                return Err(std::io::Error::last_os_error())
+                    .context("Failed to set no new privileges");
+            }
+            if libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) != 0 {
+                return Err(std::io::Error::last_os_error())
+                    .context("Failed to apply the Landlock ruleset");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The Landlock ABI version the kernel supports, if any.
+pub fn landlock_abi() -> Option<i32> {
+    #[cfg(target_os = "linux")]
+    return landlock::abi();
+    #[cfg(not(target_os = "linux"))]
+    None
+}
+
+/// Confine the process to `paths` and the system paths with Landlock, before any further
+/// threads are created. Returns the Landlock ABI version, or `None` if the kernel lacks it.
+#[cfg(target_os = "linux")]
+pub fn landlock(paths: &[(PathBuf, Access)]) -> Result<Option<i32>> {
+    let Some(abi) = landlock_abi() else {
+        return Ok(None);
+    };
+    let mut rules: Vec<(&Path, Access)> = paths
+        .iter()
+        .map(|(path, access)| (path.as_path(), *access))
+        .collect();
+    rules.extend(
+        SYSTEM_PATHS
+            .iter()
+            .map(Path::new)
+            .filter(|path| path.exists())
+            .map(|path| (path, Access::Read)),
+    );
+    landlock::restrict(abi, &rules)?;
+    Ok(Some(abi))
+}
+
+/// Landlock is only available on Linux.
+#[cfg(not(target_os = "linux"))]
+pub fn landlock(_paths: &[(PathBuf, Access)]) -> Result<Option<i32>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_common_root() {
+        let paths = [
+            PathBuf::from("/srv/pantosmime/certs"),
+            PathBuf::from("/srv/pantosmime/responsible.txt"),
+        ];
+        assert_eq!(common_root(&paths).unwrap(), Path::new("/srv/pantosmime"));
+        let paths = [PathBuf::from("/srv/certs"), PathBuf::from("/etc/hosts")];
+        assert!(common_root(&paths).is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+        let mut path = dir.path().join("events.json");
+        rebase(&mut path, &root).unwrap();
+        assert_eq!(path, Path::new("/events.json"));
+        assert!(rebase(&mut PathBuf::from("/etc/hosts"), &root).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_landlock() {
+        if landlock_abi().is_none() {
+            return;
+        }
+        let (inside, outside) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let secret = outside.path().join("secret");
+        std::fs::write(&secret, "secret").unwrap();
+        let paths = vec![(inside.path().to_path_buf(), Access::Write)];
+
+        // Only the thread applying it is confined, not the other tests.
+        let inside_path = inside.path().to_path_buf();
+        std::thread::spawn(move || {
+            landlock(&paths).unwrap().unwrap();
+            std::fs::write(inside_path.join("cert.pem"), "cert").unwrap();
+            let error = std::fs::read(&secret).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+        })
+        .join()
+        .unwrap();
+        assert!(std::fs::read(outside.path().join("secret")).is_ok());
+    }
+}
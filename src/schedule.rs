@@ -0,0 +1,313 @@
+//! Policy changes that apply within a time window, like requiring encryption to a partner from
+//! a set date or deferring instead of rejecting during maintenance, so nobody has to flip
+//! options at midnight.
+//!
+//! Times without an offset are in the local time zone, from `TZ` or `/etc/localtime`, daylight
+//! saving time included.
+
+use std::fmt;
+
+use crate::address;
+use crate::settings::parse_domain_pattern;
+
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// When a change applies.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Window {
+    /// From and until a point in time, in seconds since the epoch.
+    Range {
+        from: Option<i64>,
+        until: Option<i64>,
+    },
+    /// Every week on the given days, by bit from Sunday on, between minutes of the local day.
+    /// Windows ending before they start continue into the next day.
+    Weekly { days: u8, start: u32, end: u32 },
+}
+
+/// What changes within a window.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    /// Mail to the domain must be encrypted, whatever the TLS policy or a subaddress says.
+    Encrypt(String),
+    /// Refuse messages that can't be processed, or lack certificates, with a temporary error.
+    Tempfail,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::Encrypt(domain) => write!(f, "encrypt:{}", domain),
+            Change::Tempfail => f.write_str("tempfail"),
+        }
+    }
+}
+
+/// A change and when it applies.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scheduled {
+    pub window: Window,
+    pub change: Change,
+}
+
+/// Days since the epoch of a civil date, after Howard Hinnant.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Parse `HH:MM` into minutes of the day.
+fn parse_clock(s: &str) -> Option<u32> {
+    let (hours, minutes) = s.split_once(':')?;
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    (hours < 24 && minutes < 60 && s.len() == 5).then_some(hours * 60 + minutes)
+}
+
+/// Parse `2025-01-01`, `2025-01-01T08:00` or `2025-01-01T08:00:00`, in local time unless
+/// followed by `Z` or an offset like `+01:00`.
+fn parse_time(s: &str) -> Result<i64, String> {
+    let invalid = || format!("invalid time {:?}", s);
+    let (date, time) = s.split_once('T').unwrap_or((s, "00:00"));
+    let (time, offset) = match time.find(['Z', '+', '-']) {
+        Some(index) => (&time[..index], Some(&time[index..])),
+        None => (time, None),
+    };
+    let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+    let (Some(Ok(year)), Some(Ok(month)), Some(Ok(day))) = (date.next(), date.next(), date.next())
+    else {
+        return Err(invalid());
+    };
+    let (clock, seconds) = match time.len() {
+        8 => (&time[..5], time[6..].parse::<i64>().map_err(|_| invalid())?),
+        _ => (time, 0),
+    };
+    let minutes = parse_clock(clock).ok_or_else(invalid)?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || !(0..60).contains(&seconds) {
+        return Err(invalid());
+    }
+
+    let offset = match offset {
+        None => None,
+        Some("Z") => Some(0),
+        Some(offset) => {
+            let minutes = parse_clock(&offset[1..]).ok_or_else(invalid)?;
+            let sign = if offset.starts_with('-') { -1 } else { 1 };
+            Some(sign * i64::from(minutes) * 60)
+        }
+    };
+    match offset {
+        Some(offset) => Ok(days_from_civil(year, month, day) * 86400
+            + i64::from(minutes) * 60
+            + seconds
+            - offset),
+        None => {
+            // SAFETY: tm is plain data; mktime only reads the time zone database.
+            let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+            tm.tm_year = (year - 1900) as libc::c_int;
+            tm.tm_mon = (month - 1) as libc::c_int;
+            tm.tm_mday = day as libc::c_int;
+            tm.tm_hour = (minutes / 60) as libc::c_int;
+            tm.tm_min = (minutes % 60) as libc::c_int;
+            tm.tm_sec = seconds as libc::c_int;
+            tm.tm_isdst = -1;
+            match unsafe { libc::mktime(&mut tm) } {
+                -1 => Err(invalid()),
+                time => Ok(time),
+            }
+        }
+    }
+}
+
+/// Parse `2025-01-01`, `2025-01-01..2025-02-01`, `..2025-02-01`, `daily 02:00-04:00` or
+/// `sat,sun 22:00-06:00`.
+fn parse_window(s: &str) -> Result<Window, String> {
+    if let Some((days, clocks)) = s.split_once(' ') {
+        let days = match days {
+            "daily" => 0x7f,
+            days => days.split(',').try_fold(0u8, |mask, day| {
+                let index = WEEKDAYS
+                    .iter()
+                    .position(|name| day.eq_ignore_ascii_case(name))
+                    .ok_or_else(|| format!("invalid day {:?}", day))?;
+                Ok::<_, String>(mask | 1 << index)
+            })?,
+        };
+        let (start, end) = clocks
+            .split_once('-')
+            .and_then(|(start, end)| Some((parse_clock(start)?, parse_clock(end)?)))
+            .filter(|(start, end)| start != end)
+            .ok_or_else(|| format!("invalid times {:?}", clocks))?;
+        return Ok(Window::Weekly { days, start, end });
+    }
+    let (from, until) = match s.split_once("..") {
+        Some((from, until)) => (from, until),
+        None => (s, ""),
+    };
+    let parse = |s: &str| match s {
+        "" => Ok(None),
+        s => parse_time(s).map(Some),
+    };
+    let (from, until) = (parse(from)?, parse(until)?);
+    match (from, until) {
+        (None, None) => Err(format!("invalid window {:?}", s)),
+        (Some(from), Some(until)) if from >= until => {
+            Err(format!("window {:?} ends before it starts", s))
+        }
+        _ => Ok(Window::Range { from, until }),
+    }
+}
+
+/// Parse `<window>=<change>`, with `tempfail` or `encrypt:<domain>` as the change.
+pub fn parse_scheduled(s: &str) -> Result<Scheduled, String> {
+    let (window, change) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <window>=<change>, got {:?}", s))?;
+    let change = match change.split_once(':') {
+        None if change == "tempfail" => Change::Tempfail,
+        Some(("encrypt", domain)) => Change::Encrypt(parse_domain_pattern(domain)?),
+        _ => return Err(format!("invalid change {:?}", change)),
+    };
+    Ok(Scheduled {
+        window: parse_window(window.trim())?,
+        change,
+    })
+}
+
+/// Whether the weekday, from Sunday on, and minute of the day are within a weekly window.
+fn weekly_contains(days: u8, start: u32, end: u32, weekday: u32, minute: u32) -> bool {
+    let on = |weekday: u32| days & 1 << (weekday % 7) != 0;
+    match start < end {
+        true => on(weekday) && (start..end).contains(&minute),
+        false => (on(weekday) && minute >= start) || (on(weekday + 6) && minute < end),
+    }
+}
+
+impl Window {
+    /// Whether `time`, in seconds since the epoch, is within the window.
+    pub fn contains(&self, time: i64) -> bool {
+        match *self {
+            Window::Range { from, until } => {
+                from.is_none_or(|from| from <= time) && until.is_none_or(|until| time < until)
+            }
+            Window::Weekly { days, start, end } => {
+                let time = time as libc::time_t;
+                // SAFETY: tm is plain data localtime_r writes to.
+                let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+                if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+                    return false;
+                }
+                let minute = (tm.tm_hour * 60 + tm.tm_min) as u32;
+                weekly_contains(days, start, end, tm.tm_wday as u32, minute)
+            }
+        }
+    }
+
+    /// Whether the window is over for good.
+    pub fn has_ended(&self, time: i64) -> bool {
+        matches!(*self, Window::Range { until: Some(until), .. } if until <= time)
+    }
+}
+
+/// The changes applying at `time`.
+pub fn active(schedules: &[Scheduled], time: i64) -> impl Iterator<Item = &Change> {
+    schedules
+        .iter()
+        .filter(move |scheduled| scheduled.window.contains(time))
+        .map(|scheduled| &scheduled.change)
+}
+
+/// Whether mail to `recipient` must be encrypted at `time`.
+pub fn encryption_required(schedules: &[Scheduled], recipient: &str, time: i64) -> bool {
+    let domain = recipient.rsplit_once('@').map_or("", |(_, domain)| domain);
+    active(schedules, time).any(|change| match change {
+        Change::Encrypt(pattern) => address::domain_matches(pattern, domain),
+        Change::Tempfail => false,
+    })
+}
+
+/// Whether failures are to be refused with a temporary error at `time`.
+pub fn tempfail(schedules: &[Scheduled], time: i64) -> bool {
+    active(schedules, time).any(|change| *change == Change::Tempfail)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("1970-01-01T00:00Z"), Ok(0));
+        assert_eq!(parse_time("2025-01-01T00:00:00Z"), Ok(1_735_689_600));
+        assert_eq!(parse_time("2025-01-01T01:00+01:00"), Ok(1_735_689_600));
+        assert_eq!(parse_time("2024-12-31T19:00-05:00"), Ok(1_735_689_600));
+        assert!(parse_time("2025-13-01").is_err());
+        assert!(parse_time("2025-01-01T24:00").is_err());
+        assert!(parse_time("tomorrow").is_err());
+    }
+
+    #[test]
+    fn test_parse_scheduled() {
+        assert_eq!(
+            parse_scheduled("2025-01-01T00:00Z=encrypt:partner.example"),
+            Ok(Scheduled {
+                window: Window::Range {
+                    from: Some(1_735_689_600),
+                    until: None
+                },
+                change: Change::Encrypt("partner.example".into()),
+            })
+        );
+        assert_eq!(
+            parse_scheduled("sat,sun 22:00-06:00=tempfail"),
+            Ok(Scheduled {
+                window: Window::Weekly {
+                    days: 0b100_0001,
+                    start: 22 * 60,
+                    end: 6 * 60
+                },
+                change: Change::Tempfail,
+            })
+        );
+        assert!(parse_scheduled("2025-02-01T00:00Z..2025-01-01T00:00Z=tempfail").is_err());
+        assert!(parse_scheduled("someday 02:00-04:00=tempfail").is_err());
+        assert!(parse_scheduled("daily 02:00-04:00=reject").is_err());
+        assert!(parse_scheduled("daily 02:00-04:00").is_err());
+    }
+
+    #[test]
+    fn test_windows() {
+        let schedules = [
+            parse_scheduled("..2025-01-01T00:00Z=tempfail").unwrap(),
+            parse_scheduled("2025-01-01T00:00Z=encrypt:*.partner.example").unwrap(),
+        ];
+        let new_year = 1_735_689_600;
+        assert!(tempfail(&schedules, new_year - 1));
+        assert!(!tempfail(&schedules, new_year));
+        assert!(schedules[0].window.has_ended(new_year));
+        assert!(!encryption_required(
+            &schedules,
+            "a@mx.partner.example",
+            new_year - 1
+        ));
+        assert!(encryption_required(
+            &schedules,
+            "a@mx.partner.example",
+            new_year
+        ));
+        assert!(!encryption_required(&schedules, "a@example.com", new_year));
+
+        // Saturday and Sunday from 22:00 to 06:00 the next morning.
+        let (days, start, end) = (0b100_0001, 22 * 60, 6 * 60);
+        assert!(weekly_contains(days, start, end, 6, 23 * 60));
+        assert!(weekly_contains(days, start, end, 0, 5 * 60));
+        assert!(weekly_contains(days, start, end, 1, 5 * 60));
+        assert!(!weekly_contains(days, start, end, 1, 23 * 60));
+        assert!(!weekly_contains(days, start, end, 6, 5 * 60));
+        assert!(weekly_contains(0x7f, 2 * 60, 4 * 60, 3, 3 * 60));
+        assert!(!weekly_contains(0x7f, 2 * 60, 4 * 60, 3, 4 * 60));
+    }
+}
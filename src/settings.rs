@@ -0,0 +1,725 @@
+//! Settings shared by all milter connections.
+
+use openssl::x509::X509;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+use crate::address;
+use crate::address_list::{self, Pattern};
+use crate::authentication_results::Method;
+use crate::body_normalization::Normalization;
+use crate::cert_store::{CertCache, CertStore, StoreHealth};
+use crate::cert_usage::{self, UsageTracker};
+use crate::compat::Compatibility;
+use crate::config_reload::Reloadable;
+use crate::crypto_profile::CryptoProfile;
+use crate::decision_cache::DecisionCache;
+use crate::event_report::ReportQueue;
+use crate::gateway_identity::GatewayIdentity;
+use crate::hook::Hook;
+use crate::key_request::KeyRequest;
+use crate::milter_callbacks::MilterAction;
+use crate::network::InternalNetwork;
+use crate::pipeline::Pipeline;
+use crate::replication::Replication;
+use crate::schedule::{self, Scheduled};
+use crate::templates::Templates;
+use crate::transfer_encoding::EnvelopeEncoding;
+use crate::trust::TrustAnchors;
+
+#[cfg(feature = "lua")]
+use crate::policy_script::{PolicyDecision, PolicyScript};
+
+/// Handling chosen by the sender with a tag on a recipient, like `user+plain@example.com`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SubaddressAction {
+    /// Leave the message unencrypted.
+    Plain,
+    /// Encrypt the message, even if we are not responsible for the sender.
+    Encrypt,
+}
+
+/// Parse a `<TAG>=<ACTION>` subaddress mapping, the action being `plain` or `encrypt`.
+pub fn parse_subaddress(s: &str) -> Result<(String, SubaddressAction), String> {
+    let (tag, action) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <TAG>=<ACTION>, got {:?}", s))?;
+    let action = match action.trim() {
+        "plain" => SubaddressAction::Plain,
+        "encrypt" => SubaddressAction::Encrypt,
+        other => return Err(format!("unknown subaddress action {:?}", other)),
+    };
+    match tag.trim() {
+        "" => Err(format!("empty subaddress tag in {:?}", s)),
+        tag => Ok((tag.to_string(), action)),
+    }
+}
+
+/// What to do with a message for a recipient without a usable certificate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CertFailureAction {
+    /// Refuse the message for good.
+    Reject,
+    /// Have the sending MTA retry later, e.g. until the recipient sent a renewed certificate.
+    Tempfail,
+    /// Remove the recipient, encrypting for the others and naming it in a header.
+    Exclude,
+}
+
+/// Parse a `reject`, `tempfail` or `exclude` action.
+pub fn parse_cert_failure_action(s: &str) -> Result<CertFailureAction, String> {
+    match s {
+        "reject" => Ok(CertFailureAction::Reject),
+        "tempfail" => Ok(CertFailureAction::Tempfail),
+        "exclude" => Ok(CertFailureAction::Exclude),
+        other => Err(format!(
+            "unknown action {:?}, expected reject, tempfail or exclude",
+            other
+        )),
+    }
+}
+
+/// What to do with messages from responsible senders already encrypted with inline PGP.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InlinePgpAction {
+    /// Encrypt them anyway, logging a warning.
+    Warn,
+    /// Leave them as they are, respecting the sender's choice of PGP.
+    Skip,
+}
+
+/// Parse a `warn` or `skip` action.
+pub fn parse_inline_pgp_action(s: &str) -> Result<InlinePgpAction, String> {
+    match s {
+        "warn" => Ok(InlinePgpAction::Warn),
+        "skip" => Ok(InlinePgpAction::Skip),
+        other => Err(format!("unknown action {:?}, expected warn or skip", other)),
+    }
+}
+
+/// What to do with messages growing past the maximum size once encrypted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OversizeAction {
+    /// Reject them, rather than leaving it to a later hop.
+    Reject,
+    /// Encrypt them compressed, rejecting them if they are still too large.
+    Compress,
+    /// Pass them on anyway.
+    Proceed,
+}
+
+/// Parse a `reject`, `compress` or `proceed` action.
+pub fn parse_oversize_action(s: &str) -> Result<OversizeAction, String> {
+    match s {
+        "reject" => Ok(OversizeAction::Reject),
+        "compress" => Ok(OversizeAction::Compress),
+        "proceed" => Ok(OversizeAction::Proceed),
+        other => Err(format!(
+            "unknown action {:?}, expected reject, compress or proceed",
+            other
+        )),
+    }
+}
+
+/// What to do with messages whose headers exceed the limits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeaderOverflowAction {
+    /// Have the sending MTA retry later.
+    Tempfail,
+    /// Leave the message alone, unless it is to be encrypted.
+    PassThrough,
+}
+
+/// Parse a `tempfail` or `pass-through` action.
+pub fn parse_header_overflow_action(s: &str) -> Result<HeaderOverflowAction, String> {
+    match s {
+        "tempfail" => Ok(HeaderOverflowAction::Tempfail),
+        "pass-through" => Ok(HeaderOverflowAction::PassThrough),
+        other => Err(format!(
+            "unknown action {:?}, expected tempfail or pass-through",
+            other
+        )),
+    }
+}
+
+/// How the `From` header of a message to harvest from must match its envelope sender.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FromHeaderMatch {
+    /// Only the envelope sender counts.
+    Ignore,
+    /// A single `From` header address at the domain of the envelope sender.
+    SameDomain,
+    /// A single `From` header address that is the envelope sender.
+    SameAddress,
+}
+
+/// Parse `ignore`, `same-domain` or `same-address`.
+pub fn parse_from_header_match(s: &str) -> Result<FromHeaderMatch, String> {
+    match s {
+        "ignore" => Ok(FromHeaderMatch::Ignore),
+        "same-domain" => Ok(FromHeaderMatch::SameDomain),
+        "same-address" => Ok(FromHeaderMatch::SameAddress),
+        other => Err(format!(
+            "unknown From header match {:?}, expected ignore, same-domain or same-address",
+            other
+        )),
+    }
+}
+
+/// Address headers `--capture-header` can keep with a message.
+pub const ADDRESS_HEADERS: [&str; 4] = ["From", "Reply-To", "To", "Cc"];
+
+/// Parse the name of an address header, returning it in its usual spelling.
+pub fn parse_address_header(s: &str) -> Result<String, String> {
+    ADDRESS_HEADERS
+        .iter()
+        .find(|header| header.eq_ignore_ascii_case(s))
+        .map(|header| header.to_string())
+        .ok_or_else(|| {
+            format!(
+                "unknown address header {:?}, expected one of {}",
+                s,
+                ADDRESS_HEADERS.join(", ")
+            )
+        })
+}
+
+/// Which processing an instance does, to split it across dedicated instances sharing a
+/// certificate store, like harvesting on the inbound MX and encrypting on the submission host.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Mode {
+    /// Encrypt and harvest.
+    #[default]
+    Both,
+    /// Only encrypt, accepting messages to harvest unchanged.
+    EncryptOnly,
+    /// Only harvest and enroll certificates, accepting messages to encrypt unchanged.
+    HarvestOnly,
+}
+
+impl Mode {
+    /// Whether the instance performs `action`.
+    pub fn allows(self, action: &MilterAction) -> bool {
+        match self {
+            Mode::Both => true,
+            Mode::EncryptOnly => *action == MilterAction::Encrypt,
+            Mode::HarvestOnly => *action != MilterAction::Encrypt,
+        }
+    }
+}
+
+/// Parse `both`, `encrypt-only` or `harvest-only`.
+pub fn parse_mode(s: &str) -> Result<Mode, String> {
+    match s {
+        "both" => Ok(Mode::Both),
+        "encrypt-only" => Ok(Mode::EncryptOnly),
+        "harvest-only" => Ok(Mode::HarvestOnly),
+        other => Err(format!(
+            "unknown mode {:?}, expected both, encrypt-only or harvest-only",
+            other
+        )),
+    }
+}
+
+/// Milter protocol steps pantosmime has no use for, which the MTA can leave out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MilterStep {
+    Connect,
+    Helo,
+    Data,
+    Unknown,
+}
+
+/// The steps with their names.
+pub const MILTER_STEPS: [(&str, MilterStep); 4] = [
+    ("connect", MilterStep::Connect),
+    ("helo", MilterStep::Helo),
+    ("data", MilterStep::Data),
+    ("unknown", MilterStep::Unknown),
+];
+
+/// Parse `connect`, `helo`, `data` or `unknown`.
+pub fn parse_milter_step(s: &str) -> Result<MilterStep, String> {
+    MILTER_STEPS
+        .iter()
+        .find(|(name, _)| *name == s)
+        .map(|(_, step)| *step)
+        .ok_or_else(|| {
+            format!(
+                "unknown step {:?}, expected connect, helo, data or unknown",
+                s
+            )
+        })
+}
+
+/// When S/MIME is only a fallback for TLS, which messages to leave unencrypted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TlsPolicy {
+    /// Encrypt regardless of TLS.
+    Always,
+    /// Only encrypt messages submitted without TLS, as told by the `{tls_version}` macro.
+    UnprotectedSubmission,
+    /// Leave messages unencrypted if all recipients are at domains with verified TLS.
+    SkipVerifiedTls,
+}
+
+/// Parse `always`, `unprotected-submission` or `skip-verified-tls`.
+pub fn parse_tls_policy(s: &str) -> Result<TlsPolicy, String> {
+    match s {
+        "always" => Ok(TlsPolicy::Always),
+        "unprotected-submission" => Ok(TlsPolicy::UnprotectedSubmission),
+        "skip-verified-tls" => Ok(TlsPolicy::SkipVerifiedTls),
+        other => Err(format!(
+            "unknown TLS policy {:?}, expected always, unprotected-submission or skip-verified-tls",
+            other
+        )),
+    }
+}
+
+/// Parse a domain pattern (`example.com`, `*.example.com` or `*`) into its ASCII form.
+pub fn parse_domain_pattern(s: &str) -> Result<String, String> {
+    match idna::domain_to_ascii(s.trim()) {
+        Ok(ascii) if !ascii.is_empty() => Ok(ascii),
+        _ => Err(format!("invalid domain {:?}", s)),
+    }
+}
+
+/// Compile the responsible addresses, normalizing the wildcard patterns. Entries were
+/// validated when loaded, broken ones are left out.
+fn compile_all(addresses: &[String]) -> Vec<Pattern> {
+    addresses
+        .iter()
+        .filter_map(|entry| match Pattern::parse(entry) {
+            Ok(Pattern::Wildcard(pattern)) => Some(Pattern::Wildcard(address::normalize(&pattern))),
+            Ok(regex) => Some(regex),
+            Err(error) => {
+                tracing::error!(?error, "Ignoring responsible address");
+                None
+            }
+        })
+        .collect()
+}
+
+/// Everything the callbacks need to know about the deployment.
+pub struct Settings {
+    /// Directory holding the `<address>.pem` certificate chains.
+    pub cert_dir: PathBuf,
+    /// Alternate certificate directories for address patterns, first match wins.
+    pub cert_dir_overrides: Vec<(String, PathBuf)>,
+    /// Addresses we encrypt for and harvest certificates for.
+    pub responsible: Reloadable<Vec<Pattern>>,
+    /// Processing done by this instance.
+    pub mode: Mode,
+    /// Protocol steps the MTA is asked to leave out.
+    pub milter_skip_steps: Vec<MilterStep>,
+    /// Accept messages to harvest from after their headers unless they are multipart.
+    pub skip_unsigned_bodies: bool,
+    /// Clients whose mail counts as internal.
+    pub internal_networks: Vec<InternalNetwork>,
+    /// Leave mail from external clients unencrypted.
+    pub encrypt_internal_only: bool,
+    /// Don't harvest certificates from mail of internal clients.
+    pub harvest_external_only: bool,
+    /// Classify messages by their `Received` headers where the macros are unavailable.
+    pub origin_from_received: bool,
+    /// Store taking the place of the certificate directories for looking up recipients and
+    /// storing harvested chains, for programs embedding the gateway.
+    pub store: Option<Arc<dyn CertStore>>,
+    /// Certificates looked up for encryption.
+    pub cert_cache: CertCache,
+    /// Circuit breaker for unavailable certificate directories.
+    pub store_health: StoreHealth,
+    /// Certificates of all recipients of recent envelopes.
+    pub recipient_certs: DecisionCache<Vec<(X509, String)>>,
+    /// Uses of the certificates, not yet merged into the usage records.
+    pub cert_usage: UsageTracker,
+    /// Content transfer encoding of encrypted messages.
+    pub envelope_encoding: EnvelopeEncoding,
+    /// Workarounds for picky receiving clients.
+    pub compat: Compatibility,
+    /// Crypto profiles for recipient domains, first match wins.
+    pub crypto_profiles: Vec<(String, CryptoProfile)>,
+    /// Rewrites of envelope addresses before matching and certificate lookups, first match wins.
+    pub address_rewrites: Vec<(String, String)>,
+    /// Domain qualifying bare local usernames and address literals, which are skipped without.
+    pub local_domain: Option<String>,
+    /// Recipient subaddress tags choosing the handling of a message.
+    pub subaddress_actions: Vec<(String, SubaddressAction)>,
+    /// Headers removed from encrypted messages.
+    pub strip_headers: Vec<String>,
+    /// Address headers kept with each message and protected by copying them into the
+    /// encrypted entity.
+    pub capture_headers: Vec<String>,
+    /// Also leave calendar invitations (`text/calendar`) unencrypted.
+    pub exempt_calendar: bool,
+    /// Handling of messages already encrypted with inline PGP.
+    pub inline_pgp_action: InlinePgpAction,
+    /// Percentage of the messages to encrypt that actually are, the others are marked instead.
+    pub encrypt_percent: u8,
+    /// Policy changes applying within time windows.
+    pub schedules: Vec<Scheduled>,
+    /// Handling of messages to recipients without any usable certificate on file.
+    pub missing_cert_action: CertFailureAction,
+    /// Handling of messages to recipients whose certificate expired, after the grace period.
+    pub expired_cert_action: CertFailureAction,
+    /// Days after the expiry of a certificate to tempfail messages for its owner.
+    pub expired_cert_grace_days: u32,
+    /// Which messages TLS protects well enough to leave them unencrypted.
+    pub tls_policy: TlsPolicy,
+    /// Domain patterns whose MX hosts we deliver to with verified TLS only.
+    pub verified_tls_domains: Vec<String>,
+    /// How the `From` header must match the envelope sender to harvest.
+    pub harvest_from_header: FromHeaderMatch,
+    /// Methods one of which must pass aligned with the sender to harvest, none to always.
+    pub harvest_require_auth: Vec<Method>,
+    /// Authentication services whose `Authentication-Results` headers are trusted.
+    pub authserv_ids: Vec<String>,
+    /// CAs the certificates of senders at matching domains must be issued by, first match wins.
+    pub trust_anchors: Reloadable<Vec<TrustAnchors>>,
+    /// Most certificates stored from a signature or enrollment.
+    pub max_chain_certs: usize,
+    /// Largest total DER size of the certificates stored from a signature or enrollment.
+    pub max_chain_bytes: usize,
+    /// Service addresses taking certificates sent as certs-only messages.
+    pub enrollment_addresses: Vec<String>,
+    /// Confirm imported certificates to the sender, instead of silently discarding the message.
+    pub enrollment_reply: bool,
+    /// SMTP server to submit notifications and replies to.
+    pub smtp_server: String,
+    /// Ask recipients without a certificate for a signed message.
+    pub key_request: Option<KeyRequest>,
+    /// Identity signing the notifications and replies sent.
+    pub gateway_identity: Option<GatewayIdentity>,
+    /// Encrypt the notifications and replies sent to recipients with a certificate on file.
+    pub encrypt_notifications: bool,
+    /// Attach the chain of the gateway identity as a certs-only part to the mail it signs.
+    pub publish_gateway_certificate: bool,
+    /// Texts of the notifications and replies sent.
+    pub templates: Templates,
+    /// Largest message accepted, in bytes.
+    pub max_message_size: Option<u64>,
+    /// Handling of messages exceeding the maximum size once encrypted.
+    pub oversize_action: OversizeAction,
+    /// Most recipients to encrypt a message to.
+    pub max_cms_recipients: Option<usize>,
+    /// Limits the OpenSSL jobs running at once on the blocking thread pool.
+    pub crypto_jobs: Option<Semaphore>,
+    /// Most headers accepted per message.
+    pub max_headers: usize,
+    /// Largest total size of the header names and values per message, in bytes.
+    pub max_header_bytes: usize,
+    /// Handling of messages exceeding the header limits.
+    pub header_overflow_action: HeaderOverflowAction,
+    /// MTA artifacts to undo in the body.
+    pub body_normalizations: Vec<Normalization>,
+    /// Where to save messages rejected because processing failed.
+    pub dead_letter_dir: Option<PathBuf>,
+    /// Where to keep harvested chains until their certificate directory is available.
+    pub harvest_spool_dir: Option<PathBuf>,
+    /// Secret shared by all hosts to authenticate the marker of processed messages.
+    pub reinjection_secret: Option<Vec<u8>>,
+    /// Secret shared with downstream filters to authenticate the result header.
+    pub result_secret: Option<Vec<u8>>,
+    /// Standby hosts to copy harvested certificates to.
+    pub replication: Option<Replication>,
+    /// Certificate every encrypted message is also encrypted to, to recover it with the escrow
+    /// key.
+    pub escrow_certificate: Option<X509>,
+    /// Stages run at the end of messages to encrypt.
+    pub encrypt_pipeline: Pipeline,
+    /// Stages run at the end of messages to harvest certificates from.
+    pub harvest_pipeline: Pipeline,
+    /// Stages run at the end of certs-only messages to enrollment addresses.
+    pub enroll_pipeline: Pipeline,
+    /// Where to send the per-message event reports.
+    pub report_sink: Option<ReportQueue>,
+    /// Run with the report of each encrypted or harvested message.
+    pub post_process_hook: Option<Arc<Hook>>,
+    /// Script overriding the action decision per message.
+    #[cfg(feature = "lua")]
+    pub policy_script: Reloadable<Option<PolicyScript>>,
+    /// Recent decisions of the script, by envelope.
+    #[cfg(feature = "lua")]
+    pub policy_decisions: DecisionCache<PolicyDecision>,
+}
+
+impl Settings {
+    pub fn new(cert_dir: PathBuf, responsible: Vec<String>) -> Self {
+        Self {
+            cert_dir,
+            cert_dir_overrides: Vec::new(),
+            responsible: Reloadable::new(compile_all(&responsible)),
+            mode: Mode::Both,
+            milter_skip_steps: Vec::new(),
+            skip_unsigned_bodies: false,
+            internal_networks: Vec::new(),
+            encrypt_internal_only: false,
+            harvest_external_only: false,
+            origin_from_received: false,
+            store: None,
+            cert_cache: CertCache::default(),
+            store_health: StoreHealth::default(),
+            recipient_certs: DecisionCache::default(),
+            cert_usage: UsageTracker::default(),
+            envelope_encoding: EnvelopeEncoding::default(),
+            compat: Compatibility::default(),
+            crypto_profiles: Vec::new(),
+            address_rewrites: Vec::new(),
+            local_domain: None,
+            subaddress_actions: Vec::new(),
+            strip_headers: Vec::new(),
+            capture_headers: Vec::new(),
+            exempt_calendar: false,
+            inline_pgp_action: InlinePgpAction::Warn,
+            encrypt_percent: 100,
+            schedules: Vec::new(),
+            missing_cert_action: CertFailureAction::Reject,
+            expired_cert_action: CertFailureAction::Reject,
+            expired_cert_grace_days: 0,
+            tls_policy: TlsPolicy::Always,
+            verified_tls_domains: Vec::new(),
+            harvest_from_header: FromHeaderMatch::Ignore,
+            harvest_require_auth: Vec::new(),
+            authserv_ids: Vec::new(),
+            trust_anchors: Reloadable::default(),
+            max_chain_certs: 10,
+            max_chain_bytes: 65_536,
+            enrollment_addresses: Vec::new(),
+            enrollment_reply: false,
+            smtp_server: "localhost:25".to_string(),
+            key_request: None,
+            gateway_identity: None,
+            encrypt_notifications: false,
+            publish_gateway_certificate: false,
+            templates: Templates::default(),
+            max_message_size: None,
+            oversize_action: OversizeAction::Reject,
+            max_cms_recipients: None,
+            max_headers: 10_000,
+            max_header_bytes: 1024 * 1024,
+            header_overflow_action: HeaderOverflowAction::Tempfail,
+            body_normalizations: Vec::new(),
+            dead_letter_dir: None,
+            harvest_spool_dir: None,
+            crypto_jobs: None,
+            reinjection_secret: None,
+            result_secret: None,
+            escrow_certificate: None,
+            replication: None,
+            encrypt_pipeline: Pipeline::encrypt(),
+            harvest_pipeline: Pipeline::harvest(),
+            enroll_pipeline: Pipeline::enroll(),
+            report_sink: None,
+            post_process_hook: None,
+            #[cfg(feature = "lua")]
+            policy_script: Reloadable::default(),
+            #[cfg(feature = "lua")]
+            policy_decisions: DecisionCache::default(),
+        }
+    }
+
+    /// Certificate directory for the given responsible address.
+    pub fn cert_dir_for(&self, email: &str) -> &Path {
+        self.cert_dir_overrides
+            .iter()
+            .find(|(pattern, _)| address_list::matches(pattern, email))
+            .map_or(&self.cert_dir, |(_, dir)| dir)
+    }
+
+    /// The main and all alternate certificate directories, without duplicates.
+    pub fn all_cert_dirs(&self) -> Vec<&Path> {
+        let mut dirs: Vec<&Path> = std::iter::once(self.cert_dir.as_path())
+            .chain(self.cert_dir_overrides.iter().map(|(_, dir)| dir.as_path()))
+            .collect();
+        dirs.sort();
+        dirs.dedup();
+        dirs
+    }
+
+    /// Handling chosen with the subaddress tag of a recipient.
+    pub fn subaddress_action(&self, tag: &str) -> Option<SubaddressAction> {
+        self.subaddress_actions
+            .iter()
+            .find(|(t, _)| t.eq_ignore_ascii_case(tag))
+            .map(|(_, action)| *action)
+    }
+
+    /// Whether a schedule requires mail to `recipient` to be encrypted right now.
+    pub fn encryption_required(&self, recipient: &str) -> bool {
+        schedule::encryption_required(&self.schedules, recipient, cert_usage::now() as i64)
+    }
+
+    /// Whether a schedule asks to refuse failing messages with a temporary error right now.
+    pub fn failures_tempfail(&self) -> bool {
+        schedule::tempfail(&self.schedules, cert_usage::now() as i64)
+    }
+
+    /// Handling of a message to a recipient whose certificate expired `days_ago` days ago.
+    pub fn action_for_expired(&self, days_ago: u32) -> CertFailureAction {
+        if days_ago < self.expired_cert_grace_days {
+            CertFailureAction::Tempfail
+        } else {
+            self.expired_cert_action
+        }
+    }
+
+    /// Why TLS makes encrypting a message unnecessary, given the `{tls_version}` of its
+    /// submission, if any.
+    pub fn tls_suffices(&self, inbound_tls: Option<&str>, recipients: &[String]) -> Option<&str> {
+        if recipients.iter().any(|r| self.encryption_required(r)) {
+            return None;
+        }
+        match self.tls_policy {
+            TlsPolicy::Always => None,
+            TlsPolicy::UnprotectedSubmission => inbound_tls
+                .filter(|version| !version.is_empty())
+                .map(|_| "Message was submitted with TLS"),
+            TlsPolicy::SkipVerifiedTls => {
+                let verified = |recipient: &String| {
+                    let domain = recipient.rsplit_once('@').map_or("", |(_, domain)| domain);
+                    self.verified_tls_domains
+                        .iter()
+                        .any(|pattern| address::domain_matches(pattern, domain))
+                };
+                (!recipients.is_empty() && recipients.iter().all(verified))
+                    .then_some("All recipients are at domains with verified TLS")
+            }
+        }
+    }
+
+    /// Whether content of the given `Content-Type` must never be encrypted, as the recipient
+    /// processes it automatically: delivery and read reports, and optionally invitations.
+    pub fn is_exempt(&self, content_type: &str) -> bool {
+        let mime_type = content_type.split(';').next().unwrap_or_default().trim();
+        mime_type.eq_ignore_ascii_case("multipart/report")
+            || (self.exempt_calendar && mime_type.eq_ignore_ascii_case("text/calendar"))
+    }
+
+    /// Whether certificates sent to the given address are to be imported.
+    pub fn is_enrollment_address(&self, email: &str) -> bool {
+        self.enrollment_addresses
+            .iter()
+            .any(|pattern| address_list::matches(pattern, email))
+    }
+
+    /// Replace the responsible addresses.
+    pub fn set_responsible(&self, responsible: Vec<String>) {
+        self.responsible.set(compile_all(&responsible));
+    }
+
+    /// Whether the address header `name` is kept with the message, as asked or to match the
+    /// `From` header with the sender.
+    pub fn captures_header(&self, name: &str) -> bool {
+        self.capture_headers
+            .iter()
+            .any(|header| header.eq_ignore_ascii_case(name))
+            || (self.harvest_from_header != FromHeaderMatch::Ignore
+                && name.eq_ignore_ascii_case("From"))
+    }
+
+    /// Whether we are responsible for the given address.
+    pub fn is_responsible(&self, email: &str) -> bool {
+        self.responsible
+            .get()
+            .iter()
+            .any(|pattern| pattern.matches(email))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cert_dir_for() {
+        let mut settings = Settings::new("/certs".into(), vec![]);
+        settings.cert_dir_overrides = vec![
+            ("boss@acme.test".into(), "/certs/boss".into()),
+            ("*@acme.test".into(), "/certs/acme".into()),
+        ];
+        assert_eq!(
+            settings.cert_dir_for("Boss@acme.test"),
+            Path::new("/certs/boss")
+        );
+        assert_eq!(
+            settings.cert_dir_for("a@acme.test"),
+            Path::new("/certs/acme")
+        );
+        assert_eq!(settings.cert_dir_for("a@example.com"), Path::new("/certs"));
+    }
+
+    #[test]
+    fn test_is_responsible() {
+        let settings = Settings::new("/certs".into(), vec!["@Example.com".into()]);
+        assert!(settings.is_responsible("a@example.com"));
+        assert!(!settings.is_responsible("a@sub.example.com"));
+        settings.set_responsible(vec!["@*.example.com".into()]);
+        assert!(settings.is_responsible("a@sub.example.com"));
+        assert!(!settings.is_responsible("a@example.com"));
+        settings.set_responsible(vec![r"/^sales-.*@corp\.example$/".into(), "/(/".into()]);
+        assert!(settings.is_responsible("sales-emea@corp.example"));
+        assert!(!settings.is_responsible("a@corp.example"));
+        assert_eq!(settings.responsible.get().len(), 1);
+    }
+
+    #[test]
+    fn test_parse_subaddress() {
+        assert_eq!(
+            parse_subaddress("nocrypt=plain"),
+            Ok(("nocrypt".to_string(), SubaddressAction::Plain))
+        );
+        assert_eq!(
+            parse_subaddress("secure=encrypt"),
+            Ok(("secure".to_string(), SubaddressAction::Encrypt))
+        );
+        assert!(parse_subaddress("sign=sign").is_err());
+        assert!(parse_subaddress("=plain").is_err());
+        assert!(parse_subaddress("plain").is_err());
+    }
+
+    #[test]
+    fn test_action_for_expired() {
+        let mut settings = Settings::new("/certs".into(), vec![]);
+        assert_eq!(settings.action_for_expired(0), CertFailureAction::Reject);
+        settings.expired_cert_grace_days = 7;
+        assert_eq!(settings.action_for_expired(6), CertFailureAction::Tempfail);
+        assert_eq!(settings.action_for_expired(7), CertFailureAction::Reject);
+        assert_eq!(
+            parse_cert_failure_action("tempfail"),
+            Ok(CertFailureAction::Tempfail)
+        );
+        assert!(parse_cert_failure_action("accept").is_err());
+    }
+
+    #[test]
+    fn test_tls_suffices() {
+        let mut settings = Settings::new("/certs".into(), vec![]);
+        let recipients = vec!["a@partner.test".to_string(), "b@mx.bank.test".to_string()];
+        assert_eq!(settings.tls_suffices(Some("TLSv1.3"), &recipients), None);
+
+        settings.tls_policy = parse_tls_policy("unprotected-submission").unwrap();
+        assert!(settings
+            .tls_suffices(Some("TLSv1.3"), &recipients)
+            .is_some());
+        assert_eq!(settings.tls_suffices(None, &recipients), None);
+
+        settings.tls_policy = parse_tls_policy("skip-verified-tls").unwrap();
+        settings.verified_tls_domains = vec!["partner.test".into(), "*.bank.test".into()];
+        assert!(settings.tls_suffices(None, &recipients).is_some());
+        let recipients = [recipients, vec!["c@example.com".to_string()]].concat();
+        assert_eq!(settings.tls_suffices(Some("TLSv1.3"), &recipients), None);
+        assert!(parse_tls_policy("never").is_err());
+    }
+
+    #[test]
+    fn test_is_exempt() {
+        let mut settings = Settings::new("/certs".into(), vec![]);
+        assert!(settings.is_exempt("multipart/report; report-type=delivery-status"));
+        assert!(settings.is_exempt("Multipart/Report"));
+        assert!(!settings.is_exempt("text/calendar; method=REQUEST"));
+        assert!(!settings.is_exempt("multipart/mixed"));
+        settings.exempt_calendar = true;
+        assert!(settings.is_exempt("text/calendar; method=REQUEST"));
+    }
+}
@@ -1,23 +1,29 @@
 use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
+use openssl::asn1::Asn1Object;
 use openssl::cms::{CMSOptions, CmsContentInfo};
 use openssl::nid::Nid;
 use openssl::pkcs7::Pkcs7;
+use openssl::pkey::{Id, PKeyRef, Private};
 use openssl::stack::Stack;
 use openssl::symm::Cipher;
 use openssl::x509::{X509Ref, X509};
 use std::convert::AsRef;
 use std::iter::IntoIterator;
-use std::path::Path;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
 
+use crate::address;
+use crate::cert_store::CertStore;
+use crate::der::{self, children, oid_to_string, read_tlv};
+use crate::smime_attributes::CertMetadata;
+
 /// Extracts signer certificates plus intermediates from PKCS#7 DER file content (.p7s)
 pub fn extract_certificates_from_p7s(der_data: &[u8]) -> Result<Vec<X509>> {
-    let pkcs7 =
-        Pkcs7::from_der(der_data).with_context(|| format!("Failed to parse PKCS#7 data"))?;
+    let pkcs7 = Pkcs7::from_der(der_data).with_context(|| "Failed to parse PKCS#7 data")?;
 
     let signed = pkcs7
         .signed()
@@ -28,25 +34,45 @@ pub fn extract_certificates_from_p7s(der_data: &[u8]) -> Result<Vec<X509>> {
     Ok(certs.into_iter().map(|e| e.to_owned()).collect())
 }
 
-/// Finds the first certificate in the list that matches the given email address.
+/// DER encoded certs-only signed-data, without signers or content, carrying `certs`.
+/// OpenSSL's safe bindings only build signed-data with a signer.
+pub fn certs_only(certs: &[X509]) -> Result<Vec<u8>> {
+    let mut encoded = Vec::new();
+    for cert in certs {
+        encoded.extend_from_slice(&cert.to_der()?);
+    }
+    let signed_data = der::encode(
+        0x30,
+        &[
+            der::encode(0x02, &[1]),
+            der::encode(0x31, &[]),
+            der::encode(0x30, &der::encode(0x06, der::DATA)),
+            der::encode(0xa0, &encoded),
+            der::encode(0x31, &[]),
+        ]
+        .concat(),
+    );
+    Ok(der::encode(
+        0x30,
+        &[
+            der::encode(0x06, der::SIGNED_DATA),
+            der::encode(0xa0, &signed_data),
+        ]
+        .concat(),
+    ))
+}
+
+/// Whether the certificate is issued for the given email address.
 /// It checks Subject Alternative Name (SAN) first, then falls back to Subject DN.
-pub fn find_cert_for_email<'a, C, I>(certs: I, email: &str) -> Result<X509>
-where
-    C: AsRef<X509Ref>,
-    I: IntoIterator<Item = C>,
-{
-    certs
-        .into_iter()
-        .find(|cert| {
-            let cert = cert.as_ref();
-            // Check Subject Alternative Names
-            cert.subject_alt_names()
-            .map(|san| {
-                san.iter()
-                    .filter_map(|name| name.email())
-                    .any(|san_email| san_email.eq_ignore_ascii_case(email))
-            })
-            .unwrap_or(false)
+fn is_issued_for(cert: &X509Ref, email: &str) -> bool {
+    // Check Subject Alternative Names
+    cert.subject_alt_names()
+        .map(|san| {
+            san.iter()
+                .filter_map(|name| name.email())
+                .any(|san_email| address::same_address(san_email, email))
+        })
+        .unwrap_or(false)
         ||
         // Fallback: Check Subject DN for Email or Common Name
         cert.subject_name()
@@ -54,15 +80,172 @@ where
             .filter_map(|entry| {
                 let nid = entry.object().nid();
                 if nid == Nid::PKCS9_EMAILADDRESS || nid == Nid::COMMONNAME {
-                    entry.data().as_utf8().ok().map(|s| s.to_string())
+                    entry.data().to_string().ok()
                 } else {
                     None
                 }
             })
-            .any(|name| name.eq_ignore_ascii_case(email))
-        })
+            .any(|name| address::same_address(&name, email))
+}
+
+/// Finds the first certificate in the list that matches the given email address.
+pub fn find_cert_for_email<C, I>(certs: I, email: &str) -> Result<X509>
+where
+    C: AsRef<X509Ref>,
+    I: IntoIterator<Item = C>,
+{
+    certs
+        .into_iter()
+        .find(|cert| is_issued_for(cert.as_ref(), email))
         .ok_or_else(|| anyhow!("Failed to find cert for {} in cert stack", email))
-        .and_then(|c| Ok(c.as_ref().to_owned()))
+        .map(|c| c.as_ref().to_owned())
+}
+
+/// Finds the first certificate for the given email address its key may be used to encrypt for.
+/// Owners of dual key pairs may only have their signing certificate on file.
+pub fn find_encryption_cert<C, I>(certs: I, email: &str) -> Result<X509>
+where
+    C: AsRef<X509Ref>,
+    I: IntoIterator<Item = C>,
+{
+    let mut signing_only = false;
+    let mut unsupported_key = None;
+    for cert in certs {
+        let cert = cert.as_ref();
+        if !is_issued_for(cert, email) {
+            continue;
+        }
+        if cert_usage(cert).is_ok_and(|usage| usage.encryption) {
+            if has_encryption_key(cert) {
+                return Ok(cert.to_owned());
+            }
+            unsupported_key.get_or_insert_with(|| cert.to_owned());
+            continue;
+        }
+        signing_only = true;
+    }
+    // Encrypting to it fails with a diagnosis of the key type, naming the certificate.
+    if let Some(cert) = unsupported_key {
+        return Ok(cert);
+    }
+    if signing_only {
+        bail!("Only a signing certificate on file for {}", email);
+    }
+    bail!("Failed to find cert for {} in cert stack", email)
+}
+
+/// Whether S/MIME can encrypt to the key of the certificate. Only RSA and EC keys qualify:
+/// EdDSA and RSASSA-PSS keys only sign, X25519 and X448 aren't supported by OpenSSL 3.0 in
+/// CMS, and keys of algorithms OpenSSL doesn't know can't be used at all.
+pub fn has_encryption_key(cert: &X509Ref) -> bool {
+    cert.public_key()
+        .is_ok_and(|key| matches!(key.id(), Id::RSA | Id::EC))
+}
+
+/// Name of the public key algorithm of the certificate, like `ED25519`, or its OID if OpenSSL
+/// doesn't know it.
+pub fn key_algorithm(cert: &X509Ref) -> Result<String> {
+    let der = cert.to_der().context("Failed to encode certificate")?;
+    let (certificate, _) = read_tlv(&der)?;
+    let tbs = children(certificate.value)?
+        .into_iter()
+        .next()
+        .context("Empty certificate")?;
+    let fields = children(tbs.value)?;
+    // Serial, signature, issuer, validity and subject come first, after the optional version.
+    let skip = match fields.first() {
+        Some(version) if version.tag == 0xa0 => 6,
+        _ => 5,
+    };
+    let public_key = fields.get(skip).context("Certificate lacks a public key")?;
+    let algorithm = children(public_key.value)?
+        .into_iter()
+        .next()
+        .context("Public key lacks an algorithm")?;
+    let oid = children(algorithm.value)?
+        .into_iter()
+        .next()
+        .context("Public key algorithm lacks an OID")?;
+    let oid = oid_to_string(oid.value);
+    let name = Asn1Object::from_str(&oid)
+        .ok()
+        .and_then(|object| object.nid().long_name().ok().map(str::to_string));
+    Ok(name.unwrap_or(oid))
+}
+
+const KEY_USAGE: &str = "2.5.29.15";
+const EXTENDED_KEY_USAGE: &str = "2.5.29.37";
+const ANY_EXTENDED_KEY_USAGE: &str = "2.5.29.37.0";
+const EMAIL_PROTECTION: &str = "1.3.6.1.5.5.7.3.4";
+
+/// What the key of a certificate may be used for in S/MIME.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CertUsage {
+    pub signing: bool,
+    pub encryption: bool,
+}
+
+impl CertUsage {
+    /// Names of the usages, as stored in the certificate metadata.
+    pub fn tags(&self) -> Vec<String> {
+        let mut tags = Vec::new();
+        if self.signing {
+            tags.push("signing".to_string());
+        }
+        if self.encryption {
+            tags.push("encryption".to_string());
+        }
+        tags
+    }
+}
+
+/// Read the usage from the key usage and extended key usage extensions, missing extensions
+/// not restricting it.
+pub fn cert_usage(cert: &X509Ref) -> Result<CertUsage> {
+    let mut usage = CertUsage {
+        signing: true,
+        encryption: true,
+    };
+    let der = cert.to_der().context("Failed to encode certificate")?;
+    let (certificate, _) = read_tlv(&der)?;
+    let tbs = children(certificate.value)?
+        .into_iter()
+        .next()
+        .context("Empty certificate")?;
+    let Some(extensions) = children(tbs.value)?.into_iter().find(|tlv| tlv.tag == 0xa3) else {
+        return Ok(usage);
+    };
+    let (extensions, _) = read_tlv(extensions.value)?;
+    for extension in children(extensions.value)? {
+        let parts = children(extension.value)?;
+        // The critical flag in between is optional, the value always comes last.
+        let (Some(oid), Some(value)) = (parts.first(), parts.last()) else {
+            bail!("Incomplete certificate extension");
+        };
+        match oid_to_string(oid.value).as_str() {
+            KEY_USAGE => {
+                let (bits, _) = read_tlv(value.value)?;
+                let first = bits.value.get(1).copied().unwrap_or(0);
+                // digitalSignature, nonRepudiation
+                usage.signing &= first & 0xc0 != 0;
+                // keyEncipherment, keyAgreement
+                usage.encryption &= first & 0x28 != 0;
+            }
+            EXTENDED_KEY_USAGE => {
+                let (purposes, _) = read_tlv(value.value)?;
+                let email = children(purposes.value)?.iter().any(|purpose| {
+                    matches!(
+                        oid_to_string(purpose.value).as_str(),
+                        EMAIL_PROTECTION | ANY_EXTENDED_KEY_USAGE
+                    )
+                });
+                usage.signing &= email;
+                usage.encryption &= email;
+            }
+            _ => {}
+        }
+    }
+    Ok(usage)
 }
 
 // Loads a certificate stack from a file with multiple PEM certificates
@@ -71,8 +254,30 @@ pub async fn load_pem_stack(cert: impl AsRef<Path>) -> Result<Vec<X509>> {
         .await
         .with_context(|| format!("Failed to read certificate {:?}", cert.as_ref()))?;
 
-    Ok(X509::stack_from_pem(&cert_content)
-        .with_context(|| format!("Failed to parse PEM certificate {:?}", cert.as_ref()))?)
+    X509::stack_from_pem(&cert_content)
+        .with_context(|| format!("Failed to parse PEM certificate {:?}", cert.as_ref()))
+}
+
+/// Subdirectory of each certificate directory holding the intermediates of the stored chains,
+/// once each as `<SHA-256 fingerprint>.pem`.
+pub const INTERMEDIATES: &str = "intermediates";
+
+/// Path of the pooled intermediate with `fingerprint`.
+pub fn pooled_path(cert_dir: &Path, fingerprint: &str) -> PathBuf {
+    cert_dir
+        .join(INTERMEDIATES)
+        .join(format!("{}.pem", fingerprint))
+}
+
+/// Load the chain stored under `name` in `cert_dir`, followed by the pooled intermediates its
+/// metadata refers to.
+pub async fn load_chain(cert_dir: &Path, name: &str) -> Result<Vec<X509>> {
+    let mut chain = load_pem_stack(cert_dir.join(format!("{}.pem", name))).await?;
+    let metadata = CertMetadata::load(&CertMetadata::path(cert_dir, name)).await?;
+    for fingerprint in metadata.map(|m| m.intermediates).unwrap_or_default() {
+        chain.extend(load_pem_stack(pooled_path(cert_dir, &fingerprint)).await?);
+    }
+    Ok(chain)
 }
 
 // Write a certificate stack to a file with multiple PEM certificates
@@ -81,52 +286,183 @@ where
     C: AsRef<X509Ref>,
     I: IntoIterator<Item = C>,
 {
-    // Open or create the file, truncate it if it already exists.
-    let mut file = fs::OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(to)
-        .await
-        .with_context(|| format!("Failed to create PEM file at {:?}", to))?;
-
+    let mut pem = Vec::new();
     for cert in stack.into_iter() {
-        let pem = cert
-            .as_ref()
-            .to_pem()
-            .with_context(|| "Failed to encode certificate to PEM")?;
-        file.write_all(&pem)
-            .await
-            .with_context(|| "Failed to write certificate PEM to file")?;
+        pem.extend(
+            cert.as_ref()
+                .to_pem()
+                .with_context(|| "Failed to encode certificate to PEM")?,
+        );
     }
 
-    Ok(())
+    // Replace the file at once, so readers and other instances sharing the directory never
+    // see half of a chain.
+    let file_name = to.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = to.with_file_name(format!(".{}.{}.tmp", file_name, uuid::Uuid::new_v4()));
+    let mut file = fs::File::create(&tmp)
+        .await
+        .with_context(|| format!("Failed to create PEM file at {:?}", tmp))?;
+    let written = async {
+        file.write_all(&pem).await?;
+        file.sync_all().await?;
+        fs::rename(&tmp, to).await
+    }
+    .await;
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp).await;
+    }
+    written.with_context(|| format!("Failed to write certificate PEM to {:?}", to))
 }
 
-pub async fn encrypt_data<S, I>(content: &[u8], to: I, cert_dir: &PathBuf) -> Result<Vec<u8>>
+/// Encrypts content, a MIME entity, to the certificates `store` has for the given addresses,
+/// returning DER encoded CMS enveloped data.
+pub async fn encrypt_data<S, I>(content: &[u8], to: I, store: &dyn CertStore) -> Result<Vec<u8>>
 where
     S: AsRef<str>,
     I: IntoIterator<Item = S>,
 {
-    let mut recipients =
-        Stack::new().with_context(|| format!("Failed to create Stack for Recipient Certs"))?;
+    let mut recipients = Vec::new();
     for mail in to.into_iter() {
         let mail = mail.as_ref();
-        let pubkey_chain = load_pem_stack(&cert_dir.join(format!("{}.pem", mail)))
-            .await
-            .with_context(|| format!("Failed to load certificates for {}", mail))?;
-        let pubkey = find_cert_for_email(&pubkey_chain, &mail)?;
+        let pubkey_chain = store.load(mail).await?;
+        recipients.push(find_encryption_cert(&pubkey_chain, mail)?);
+    }
+    encrypt_for(content, &recipients)
+}
+
+/// Encrypts content to the given recipient certificates, returning DER encoded CMS enveloped data.
+pub fn encrypt_for(content: &[u8], to: &[X509]) -> Result<Vec<u8>> {
+    let mut recipients =
+        Stack::new().with_context(|| "Failed to create Stack for Recipient Certs")?;
+    for cert in to {
         recipients
-            .push(pubkey.clone())
-            .with_context(|| format!("Failed to add X509 Cert for {} to Stack", mail))?;
+            .push(cert.clone())
+            .with_context(|| "Failed to add X509 Cert to Stack")?;
     }
 
     let cipher: Cipher = Cipher::aes_256_cbc();
     let cms = CmsContentInfo::encrypt(&recipients, content, cipher, CMSOptions::BINARY)
-        .with_context(|| format!("Failed to encrypt content"))?;
+        .with_context(|| "Failed to encrypt content")?;
 
     cms.to_der()
-        .with_context(|| format!("Failed to convert CMS result to DER"))
+        .with_context(|| "Failed to convert CMS result to DER")
+}
+
+/// Decrypts DER encoded CMS enveloped data with the given recipient certificate and key.
+#[allow(dead_code)]
+pub fn decrypt_data(der_data: &[u8], cert: &X509, key: &PKeyRef<Private>) -> Result<Vec<u8>> {
+    let cms = CmsContentInfo::from_der(der_data).with_context(|| "Failed to parse CMS content")?;
+    cms.decrypt(key, cert)
+        .with_context(|| "Failed to decrypt content")
 }
 
 // TODO: Test at least extract_certificates_from_p7s
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_pki::{
+        rsa_pss_key, self_signed_ed25519_identity, self_signed_identity,
+        self_signed_identity_with_key, self_signed_signing_identity, TestCa,
+    };
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::pkey::PKey;
+
+    #[test]
+    fn test_cert_usage() {
+        let (dual, _) = self_signed_identity("a@example.com");
+        let usage = cert_usage(&dual).unwrap();
+        assert_eq!(usage.tags(), ["signing", "encryption"]);
+
+        let (signing, _) = self_signed_signing_identity("a@example.com");
+        let usage = cert_usage(&signing).unwrap();
+        assert_eq!(usage.tags(), ["signing"]);
+
+        let ca = TestCa::new("Test CA");
+        assert!(cert_usage(&ca.cert).unwrap().tags().is_empty());
+    }
+
+    #[test]
+    fn test_find_encryption_cert() {
+        let (signing, _) = self_signed_signing_identity("a@example.com");
+        let (encryption, _) = self_signed_identity("a@example.com");
+        assert_eq!(
+            find_encryption_cert([&signing, &encryption], "a@example.com").unwrap(),
+            encryption
+        );
+        let error = find_encryption_cert([&signing], "a@example.com").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Only a signing certificate on file for a@example.com"
+        );
+        let error = find_encryption_cert([&signing], "b@example.com").unwrap_err();
+        assert!(error.to_string().starts_with("Failed to find cert"));
+
+        // A key that can only sign is picked last, for encrypting to it to explain the problem.
+        let (ed25519, _) = self_signed_ed25519_identity("a@example.com");
+        assert_eq!(
+            find_encryption_cert([&ed25519, &encryption], "a@example.com").unwrap(),
+            encryption
+        );
+        assert_eq!(
+            find_encryption_cert([&signing, &ed25519], "a@example.com").unwrap(),
+            ed25519
+        );
+    }
+
+    #[test]
+    fn test_key_algorithm() {
+        let ca = TestCa::new("Test CA");
+        let ec =
+            EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap();
+        let cases = [
+            (
+                self_signed_identity("a@example.com").0,
+                "rsaEncryption",
+                true,
+            ),
+            (
+                ca.issue_with_key("a@example.com", PKey::from_ec_key(ec).unwrap())
+                    .0,
+                "id-ecPublicKey",
+                true,
+            ),
+            (
+                self_signed_ed25519_identity("a@example.com").0,
+                "ED25519",
+                false,
+            ),
+            (
+                self_signed_identity_with_key("a@example.com", PKey::generate_ed448().unwrap()).0,
+                "ED448",
+                false,
+            ),
+            (
+                self_signed_identity_with_key("a@example.com", rsa_pss_key()).0,
+                "rsassaPss",
+                false,
+            ),
+            (
+                ca.issue_with_key("a@example.com", PKey::generate_x25519().unwrap())
+                    .0,
+                "X25519",
+                false,
+            ),
+        ];
+        for (cert, algorithm, encryption) in cases {
+            assert_eq!(key_algorithm(&cert).unwrap(), algorithm);
+            assert_eq!(has_encryption_key(&cert), encryption, "{}", algorithm);
+        }
+    }
+
+    #[test]
+    fn test_certs_only() {
+        let ca = TestCa::new("Test CA");
+        let (cert, _) = ca.issue("a@example.com");
+        let certs_only = certs_only(&[cert.clone(), ca.cert.clone()]).unwrap();
+        assert_eq!(
+            extract_certificates_from_p7s(&certs_only).unwrap(),
+            [cert, ca.cert]
+        );
+    }
+}
@@ -0,0 +1,295 @@
+//! Signed attributes of S/MIME signatures: the algorithms the sender can decrypt
+//! (SMIMECapabilities) and which of their certificates to encrypt for
+//! (SMIMEEncryptionKeyPreference), both from RFC 8551.
+//!
+//! OpenSSL does not expose signed attributes, so they are read from the DER encoding of the
+//! signature. What was found is stored next to the harvested certificate as `<address>.json`.
+
+use anyhow::{bail, Context, Result};
+use openssl::asn1::Asn1Object;
+use openssl::nid::Nid;
+use openssl::pkcs7::Pkcs7;
+use openssl::x509::X509Ref;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::der::{children, oid_to_string, read_tlv, Tlv};
+
+const SMIME_CAPABILITIES: &str = "1.2.840.113549.1.9.15";
+const ENCRYPTION_KEY_PREFERENCE: &str = "1.2.840.113549.1.9.16.2.11";
+
+/// Name of an algorithm, e.g. `AES-256-CBC`, or the OID if unknown to OpenSSL.
+fn algorithm_name(oid: &str) -> String {
+    Asn1Object::from_str(oid)
+        .ok()
+        .map(|object| object.nid())
+        .filter(|nid| *nid != Nid::UNDEF)
+        .and_then(|nid| nid.short_name().ok())
+        .map_or_else(|| oid.to_string(), str::to_string)
+}
+
+/// Certificate the sender prefers to be encrypted for.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyPreference {
+    /// DER encoded issuer name, and the serial number.
+    IssuerSerial {
+        issuer: Vec<u8>,
+        serial: Vec<u8>,
+    },
+    SubjectKeyId(Vec<u8>),
+}
+
+impl KeyPreference {
+    fn parse(choice: &Tlv) -> Result<Self> {
+        match choice.tag {
+            // issuerAndSerialNumber [0] IMPLICIT IssuerAndSerialNumber
+            0xa0 => match children(choice.value)?.as_slice() {
+                [issuer, serial, ..] => Ok(Self::IssuerSerial {
+                    issuer: issuer.raw.to_vec(),
+                    serial: strip_leading_zeros(serial.value).to_vec(),
+                }),
+                _ => bail!("Incomplete issuer and serial number"),
+            },
+            // receipentKeyId [1] IMPLICIT RecipientKeyIdentifier
+            0xa1 => match children(choice.value)?.first() {
+                Some(key_id) => Ok(Self::SubjectKeyId(key_id.value.to_vec())),
+                None => bail!("Incomplete recipient key identifier"),
+            },
+            // subjectAltKeyIdentifier [2] IMPLICIT SubjectKeyIdentifier
+            0x82 => Ok(Self::SubjectKeyId(choice.value.to_vec())),
+            tag => bail!("Unknown encryption key preference {:#x}", tag),
+        }
+    }
+
+    /// Whether this is the certificate preferred.
+    pub fn matches(&self, cert: &X509Ref) -> bool {
+        match self {
+            Self::IssuerSerial { issuer, serial } => {
+                cert.issuer_name().to_der().is_ok_and(|der| der == *issuer)
+                    && cert
+                        .serial_number()
+                        .to_bn()
+                        .is_ok_and(|bn| bn.to_vec() == *serial)
+            }
+            Self::SubjectKeyId(key_id) => cert
+                .subject_key_id()
+                .is_some_and(|id| id.as_slice() == key_id.as_slice()),
+        }
+    }
+}
+
+fn strip_leading_zeros(value: &[u8]) -> &[u8] {
+    let zeros = value.iter().take_while(|b| **b == 0).count();
+    &value[zeros..]
+}
+
+/// Signed attributes of a signer.
+#[derive(Debug, Default, PartialEq)]
+pub struct SignerAttributes {
+    /// Algorithms the sender can decrypt, most preferred first.
+    pub capabilities: Vec<String>,
+    pub key_preference: Option<KeyPreference>,
+}
+
+/// Read the attributes from `SignedAttributes`, the contents of the `[0]` element of a
+/// `SignerInfo`.
+fn parse_attributes(signed_attrs: &[u8]) -> Result<SignerAttributes> {
+    let mut attributes = SignerAttributes::default();
+    for attribute in children(signed_attrs)? {
+        let parts = children(attribute.value)?;
+        let [oid, values, ..] = parts.as_slice() else {
+            bail!("Incomplete attribute");
+        };
+        let Some(value) = children(values.value)?.into_iter().next() else {
+            continue;
+        };
+        match oid_to_string(oid.value).as_str() {
+            SMIME_CAPABILITIES => {
+                for capability in children(value.value)? {
+                    if let Some(oid) = children(capability.value)?.first() {
+                        attributes
+                            .capabilities
+                            .push(algorithm_name(&oid_to_string(oid.value)));
+                    }
+                }
+            }
+            ENCRYPTION_KEY_PREFERENCE => {
+                attributes.key_preference = Some(KeyPreference::parse(&value)?);
+            }
+            _ => {}
+        }
+    }
+    Ok(attributes)
+}
+
+/// Read the attributes of the first signer of a PKCS#7 signature (.p7s).
+pub fn parse(der_data: &[u8]) -> Result<SignerAttributes> {
+    // Let OpenSSL turn BER, as written by some clients, into DER.
+    let der = Pkcs7::from_der(der_data)
+        .and_then(|pkcs7| pkcs7.to_der())
+        .context("Failed to parse PKCS#7 data")?;
+    let (content_info, _) = read_tlv(&der)?;
+    let signed_data = match children(content_info.value)?.as_slice() {
+        [_, explicit] => children(explicit.value)?
+            .into_iter()
+            .next()
+            .context("Empty signed data")?,
+        _ => bail!("No signed data in PKCS#7 data"),
+    };
+    // The signer infos are the last element of the signed data.
+    let signer_infos = children(signed_data.value)?
+        .into_iter()
+        .last()
+        .filter(|tlv| tlv.tag == 0x31)
+        .context("No signer infos in PKCS#7 data")?;
+    let Some(signer_info) = children(signer_infos.value)?.into_iter().next() else {
+        return Ok(SignerAttributes::default());
+    };
+    match children(signer_info.value)?
+        .into_iter()
+        .find(|tlv| tlv.tag == 0xa0)
+    {
+        Some(signed_attrs) => parse_attributes(signed_attrs.value),
+        None => Ok(SignerAttributes::default()),
+    }
+}
+
+/// The message a certificate was harvested from, to trace it back during incident response.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HarvestedFrom {
+    pub queue_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    /// RFC 3339 time of harvesting.
+    pub harvested_at: String,
+}
+
+/// What is known about a harvested certificate beyond the certificate itself.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CertMetadata {
+    /// Algorithms the owner can decrypt, most preferred first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub capabilities: Vec<String>,
+    /// SHA-256 fingerprint of the certificate to encrypt for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption_certificate: Option<String>,
+    /// Usages (`signing`, `encryption`) of the owner's certificates, by SHA-256 fingerprint.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub usage: BTreeMap<String, Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub harvested_from: Option<HarvestedFrom>,
+    /// SHA-256 fingerprints of the intermediates of the chain, kept in the pool.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub intermediates: Vec<String>,
+}
+
+impl CertMetadata {
+    /// Where the metadata for the certificate stored under `name` is kept.
+    pub fn path(cert_dir: &Path, name: &str) -> PathBuf {
+        cert_dir.join(format!("{}.json", name))
+    }
+
+    /// Load the metadata, if there is any.
+    pub async fn load(path: &Path) -> Result<Option<Self>> {
+        match tokio::fs::read(path).await {
+            Ok(data) => serde_json::from_slice(&data)
+                .map(Some)
+                .with_context(|| format!("Failed to parse {:?}", path)),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error).with_context(|| format!("Failed to read {:?}", path)),
+        }
+    }
+
+    /// Store the metadata, or remove stale metadata if there is nothing to store.
+    pub async fn store(&self, path: &Path) -> Result<()> {
+        if *self == Self::default() {
+            return match tokio::fs::remove_file(path).await {
+                Err(error) if error.kind() != std::io::ErrorKind::NotFound => {
+                    Err(error).with_context(|| format!("Failed to remove {:?}", path))
+                }
+                _ => Ok(()),
+            };
+        }
+        // Replaced at once like the chain, see `smime::write_pem_stack`.
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let tmp = path.with_file_name(format!(".{}.{}.tmp", file_name, uuid::Uuid::new_v4()));
+        tokio::fs::write(&tmp, serde_json::to_vec_pretty(self)?)
+            .await
+            .with_context(|| format!("Failed to write {:?}", tmp))?;
+        tokio::fs::rename(&tmp, path)
+            .await
+            .with_context(|| format!("Failed to write {:?}", path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_pki::{
+        key_preference_attribute, self_signed_identity, signature_with_attributes,
+    };
+    use openssl::pkcs7::Pkcs7Flags;
+    use openssl::stack::Stack;
+
+    #[test]
+    fn test_algorithm_name() {
+        assert_eq!(algorithm_name("2.16.840.1.101.3.4.1.42"), "AES-256-CBC");
+        assert_eq!(algorithm_name("1.3.6.1.4.1.99999.1"), "1.3.6.1.4.1.99999.1");
+    }
+
+    #[test]
+    fn test_parse_openssl_capabilities() {
+        let (cert, key) = self_signed_identity("a@example.com");
+        let signature = Pkcs7::sign(
+            &cert,
+            &key,
+            &Stack::new().unwrap(),
+            b"hello",
+            Pkcs7Flags::DETACHED,
+        )
+        .unwrap()
+        .to_der()
+        .unwrap();
+        let attributes = parse(&signature).unwrap();
+        assert!(attributes.capabilities.contains(&"AES-256-CBC".to_string()));
+        assert_eq!(attributes.key_preference, None);
+    }
+
+    #[test]
+    fn test_parse_key_preference() {
+        let (signing, _) = self_signed_identity("a@example.com");
+        let (encryption, _) = self_signed_identity("a@example.com");
+        let attributes = key_preference_attribute(&encryption);
+        let signature = signature_with_attributes(&[&signing, &encryption], &signing, &attributes);
+
+        let parsed = parse(&signature).unwrap();
+        let preference = parsed.key_preference.unwrap();
+        assert!(preference.matches(&encryption));
+        assert!(!preference.matches(&signing));
+    }
+
+    #[tokio::test]
+    async fn test_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = CertMetadata::path(dir.path(), "a@example.com");
+        assert_eq!(CertMetadata::load(&path).await.unwrap(), None);
+
+        let metadata = CertMetadata {
+            capabilities: vec!["AES-256-CBC".into()],
+            encryption_certificate: Some("00ff".into()),
+            usage: BTreeMap::from([("00ff".into(), vec!["encryption".into()])]),
+            harvested_from: Some(HarvestedFrom {
+                queue_id: "4Bc1x20kLz".into(),
+                message_id: Some("<1@example.com>".into()),
+                harvested_at: "2024-02-29T12:34:56.789Z".into(),
+            }),
+            intermediates: vec!["ab12".into()],
+        };
+        metadata.store(&path).await.unwrap();
+        assert_eq!(CertMetadata::load(&path).await.unwrap(), Some(metadata));
+
+        CertMetadata::default().store(&path).await.unwrap();
+        assert!(!path.exists());
+    }
+}
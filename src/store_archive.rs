@@ -0,0 +1,364 @@
+//! Backups of the certificate directory: `cert export` writes it to a zstd compressed tar
+//! archive with a manifest of hashes and the provenance of each certificate, `cert import`
+//! restores it after checking the hashes.
+
+use anyhow::{bail, Context, Result};
+use openssl::x509::X509;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+use crate::cert_command::LDAP_SYNC_MANIFEST;
+use crate::cert_store;
+use crate::smime;
+use crate::smime_attributes::{CertMetadata, HarvestedFrom};
+
+/// Archive member listing the SHA-256 hashes of all other members, in `sha256sum` format.
+const ARCHIVE_MANIFEST: &str = "manifest.sha256";
+/// Archive member describing where each certificate came from.
+const ARCHIVE_PROVENANCE: &str = "provenance.tsv";
+/// Archive directory holding the files of the certificate directory.
+const ARCHIVE_CERTS: &str = "certs/";
+
+fn sha256_hex(data: &[u8]) -> String {
+    openssl::sha::sha256(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn mtime(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Describe the certificates of a file for the provenance listing.
+fn provenance(
+    name: &str,
+    data: &[u8],
+    source: &str,
+    modified: u64,
+    harvested_from: Option<&HarvestedFrom>,
+) -> String {
+    let email = name.strip_suffix(".pem").unwrap_or(name);
+    let certs = X509::stack_from_pem(data).unwrap_or_default();
+    let subject = certs
+        .first()
+        .map(|cert| {
+            cert.subject_name()
+                .entries()
+                .filter_map(|e| e.data().to_string().ok())
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+        .unwrap_or_default();
+    let not_after = certs
+        .first()
+        .map(|cert| cert.not_after().to_string())
+        .unwrap_or_default();
+    let (queue_id, message_id) = harvested_from.map_or(("", ""), |h| {
+        (
+            h.queue_id.as_str(),
+            h.message_id.as_deref().unwrap_or_default(),
+        )
+    });
+    format!(
+        "{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+        email, source, modified, not_after, subject, queue_id, message_id
+    )
+}
+
+fn append(
+    builder: &mut tar::Builder<impl std::io::Write>,
+    path: &str,
+    data: &[u8],
+    mtime: u64,
+) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o640);
+    header.set_mtime(mtime);
+    header.set_cksum();
+    builder
+        .append_data(&mut header, path, data)
+        .with_context(|| format!("Failed to add {} to archive", path))
+}
+
+/// Write all files of `cert_dir` to a zstd compressed tar archive at `output`,
+/// together with a provenance listing and a manifest of their hashes. Returns
+/// the number of certificate files.
+pub fn export_store(cert_dir: &Path, output: &Path) -> Result<usize> {
+    let ldap_synced: Vec<String> = fs::read_to_string(cert_dir.join(LDAP_SYNC_MANIFEST))
+        .map(|s| s.lines().map(str::to_string).collect())
+        .unwrap_or_default();
+
+    let mut files = BTreeMap::new();
+    let pool = cert_dir.join(smime::INTERMEDIATES);
+    let pooled = match fs::read_dir(&pool) {
+        Ok(entries) => entries.collect::<std::io::Result<Vec<_>>>()?,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(error) => return Err(error).with_context(|| format!("Failed to read {:?}", pool)),
+    };
+    let entries = fs::read_dir(cert_dir)
+        .with_context(|| format!("Failed to read certificate directory {:?}", cert_dir))?
+        .map(|entry| entry.map(|entry| (String::new(), entry)))
+        .chain(
+            pooled
+                .into_iter()
+                .map(|entry| Ok((format!("{}/", smime::INTERMEDIATES), entry))),
+        );
+    for entry in entries {
+        let (prefix, entry) = entry?;
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            warn!(path = ?entry.path(), "Skipping file with non UTF-8 name");
+            continue;
+        };
+        // Leave out the lock and unfinished writes of other instances.
+        if name == cert_store::LOCK || (name.starts_with('.') && name.ends_with(".tmp")) {
+            continue;
+        }
+        let data =
+            fs::read(entry.path()).with_context(|| format!("Failed to read {:?}", entry.path()))?;
+        files.insert(format!("{}{}", prefix, name), (data, mtime(&metadata)));
+    }
+
+    let file =
+        File::create(output).with_context(|| format!("Failed to create archive {:?}", output))?;
+    let mut builder = tar::Builder::new(zstd::Encoder::new(file, 0)?.auto_finish());
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    let mut manifest = String::new();
+    let mut provenance_tsv =
+        String::from("# address\tsource\tmodified\tnot after\tsubject\tqueue id\tmessage id\n");
+    let mut certificates = 0;
+    for (name, (data, modified)) in &files {
+        if let Some(email) = name
+            .strip_suffix(".pem")
+            .filter(|_| !cert_store::is_pooled(name))
+        {
+            let source = if ldap_synced.iter().any(|e| e == email) {
+                "ldap"
+            } else {
+                "local"
+            };
+            let harvested_from = files
+                .get(&format!("{}.json", email))
+                .and_then(|(data, _)| serde_json::from_slice::<CertMetadata>(data).ok())
+                .and_then(|metadata| metadata.harvested_from);
+            provenance_tsv.push_str(&provenance(
+                name,
+                data,
+                source,
+                *modified,
+                harvested_from.as_ref(),
+            ));
+            certificates += 1;
+        }
+        let path = format!("{}{}", ARCHIVE_CERTS, name);
+        manifest.push_str(&format!("{}  {}\n", sha256_hex(data), path));
+        append(&mut builder, &path, data, *modified)?;
+    }
+    manifest.push_str(&format!(
+        "{}  {}\n",
+        sha256_hex(provenance_tsv.as_bytes()),
+        ARCHIVE_PROVENANCE
+    ));
+    append(
+        &mut builder,
+        ARCHIVE_PROVENANCE,
+        provenance_tsv.as_bytes(),
+        now,
+    )?;
+    append(&mut builder, ARCHIVE_MANIFEST, manifest.as_bytes(), now)?;
+    builder.into_inner()?;
+    Ok(certificates)
+}
+
+/// Counts of an archive import.
+#[derive(Debug, Default, PartialEq)]
+pub struct RestoreSummary {
+    pub restored: usize,
+    pub skipped_existing: usize,
+}
+
+/// Restore the files of an archive written by [`export_store`] into `cert_dir`.
+/// The whole archive is verified against its manifest before anything is written.
+pub fn import_store(cert_dir: &Path, input: &Path, overwrite: bool) -> Result<RestoreSummary> {
+    let file = File::open(input).with_context(|| format!("Failed to open archive {:?}", input))?;
+    let mut archive = tar::Archive::new(zstd::Decoder::new(file)?);
+
+    let mut members = BTreeMap::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().to_string();
+        let mtime = entry.header().mtime()?;
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        members.insert(path, (data, mtime));
+    }
+
+    let Some((manifest, _)) = members.remove(ARCHIVE_MANIFEST) else {
+        bail!("Archive lacks {}", ARCHIVE_MANIFEST);
+    };
+    let mut hashes = BTreeMap::new();
+    for line in String::from_utf8(manifest)?.lines() {
+        let Some((hash, path)) = line.split_once("  ") else {
+            bail!("Malformed manifest line {:?}", line);
+        };
+        hashes.insert(path.to_string(), hash.to_string());
+    }
+    for (path, (data, _)) in &members {
+        match hashes.remove(path) {
+            Some(hash) if hash == sha256_hex(data) => {}
+            Some(_) => bail!("Hash of {} does not match the manifest", path),
+            None => bail!("{} is not listed in the manifest", path),
+        }
+    }
+    if let Some(path) = hashes.keys().next() {
+        bail!("{} is listed in the manifest, but missing", path);
+    }
+
+    let mut files = Vec::new();
+    for (path, (data, mtime)) in members {
+        let Some(name) = path.strip_prefix(ARCHIVE_CERTS) else {
+            continue;
+        };
+        if name.is_empty()
+            || (name.contains('/') && !cert_store::is_pooled(name))
+            || name == "."
+            || name == ".."
+        {
+            bail!("Refusing to restore {:?}", path);
+        }
+        if name.ends_with(".pem") {
+            X509::stack_from_pem(&data).with_context(|| format!("Invalid certificate {}", path))?;
+        }
+        files.push((name.to_string(), data, mtime));
+    }
+
+    let mut summary = RestoreSummary::default();
+    for (name, data, mtime) in files {
+        let path = cert_dir.join(&name);
+        if !overwrite && path.exists() {
+            info!(?path, "File already exists, skipping");
+            summary.skipped_existing += 1;
+            continue;
+        }
+        if cert_store::is_pooled(&name) {
+            fs::create_dir_all(cert_dir.join(smime::INTERMEDIATES))?;
+        }
+        fs::write(&path, &data).with_context(|| format!("Failed to write {:?}", path))?;
+        File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(UNIX_EPOCH + Duration::from_secs(mtime))?;
+        summary.restored += 1;
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_pki::self_signed_identity;
+
+    #[test]
+    fn test_export_import_store() {
+        let source = tempfile::tempdir().unwrap();
+        let (alice, _) = self_signed_identity("alice@example.com");
+        let (bob, _) = self_signed_identity("bob@example.com");
+        fs::write(
+            source.path().join("alice@example.com.pem"),
+            alice.to_pem().unwrap(),
+        )
+        .unwrap();
+        fs::write(
+            source.path().join("bob@example.com.pem"),
+            bob.to_pem().unwrap(),
+        )
+        .unwrap();
+        fs::write(source.path().join(LDAP_SYNC_MANIFEST), "bob@example.com\n").unwrap();
+        let pooled = smime::pooled_path(source.path(), "ab12");
+        fs::create_dir(pooled.parent().unwrap()).unwrap();
+        fs::write(&pooled, alice.to_pem().unwrap()).unwrap();
+        File::options()
+            .write(true)
+            .open(source.path().join("alice@example.com.pem"))
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+            .unwrap();
+
+        let archive = tempfile::tempdir().unwrap();
+        let output = archive.path().join("backup.tar.zst");
+        assert_eq!(export_store(source.path(), &output).unwrap(), 2);
+
+        let target = tempfile::tempdir().unwrap();
+        let summary = import_store(target.path(), &output, false).unwrap();
+        assert_eq!(summary.restored, 4);
+        for name in [
+            "alice@example.com.pem",
+            "bob@example.com.pem",
+            LDAP_SYNC_MANIFEST,
+            "intermediates/ab12.pem",
+        ] {
+            assert_eq!(
+                fs::read(target.path().join(name)).unwrap(),
+                fs::read(source.path().join(name)).unwrap()
+            );
+        }
+        let metadata = fs::metadata(target.path().join("alice@example.com.pem")).unwrap();
+        assert_eq!(mtime(&metadata), 1_700_000_000);
+
+        let summary = import_store(target.path(), &output, false).unwrap();
+        assert_eq!(summary.skipped_existing, 4);
+    }
+
+    #[test]
+    fn test_provenance() {
+        let (alice, _) = self_signed_identity("alice@example.com");
+        let pem = alice.to_pem().unwrap();
+        let line = provenance("alice@example.com.pem", &pem, "local", 1, None);
+        assert!(line.starts_with("alice@example.com\tlocal\t1\t"));
+        assert!(line.ends_with("alice@example.com\t\t\n"));
+
+        let harvested_from = HarvestedFrom {
+            queue_id: "4Bc1x20kLz".into(),
+            message_id: Some("<1@example.com>".into()),
+            harvested_at: "2024-02-29T12:34:56.789Z".into(),
+        };
+        let line = provenance(
+            "alice@example.com.pem",
+            &pem,
+            "local",
+            1,
+            Some(&harvested_from),
+        );
+        assert!(line.ends_with("\t4Bc1x20kLz\t<1@example.com>\n"));
+    }
+
+    #[test]
+    fn test_import_store_tampered() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("backup.tar.zst");
+        let file = File::create(&output).unwrap();
+        let mut builder = tar::Builder::new(zstd::Encoder::new(file, 0).unwrap().auto_finish());
+        append(&mut builder, "certs/a@example.com.pem", b"tampered", 0).unwrap();
+        let manifest = format!("{}  certs/a@example.com.pem\n", sha256_hex(b"original"));
+        append(&mut builder, ARCHIVE_MANIFEST, manifest.as_bytes(), 0).unwrap();
+        builder.into_inner().unwrap();
+
+        let target = tempfile::tempdir().unwrap();
+        assert!(import_store(target.path(), &output, false).is_err());
+        assert!(!target.path().join("a@example.com.pem").exists());
+    }
+}
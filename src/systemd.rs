@@ -0,0 +1,92 @@
+//! Service notifications for systemd units of `Type=notify`: readiness once the milter
+//! sockets accept connections, and keep-alive pings for `WatchdogSec=`, so a hung milter is
+//! restarted instead of mail silently queueing up at the MTA.
+
+use anyhow::{Context, Result};
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+use tracing::warn;
+
+/// Connection to the notification socket of the service manager.
+pub struct Notifier {
+    socket: UnixDatagram,
+    /// Interval the service manager expects pings at, if the watchdog is enabled for us.
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    /// Connect to `$NOTIFY_SOCKET` if the service manager set it, and read `$WATCHDOG_USEC`.
+    /// Both are removed from the environment, so commands run later don't notify in our
+    /// stead. The socket is connected right away, as the sandbox might hide it later.
+    ///
+    /// Must be called before other threads are started, as it changes the environment.
+    pub fn from_env() -> Result<Option<Self>> {
+        let path = std::env::var_os("NOTIFY_SOCKET");
+        let watchdog_usec = std::env::var("WATCHDOG_USEC").ok();
+        let watchdog_pid = std::env::var("WATCHDOG_PID").ok();
+        for name in ["NOTIFY_SOCKET", "WATCHDOG_USEC", "WATCHDOG_PID"] {
+            std::env::remove_var(name);
+        }
+        let Some(path) = path else {
+            return Ok(None);
+        };
+
+        let socket = UnixDatagram::unbound().context("Failed to create notification socket")?;
+        let bytes = path.as_encoded_bytes();
+        match bytes.strip_prefix(b"@") {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                socket.connect_addr(&address)
+            }
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => Err(std::io::ErrorKind::Unsupported.into()),
+            None => socket.connect(&path),
+        }
+        .with_context(|| format!("Failed to connect to notification socket {:?}", path))?;
+
+        let ours = watchdog_pid.is_none_or(|pid| pid == std::process::id().to_string());
+        let watchdog = watchdog_usec
+            .and_then(|usec| usec.parse().ok())
+            .filter(|usec| *usec > 0 && ours)
+            .map(Duration::from_micros);
+        Ok(Some(Self { socket, watchdog }))
+    }
+
+    /// Send a state change, like `READY=1`. Failures are only logged.
+    pub fn notify(&self, state: &str) {
+        if let Err(error) = self.socket.send(state.as_bytes()) {
+            warn!(?error, state, "Failed to notify the service manager");
+        }
+    }
+
+    /// How often to ping the watchdog: twice per interval the service manager expects, so a
+    /// late ping doesn't get us killed.
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog.map(|interval| interval / 2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notify() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        let socket = UnixDatagram::unbound().unwrap();
+        socket.connect(&path).unwrap();
+        let notifier = Notifier {
+            socket,
+            watchdog: Some(Duration::from_secs(30)),
+        };
+        notifier.notify("READY=1");
+        let mut buffer = [0; 64];
+        let len = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"READY=1");
+        assert_eq!(notifier.watchdog_interval(), Some(Duration::from_secs(15)));
+    }
+}
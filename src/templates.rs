@@ -0,0 +1,191 @@
+//! Texts of the messages pantosmime generates, like expiry warnings and key requests.
+//!
+//! The built-in English texts can be replaced per recipient domain and language by files in a
+//! template directory, looked up most specific first: `<name>.<domain>.<language>.txt`,
+//! `<name>.<domain>.txt`, `<name>.<language>.txt` and `<name>.txt`. Templates are plain text
+//! with `{placeholder}`s, optionally starting with a `Subject:` line.
+
+use std::path::PathBuf;
+use tracing::warn;
+
+use crate::address;
+
+/// A generated message, with its built-in text.
+pub struct Template {
+    /// File name in the template directory, without extension.
+    pub name: &'static str,
+    default: &'static str,
+}
+
+/// Asks a recipient without a certificate for a signed message, with `{sender}` and
+/// `{recipient}`.
+pub const KEY_REQUEST: Template = Template {
+    name: "key-request",
+    default: "Subject: Please send a signed message to {sender}\n\
+\n\
+{sender} tried to send you an encrypted message, but has no S/MIME certificate\n\
+for {recipient} yet.\n\
+\n\
+Please reply to this message with a signed one, so your certificate is known\n\
+and mail to you can be encrypted from now on.\n",
+};
+
+/// Warns the owner of a certificate about to expire, with `{email}`, `{not_after}` and
+/// `{days}` left.
+pub const EXPIRING_CERTIFICATE: Template = Template {
+    name: "expiring-certificate",
+    default: "Subject: Your S/MIME certificate is expiring\n\
+\n\
+The S/MIME certificate we use to encrypt mail to {email} expires on {not_after}.\n\
+\n\
+Please send us a signed message with your renewed certificate, so\n\
+we can keep sending you encrypted mail.\n",
+};
+
+/// Tells the owner of an expired certificate, with `{email}`, `{not_after}` and `{days}` ago.
+pub const EXPIRED_CERTIFICATE: Template = Template {
+    name: "expired-certificate",
+    default: "Subject: Your S/MIME certificate has expired\n\
+\n\
+The S/MIME certificate we use to encrypt mail to {email} has expired on {not_after}.\n\
+\n\
+Please send us a signed message with your renewed certificate, so\n\
+we can keep sending you encrypted mail.\n",
+};
+
+/// Confirms a certificate sent to an enrollment address, with `{sender}`.
+pub const ENROLLMENT_REPLY: Template = Template {
+    name: "enrollment-reply",
+    default: "Subject: Your S/MIME certificate was imported\n\
+\n\
+The S/MIME certificate sent from {sender} has been imported.\n\
+\n\
+Mail to you is encrypted with it from now on.\n",
+};
+
+/// Subject and text of a template, filled in.
+pub fn render_text(text: &str, values: &[(&str, &str)]) -> (Option<String>, String) {
+    let text = values.iter().fold(text.to_string(), |text, (name, value)| {
+        text.replace(&format!("{{{}}}", name), value)
+    });
+    match text.split_once('\n') {
+        Some((first, rest)) if first.starts_with("Subject:") => (
+            Some(first["Subject:".len()..].trim().to_string()),
+            rest.trim_start_matches(['\r', '\n']).to_string(),
+        ),
+        _ => (None, text),
+    }
+}
+
+/// Where to find replacements of the built-in texts.
+#[derive(Default)]
+pub struct Templates {
+    pub dir: Option<PathBuf>,
+    /// Languages of recipient domain patterns, first match wins.
+    pub languages: Vec<(String, String)>,
+}
+
+/// Parse `<DOMAIN>=<LANGUAGE>`, e.g. `example.de=de` or `*=en`.
+pub fn parse_language(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((domain, language))
+            if !domain.is_empty()
+                && !language.is_empty()
+                && language
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-') =>
+        {
+            Ok((domain.to_ascii_lowercase(), language.to_string()))
+        }
+        _ => Err(format!("expected <DOMAIN>=<LANGUAGE>, got {:?}", s)),
+    }
+}
+
+impl Templates {
+    /// Text of the template for messages to `recipient`.
+    fn text_for(&self, template: &Template, recipient: &str) -> String {
+        let Some(dir) = &self.dir else {
+            return template.default.to_string();
+        };
+        let domain = recipient
+            .rsplit_once('@')
+            .map_or("", |(_, domain)| domain)
+            .to_ascii_lowercase();
+        let language = self
+            .languages
+            .iter()
+            .find(|(pattern, _)| address::domain_matches(pattern, &domain))
+            .map(|(_, language)| language.as_str());
+        let name = template.name;
+        let mut candidates = Vec::new();
+        if let Some(language) = language {
+            candidates.push(format!("{}.{}.{}.txt", name, domain, language));
+        }
+        candidates.push(format!("{}.{}.txt", name, domain));
+        if let Some(language) = language {
+            candidates.push(format!("{}.{}.txt", name, language));
+        }
+        candidates.push(format!("{}.txt", name));
+        for candidate in candidates {
+            match std::fs::read_to_string(dir.join(&candidate)) {
+                Ok(text) => return text,
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+                Err(error) => warn!(?error, candidate, "Ignoring unreadable template"),
+            }
+        }
+        template.default.to_string()
+    }
+
+    /// Subject and text of the message to `recipient`, the subject defaulting to the built-in
+    /// one.
+    pub fn render(
+        &self,
+        template: &Template,
+        recipient: &str,
+        values: &[(&str, &str)],
+    ) -> (String, String) {
+        let (subject, text) = render_text(&self.text_for(template, recipient), values);
+        let subject =
+            subject.unwrap_or_else(|| render_text(template.default, values).0.unwrap_or_default());
+        (subject, text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("enrollment-reply.de.txt"),
+            "Subject: Zertifikat importiert\n\nDas Zertifikat von {sender} ist importiert.\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("enrollment-reply.example.at.txt"),
+            "Servus {sender}!\n",
+        )
+        .unwrap();
+        let templates = Templates {
+            dir: Some(dir.path().to_path_buf()),
+            languages: vec![parse_language("*.de=de").unwrap()],
+        };
+        let values = [("sender", "a@example.com")];
+
+        let (subject, text) = templates.render(&ENROLLMENT_REPLY, "b@mail.example.de", &values);
+        assert_eq!(subject, "Zertifikat importiert");
+        assert_eq!(text, "Das Zertifikat von a@example.com ist importiert.\n");
+
+        // Without a subject line, the built-in subject is kept.
+        let (subject, text) = templates.render(&ENROLLMENT_REPLY, "b@example.at", &values);
+        assert_eq!(subject, "Your S/MIME certificate was imported");
+        assert_eq!(text, "Servus a@example.com!\n");
+
+        let (_, text) = templates.render(&ENROLLMENT_REPLY, "b@example.com", &values);
+        assert!(text.starts_with("The S/MIME certificate sent from a@example.com"));
+
+        assert!(parse_language("example.de").is_err());
+    }
+}
@@ -0,0 +1,456 @@
+//! Throwaway certificate authorities and user identities for tests.
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::cms::{CMSOptions, CmsContentInfo};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkcs7::{Pkcs7, Pkcs7Flags};
+use openssl::pkey::{Id, PKey, Private};
+use openssl::pkey_ctx::PkeyCtx;
+use openssl::rsa::Rsa;
+use openssl::sign::Signer;
+use openssl::stack::Stack;
+use openssl::x509::extension::{
+    BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName, SubjectKeyIdentifier,
+};
+use openssl::x509::{X509Builder, X509NameBuilder, X509Ref, X509};
+
+use crate::der::encode as der;
+
+fn generate_key() -> PKey<Private> {
+    PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap()
+}
+
+/// Start a certificate valid from today for a year, without extensions.
+fn certificate_builder(subject: &str, pkey: &PKey<Private>) -> X509Builder {
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_nid(Nid::COMMONNAME, subject).unwrap();
+    let name = name.build();
+
+    let mut serial = BigNum::new().unwrap();
+    serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+
+    let mut builder = X509Builder::new().unwrap();
+    builder.set_version(2).unwrap();
+    builder
+        .set_serial_number(&serial.to_asn1_integer().unwrap())
+        .unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(pkey).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(365).unwrap())
+        .unwrap();
+    builder
+}
+
+/// Add the extensions of a typical S/MIME end-entity certificate.
+fn add_smime_extensions(builder: &mut X509Builder, email: &str, issuer: Option<&X509Ref>) {
+    let mut key_usage = KeyUsage::new();
+    key_usage.digital_signature().key_encipherment();
+    add_extensions(builder, email, issuer, key_usage);
+}
+
+fn add_extensions(
+    builder: &mut X509Builder,
+    email: &str,
+    issuer: Option<&X509Ref>,
+    mut key_usage: KeyUsage,
+) {
+    let san = SubjectAlternativeName::new()
+        .email(email)
+        .build(&builder.x509v3_context(issuer, None))
+        .unwrap();
+    builder.append_extension(san).unwrap();
+    builder
+        .append_extension(key_usage.critical().build().unwrap())
+        .unwrap();
+    builder
+        .append_extension(ExtendedKeyUsage::new().email_protection().build().unwrap())
+        .unwrap();
+    let key_id = SubjectKeyIdentifier::new()
+        .build(&builder.x509v3_context(issuer, None))
+        .unwrap();
+    builder.append_extension(key_id).unwrap();
+}
+
+/// Generate a throwaway self-signed certificate and key for the given email.
+pub fn self_signed_identity(email: &str) -> (X509, PKey<Private>) {
+    let pkey = generate_key();
+    let mut builder = certificate_builder(email, &pkey);
+    add_smime_extensions(&mut builder, email, None);
+    builder.sign(&pkey, MessageDigest::sha256()).unwrap();
+    (builder.build(), pkey)
+}
+
+/// Generate a self-signed certificate for the given email which is only good for signing,
+/// like the signing half of a dual key pair.
+pub fn self_signed_signing_identity(email: &str) -> (X509, PKey<Private>) {
+    let pkey = generate_key();
+    let mut builder = certificate_builder(email, &pkey);
+    let mut key_usage = KeyUsage::new();
+    key_usage.digital_signature().non_repudiation();
+    add_extensions(&mut builder, email, None, key_usage);
+    builder.sign(&pkey, MessageDigest::sha256()).unwrap();
+    (builder.build(), pkey)
+}
+
+/// Generate a self-signed certificate for the given email with an Ed25519 key, which can sign
+/// but not be encrypted to.
+pub fn self_signed_ed25519_identity(email: &str) -> (X509, PKey<Private>) {
+    self_signed_identity_with_key(email, PKey::generate_ed25519().unwrap())
+}
+
+/// Generate an RSA key restricted to RSASSA-PSS signatures.
+pub fn rsa_pss_key() -> PKey<Private> {
+    let mut ctx = PkeyCtx::new_id(Id::RSA_PSS).unwrap();
+    ctx.keygen_init().unwrap();
+    ctx.set_rsa_keygen_bits(2048).unwrap();
+    ctx.keygen().unwrap()
+}
+
+/// Generate a self-signed certificate for the given email with `pkey`, of any type.
+pub fn self_signed_identity_with_key(email: &str, pkey: PKey<Private>) -> (X509, PKey<Private>) {
+    let mut builder = certificate_builder(email, &pkey);
+    add_smime_extensions(&mut builder, email, None);
+    builder.sign(&pkey, digest_for(&pkey)).unwrap();
+    (builder.build(), pkey)
+}
+
+/// EdDSA keys hash themselves and take no digest.
+fn digest_for(pkey: &PKey<Private>) -> MessageDigest {
+    match pkey.id() {
+        Id::ED25519 | Id::ED448 => MessageDigest::null(),
+        _ => MessageDigest::sha256(),
+    }
+}
+
+/// Generate a self-signed certificate and key for the given email, expiring
+/// at the given unix time.
+pub fn self_signed_identity_until(email: &str, not_after: i64) -> (X509, PKey<Private>) {
+    let pkey = generate_key();
+    let mut builder = certificate_builder(email, &pkey);
+    let not_after = Asn1Time::from_unix(not_after).unwrap();
+    if not_after < Asn1Time::days_from_now(0).unwrap() {
+        builder.set_not_before(&not_after).unwrap();
+    }
+    builder.set_not_after(&not_after).unwrap();
+    add_smime_extensions(&mut builder, email, None);
+    builder.sign(&pkey, MessageDigest::sha256()).unwrap();
+    (builder.build(), pkey)
+}
+
+/// A certificate authority issuing user certificates.
+pub struct TestCa {
+    pub cert: X509,
+    pub key: PKey<Private>,
+}
+
+impl TestCa {
+    /// Generate a new self-signed root.
+    pub fn new(name: &str) -> Self {
+        Self::with_key(name, generate_key())
+    }
+
+    /// Generate a new self-signed root with `key`, of any type.
+    pub fn with_key(name: &str, key: PKey<Private>) -> Self {
+        let mut builder = certificate_builder(name, &key);
+        builder
+            .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+            .unwrap();
+        builder
+            .append_extension(
+                KeyUsage::new()
+                    .critical()
+                    .key_cert_sign()
+                    .crl_sign()
+                    .build()
+                    .unwrap(),
+            )
+            .unwrap();
+        builder.sign(&key, digest_for(&key)).unwrap();
+        Self {
+            cert: builder.build(),
+            key,
+        }
+    }
+
+    /// Issue an S/MIME certificate for the given email.
+    pub fn issue(&self, email: &str) -> (X509, PKey<Private>) {
+        self.issue_with_key(email, generate_key())
+    }
+
+    /// Issue an S/MIME certificate for the given email with `pkey`, of any type.
+    pub fn issue_with_key(&self, email: &str, pkey: PKey<Private>) -> (X509, PKey<Private>) {
+        let mut builder = certificate_builder(email, &pkey);
+        builder.set_issuer_name(self.cert.subject_name()).unwrap();
+        add_smime_extensions(&mut builder, email, Some(&self.cert));
+        builder.sign(&self.key, digest_for(&self.key)).unwrap();
+        (builder.build(), pkey)
+    }
+}
+
+/// Build a clear-signed multipart/signed message the way common MUAs do.
+pub fn signed_message(cert: &X509, key: &PKey<Private>, from: &str, text: &str) -> Vec<u8> {
+    let content = text_content(text);
+    let signature = match key.id() {
+        Id::RSA | Id::EC => {
+            let certs = Stack::new().unwrap();
+            Pkcs7::sign(
+                cert,
+                key,
+                &certs,
+                content.as_bytes(),
+                Pkcs7Flags::DETACHED | Pkcs7Flags::BINARY,
+            )
+            .unwrap()
+            .to_der()
+            .unwrap()
+        }
+        // PKCS#7 can't sign with them, and CMS in OpenSSL 3.0 has no way to pick the digest
+        // EdDSA requires.
+        Id::ED25519 | Id::ED448 => eddsa_signature(cert, key, content.as_bytes()),
+        _ => CmsContentInfo::sign(
+            Some(cert),
+            Some(key),
+            None,
+            Some(content.as_bytes()),
+            CMSOptions::DETACHED | CMSOptions::BINARY,
+        )
+        .unwrap()
+        .to_der()
+        .unwrap(),
+    };
+    multipart_signed(from, &content, &signature)
+}
+
+fn text_content(text: &str) -> String {
+    format!(
+        "Content-Type: text/plain; charset=utf-8\r\n\r\n{}\r\n",
+        text
+    )
+}
+
+/// Wrap content and its detached signature into a multipart/signed message.
+fn multipart_signed(from: &str, content: &str, signature: &[u8]) -> Vec<u8> {
+    let boundary = "----=_signed_boundary";
+    let mut signature = BASE64_STANDARD.encode(signature);
+    let mut wrapped = String::new();
+    while !signature.is_empty() {
+        let rest = signature.split_off(signature.len().min(76));
+        wrapped.push_str(&signature);
+        wrapped.push_str("\r\n");
+        signature = rest;
+    }
+
+    format!(
+        "From: {from}\r\n\
+         Message-ID: <signed@test.example>\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/signed; protocol=\"application/pkcs7-signature\"; micalg=sha-256; boundary=\"{boundary}\"\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         {content}\
+         --{boundary}\r\n\
+         Content-Type: application/pkcs7-signature; name=smime.p7s\r\n\
+         Content-Transfer-Encoding: base64\r\n\
+         \r\n\
+         {wrapped}\
+         --{boundary}--\r\n"
+    )
+    .into_bytes()
+}
+
+/// DER encode an unsigned big-endian number as INTEGER.
+pub fn der_integer(unsigned: &[u8]) -> Vec<u8> {
+    if unsigned.first().is_some_and(|b| b & 0x80 != 0) {
+        der(0x02, &[&[0], unsigned].concat())
+    } else {
+        der(0x02, unsigned)
+    }
+}
+
+/// DER encode an SMIMEEncryptionKeyPreference attribute naming `cert` by issuer and serial.
+pub fn key_preference_attribute(cert: &X509) -> Vec<u8> {
+    let serial = cert.serial_number().to_bn().unwrap().to_vec();
+    let issuer_serial = [cert.issuer_name().to_der().unwrap(), der_integer(&serial)].concat();
+    der(
+        0x30,
+        &[
+            der(
+                0x06,
+                &[
+                    0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x10, 0x02, 0x0b,
+                ],
+            ),
+            der(0x31, &der(0xa0, &issuer_serial)),
+        ]
+        .concat(),
+    )
+}
+
+/// Build a PKCS#7 signature carrying the given DER encoded signed attributes, as signed by
+/// `signer` but with a bogus signature value. OpenSSL offers no way to add attributes.
+pub fn signature_with_attributes(certs: &[&X509], signer: &X509, attributes: &[u8]) -> Vec<u8> {
+    let sha256 = der(
+        0x30,
+        &[
+            der(
+                0x06,
+                &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01],
+            ),
+            der(0x05, &[]),
+        ]
+        .concat(),
+    );
+    let rsa = der(
+        0x30,
+        &[
+            der(
+                0x06,
+                &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01],
+            ),
+            der(0x05, &[]),
+        ]
+        .concat(),
+    );
+    signed_data(certs, signer, &sha256, Some(attributes), &rsa, &[0; 256])
+}
+
+/// Build a detached EdDSA signature of `content`, without signed attributes, like RFC 8419
+/// allows.
+fn eddsa_signature(cert: &X509, key: &PKey<Private>, content: &[u8]) -> Vec<u8> {
+    let (digest, algorithm): (&[u8], &[u8]) = match key.id() {
+        // SHA-512 and Ed25519
+        Id::ED25519 => (
+            &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03],
+            &[0x2b, 0x65, 0x70],
+        ),
+        // SHAKE256 and Ed448
+        _ => (
+            &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x0c],
+            &[0x2b, 0x65, 0x71],
+        ),
+    };
+    let signature = Signer::new_without_digest(key)
+        .unwrap()
+        .sign_oneshot_to_vec(content)
+        .unwrap();
+    signed_data(
+        &[cert],
+        cert,
+        &der(0x30, &der(0x06, digest)),
+        None,
+        &der(0x30, &der(0x06, algorithm)),
+        &signature,
+    )
+}
+
+/// DER encode a detached PKCS#7 signature with a single signer, named by issuer and serial.
+fn signed_data(
+    certs: &[&X509],
+    signer: &X509,
+    digest: &[u8],
+    attributes: Option<&[u8]>,
+    algorithm: &[u8],
+    signature: &[u8],
+) -> Vec<u8> {
+    let oid = |encoded: &[u8]| der(0x06, encoded);
+    let serial = signer.serial_number().to_bn().unwrap().to_vec();
+    let signer_info = der(
+        0x30,
+        &[
+            der_integer(&[1]),
+            der(
+                0x30,
+                &[signer.issuer_name().to_der().unwrap(), der_integer(&serial)].concat(),
+            ),
+            digest.to_vec(),
+            attributes.map(|a| der(0xa0, a)).unwrap_or_default(),
+            algorithm.to_vec(),
+            der(0x04, signature),
+        ]
+        .concat(),
+    );
+    let certs: Vec<u8> = certs.iter().flat_map(|c| c.to_der().unwrap()).collect();
+    let signed_data = der(
+        0x30,
+        &[
+            der_integer(&[1]),
+            der(0x31, digest),
+            der(
+                0x30,
+                &oid(&[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x01]),
+            ),
+            der(0xa0, &certs),
+            der(0x31, &signer_info),
+        ]
+        .concat(),
+    );
+    der(
+        0x30,
+        &[
+            oid(&[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x02]),
+            der(0xa0, &signed_data),
+        ]
+        .concat(),
+    )
+}
+
+/// Build a multipart/signed message with a signature from [`signature_with_attributes`].
+pub fn signed_message_with_attributes(
+    certs: &[&X509],
+    signer: &X509,
+    attributes: &[u8],
+    from: &str,
+    text: &str,
+) -> Vec<u8> {
+    let signature = signature_with_attributes(certs, signer, attributes);
+    multipart_signed(from, &text_content(text), &signature)
+}
+
+/// Build an `application/pkcs7-mime; smime-type=certs-only` message, a degenerate signed data
+/// without signers as MUAs send to publish certificates.
+pub fn certs_only_message(certs: &[&X509], from: &str, to: &str) -> Vec<u8> {
+    let oid = |encoded: &[u8]| der(0x06, encoded);
+    let certs: Vec<u8> = certs.iter().flat_map(|c| c.to_der().unwrap()).collect();
+    let signed_data = der(
+        0x30,
+        &[
+            der_integer(&[1]),
+            der(0x31, &[]),
+            der(
+                0x30,
+                &oid(&[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x01]),
+            ),
+            der(0xa0, &certs),
+            der(0x31, &[]),
+        ]
+        .concat(),
+    );
+    let content_info = der(
+        0x30,
+        &[
+            oid(&[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x02]),
+            der(0xa0, &signed_data),
+        ]
+        .concat(),
+    );
+    format!(
+        "From: {from}\r\n\
+         To: {to}\r\n\
+         Message-ID: <certs@test.example>\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: application/pkcs7-mime; smime-type=certs-only; name=smime.p7c\r\n\
+         Content-Transfer-Encoding: base64\r\n\
+         \r\n\
+         {}\r\n",
+        BASE64_STANDARD.encode(content_info)
+    )
+    .into_bytes()
+}
@@ -0,0 +1,143 @@
+//! Content transfer encoding of generated bodies.
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use bytes::BytesMut;
+
+/// Wrap the buffer in place into lines of at most `wrap_at` bytes, separated by CRLF.
+pub fn wrap_bytes_crlf(buf: &mut BytesMut, wrap_at: usize) {
+    let line_ending = line_wrap::crlf();
+    let len = buf.len();
+    let mut additional_len = (len / wrap_at) * 2;
+    if len.is_multiple_of(wrap_at) {
+        additional_len -= 2;
+    }
+    buf.resize(len + additional_len, 0);
+    line_wrap::line_wrap(buf, len, wrap_at, &line_ending);
+}
+
+/// Base64 encode the data and wrap it into CRLF separated lines of `wrap_at` characters.
+pub fn encode_base64_wrapped(data: &[u8], wrap_at: usize) -> BytesMut {
+    let encoded = BASE64_STANDARD.encode(data);
+    let mut wrapped = BytesMut::from(encoded.as_bytes());
+    wrap_bytes_crlf(&mut wrapped, wrap_at);
+    wrapped
+}
+
+/// Longest base64 line allowed by RFC 2045.
+const MAX_LINE_LENGTH: usize = 76;
+
+/// Content transfer encoding of the encrypted envelope.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EnvelopeEncoding {
+    /// Base64, wrapped at the given line length.
+    Base64 { line_length: usize },
+    /// Raw DER, requires BINARYMIME on every hop.
+    Binary,
+}
+
+impl Default for EnvelopeEncoding {
+    fn default() -> Self {
+        Self::Base64 {
+            line_length: MAX_LINE_LENGTH,
+        }
+    }
+}
+
+impl EnvelopeEncoding {
+    /// Value of the Content-Transfer-Encoding header.
+    pub fn header_value(&self) -> &'static str {
+        match self {
+            Self::Base64 { .. } => "base64",
+            Self::Binary => "binary",
+        }
+    }
+
+    /// Length of an envelope of `len` bytes once encoded.
+    pub fn encoded_len(&self, len: usize) -> usize {
+        match self {
+            Self::Base64 { line_length } => {
+                let encoded = len.div_ceil(3) * 4;
+                encoded + encoded.div_ceil(*line_length).saturating_sub(1) * 2
+            }
+            Self::Binary => len,
+        }
+    }
+
+    /// Encode the envelope body.
+    pub fn encode(&self, data: &[u8]) -> BytesMut {
+        match self {
+            Self::Base64 { line_length } => encode_base64_wrapped(data, *line_length),
+            Self::Binary => BytesMut::from(data),
+        }
+    }
+}
+
+/// Parse a base64 line length, which has to be a multiple of 4 of at most 76
+/// characters, so lines end on whole groups and stay within RFC 2045.
+pub fn parse_line_length(s: &str) -> Result<usize, String> {
+    let length: usize = s.parse().map_err(|e| format!("{}", e))?;
+    if length == 0 || length > MAX_LINE_LENGTH || !length.is_multiple_of(4) {
+        return Err(format!(
+            "{} is not a multiple of 4 between 4 and {}",
+            length, MAX_LINE_LENGTH
+        ));
+    }
+    Ok(length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_wrap() {
+        let mut data = BytesMut::from("testtest".as_bytes());
+        wrap_bytes_crlf(&mut data, 4);
+        assert_eq!(data, BytesMut::from("test\r\ntest"));
+
+        data = BytesMut::from("testtest".as_bytes());
+        wrap_bytes_crlf(&mut data, 6);
+        assert_eq!(data, BytesMut::from("testte\r\nst"));
+    }
+
+    #[test]
+    fn test_encode_base64_wrapped() {
+        let wrapped = encode_base64_wrapped(&[0u8; 60], 76);
+        let lines: Vec<&[u8]> = wrapped[..].split(|b| *b == b'\n').collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].len(), 77);
+        assert!(lines[0].ends_with(b"\r"));
+        assert_eq!(lines[1].len(), 4);
+    }
+
+    #[test]
+    fn test_envelope_encoding() {
+        let data = [0u8; 60];
+        let encoded = EnvelopeEncoding::Base64 { line_length: 64 }.encode(&data);
+        assert_eq!(encoded.iter().position(|b| *b == b'\r'), Some(64));
+        assert_eq!(EnvelopeEncoding::default().encode(&data).len(), 80 + 2);
+        assert_eq!(EnvelopeEncoding::Binary.encode(&data), &data[..]);
+        for encoding in [
+            EnvelopeEncoding::default(),
+            EnvelopeEncoding::Base64 { line_length: 64 },
+            EnvelopeEncoding::Binary,
+        ] {
+            for len in [1, 57, 58, 100, 1000] {
+                let encoded = encoding.encode(&vec![0u8; len]);
+                assert_eq!(
+                    encoding.encoded_len(len),
+                    encoded.len(),
+                    "{:?} {}",
+                    encoding,
+                    len
+                );
+            }
+        }
+
+        assert_eq!(parse_line_length("64"), Ok(64));
+        assert_eq!(parse_line_length("76"), Ok(76));
+        for invalid in ["0", "66", "80", "-4", "x"] {
+            assert!(parse_line_length(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Trust anchors for certificates harvested from or enrolled by senders at a domain, like a
+//! partner's private PKI for `partner.example` and the public CAs for `*`.
+//!
+//! Senders at domains without trust anchors are trusted on first use, as before.
+
+use anyhow::{bail, Context, Result};
+use openssl::stack::Stack;
+use openssl::x509::store::{X509Store, X509StoreBuilder};
+use openssl::x509::{X509StoreContext, X509};
+use std::path::{Path, PathBuf};
+
+use crate::address;
+use crate::settings;
+use crate::smime;
+
+/// CA certificates the chains of senders at matching domains must lead to.
+pub struct TrustAnchors {
+    /// Domain pattern, `example.com`, `*.example.com` or `*`.
+    pub domain: String,
+    path: PathBuf,
+    store: X509Store,
+}
+
+/// Parse `<DOMAIN>=<CA FILE>`.
+pub fn parse_trust_anchors(s: &str) -> Result<(String, PathBuf), String> {
+    match s.split_once('=') {
+        Some((domain, path)) if !path.is_empty() => {
+            Ok((settings::parse_domain_pattern(domain)?, PathBuf::from(path)))
+        }
+        _ => Err(format!("expected <DOMAIN>=<CA FILE>, got {:?}", s)),
+    }
+}
+
+impl TrustAnchors {
+    /// Load the PEM certificates of a CA bundle.
+    pub fn load(domain: String, path: &Path) -> Result<Self> {
+        let data =
+            std::fs::read(path).with_context(|| format!("Failed to read CA bundle {:?}", path))?;
+        let certs = X509::stack_from_pem(&data)
+            .with_context(|| format!("Failed to parse CA bundle {:?}", path))?;
+        if certs.is_empty() {
+            bail!("CA bundle {:?} holds no certificates", path);
+        }
+        let mut builder = X509StoreBuilder::new()?;
+        for cert in certs {
+            builder.add_cert(cert)?;
+        }
+        Ok(Self {
+            domain,
+            path: path.to_path_buf(),
+            store: builder.build(),
+        })
+    }
+
+    /// Check that the certificates of `owner` in `chain` lead to one of the anchors, with the
+    /// other certificates as intermediates.
+    pub fn verify(&self, chain: &[X509], owner: &str) -> Result<()> {
+        let mut intermediates = Stack::new()?;
+        for cert in chain {
+            intermediates.push(cert.clone())?;
+        }
+        let owned: Vec<&X509> = chain
+            .iter()
+            .filter(|cert| smime::find_cert_for_email([cert], owner).is_ok())
+            .collect();
+        if owned.is_empty() {
+            bail!("No certificate for {}", owner);
+        }
+        for cert in owned {
+            let mut context = X509StoreContext::new()?;
+            let error = context.init(&self.store, cert, &intermediates, |c| {
+                Ok(match c.verify_cert()? {
+                    true => None,
+                    false => Some(c.error()),
+                })
+            })?;
+            if let Some(error) = error {
+                bail!(
+                    "Certificate of {} is not issued by a CA in {:?}: {}",
+                    owner,
+                    self.path,
+                    error.error_string()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The trust anchors for senders like `email`, first matching domain wins.
+pub fn anchors_for<'a>(anchors: &'a [TrustAnchors], email: &str) -> Option<&'a TrustAnchors> {
+    let domain = email.rsplit_once('@').map_or("", |(_, domain)| domain);
+    anchors
+        .iter()
+        .find(|anchors| address::domain_matches(&anchors.domain, domain))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_pki::{self_signed_identity, TestCa};
+
+    #[test]
+    fn test_verify() {
+        let partner = TestCa::new("Partner CA");
+        let other = TestCa::new("Other CA");
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("partner.pem");
+        std::fs::write(&path, partner.cert.to_pem().unwrap()).unwrap();
+        let anchors = [TrustAnchors::load("partner.example".into(), &path).unwrap()];
+
+        let anchors = anchors_for(&anchors, "a@partner.example").unwrap();
+        let (cert, _) = partner.issue("a@partner.example");
+        anchors.verify(&[cert], "a@partner.example").unwrap();
+        let (cert, _) = other.issue("a@partner.example");
+        assert!(anchors
+            .verify(&[cert, other.cert.clone()], "a@partner.example")
+            .is_err());
+        let (cert, _) = self_signed_identity("a@partner.example");
+        assert!(anchors.verify(&[cert], "a@partner.example").is_err());
+
+        assert!(anchors_for(&[], "a@partner.example").is_none());
+        assert!(parse_trust_anchors("partner.example").is_err());
+        assert_eq!(
+            parse_trust_anchors("*=/etc/ssl/certs/ca-bundle.crt"),
+            Ok(("*".into(), PathBuf::from("/etc/ssl/certs/ca-bundle.crt")))
+        );
+    }
+}
@@ -0,0 +1,64 @@
+//! Golden-file tests of the complete rewritten message, run through the `replay` subcommand of
+//! the daemon with deterministic randomness, so any change of the serialization shows up
+//! byte for byte.
+//!
+//! After an intended change, or an OpenSSL update consuming randomness differently, regenerate
+//! the expected files with `PANTOSMIME_UPDATE_GOLDEN=1 cargo test --test golden`.
+
+#![cfg(feature = "replay")]
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+fn replay(input: &Path) -> Vec<u8> {
+    let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("data");
+    let cert_dir = tempfile::tempdir().unwrap();
+    std::fs::copy(
+        data.join("golden/b@example.com.pem"),
+        cert_dir.path().join("b@example.com.pem"),
+    )
+    .unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_pantosmimed"))
+        .arg("-c")
+        .arg(cert_dir.path())
+        .args(["--address=a@example.com", "--deterministic-seed", "1"])
+        .args(["replay", "--from", "a@example.com", "--to", "b@example.com"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let message = std::fs::read(input).unwrap();
+    child.stdin.take().unwrap().write_all(&message).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    output.stdout
+}
+
+fn check(name: &str) {
+    let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("data");
+    let output = replay(&data.join("mime").join(format!("{}.eml", name)));
+    let expected_path = data.join("golden").join(format!("{}.encrypted.eml", name));
+    if std::env::var_os("PANTOSMIME_UPDATE_GOLDEN").is_some() {
+        std::fs::write(&expected_path, &output).unwrap();
+    }
+    let expected = std::fs::read(&expected_path).unwrap();
+    assert!(
+        output == expected,
+        "{} differs from {:?}:\n{}",
+        name,
+        expected_path,
+        String::from_utf8_lossy(&output)
+    );
+}
+
+#[test]
+fn test_golden_simple() {
+    check("simple_email");
+}
+
+#[test]
+fn test_golden_multipart() {
+    check("multipart_example");
+}